@@ -0,0 +1,521 @@
+//! Static recovery of the control-flow graph of an assembled program.
+//!
+//! Jumps take their target from a register, so a target is only known when the register
+//! holds a constant: loaded earlier in the same basic block, or loaded with the same value
+//! everywhere the program writes it. Any other jump leads to `Successor::Unknown`.
+//!
+//! `BlockTransitions` complements the graph with the jumps a run actually took.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Write,
+    ops::Range,
+};
+
+use crate::{
+    assembler::{
+        assembler::{SymbolTable, SymbolType},
+        container::{self, SectionKind},
+    },
+    instruction::{disassemble, Format, Opcode, INSTRUCTION_LENGTH},
+    vm::VECTOR_WIDTH,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Successor {
+    /// The basic block starting at this address.
+    Block(usize),
+    Unknown,
+}
+
+/// A run of instructions only entered at the top and only left at the bottom.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub instructions: Vec<[u8; INSTRUCTION_LENGTH]>,
+    /// Empty when the block halts or runs off the end of the code.
+    pub successors: Vec<Successor>,
+}
+
+impl BasicBlock {
+    pub fn end(&self) -> usize {
+        self.start + self.instructions.len() * INSTRUCTION_LENGTH
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlFlowGraph {
+    /// Ordered by address, the first one is the entry point.
+    pub blocks: Vec<BasicBlock>,
+    labels: BTreeMap<usize, String>,
+}
+
+impl ControlFlowGraph {
+    pub fn from_program(program: &[u8]) -> Result<Self, String> {
+        if container::read_header(program)?.format != Format::Narrow {
+            return Err("Only programs with 4 byte instructions can be analyzed".to_string());
+        }
+        let sections = container::read_sections(program)?;
+        let code = container::code_section(&sections).ok_or("Program has no code section")?;
+        let instructions: Vec<(usize, [u8; INSTRUCTION_LENGTH])> = code
+            .contents(program)
+            .chunks_exact(INSTRUCTION_LENGTH)
+            .enumerate()
+            .map(|(index, bytes)| {
+                let pc = code.offset + index * INSTRUCTION_LENGTH;
+                (pc, bytes.try_into().expect("chunks are instruction sized"))
+            })
+            .collect();
+        let code_range = code.offset..code.offset + instructions.len() * INSTRUCTION_LENGTH;
+
+        let mut labels = BTreeMap::new();
+        for section in sections.iter().filter(|s| s.kind == SectionKind::Symbols) {
+            for symbol in SymbolTable::from_bytes(section.contents(program))?.iter() {
+                if *symbol.symbol_type() == SymbolType::Label {
+                    labels.insert(symbol.address() as usize, symbol.name().to_string());
+                }
+            }
+        }
+
+        // Resolving a jump can split a block, which can change what is known about the
+        // registers in it, so keep going until the set of block starts settles
+        let constants = program_constants(&instructions);
+        let mut leaders: BTreeSet<usize> = BTreeSet::from([code_range.start]);
+        leaders.extend(
+            instructions
+                .iter()
+                .filter(|(_, instruction)| ends_block(instruction))
+                .map(|(pc, _)| pc + INSTRUCTION_LENGTH),
+        );
+        loop {
+            let successors = successors(&instructions, &leaders, &constants, &code_range);
+            let targets: Vec<usize> = successors
+                .values()
+                .flatten()
+                .filter_map(|successor| match successor {
+                    Successor::Block(start) => Some(*start),
+                    Successor::Unknown => None,
+                })
+                .filter(|start| !leaders.contains(start))
+                .collect();
+            if targets.is_empty() {
+                let blocks = build_blocks(&instructions, &leaders, &successors);
+                return Ok(Self { blocks, labels });
+            }
+            leaders.extend(targets);
+        }
+    }
+
+    /// Jumps whose target could not be worked out.
+    pub fn unresolved(&self) -> usize {
+        self.blocks
+            .iter()
+            .flat_map(|block| &block.successors)
+            .filter(|successor| **successor == Successor::Unknown)
+            .count()
+    }
+
+    /// The block holding the instruction at `pc`.
+    pub fn block_at(&self, pc: usize) -> Option<&BasicBlock> {
+        let index = self.blocks.partition_point(|block| block.start <= pc);
+        self.blocks[..index].last().filter(|block| pc < block.end())
+    }
+
+    /// Renders the graph in Graphviz DOT, one node per block listing its instructions.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph cfg {\n");
+        self.write_nodes(&mut out);
+        for block in &self.blocks {
+            for successor in &block.successors {
+                match successor {
+                    Successor::Block(start) => {
+                        let _ = writeln!(out, "  b{} -> b{start};", block.start);
+                    }
+                    Successor::Unknown => {
+                        let _ = writeln!(out, "  b{} -> unknown;", block.start);
+                    }
+                }
+            }
+        }
+        if self.unresolved() > 0 {
+            out.push_str("  unknown [shape=ellipse, label=\"?\"];\n");
+        }
+        out.push_str("}\n");
+
+        out
+    }
+
+    fn write_nodes(&self, out: &mut String) {
+        out.push_str("  node [shape=box, fontname=\"monospace\"];\n");
+        for block in &self.blocks {
+            let mut label = format!("{}", block.start);
+            if let Some(name) = self.labels.get(&block.start) {
+                let _ = write!(label, ": {name}");
+            }
+            label.push_str("\\l");
+            for instruction in &block.instructions {
+                let _ = write!(label, "  {}\\l", disassemble(instruction));
+            }
+            let _ = writeln!(out, "  b{} [label=\"{label}\"];", block.start);
+        }
+    }
+}
+
+/// Counts how often a run moved from one basic block to another, fed one program counter
+/// per executed instruction, e.g. from `VM::run_with`.
+#[derive(Debug, Clone)]
+pub struct BlockTransitions {
+    cfg: ControlFlowGraph,
+    // (from, to) block starts
+    counts: BTreeMap<(usize, usize), u64>,
+    // Block and pc of the previous instruction
+    last: Option<(usize, usize)>,
+}
+
+impl BlockTransitions {
+    pub fn new(cfg: ControlFlowGraph) -> Self {
+        Self {
+            cfg,
+            counts: BTreeMap::new(),
+            last: None,
+        }
+    }
+
+    /// Records that the instruction at `pc` is about to run. A block is entered when the
+    /// program reaches its start, or anywhere in it by a jump.
+    pub fn record(&mut self, pc: usize) {
+        let Some(block) = self.cfg.block_at(pc).map(|block| block.start) else {
+            return;
+        };
+        if let Some((from, last_pc)) = self.last {
+            if pc == block || pc != last_pc + INSTRUCTION_LENGTH {
+                *self.counts.entry((from, block)).or_default() += 1;
+            }
+        }
+        self.last = Some((block, pc));
+    }
+
+    /// Times the run went from the block starting at `from` to the one at `to`.
+    pub fn count(&self, from: usize, to: usize) -> u64 {
+        self.counts.get(&(from, to)).copied().unwrap_or_default()
+    }
+
+    /// Renders the graph in Graphviz DOT with every edge labelled by how often it was
+    /// taken. Edges the static analysis did not predict are red, predicted ones never
+    /// taken are dotted.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph flow {\n");
+        self.cfg.write_nodes(&mut out);
+        for block in &self.cfg.blocks {
+            for successor in &block.successors {
+                if let Successor::Block(to) = *successor {
+                    if self.count(block.start, to) == 0 {
+                        let _ = writeln!(out, "  b{} -> b{to} [style=dotted];", block.start);
+                    }
+                }
+            }
+        }
+        for (&(from, to), &count) in &self.counts {
+            let predicted = self
+                .cfg
+                .block_at(from)
+                .is_some_and(|block| block.successors.contains(&Successor::Block(to)));
+            let colour = if predicted { "" } else { ", color=red" };
+            let _ = writeln!(
+                out,
+                "  b{from} -> b{to} [label=\"{count}\", penwidth={}{colour}];",
+                1 + count.ilog10()
+            );
+        }
+        out.push_str("}\n");
+
+        out
+    }
+}
+
+fn ends_block(instruction: &[u8]) -> bool {
+    let opcode = Opcode::from(instruction[0]);
+    is_jump(opcode) || matches!(opcode, Opcode::HLT | Opcode::CALL | Opcode::RET)
+}
+
+fn is_jump(opcode: Opcode) -> bool {
+    matches!(opcode, Opcode::JMP | Opcode::JMPF | Opcode::JMPB) || is_branch(opcode)
+}
+
+fn is_branch(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::JEQ
+            | Opcode::JNEQ
+            | Opcode::JLT
+            | Opcode::JGT
+            | Opcode::JLE
+            | Opcode::JGE
+            | Opcode::JZ
+            | Opcode::JNZ
+            | Opcode::JC
+            | Opcode::JO
+    ) || is_compare_and_branch(opcode)
+}
+
+fn is_compare_and_branch(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::BEQ | Opcode::BNE | Opcode::BGT | Opcode::BLT | Opcode::BGE | Opcode::BLE
+    )
+}
+
+// The registers an instruction overwrites, and the constant it loads into them if any
+fn writes(instruction: &[u8]) -> (Range<usize>, Option<i32>) {
+    let register = |index: usize| instruction[index] as usize;
+    let value = u16::from_be_bytes([instruction[2], instruction[3]]);
+    match Opcode::from(instruction[0]) {
+        Opcode::LOAD | Opcode::LOADU => (register(1)..register(1) + 1, Some(value as i32)),
+        Opcode::LOADS => (register(1)..register(1) + 1, Some(value as i16 as i32)),
+        Opcode::LUI => (
+            register(1)..register(1) + 1,
+            Some(((value as u32) << 16) as i32),
+        ),
+        Opcode::ADD
+        | Opcode::SUB
+        | Opcode::MUL
+        | Opcode::DIV
+        | Opcode::MOD
+        | Opcode::SEQ
+        | Opcode::SNE
+        | Opcode::SLT
+        | Opcode::SLE
+        | Opcode::AND
+        | Opcode::OR
+        | Opcode::XOR => (register(3)..register(3) + 1, None),
+        Opcode::NOT | Opcode::MOV => (register(2)..register(2) + 1, None),
+        Opcode::VADD | Opcode::VMUL => (register(3)..register(3) + VECTOR_WIDTH, None),
+        Opcode::INC
+        | Opcode::DEC
+        | Opcode::SHL
+        | Opcode::SHR
+        | Opcode::SHLI
+        | Opcode::SHRI
+        | Opcode::FUEL
+        | Opcode::MFLAGS
+        | Opcode::CMOV
+        | Opcode::RDPERF
+        | Opcode::RDCYCLE
+        | Opcode::LW
+        | Opcode::LB
+        | Opcode::ORI
+        | Opcode::LOADC => (register(1)..register(1) + 1, None),
+        _ => (0..0, None),
+    }
+}
+
+// Registers that only ever receive one constant
+fn program_constants(instructions: &[(usize, [u8; INSTRUCTION_LENGTH])]) -> HashMap<usize, i32> {
+    let mut candidates: HashMap<usize, Option<i32>> = HashMap::new();
+    for (_, instruction) in instructions {
+        let (registers, value) = writes(instruction);
+        for register in registers {
+            let candidate = candidates.entry(register).or_insert(value);
+            if *candidate != value {
+                *candidate = None;
+            }
+        }
+    }
+
+    candidates
+        .into_iter()
+        .filter_map(|(register, value)| Some((register, value?)))
+        .collect()
+}
+
+// Successors of every instruction that ends a block, keyed by its address
+fn successors(
+    instructions: &[(usize, [u8; INSTRUCTION_LENGTH])],
+    leaders: &BTreeSet<usize>,
+    constants: &HashMap<usize, i32>,
+    code: &Range<usize>,
+) -> BTreeMap<usize, Vec<Successor>> {
+    let mut successors = BTreeMap::new();
+    let mut known = constants.clone();
+
+    for &(pc, instruction) in instructions {
+        if leaders.contains(&pc) {
+            known = constants.clone();
+        }
+
+        let opcode = Opcode::from(instruction[0]);
+        let fallthrough = pc + INSTRUCTION_LENGTH;
+        let to_successor = |target: Option<usize>| match target {
+            Some(target)
+                if code.contains(&target)
+                    && (target - code.start).is_multiple_of(INSTRUCTION_LENGTH) =>
+            {
+                Successor::Block(target)
+            }
+            _ => Successor::Unknown,
+        };
+        if opcode == Opcode::HLT {
+            successors.insert(pc, Vec::new());
+        } else if opcode == Opcode::RET {
+            // Returns go wherever the matching CALL came from
+            successors.insert(pc, vec![Successor::Unknown]);
+        } else if opcode == Opcode::CALL {
+            let target = u16::from_be_bytes([instruction[1], instruction[2]]) as usize;
+            let mut next = vec![to_successor(Some(target))];
+            if code.contains(&fallthrough) {
+                next.push(Successor::Block(fallthrough));
+            }
+            successors.insert(pc, next);
+        } else if is_jump(opcode) {
+            // Compare-and-branch keeps its target after the two compared registers
+            let register = if is_compare_and_branch(opcode) {
+                instruction[3]
+            } else {
+                instruction[1]
+            };
+            let value = known.get(&(register as usize)).copied();
+            // Relative jumps are taken from just after the register operand
+            let target = match opcode {
+                Opcode::JMPF => value.and_then(|v| (pc + 2).checked_add_signed(v as isize)),
+                Opcode::JMPB => value.and_then(|v| (pc + 2).checked_add_signed(-(v as isize))),
+                _ => value.and_then(|v| usize::try_from(v).ok()),
+            };
+            let mut next = vec![to_successor(target)];
+            if is_branch(opcode) && code.contains(&fallthrough) {
+                next.push(Successor::Block(fallthrough));
+            }
+            successors.insert(pc, next);
+        }
+
+        let (registers, value) = writes(&instruction);
+        for register in registers {
+            match value {
+                Some(value) => known.insert(register, value),
+                None => known.remove(&register),
+            };
+        }
+    }
+
+    successors
+}
+
+fn build_blocks(
+    instructions: &[(usize, [u8; INSTRUCTION_LENGTH])],
+    leaders: &BTreeSet<usize>,
+    successors: &BTreeMap<usize, Vec<Successor>>,
+) -> Vec<BasicBlock> {
+    let mut blocks: Vec<BasicBlock> = Vec::new();
+    for &(pc, instruction) in instructions {
+        if leaders.contains(&pc) || blocks.is_empty() {
+            // A block that runs into the next one falls through to it
+            if let Some(previous) = blocks.last_mut() {
+                if previous.successors.is_empty()
+                    && !ends_block(previous.instructions.last().unwrap())
+                {
+                    previous.successors.push(Successor::Block(pc));
+                }
+            }
+            blocks.push(BasicBlock {
+                start: pc,
+                instructions: Vec::new(),
+                successors: Vec::new(),
+            });
+        }
+
+        let block = blocks.last_mut().expect("a block was just started");
+        block.instructions.push(instruction);
+        if let Some(next) = successors.get(&pc) {
+            block.successors = next.clone();
+        }
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        analyze::{BlockTransitions, ControlFlowGraph, Successor},
+        assembler::assembler::Assembler,
+        vm::{ExitReason, VM},
+    };
+
+    fn cfg(source: &str) -> ControlFlowGraph {
+        let program = Assembler::new().assemble(source).unwrap();
+        ControlFlowGraph::from_program(&program).unwrap()
+    }
+
+    fn starts(cfg: &ControlFlowGraph) -> Vec<usize> {
+        cfg.blocks.iter().map(|block| block.start).collect()
+    }
+
+    #[test]
+    fn test_straight_line() {
+        let cfg = cfg("load $0 #1\ninc $0\nhlt");
+        assert_eq!(starts(&cfg), vec![64]);
+        assert_eq!(cfg.blocks[0].instructions.len(), 3);
+        assert!(cfg.blocks[0].successors.is_empty());
+    }
+
+    #[test]
+    fn test_loop_with_branch() {
+        // Counts $1 up to 5
+        let cfg = cfg("load $0 #72\nload $2 #5\nloop: inc $1\neq $1 $2\njneq $0\nhlt");
+        assert_eq!(starts(&cfg), vec![64, 72, 84]);
+        assert_eq!(cfg.blocks[0].successors, vec![Successor::Block(72)]);
+        assert_eq!(
+            cfg.blocks[1].successors,
+            vec![Successor::Block(72), Successor::Block(84)]
+        );
+        assert!(cfg.blocks[2].successors.is_empty());
+        assert_eq!(cfg.unresolved(), 0);
+
+        let dot = cfg.to_dot();
+        assert!(dot.contains("b72 [label=\"72: loop\\l  inc $1\\l  eq $1 $2\\l  jneq $0\\l\"];"));
+        assert!(dot.contains("b72 -> b84;"));
+        assert!(!dot.contains("unknown"));
+    }
+
+    #[test]
+    fn test_local_constants_and_unknown_targets() {
+        // $0 is loaded twice, so only the load in the same block resolves the first jump
+        let cfg = cfg("load $0 #72\njmp $0\nload $0 #64\ninc $0\njmp $0");
+        assert_eq!(starts(&cfg), vec![64, 72]);
+        assert_eq!(cfg.blocks[0].successors, vec![Successor::Block(72)]);
+        assert_eq!(cfg.blocks[1].successors, vec![Successor::Unknown]);
+        assert_eq!(cfg.unresolved(), 1);
+        assert!(cfg.to_dot().contains("b72 -> unknown;"));
+    }
+
+    #[test]
+    fn test_relative_jump() {
+        // JMPF is taken from pc + 2: 70 + 10 lands on the HLT at 80
+        let cfg = cfg("load $0 #10\njmpf $0\ninc $1\ninc $1\nhlt");
+        assert_eq!(starts(&cfg), vec![64, 72, 80]);
+        assert_eq!(cfg.blocks[0].successors, vec![Successor::Block(80)]);
+        assert_eq!(cfg.blocks[1].successors, vec![Successor::Block(80)]);
+    }
+
+    #[test]
+    fn test_block_transitions() {
+        // The last jump lands on the HLT through a computed target the static analysis cannot see
+        let source =
+            "load $0 #72\nload $2 #3\nloop: inc $1\neq $1 $2\njneq $0\nload $3 #24\nadd $0 $3 $5\njmp $5\nhlt";
+        let program = Assembler::new().assemble(source).unwrap();
+        let mut transitions =
+            BlockTransitions::new(ControlFlowGraph::from_program(&program).unwrap());
+        let mut vm = VM::new();
+        vm.load_program(program);
+        let outcome = vm.run_with(|vm| transitions.record(vm.program_counter()));
+        assert_eq!(outcome.exit, ExitReason::Halted);
+
+        assert_eq!(transitions.count(64, 72), 1);
+        assert_eq!(transitions.count(72, 72), 2);
+        assert_eq!(transitions.count(72, 84), 1);
+        assert_eq!(transitions.count(84, 96), 1);
+        let dot = transitions.to_dot();
+        assert!(dot.starts_with("digraph flow {"));
+        assert!(dot.contains("b72 -> b72 [label=\"2\", penwidth=1];"));
+        assert!(dot.contains("b84 -> b96 [label=\"1\", penwidth=1, color=red];"));
+        assert!(!dot.contains("style=dotted"));
+    }
+}
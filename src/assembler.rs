@@ -1,2 +1,6 @@
+#[cfg(feature = "assembler")]
+#[allow(clippy::module_inception)]
 pub mod assembler;
+pub mod container;
+#[cfg(feature = "assembler")]
 pub mod parser;
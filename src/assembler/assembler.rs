@@ -1,12 +1,69 @@
-use super::parser::Program;
+use super::{
+    container::{ProgramWriter, SectionKind},
+    parser::{AssemblerInstruction, Program},
+};
+use crate::{
+    encoding,
+    instruction::{Format, MnemonicAlias, Opcode},
+    json::Json,
+};
 
-pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
-pub const PIE_HEADER_LENGTH: usize = 64;
+pub use super::container::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX};
 
 #[derive(Debug)]
 pub struct Assembler {
     phase: AssemblerPhase,
     symbols: SymbolTable,
+    metadata: Vec<(String, Vec<u8>)>,
+    bss_size: usize,
+    constants: Vec<i32>,
+    rodata: Vec<u8>,
+    origin: u32,
+    externals: Vec<(String, u32)>,
+    format: Format,
+    fuse_branches: bool,
+    strict: bool,
+    enabled_warnings: Vec<Warning>,
+    deny_warnings: bool,
+    warnings: Vec<String>,
+}
+
+/// Categories of assembler warnings, each enabled separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warning {
+    /// A label that no instruction refers to.
+    UnusedLabel,
+    /// A directive skipped in lenient mode.
+    UnknownDirective,
+    /// A mnemonic alias that is being phased out.
+    DeprecatedMnemonic,
+}
+
+impl Warning {
+    pub const ALL: [Warning; 3] = [
+        Warning::UnusedLabel,
+        Warning::UnknownDirective,
+        Warning::DeprecatedMnemonic,
+    ];
+
+    /// The name used on the command line, e.g. `-W unused-label`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Warning::UnusedLabel => "unused-label",
+            Warning::UnknownDirective => "unknown-directive",
+            Warning::DeprecatedMnemonic => "deprecated-mnemonic",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|warning| warning.name() == name)
+    }
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Assembler {
@@ -14,63 +71,462 @@ impl Assembler {
         Self {
             phase: AssemblerPhase::First,
             symbols: SymbolTable::new(),
+            metadata: Vec::new(),
+            bss_size: 0,
+            constants: Vec::new(),
+            rodata: Vec::new(),
+            origin: PIE_HEADER_LENGTH as u32,
+            externals: Vec::new(),
+            format: Format::default(),
+            fuse_branches: false,
+            strict: true,
+            enabled_warnings: vec![Warning::UnknownDirective, Warning::DeprecatedMnemonic],
+            deny_warnings: false,
+            warnings: Vec::new(),
+        }
+    }
+
+    /// In strict mode, the default, unknown directives are errors. Otherwise they are
+    /// skipped with a warning.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Address of the first instruction, used to resolve labels. Defaults to the end of the
+    /// header; code appended to an already loaded program, as in the REPL, starts later.
+    pub fn set_origin(&mut self, address: u32) {
+        self.origin = address;
+    }
+
+    /// Resolves `@name` to `address` when the source does not define it, so code assembled
+    /// on its own can refer to the symbols of an already loaded program.
+    pub fn define_external(&mut self, name: &str, address: u32) {
+        self.externals.push((name.to_string(), address));
+    }
+
+    /// Instruction encoding of the output. The wide one takes twice the space but has
+    /// 32-bit integer operands instead of 16-bit ones.
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    /// Reports warnings of this category. Unknown directives and deprecated mnemonics are
+    /// reported by default.
+    pub fn enable_warning(&mut self, warning: Warning) {
+        if !self.enabled_warnings.contains(&warning) {
+            self.enabled_warnings.push(warning);
         }
     }
 
+    /// Fails the assembly when any enabled warning is reported.
+    pub fn set_deny_warnings(&mut self, deny: bool) {
+        self.deny_warnings = deny;
+    }
+
+    /// Problems that did not stop the last assembly.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Assembles a comparison directly followed by a JEQ as one compare-and-branch
+    /// instruction. Off by default: every instruction after a fused pair moves back 4
+    /// bytes, which breaks jump targets written as numbers instead of labels.
+    pub fn set_fuse_branches(&mut self, fuse: bool) {
+        self.fuse_branches = fuse;
+    }
+
+    /// Embeds a named metadata section (author, license, build info, ...) in the output.
+    pub fn add_metadata(&mut self, name: &str, bytes: Vec<u8>) {
+        self.metadata.push((name.to_string(), bytes));
+    }
+
+    /// Assembles `raw` without reporting why it failed, see `try_assemble` and `warnings`.
     pub fn assemble(&mut self, raw: &str) -> Option<Vec<u8>> {
-        Program::parse(raw).map_or_else(
-            |e| {
-                println!("There was an error assembling the code: {:?}", e);
-                None
-            },
-            |(_remainder, program)| {
-                let mut assembled_program = self.write_pie_header();
-                self.process_first_phase(&program);
-                if let Ok(body) = self.process_second_phase(&program) {
-                    assembled_program.extend_from_slice(&body);
+        self.try_assemble(raw).ok()
+    }
+
+    /// Assembles `raw`, or returns every problem found in it, each prefixed with its line.
+    pub fn try_assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<String>> {
+        let (program, lines, mut errors) = parse_lines(raw);
+        if program.instructions.is_empty() && errors.is_empty() {
+            errors.push("Nothing to assemble".to_string());
+        }
+
+        self.process_first_phase(&program);
+        self.check_deprecated(raw);
+        let body = match self.process_second_phase(&program, &lines) {
+            Ok(body) if errors.is_empty() => body,
+            Ok(_) => return Err(errors),
+            Err(more) => {
+                errors.extend(more);
+                errors.sort_by_key(|e| line_number(e));
+                return Err(errors);
+            }
+        };
+
+        if self.deny_warnings && !self.warnings.is_empty() {
+            return Err(std::mem::take(&mut self.warnings));
+        }
+
+        self.write(body).map_err(|e| vec![e])
+    }
+
+    // Operands all start with a sigil, so any bare word before a string constant is a
+    // mnemonic
+    fn check_deprecated(&mut self, raw: &str) {
+        for (index, line) in raw.lines().enumerate() {
+            let code = line.split('\'').next().unwrap_or_default();
+            for word in code.split_whitespace() {
+                if let Some(alias) = MnemonicAlias::lookup(word).filter(|alias| alias.deprecated) {
+                    let mnemonic = alias.opcode.info().map_or("", |info| info.mnemonic);
+                    self.warn(
+                        Warning::DeprecatedMnemonic,
+                        format!("line {}: {word} is deprecated, use {mnemonic}", index + 1),
+                    );
                 }
+            }
+        }
+    }
+
+    fn warn(&mut self, warning: Warning, message: String) {
+        if self.enabled_warnings.contains(&warning) {
+            self.warnings
+                .push(format!("{message} [-W{}]", warning.name()));
+        }
+    }
+
+    fn write(&self, body: Vec<u8>) -> Result<Vec<u8>, String> {
+        let mut writer = ProgramWriter::new(body);
+        writer.set_format(self.format);
+        if self.bss_size > 0 {
+            writer.add_bss("bss", self.bss_size)?;
+        }
+        if !self.constants.is_empty() {
+            let pool = self
+                .constants
+                .iter()
+                .flat_map(|value| encoding::encode_u32(*value as u32))
+                .collect();
+            writer.add_section(SectionKind::Constants, "constants", pool)?;
+        }
+        if !self.rodata.is_empty() {
+            writer.add_section(SectionKind::Data, "rodata", self.rodata.clone())?;
+        }
+        if !self.symbols.symbols.is_empty() {
+            writer.add_section(SectionKind::Symbols, "symbols", self.symbols.to_bytes()?)?;
+        }
+        for (name, bytes) in &self.metadata {
+            writer.set_section(SectionKind::Custom, name, bytes.clone())?;
+        }
 
-                Some(assembled_program)
-            },
-        )
+        Ok(writer.finish())
     }
 
     fn process_first_phase(&mut self, p: &Program) {
+        self.symbols = SymbolTable::new();
+        self.symbols.origin = self.origin;
+        self.symbols.externals = self.externals.clone();
+        self.bss_size = 0;
+        self.constants.clear();
+        self.rodata.clear();
+        self.warnings.clear();
         self.extract_labels(p);
         self.phase = AssemblerPhase::Second;
     }
 
-    fn process_second_phase(&mut self, p: &Program) -> Result<Vec<u8>, String> {
+    // `lines` holds the source line of every instruction, for the error messages
+    fn process_second_phase(
+        &mut self,
+        p: &Program,
+        lines: &[usize],
+    ) -> Result<Vec<u8>, Vec<String>> {
         let mut program = Vec::new();
-        for instruction in &p.instructions {
-            let mut bytes = instruction.to_bytes()?;
-            program.append(&mut bytes);
+        let mut errors = Vec::new();
+        let mut index = 0;
+        while let Some(instruction) = p.instructions.get(index) {
+            let line = lines[index];
+            index += 1;
+
+            let result = match instruction.directive_name() {
+                Some(name) if !DIRECTIVES.iter().any(|(known, _)| *known == name) => {
+                    if self.strict {
+                        Err(format!("Unknown directive: .{name}"))
+                    } else {
+                        self.warn(
+                            Warning::UnknownDirective,
+                            format!("line {line}: ignoring unknown directive .{name}"),
+                        );
+                        Ok(Vec::new())
+                    }
+                }
+                Some(_) => check_directive(instruction).map(|_| Vec::new()),
+                None => {
+                    let next = p.instructions.get(index);
+                    match self.fused_opcode(instruction, next) {
+                        Some(fused) => {
+                            index += 1;
+                            self.encode_fused(
+                                instruction,
+                                next.expect("fusing needs a jump"),
+                                fused,
+                            )
+                        }
+                        None if instruction.opcode() == Some(Opcode::LOADC) => {
+                            self.encode_constant(instruction)
+                        }
+                        None => {
+                            instruction.to_bytes_with_symbols(&self.symbols, self.format.encoding())
+                        }
+                    }
+                }
+            };
+            match result {
+                Ok(mut bytes) => program.append(&mut bytes),
+                Err(e) => errors.push(format!("line {line}: {e}")),
+            }
+        }
+
+        let used: Vec<&str> = p
+            .instructions
+            .iter()
+            .flat_map(AssemblerInstruction::label_usages)
+            .collect();
+        for (instruction, line) in p.instructions.iter().zip(lines) {
+            if let Some(name) = instruction.label_name() {
+                if !used.contains(&name.as_str()) {
+                    self.warn(
+                        Warning::UnusedLabel,
+                        format!("line {line}: label {name} is never used"),
+                    );
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(errors)
+        }
+    }
+
+    // LOADC with its value replaced by the value's index in the pool, shared by every
+    // LOADC of the same value
+    fn encode_constant(&mut self, instruction: &AssemblerInstruction) -> Result<Vec<u8>, String> {
+        let (Some(register), Some(value), 2) = (
+            instruction.register(),
+            instruction.constant(),
+            instruction.operand_count(),
+        ) else {
+            return Err("Expected loadc $reg #value".to_string());
+        };
+        let index = match self.constants.iter().position(|&known| known == value) {
+            Some(index) => index,
+            None => {
+                self.constants.push(value);
+                self.constants.len() - 1
+            }
+        };
+        let encoding = self.format.encoding();
+        let index = encoding
+            .encode_immediate(index as i64, false)
+            .ok_or("Constant pool is full")?;
+
+        let mut operands = vec![register];
+        operands.extend_from_slice(&index);
+        Ok(encoding.instruction(Opcode::LOADC, &operands))
+    }
+
+    fn encode_fused(
+        &self,
+        comparison: &AssemblerInstruction,
+        jump: &AssemblerInstruction,
+        fused: Opcode,
+    ) -> Result<Vec<u8>, String> {
+        let encoding = self.format.encoding();
+        let comparison = comparison.to_bytes_with_symbols(&self.symbols, encoding)?;
+        let jump = jump.to_bytes_with_symbols(&self.symbols, encoding)?;
+        let first = encoding.operands_offset();
+
+        Ok(encoding.instruction(
+            fused,
+            &[comparison[first], comparison[first + 1], jump[first]],
+        ))
+    }
+
+    // The compare-and-branch opcode replacing `instruction` and the JEQ after it, if any
+    fn fused_opcode(
+        &self,
+        instruction: &AssemblerInstruction,
+        next: Option<&AssemblerInstruction>,
+    ) -> Option<Opcode> {
+        let next = next.filter(|next| next.label_name().is_none())?;
+        if !self.fuse_branches || next.opcode() != Some(Opcode::JEQ) {
+            return None;
         }
 
-        Ok(program)
+        match instruction.opcode()? {
+            Opcode::EQ => Some(Opcode::BEQ),
+            Opcode::NEQ => Some(Opcode::BNE),
+            Opcode::GT => Some(Opcode::BGT),
+            Opcode::LT => Some(Opcode::BLT),
+            Opcode::GTE => Some(Opcode::BGE),
+            Opcode::LTE => Some(Opcode::BLE),
+            _ => None,
+        }
     }
 
     fn extract_labels(&mut self, p: &Program) {
         let mut offset = 0;
-        for instruction in &p.instructions {
+        let mut instructions = p.instructions.iter().peekable();
+        while let Some(instruction) = instructions.next() {
+            if instruction.directive_name() == Some("asciiz") {
+                if let Some(name) = instruction.label_name() {
+                    let symbol = Symbol::new(name, SymbolType::Data, self.rodata.len() as u32);
+                    self.symbols.add_symbol(symbol);
+                }
+                // Missing strings are reported in the second phase
+                self.rodata
+                    .extend(instruction.string().unwrap_or_default().bytes());
+                self.rodata.push(0);
+                continue;
+            }
+            if !instruction.is_opcode() {
+                // Sizes are validated in the second phase
+                let size = space_size(instruction).unwrap_or(0);
+                if let Some(name) = instruction.label_name() {
+                    let symbol = Symbol::new(name, SymbolType::Space, self.bss_size as u32);
+                    self.symbols.add_symbol(symbol);
+                }
+                self.bss_size += size;
+                continue;
+            }
             if instruction.is_label() {
                 if let Some(name) = instruction.label_name() {
                     let symbol = Symbol::new(name, SymbolType::Label, offset);
                     self.symbols.add_symbol(symbol);
                 }
             }
-            offset += 4;
+            if self
+                .fused_opcode(instruction, instructions.peek().copied())
+                .is_some()
+            {
+                instructions.next();
+            }
+            offset += self.format.encoding().width() as u32;
         }
+        self.symbols.code_size = offset;
     }
 
-    fn write_pie_header(&self) -> Vec<u8> {
-        let mut header: Vec<u8> = PIE_HEADER_PREFIX.to_vec();
+    /// The symbols of the last assembly as JSON, for debuggers and other tools. Labels are
+    /// in the `code` section and `.space` reservations in `bss`; a symbol's size runs up to
+    /// the next symbol in its section. Every symbol is visible program-wide.
+    pub fn symbols_json(&self) -> Json {
+        let symbols = self
+            .symbols
+            .iter()
+            .map(|symbol| {
+                let (section, end) = match symbol.symbol_type {
+                    SymbolType::Label => ("code", self.symbols.code_size),
+                    SymbolType::Space => ("bss", self.bss_size as u32),
+                    SymbolType::Data => ("rodata", self.rodata.len() as u32),
+                };
+                let next = self
+                    .symbols
+                    .iter()
+                    .filter(|other| {
+                        other.symbol_type == symbol.symbol_type && other.offset > symbol.offset
+                    })
+                    .map(|other| other.offset)
+                    .min();
+
+                Json::object([
+                    ("name", Json::from(symbol.name())),
+                    ("section", Json::from(section)),
+                    ("offset", Json::from(symbol.offset as u64)),
+                    (
+                        "address",
+                        Json::from(self.symbols.address(symbol.name()).map_or(0, u64::from)),
+                    ),
+                    (
+                        "size",
+                        Json::from((next.unwrap_or(end) - symbol.offset) as u64),
+                    ),
+                    ("visibility", Json::from("global")),
+                ])
+            })
+            .collect();
+
+        Json::object([
+            ("version", Json::from(1)),
+            ("symbols", Json::Array(symbols)),
+        ])
+    }
+}
+
+// Parses the source a line at a time so that one bad line does not hide the problems in
+// the ones after it. Returns the instructions, the line each came from and the errors.
+fn parse_lines(raw: &str) -> (Program, Vec<usize>, Vec<String>) {
+    let mut instructions = Vec::new();
+    let mut lines = Vec::new();
+    let mut errors = Vec::new();
+
+    for (index, line) in raw.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
 
-        while header.len() < PIE_HEADER_LENGTH {
-            header.push(0 as u8);
+        match Program::parse(line) {
+            Ok((rest, program)) if rest.trim().is_empty() => {
+                lines.extend(program.instructions.iter().map(|_| number));
+                instructions.extend(program.instructions);
+            }
+            Ok((rest, _)) => errors.push(format!("line {number}: unexpected input: {rest}")),
+            Err(_) => errors.push(format!("line {number}: unable to parse: {line}")),
         }
+    }
+
+    (Program { instructions }, lines, errors)
+}
+
+fn line_number(error: &str) -> usize {
+    error
+        .strip_prefix("line ")
+        .and_then(|rest| rest.split(':').next())
+        .and_then(|number| number.parse().ok())
+        .unwrap_or(0)
+}
 
-        header
+/// Directives the assembler understands, with the number of operands each takes.
+pub const DIRECTIVES: &[(&str, usize)] = &[("space", 1), ("bss", 1), ("asciiz", 1)];
+
+fn check_directive(instruction: &AssemblerInstruction) -> Result<(), String> {
+    let name = instruction.directive_name().unwrap_or_default();
+    let arity = DIRECTIVES
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map_or(0, |(_, arity)| *arity);
+    let count = instruction.operand_count();
+    if count != arity {
+        return Err(format!(".{name} takes {arity} operand(s), got {count}"));
+    }
+
+    if name == "asciiz" && instruction.string().is_none() {
+        return Err(".asciiz needs a string, e.g. .asciiz 'hello'".to_string());
+    }
+
+    space_size(instruction).map(|_| ())
+}
+
+// Number of zeroed heap bytes reserved by a `.space #n` (or `.bss #n`) directive
+fn space_size(instruction: &AssemblerInstruction) -> Result<usize, String> {
+    match (instruction.directive_name(), instruction.immediate()) {
+        (Some("space" | "bss"), Some(size)) if size >= 0 => Ok(size as usize),
+        (Some(name @ ("space" | "bss")), _) => {
+            Err(format!(".{name} needs a size in bytes, e.g. .{name} #64"))
+        }
+        _ => Ok(0),
     }
 }
 
@@ -89,17 +545,47 @@ impl Symbol {
             offset,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Offset from the start of the code section for labels, or of the BSS for `.space`.
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn symbol_type(&self) -> &SymbolType {
+        &self.symbol_type
+    }
+
+    /// Runtime address: the program counter of a label or the heap offset of a `.space`.
+    pub fn address(&self) -> u32 {
+        match self.symbol_type {
+            SymbolType::Label => PIE_HEADER_LENGTH as u32 + self.offset,
+            SymbolType::Space | SymbolType::Data => self.offset,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct SymbolTable {
     symbols: Vec<Symbol>,
+    // Address of the first instruction
+    origin: u32,
+    // Bytes of code the labels point into
+    code_size: u32,
+    // Addresses of symbols defined outside the source, looked up last
+    externals: Vec<(String, u32)>,
 }
 
 impl SymbolTable {
     fn new() -> SymbolTable {
         SymbolTable {
             symbols: Vec::new(),
+            origin: PIE_HEADER_LENGTH as u32,
+            code_size: 0,
+            externals: Vec::new(),
         }
     }
 
@@ -107,11 +593,85 @@ impl SymbolTable {
         self.symbols.push(s);
     }
 
-    fn symbol_offset(&self, s: &str) -> Option<u32> {
+    pub fn address(&self, name: &str) -> Option<u32> {
         self.symbols
             .iter()
-            .find(|&symbol| symbol.name == s)
-            .map(|symbol| symbol.offset)
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| match symbol.symbol_type {
+                SymbolType::Label => self.origin + symbol.offset,
+                SymbolType::Space | SymbolType::Data => symbol.offset,
+            })
+            .or_else(|| {
+                self.externals
+                    .iter()
+                    .find(|(external, _)| external == name)
+                    .map(|&(_, address)| address)
+            })
+    }
+
+    /// Points the label `name` at `address`, returning false if there is no such label.
+    pub fn relocate(&mut self, name: &str, address: u32) -> bool {
+        let origin = self.origin;
+        match self
+            .symbols
+            .iter_mut()
+            .find(|symbol| symbol.name == name && symbol.symbol_type == SymbolType::Label)
+        {
+            Some(symbol) if address >= origin => {
+                symbol.offset = address - origin;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Symbols in declaration order.
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    /// Serializes the table for the symbols section. Each entry is a type byte, a name
+    /// length byte, the name and a big-endian u32 offset.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        for symbol in &self.symbols {
+            let name_length = u8::try_from(symbol.name.len())
+                .map_err(|_| format!("Symbol name is too long: {}", symbol.name))?;
+            bytes.push(u8::from(&symbol.symbol_type));
+            bytes.push(name_length);
+            bytes.extend_from_slice(symbol.name.as_bytes());
+            bytes.extend_from_slice(&encoding::encode_u32(symbol.offset));
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<SymbolTable, String> {
+        let mut table = SymbolTable::new();
+        let mut position = 0;
+        while position < bytes.len() {
+            let symbol_type = match bytes[position] {
+                1 => SymbolType::Label,
+                2 => SymbolType::Space,
+                3 => SymbolType::Data,
+                n => return Err(format!("Unknown symbol type: {n}")),
+            };
+            let name_length = *bytes.get(position + 1).ok_or("Truncated symbol table")? as usize;
+            let name_start = position + 2;
+            let name = bytes
+                .get(name_start..name_start + name_length)
+                .ok_or("Truncated symbol table")?;
+            let offset = encoding::decode_u32(bytes, name_start + name_length)
+                .ok_or("Truncated symbol table")?;
+            table.add_symbol(Symbol::new(
+                String::from_utf8_lossy(name).into_owned(),
+                symbol_type,
+                offset,
+            ));
+            position = name_start + name_length + 4;
+        }
+
+        Ok(table)
     }
 }
 
@@ -121,14 +681,34 @@ enum AssemblerPhase {
     Second,
 }
 
-#[derive(Debug)]
-enum SymbolType {
+#[derive(Debug, PartialEq)]
+pub enum SymbolType {
     Label,
+    Space,
+    /// An `.asciiz` string in the read-only data.
+    Data,
+}
+
+impl From<&SymbolType> for u8 {
+    fn from(symbol_type: &SymbolType) -> Self {
+        match symbol_type {
+            SymbolType::Label => 1,
+            SymbolType::Space => 2,
+            SymbolType::Data => 3,
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::assembler::assembler::{Assembler, SymbolTable, PIE_HEADER_LENGTH};
+    use crate::{
+        assembler::{
+            assembler::{Assembler, SymbolTable, Warning},
+            container::{code_section, read_sections, SectionKind},
+        },
+        instruction::Format,
+        vm::{ExitReason, VM},
+    };
 
     use super::{Symbol, SymbolType};
 
@@ -145,16 +725,386 @@ mod test {
         let mut symbol_table = SymbolTable::new();
         let new_symbol = Symbol::new("test".to_string(), SymbolType::Label, 12);
         symbol_table.add_symbol(new_symbol);
-        let offset = symbol_table.symbol_offset("test").unwrap();
-        assert_eq!(offset, 12);
+        let symbol = symbol_table
+            .symbols
+            .iter()
+            .find(|symbol| symbol.name() == "test");
+        assert_eq!(symbol.map(Symbol::offset), Some(12));
+        assert_eq!(symbol_table.address("test"), Some(76));
+    }
+
+    #[test]
+    fn test_symbol_table_round_trip() {
+        let mut symbol_table = SymbolTable::new();
+        symbol_table.add_symbol(Symbol::new("start".to_string(), SymbolType::Label, 0));
+        symbol_table.add_symbol(Symbol::new("loop".to_string(), SymbolType::Label, 12));
+        let bytes = symbol_table.to_bytes().unwrap();
+
+        let decoded = SymbolTable::from_bytes(&bytes).unwrap();
+        let symbols: Vec<_> = decoded.iter().map(|s| (s.name(), s.offset())).collect();
+        assert_eq!(symbols, [("start", 0), ("loop", 12)]);
+        assert!(SymbolTable::from_bytes(&bytes[..bytes.len() - 1]).is_err());
     }
 
     #[test]
     fn test_assembler() {
         let mut assembler = Assembler::new();
         let raw_instructions =
-            "load $0 #100\nload $1 #1\nload $2 #0\ntest: inc $0\nneq $0 $2\njeq @test\nhlt";
+            "load $0 #100\nload $1 #1\nload $2 #0\ntest: inc $0\nneq $0 $2\nload $3 @test\njeq $3\nhlt";
         let program_bytes = assembler.assemble(raw_instructions).unwrap();
-        assert_eq!(program_bytes.len() - PIE_HEADER_LENGTH, 28);
+        let sections = read_sections(&program_bytes).unwrap();
+        assert_eq!(code_section(&sections).unwrap().length, 32);
+
+        let symbols = SymbolTable::from_bytes(&program_bytes[sections[1].range()]).unwrap();
+        assert_eq!(symbols.address("test"), Some(76));
+    }
+
+    #[test]
+    fn test_assembled_bytes_are_platform_independent() {
+        let program = Assembler::new().assemble("load $1 #4660\nhlt").unwrap();
+        let code = code_section(&read_sections(&program).unwrap())
+            .unwrap()
+            .range();
+        assert_eq!(&program[code], &[0, 1, 0x12, 0x34, 5, 0, 0, 0]);
+
+        let mut vm = VM::new();
+        vm.load_program(program);
+        vm.run();
+        assert_eq!(vm.register(1), Some(4660));
+    }
+
+    #[test]
+    fn test_assemble_with_metadata() {
+        let mut assembler = Assembler::new();
+        assembler.add_metadata("author", b"someone".to_vec());
+        let program = assembler.assemble("hlt").unwrap();
+
+        let sections = read_sections(&program).unwrap();
+        assert_eq!(sections[1].name, "author");
+        assert_eq!(&program[sections[1].range()], b"someone");
+
+        let mut assembler = Assembler::new();
+        assembler.add_metadata("code", Vec::new());
+        assert!(assembler.assemble("hlt").is_none());
+    }
+
+    #[test]
+    fn test_assemble_space() {
+        let program = Assembler::new()
+            .assemble("buffer: .space #16\ncount: .space #4\nload $0 @count\nhlt")
+            .unwrap();
+        let sections = read_sections(&program).unwrap();
+        let bss = sections
+            .iter()
+            .find(|s| s.kind == SectionKind::Bss)
+            .unwrap();
+        assert_eq!(bss.length, 20);
+        assert!(bss.contents(&program).is_empty());
+
+        let code = code_section(&sections).unwrap();
+        assert_eq!(&program[code.range()], &[0, 0, 0, 16, 5, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_assemble_label_operand() {
+        let mut vm = VM::new();
+        vm.load_program(
+            Assembler::new()
+                .assemble("load $1 @end\ninc $0\nend: hlt")
+                .unwrap(),
+        );
+        vm.run();
+        assert_eq!(vm.register(1), Some(72));
+    }
+
+    #[test]
+    fn test_assemble_label_offsets() {
+        let program = Assembler::new()
+            .assemble("table: .space #16\nload $0 @table+8\nload $1 @end-4\nend: hlt")
+            .unwrap();
+        let code = code_section(&read_sections(&program).unwrap())
+            .unwrap()
+            .range();
+        assert_eq!(&program[code][..8], &[0, 0, 0, 8, 0, 1, 0, 68]);
+
+        assert!(Assembler::new()
+            .assemble("load $0 @end-100\nend: hlt")
+            .is_none());
+    }
+
+    #[test]
+    fn test_wide_format() {
+        let source = "load $3 @done\n\
+                      load $0 #100000\n\
+                      load $1 #-70000\n\
+                      loadc $2 #5000000\n\
+                      call @double\n\
+                      lt $1 $0\n\
+                      jeq $3\n\
+                      hlt\n\
+                      done: load $4 #1\n\
+                      hlt\n\
+                      double: add $0 $0 $0\n\
+                      ret";
+        assert!(Assembler::new().try_assemble(source).is_err());
+
+        let mut assembler = Assembler::new();
+        assembler.set_format(Format::Wide);
+        assembler.set_fuse_branches(true);
+        let program = assembler.try_assemble(source).unwrap();
+        let code = code_section(&read_sections(&program).unwrap())
+            .unwrap()
+            .range();
+        assert_eq!(code.len(), 11 * 8);
+        assert_eq!(&program[code][8..16], &[0, 0, 0, 0, 1, 134, 160, 0]);
+
+        let mut vm = VM::new();
+        vm.load_program(program);
+        assert_eq!(vm.run().exit, ExitReason::Halted);
+        assert_eq!(&vm.registers()[..5], &[200000, -70000, 5000000, 120, 1]);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert!(Assembler::new().assemble("load $0 @missing").is_none());
+        assert!(Assembler::new().assemble(".space").is_none());
+        assert!(Assembler::new().assemble(".asciiz").is_none());
+    }
+
+    #[test]
+    fn test_fuse_branches() {
+        let source = "load $2 @done\nlt $0 $1\njeq $2\neq $0 $1\nskip: jeq $2\ndone: hlt";
+        let code = |assembler: &mut Assembler| {
+            let program = assembler.assemble(source).unwrap();
+            let code = code_section(&read_sections(&program).unwrap())
+                .unwrap()
+                .range();
+            program[code].to_vec()
+        };
+
+        let mut assembler = Assembler::new();
+        assert_eq!(code(&mut assembler).len(), 24);
+
+        // A labelled JEQ is a jump target of its own and stays separate
+        assembler.set_fuse_branches(true);
+        assert_eq!(
+            code(&mut assembler),
+            vec![0, 2, 0, 80, 35, 0, 1, 2, 9, 0, 1, 0, 15, 2, 0, 0, 5, 0, 0, 0]
+        );
+        assert_eq!(assembler.symbols.address("done"), Some(80));
+    }
+
+    #[test]
+    fn test_reports_every_error() {
+        let errors = Assembler::new()
+            .try_assemble("load $0 #1\n.dta #4\nload $1 @nowhere\n\n%%%\nhlt 'x\nhlt")
+            .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "line 2: Unknown directive: .dta",
+                "line 3: Unknown label: nowhere",
+                "line 5: unable to parse: %%%",
+                "line 6: unexpected input: 'x",
+            ]
+        );
+        assert!(Assembler::new().try_assemble("\n  \n").is_err());
+    }
+
+    #[test]
+    fn test_directive_validation() {
+        let source = "a: .space #4 #8\n.dta #1\nhlt";
+        assert_eq!(
+            Assembler::new().try_assemble(source).unwrap_err(),
+            vec![
+                "line 1: .space takes 1 operand(s), got 2",
+                "line 2: Unknown directive: .dta"
+            ]
+        );
+
+        let mut assembler = Assembler::new();
+        assembler.set_strict(false);
+        assert!(assembler.try_assemble(".dta #1\nhlt").is_ok());
+        assert_eq!(
+            assembler.warnings(),
+            ["line 1: ignoring unknown directive .dta [-Wunknown-directive]"]
+        );
+        // Arity is still checked for known directives
+        assert!(assembler.try_assemble(".bss\nhlt").is_err());
+    }
+
+    #[test]
+    fn test_constant_pool() {
+        let program = Assembler::new()
+            .try_assemble(
+                "loadc $0 #100000
+loadc $1 #-5
+loadc $2 #100000
+hlt",
+            )
+            .unwrap();
+        let sections = read_sections(&program).unwrap();
+        let code = code_section(&sections).unwrap().range();
+        assert_eq!(
+            &program[code][..12],
+            &[39, 0, 0, 0, 39, 1, 0, 1, 39, 2, 0, 0]
+        );
+        let pool = sections
+            .iter()
+            .find(|section| section.kind == SectionKind::Constants)
+            .unwrap();
+        assert_eq!(
+            pool.contents(&program),
+            &[0, 1, 134, 160, 255, 255, 255, 251]
+        );
+
+        let mut vm = VM::new();
+        vm.load_program(program);
+        vm.run();
+        assert_eq!(vm.registers()[..3], [100000, -5, 100000]);
+
+        assert!(Assembler::new()
+            .try_assemble(
+                "loadc $0 @here
+here: hlt"
+            )
+            .is_err());
+        assert!(Assembler::new().try_assemble("loadc $0").is_err());
+    }
+
+    #[test]
+    fn test_warning_levels() {
+        let source = "start: inc $0\nload $1 @end\nend: hlt\n.dta #1";
+        let mut assembler = Assembler::new();
+        assembler.set_strict(false);
+        assert!(assembler.try_assemble(source).is_ok());
+        assert_eq!(assembler.warnings().len(), 1);
+
+        assembler.enable_warning(Warning::from_name("unused-label").unwrap());
+        assert!(assembler.try_assemble(source).is_ok());
+        assert_eq!(
+            assembler.warnings(),
+            [
+                "line 4: ignoring unknown directive .dta [-Wunknown-directive]",
+                "line 1: label start is never used [-Wunused-label]",
+            ]
+        );
+
+        assembler.set_deny_warnings(true);
+        assert_eq!(assembler.try_assemble(source).unwrap_err().len(), 2);
+        assert!(assembler
+            .try_assemble("load $0 @end\njmp $0\nend: hlt")
+            .is_ok());
+        assert_eq!(Warning::from_name("all"), None);
+    }
+
+    #[test]
+    fn test_deprecated_mnemonics() {
+        let mut assembler = Assembler::new();
+        let program = assembler
+            .try_assemble("load $0 @end\njne $0\nend: HALT\nhalt: .space #1")
+            .unwrap();
+        assert_eq!(
+            assembler.warnings(),
+            ["line 3: HALT is deprecated, use hlt [-Wdeprecated-mnemonic]"]
+        );
+        let code = code_section(&read_sections(&program).unwrap())
+            .unwrap()
+            .range();
+        assert_eq!(&program[code][4..12], &[16, 0, 0, 0, 5, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_origin() {
+        let mut assembler = Assembler::new();
+        assembler.set_origin(8);
+        let program = assembler.try_assemble("load $0 @end\nend: hlt").unwrap();
+        let code = code_section(&read_sections(&program).unwrap())
+            .unwrap()
+            .range();
+        assert_eq!(&program[code][..4], &[0, 0, 0, 12]);
+    }
+
+    #[test]
+    fn test_symbols_json() {
+        let mut assembler = Assembler::new();
+        assembler
+            .try_assemble(
+                "buf: .space #16
+flag: .space #4
+start: load $0 #1
+inc $0
+end: hlt",
+            )
+            .unwrap();
+        assert_eq!(
+            assembler.symbols_json().to_string(),
+            "{\"version\":1,\"symbols\":[\
+            {\"name\":\"buf\",\"section\":\"bss\",\"offset\":0,\"address\":0,\"size\":16,\"visibility\":\"global\"},\
+            {\"name\":\"flag\",\"section\":\"bss\",\"offset\":16,\"address\":16,\"size\":4,\"visibility\":\"global\"},\
+            {\"name\":\"start\",\"section\":\"code\",\"offset\":0,\"address\":64,\"size\":8,\"visibility\":\"global\"},\
+            {\"name\":\"end\",\"section\":\"code\",\"offset\":8,\"address\":72,\"size\":4,\"visibility\":\"global\"}]}"
+        );
+    }
+
+    #[test]
+    fn test_asciiz() {
+        let mut assembler = Assembler::new();
+        let program = assembler
+            .try_assemble("hi: .asciiz 'hi'\nbye: .asciiz 'bye now'\nprts @bye\nhlt")
+            .unwrap();
+        let sections = read_sections(&program).unwrap();
+        let code = code_section(&sections).unwrap().range();
+        assert_eq!(&program[code][..4], &[59, 0, 3, 0]);
+        let rodata = sections
+            .iter()
+            .find(|section| section.kind == SectionKind::Data)
+            .unwrap();
+        assert_eq!(rodata.contents(&program), b"hi\0bye now\0");
+        assert!(assembler.symbols_json().to_string().contains(
+            "{\"name\":\"bye\",\"section\":\"rodata\",\"offset\":3,\"address\":3,\"size\":8,"
+        ));
+
+        assert_eq!(
+            Assembler::new().try_assemble(".asciiz #3\nhlt"),
+            Err(vec![
+                "line 1: .asciiz needs a string, e.g. .asciiz 'hello'".to_string()
+            ])
+        );
+        let mut vm = VM::new();
+        vm.load_program(program);
+        assert_eq!(vm.run().exit, ExitReason::Halted);
+    }
+
+    #[test]
+    fn test_label_where_a_register_is_expected() {
+        assert_eq!(
+            Assembler::new().try_assemble("start: inc $0\njneq @start\nhlt"),
+            Err(vec![
+                "line 2: jneq takes a register, load the address of @start into one first"
+                    .to_string()
+            ])
+        );
+        assert_eq!(
+            Assembler::new().try_assemble("end: inc $0\nhlt @end"),
+            Err(vec!["line 2: hlt takes no operands, found @end".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_subroutines() {
+        let source = "load $0 #3\nload $1 #7\ncall @double\ncall @double\nhlt\n\
+            double: push $1\nload $1 #2\nmul $0 $1 $0\npop $1\nret";
+        let program = Assembler::new().try_assemble(source).unwrap();
+        let code = code_section(&read_sections(&program).unwrap())
+            .unwrap()
+            .range();
+        assert_eq!(&program[code][8..12], &[42, 0, 84, 0]);
+
+        let mut vm = VM::new();
+        vm.load_program(program);
+        vm.run();
+        assert_eq!(vm.registers()[..2], [12, 7]);
+        assert!(vm.stack().is_empty());
     }
 }
@@ -0,0 +1,454 @@
+//! Layout of an assembled program.
+//!
+//! A program starts with the 64 byte PIE header. Bytes 4..8 of the header hold the offset
+//! of the section table, bytes 8..10 its number of entries and byte 10 the instruction
+//! encoding: 0 for 4 byte instructions, 1 for 8 byte ones. A table offset of zero means
+//! the program has no table and everything after the header is code. Each table entry is
+//! 24 bytes: kind (1), name (15, zero padded), offset (4) and length (4).
+//!
+//! The code section always starts right after the header so absolute jump targets do not
+//! depend on the other sections; the table itself is written after the last section.
+
+use std::ops::Range;
+
+use crate::{encoding, instruction::Format};
+
+pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
+pub const PIE_HEADER_LENGTH: usize = 64;
+pub const SECTION_ENTRY_LENGTH: usize = 24;
+pub const MAX_SECTION_NAME: usize = 15;
+
+const TABLE_OFFSET_FIELD: usize = 4;
+const SECTION_COUNT_FIELD: usize = 8;
+const FORMAT_FIELD: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionKind {
+    Code,
+    Data,
+    Bss,
+    Symbols,
+    Debug,
+    Custom,
+    /// 32 bit big-endian values loaded by LOADC, by index.
+    Constants,
+    /// A kind written by a newer assembler, kept as is so it survives a rewrite.
+    Unknown(u8),
+}
+
+impl From<u8> for SectionKind {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => SectionKind::Code,
+            2 => SectionKind::Data,
+            3 => SectionKind::Bss,
+            4 => SectionKind::Symbols,
+            5 => SectionKind::Debug,
+            6 => SectionKind::Custom,
+            7 => SectionKind::Constants,
+            n => SectionKind::Unknown(n),
+        }
+    }
+}
+
+impl From<SectionKind> for u8 {
+    fn from(kind: SectionKind) -> Self {
+        match kind {
+            SectionKind::Code => 1,
+            SectionKind::Data => 2,
+            SectionKind::Bss => 3,
+            SectionKind::Symbols => 4,
+            SectionKind::Debug => 5,
+            SectionKind::Custom => 6,
+            SectionKind::Constants => 7,
+            SectionKind::Unknown(n) => n,
+        }
+    }
+}
+
+/// An entry of the section table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub kind: SectionKind,
+    pub name: String,
+    pub offset: usize,
+    pub length: usize,
+}
+
+impl Section {
+    pub fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.length
+    }
+
+    /// Whether the section's bytes are stored in the program. BSS only records a size.
+    pub fn is_stored(&self) -> bool {
+        self.kind != SectionKind::Bss
+    }
+
+    pub fn contents<'a>(&self, program: &'a [u8]) -> &'a [u8] {
+        if self.is_stored() {
+            &program[self.range()]
+        } else {
+            &[]
+        }
+    }
+}
+
+/// Fields of the PIE header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub table_offset: usize,
+    pub section_count: usize,
+    pub format: Format,
+}
+
+pub fn read_header(program: &[u8]) -> Result<Header, String> {
+    if program.len() < PIE_HEADER_LENGTH || !program.starts_with(&PIE_HEADER_PREFIX) {
+        return Err("Invalid header".to_string());
+    }
+
+    let format = Format::from_id(program[FORMAT_FIELD])
+        .ok_or_else(|| format!("Unknown instruction encoding {}", program[FORMAT_FIELD]))?;
+
+    Ok(Header {
+        table_offset: encoding::decode_u32(program, TABLE_OFFSET_FIELD).unwrap_or(0) as usize,
+        section_count: encoding::decode_u16(program, SECTION_COUNT_FIELD).unwrap_or(0) as usize,
+        format,
+    })
+}
+
+/// Lists the sections of a program, in table order.
+pub fn read_sections(program: &[u8]) -> Result<Vec<Section>, String> {
+    let Header {
+        table_offset,
+        section_count: count,
+        ..
+    } = read_header(program)?;
+    if table_offset == 0 {
+        return Ok(vec![Section {
+            kind: SectionKind::Code,
+            name: "code".to_string(),
+            offset: PIE_HEADER_LENGTH,
+            length: program.len() - PIE_HEADER_LENGTH,
+        }]);
+    }
+
+    (0..count)
+        .map(|i| {
+            let start = table_offset + i * SECTION_ENTRY_LENGTH;
+            let entry = program
+                .get(start..start + SECTION_ENTRY_LENGTH)
+                .ok_or("Section table runs past the end of the program")?;
+            let name = &entry[1..1 + MAX_SECTION_NAME];
+            let name_length = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            let section = Section {
+                kind: SectionKind::from(entry[0]),
+                name: String::from_utf8_lossy(&name[..name_length]).into_owned(),
+                offset: encoding::decode_u32(entry, 16).unwrap_or(0) as usize,
+                length: encoding::decode_u32(entry, 20).unwrap_or(0) as usize,
+            };
+            let out_of_bounds =
+                section.offset < PIE_HEADER_LENGTH || section.range().end > table_offset;
+            if section.is_stored() && out_of_bounds {
+                return Err(format!("Section {} is out of bounds", section.name));
+            }
+
+            Ok(section)
+        })
+        .collect()
+}
+
+/// The first code section of a program.
+pub fn code_section(sections: &[Section]) -> Option<&Section> {
+    sections
+        .iter()
+        .find(|section| section.kind == SectionKind::Code)
+}
+
+/// Lays out a program's sections behind the header and appends the section table.
+#[derive(Debug)]
+pub struct ProgramWriter {
+    sections: Vec<PendingSection>,
+    format: Format,
+}
+
+#[derive(Debug)]
+struct PendingSection {
+    kind: SectionKind,
+    name: String,
+    bytes: Vec<u8>,
+    length: usize,
+}
+
+impl ProgramWriter {
+    pub fn new(code: Vec<u8>) -> Self {
+        Self {
+            sections: vec![PendingSection {
+                kind: SectionKind::Code,
+                name: "code".to_string(),
+                length: code.len(),
+                bytes: code,
+            }],
+            format: Format::default(),
+        }
+    }
+
+    /// Starts from an existing program, keeping every section including unknown ones.
+    pub fn from_program(program: &[u8]) -> Result<Self, String> {
+        let sections = read_sections(program)?;
+        let code = code_section(&sections).ok_or("Program has no code section")?;
+        let mut writer = Self::new(program[code.range()].to_vec());
+        writer.format = read_header(program)?.format;
+        for section in sections.iter().filter(|section| *section != code) {
+            writer.sections.push(PendingSection {
+                kind: section.kind,
+                name: section.name.clone(),
+                bytes: section.contents(program).to_vec(),
+                length: section.length,
+            });
+        }
+
+        Ok(writer)
+    }
+
+    /// Replaces the code section. Jump targets stay valid as long as the old code is kept
+    /// as a prefix, since code always starts right after the header.
+    pub fn set_code(&mut self, code: Vec<u8>) {
+        let section = &mut self.sections[0];
+        section.length = code.len();
+        section.bytes = code;
+    }
+
+    /// Sets the instruction encoding recorded in the header. It must match the code.
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    pub fn add_section(
+        &mut self,
+        kind: SectionKind,
+        name: &str,
+        bytes: Vec<u8>,
+    ) -> Result<(), String> {
+        if name.is_empty() || name.len() > MAX_SECTION_NAME || name.contains('\0') {
+            return Err(format!(
+                "Section names must be 1 to {MAX_SECTION_NAME} bytes long: {name}"
+            ));
+        }
+        self.sections.push(PendingSection {
+            kind,
+            name: name.to_string(),
+            length: bytes.len(),
+            bytes,
+        });
+
+        Ok(())
+    }
+
+    /// Reserves `size` zeroed bytes that the loader maps into the heap.
+    pub fn add_bss(&mut self, name: &str, size: usize) -> Result<(), String> {
+        self.add_section(SectionKind::Bss, name, Vec::new())?;
+        if let Some(section) = self.sections.last_mut() {
+            section.length = size;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the contents of the section called `name`, adding it if it does not exist.
+    pub fn set_section(
+        &mut self,
+        kind: SectionKind,
+        name: &str,
+        bytes: Vec<u8>,
+    ) -> Result<(), String> {
+        match self
+            .sections
+            .iter_mut()
+            .find(|section| section.name == name)
+        {
+            Some(section) if section.kind == SectionKind::Code => {
+                Err(format!("Section {name} holds code and cannot be replaced"))
+            }
+            Some(section) => {
+                section.kind = kind;
+                section.length = bytes.len();
+                section.bytes = bytes;
+                Ok(())
+            }
+            None => self.add_section(kind, name, bytes),
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let mut program = PIE_HEADER_PREFIX.to_vec();
+        program.resize(PIE_HEADER_LENGTH, 0);
+        program[FORMAT_FIELD] = self.format.id();
+
+        let mut table = Vec::with_capacity(self.sections.len() * SECTION_ENTRY_LENGTH);
+        for section in &self.sections {
+            table.push(u8::from(section.kind));
+            let mut padded_name = [0; MAX_SECTION_NAME];
+            padded_name[..section.name.len()].copy_from_slice(section.name.as_bytes());
+            table.extend_from_slice(&padded_name);
+            table.extend_from_slice(&encoding::encode_u32(program.len() as u32));
+            table.extend_from_slice(&encoding::encode_u32(section.length as u32));
+            program.extend_from_slice(&section.bytes);
+        }
+
+        let table_offset = encoding::encode_u32(program.len() as u32);
+        program[TABLE_OFFSET_FIELD..TABLE_OFFSET_FIELD + 4].copy_from_slice(&table_offset);
+        let count = encoding::encode_u16(self.sections.len() as u16);
+        program[SECTION_COUNT_FIELD..SECTION_COUNT_FIELD + 2].copy_from_slice(&count);
+        program.extend_from_slice(&table);
+
+        program
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        assembler::container::{
+            code_section, read_header, read_sections, ProgramWriter, Section, SectionKind,
+            PIE_HEADER_LENGTH, PIE_HEADER_PREFIX,
+        },
+        instruction::Format,
+    };
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = ProgramWriter::new(vec![5, 0, 0, 0]);
+        writer
+            .add_section(SectionKind::Data, "data", vec![1, 2, 3])
+            .unwrap();
+        writer
+            .add_section(SectionKind::Unknown(42), "future", Vec::new())
+            .unwrap();
+        let program = writer.finish();
+
+        let sections = read_sections(&program).unwrap();
+        assert_eq!(
+            sections,
+            vec![
+                Section {
+                    kind: SectionKind::Code,
+                    name: "code".to_string(),
+                    offset: 64,
+                    length: 4
+                },
+                Section {
+                    kind: SectionKind::Data,
+                    name: "data".to_string(),
+                    offset: 68,
+                    length: 3
+                },
+                Section {
+                    kind: SectionKind::Unknown(42),
+                    name: "future".to_string(),
+                    offset: 71,
+                    length: 0
+                },
+            ]
+        );
+        assert_eq!(&program[sections[1].range()], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_program_without_table() {
+        let mut program = PIE_HEADER_PREFIX.to_vec();
+        program.resize(PIE_HEADER_LENGTH, 0);
+        program.extend_from_slice(&[5, 0, 0, 0]);
+
+        let sections = read_sections(&program).unwrap();
+        assert_eq!(code_section(&sections).unwrap().range(), 64..68);
+    }
+
+    #[test]
+    fn test_invalid_programs() {
+        assert!(read_sections(&[5, 0, 0, 0]).is_err());
+
+        let mut program = ProgramWriter::new(vec![5, 0, 0, 0]).finish();
+        program.truncate(program.len() - 1);
+        assert!(read_sections(&program).is_err());
+
+        let mut program = ProgramWriter::new(vec![5, 0, 0, 0]).finish();
+        program[64 + 4 + 20 + 3] = 200; // code section length
+        assert!(read_sections(&program).is_err());
+    }
+
+    #[test]
+    fn test_rewrite_preserves_sections() {
+        let mut writer = ProgramWriter::new(vec![5, 0, 0, 0]);
+        writer
+            .add_section(SectionKind::Unknown(42), "future", vec![9])
+            .unwrap();
+        writer
+            .add_section(SectionKind::Custom, "author", b"someone".to_vec())
+            .unwrap();
+        let program = writer.finish();
+
+        let mut writer = ProgramWriter::from_program(&program).unwrap();
+        writer
+            .set_section(SectionKind::Custom, "author", b"someone else".to_vec())
+            .unwrap();
+        writer
+            .set_section(SectionKind::Custom, "license", b"MIT".to_vec())
+            .unwrap();
+        assert!(writer
+            .set_section(SectionKind::Custom, "code", Vec::new())
+            .is_err());
+        let program = writer.finish();
+
+        let sections = read_sections(&program).unwrap();
+        let names: Vec<&str> = sections.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["code", "future", "author", "license"]);
+        assert_eq!(sections[1].kind, SectionKind::Unknown(42));
+        assert_eq!(&program[sections[1].range()], &[9]);
+        assert_eq!(&program[sections[2].range()], b"someone else");
+        assert_eq!(&program[sections[0].range()], &[5, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_bss_is_not_stored() {
+        let mut writer = ProgramWriter::new(vec![5, 0, 0, 0]);
+        writer.add_bss("bss", 4096).unwrap();
+        let program = writer.finish();
+        assert_eq!(program.len(), 64 + 4 + 2 * 24);
+
+        let sections = read_sections(&program).unwrap();
+        assert_eq!(sections[1].kind, SectionKind::Bss);
+        assert_eq!(sections[1].length, 4096);
+        assert!(sections[1].contents(&program).is_empty());
+
+        let rewritten = ProgramWriter::from_program(&program).unwrap().finish();
+        assert_eq!(rewritten, program);
+    }
+
+    #[test]
+    fn test_format() {
+        let program = ProgramWriter::new(vec![5, 0, 0, 0]).finish();
+        assert_eq!(read_header(&program).unwrap().format, Format::Narrow);
+
+        let mut writer = ProgramWriter::new(vec![5, 0, 0, 0, 0, 0, 0, 0]);
+        writer.set_format(Format::Wide);
+        let mut program = writer.finish();
+        assert_eq!(read_header(&program).unwrap().format, Format::Wide);
+        let rewritten = ProgramWriter::from_program(&program).unwrap().finish();
+        assert_eq!(rewritten, program);
+
+        program[10] = 9;
+        assert!(read_header(&program).is_err());
+    }
+
+    #[test]
+    fn test_section_names() {
+        let mut writer = ProgramWriter::new(Vec::new());
+        assert!(writer
+            .add_section(SectionKind::Custom, "a-very-long-name", Vec::new())
+            .is_err());
+        assert!(writer
+            .add_section(SectionKind::Custom, "", Vec::new())
+            .is_err());
+    }
+}
@@ -1,12 +1,15 @@
-use crate::instruction::Opcode;
+use crate::{
+    assembler::assembler::SymbolTable,
+    instruction::{Encoding, NarrowEncoding, Opcode, OperandKind},
+};
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_until},
     character::complete::char,
     character::complete::{alpha1, alphanumeric1, digit1, multispace0, space0},
-    combinator::{map, map_res, opt},
+    combinator::{map, map_res, opt, recognize},
     multi::many1,
-    sequence::{delimited, preceded, tuple},
+    sequence::{delimited, pair, preceded, tuple},
     IResult,
 };
 
@@ -36,13 +39,29 @@ impl Program {
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
-    Opcode { opcode: Opcode },
-    Register { idx: u8 },
-    Operand { value: i32 },
-    LabelDeclaration { name: String },
-    LabelUsage { name: String },
-    Directive { name: String },
-    String { value: String },
+    Opcode {
+        opcode: Opcode,
+    },
+    Register {
+        idx: u8,
+    },
+    Operand {
+        value: i32,
+    },
+    LabelDeclaration {
+        name: String,
+    },
+    /// `@name` or `@name+8`, resolved to the label's address plus the offset.
+    LabelUsage {
+        name: String,
+        offset: i32,
+    },
+    Directive {
+        name: String,
+    },
+    String {
+        value: String,
+    },
 }
 
 impl Token {
@@ -78,7 +97,10 @@ impl Token {
 
         let (input, value) = preceded(
             tag("#"),
-            map_res(digit1, |digit_str: &str| digit_str.parse::<i32>()),
+            map_res(
+                recognize(pair(opt(char('-')), digit1)),
+                |digit_str: &str| digit_str.parse::<i32>(),
+            ),
         )(input)?;
 
         Ok((input, Token::Operand { value }))
@@ -101,11 +123,19 @@ impl Token {
 
     pub fn parse_label_usage(input: &str) -> IResult<&str, Token> {
         let (input, _) = space0(input)?; // Handle leading whitespace
-        map(preceded(tag("@"), alphanumeric1), |name: &str| {
+        let (input, name) = preceded(tag("@"), alphanumeric1)(input)?;
+        let (input, offset) = opt(map_res(
+            recognize(pair(alt((char('+'), char('-'))), digit1)),
+            |offset: &str| offset.parse::<i32>(),
+        ))(input)?;
+
+        Ok((
+            input,
             Token::LabelUsage {
                 name: name.to_string(),
-            }
-        })(input)
+                offset: offset.unwrap_or(0),
+            },
+        ))
     }
 
     fn parse_string(input: &str) -> IResult<&str, Token> {
@@ -197,14 +227,25 @@ impl AssemblerInstruction {
     }
 
     fn parse_operand(input: &str) -> IResult<&str, Token> {
-        alt((Token::parse_operand, Token::parse_register))(input)
+        alt((
+            Token::parse_operand,
+            Token::parse_register,
+            Token::parse_label_usage,
+        ))(input)
     }
 
     fn parse_label(input: &str) -> IResult<&str, Token> {
         alt((Token::parse_label_declaration, Token::parse_label_usage))(input)
     }
 
-    fn operand_to_bytes(token: &Option<Token>) -> Result<Vec<u8>, String> {
+    // Integer operands of `signed` instructions must fit the encoding's signed immediate,
+    // all others its unsigned one
+    fn operand_to_bytes(
+        token: Option<&Token>,
+        symbols: Option<&SymbolTable>,
+        signed: bool,
+        encoding: &dyn Encoding,
+    ) -> Result<Vec<u8>, String> {
         let mut bytes = Vec::new();
 
         match token {
@@ -212,11 +253,19 @@ impl AssemblerInstruction {
                 bytes.push(*n);
             }
             Some(Token::Operand { value: n }) => {
-                let val = *n as u16;
-                let second_byte = val as u8;
-                let first_byte = (val >> 8) as u8;
-                bytes.push(first_byte);
-                bytes.push(second_byte);
+                let value = encoding
+                    .encode_immediate(*n as i64, signed)
+                    .ok_or_else(|| format!("Operand out of range: #{n}"))?;
+                bytes.extend_from_slice(&value);
+            }
+            Some(Token::LabelUsage { name, offset }) => {
+                let address = symbols
+                    .and_then(|symbols| symbols.address(name))
+                    .ok_or_else(|| format!("Unknown label: {name}"))?;
+                let address = encoding
+                    .encode_immediate(address as i64 + *offset as i64, false)
+                    .ok_or_else(|| format!("Address out of range: @{name}{offset:+}"))?;
+                bytes.extend_from_slice(&address);
             }
             None => {}
             _ => {
@@ -239,25 +288,143 @@ impl AssemblerInstruction {
         None
     }
 
-    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
-        let mut bytes: Vec<u8> = Vec::new();
+    /// Names of the labels this instruction refers to with `@name`.
+    pub fn label_usages(&self) -> impl Iterator<Item = &str> {
+        [&self.label, &self.operand1, &self.operand2, &self.operand3]
+            .into_iter()
+            .filter_map(|token| match token {
+                Some(Token::LabelUsage { name, .. }) => Some(name.as_str()),
+                _ => None,
+            })
+    }
 
-        if let Some(Token::Opcode { opcode: n }) = &self.opcode {
-            bytes.push(n.clone() as u8);
-        } else {
-            return Err("Non-opcode found in opcode field".to_string());
+    pub fn is_opcode(&self) -> bool {
+        self.opcode.is_some()
+    }
+
+    pub fn opcode(&self) -> Option<Opcode> {
+        match self.opcode {
+            Some(Token::Opcode { opcode }) => Some(opcode),
+            _ => None,
         }
+    }
 
-        for operand in &[&self.operand1, &self.operand2, &self.operand3] {
-            let operand_bytes = Self::operand_to_bytes(operand)?;
-            bytes.extend_from_slice(&operand_bytes);
+    pub fn directive_name(&self) -> Option<&str> {
+        match &self.directive {
+            Some(Token::Directive { name }) => Some(name),
+            _ => None,
         }
+    }
+
+    /// Number of operands, counting a string constant as one.
+    pub fn operand_count(&self) -> usize {
+        [&self.operand1, &self.operand2, &self.operand3, &self.string]
+            .iter()
+            .filter(|operand| operand.is_some())
+            .count()
+    }
 
-        while bytes.len() < 4 {
-            bytes.push(0);
+    /// The register in the first operand, e.g. `$0` in `loadc $0 #100000`.
+    pub fn register(&self) -> Option<u8> {
+        match self.operand1 {
+            Some(Token::Register { idx }) => Some(idx),
+            _ => None,
         }
+    }
 
-        Ok(bytes)
+    /// The immediate value of the second operand, e.g. the constant in `loadc $0 #100000`.
+    pub fn constant(&self) -> Option<i32> {
+        match self.operand2 {
+            Some(Token::Operand { value }) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The string constant, e.g. the text in `.asciiz 'hello'`.
+    pub fn string(&self) -> Option<&str> {
+        match &self.string {
+            Some(Token::String { value }) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The immediate value of the first operand, e.g. the size in `.space #64`.
+    pub fn immediate(&self) -> Option<i32> {
+        match self.operand1 {
+            Some(Token::Operand { value }) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        self.encode(None, &NarrowEncoding)
+    }
+
+    /// Encodes the instruction, resolving label operands to their addresses.
+    pub fn to_bytes_with_symbols(
+        &self,
+        symbols: &SymbolTable,
+        encoding: &dyn Encoding,
+    ) -> Result<Vec<u8>, String> {
+        self.encode(Some(symbols), encoding)
+    }
+
+    fn encode(
+        &self,
+        symbols: Option<&SymbolTable>,
+        encoding: &dyn Encoding,
+    ) -> Result<Vec<u8>, String> {
+        let mut bytes: Vec<u8> = Vec::new();
+
+        let opcode = match (&self.opcode, &self.operand2) {
+            // LOAD zero-extends, so a negative constant needs the sign-extending variant
+            (
+                Some(Token::Opcode {
+                    opcode: Opcode::LOAD,
+                }),
+                Some(Token::Operand { value }),
+            ) if *value < 0 => Opcode::LOADS,
+            // Shifts by a constant have their own opcodes
+            (Some(Token::Opcode { opcode }), Some(Token::Operand { value }))
+                if matches!(opcode, Opcode::SHL | Opcode::SHR) =>
+            {
+                if !(0..=u8::MAX as i32).contains(value) {
+                    return Err(format!("Shift count out of range: #{value}"));
+                }
+                match opcode {
+                    Opcode::SHL => Opcode::SHLI,
+                    _ => Opcode::SHRI,
+                }
+            }
+            (Some(Token::Opcode { opcode }), _) => *opcode,
+            _ => return Err("Non-opcode found in opcode field".to_string()),
+        };
+
+        // A label right after the opcode, as in `call @label`, is the first operand of
+        // instructions that take an address
+        let info = opcode.info();
+        let leading = match &self.label {
+            Some(usage @ Token::LabelUsage { name, .. }) => {
+                let mnemonic = info.map_or("this instruction", |info| info.mnemonic);
+                match info.and_then(|info| info.operands.first()) {
+                    Some(operand) if operand.kind == OperandKind::Integer => Some(usage),
+                    Some(_) => {
+                        let hint = format!("load the address of @{name} into one first");
+                        return Err(format!("{mnemonic} takes a register, {hint}"));
+                    }
+                    None => return Err(format!("{mnemonic} takes no operands, found @{name}")),
+                }
+            }
+            _ => None,
+        };
+        let operands = [&self.operand1, &self.operand2, &self.operand3].map(Option::as_ref);
+        for operand in leading.into_iter().map(Some).chain(operands) {
+            let operand_bytes =
+                Self::operand_to_bytes(operand, symbols, opcode == Opcode::LOADS, encoding)?;
+            bytes.extend_from_slice(&operand_bytes);
+        }
+
+        Ok(encoding.instruction(opcode, &bytes))
     }
 }
 
@@ -361,7 +528,8 @@ mod test {
             (
                 "",
                 Token::LabelUsage {
-                    name: "label1".to_string()
+                    name: "label1".to_string(),
+                    offset: 0,
                 }
             ),
         );
@@ -829,7 +997,8 @@ mod test {
                             opcode: crate::instruction::Opcode::JMP
                         }),
                         label: Some(Token::LabelUsage {
-                            name: "test".to_string()
+                            name: "test".to_string(),
+                            offset: 0,
                         }),
                         directive: None,
                         operand1: None,
@@ -869,7 +1038,8 @@ mod test {
                                 opcode: crate::instruction::Opcode::JMP
                             }),
                             label: Some(Token::LabelUsage {
-                                name: "test".to_string()
+                                name: "test".to_string(),
+                                offset: 0,
                             }),
                             directive: None,
                             operand1: None,
@@ -883,6 +1053,22 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_label_usage_operand() {
+        let (_, instruction) = AssemblerInstruction::parse("load $0 @buffer").unwrap();
+        assert_eq!(
+            instruction.operand2,
+            Some(Token::LabelUsage {
+                name: "buffer".to_string(),
+                offset: 0,
+            })
+        );
+        assert_eq!(
+            instruction.to_bytes(),
+            Err("Unknown label: buffer".to_string())
+        );
+    }
+
     #[test]
     fn test_parse_program_to_bytes() {
         let (_, program) = Program::parse("load $0 #100").unwrap();
@@ -903,4 +1089,91 @@ mod test {
 
         assert_eq!(program.to_bytes().unwrap(), vec![1, 0, 3, 1]);
     }
+
+    #[test]
+    fn test_parse_program_to_bytes_fuel() {
+        let (_, program) = Program::parse("fuel $4").unwrap();
+
+        assert_eq!(program.to_bytes().unwrap(), vec![20, 4, 0, 0]);
+    }
+
+    #[test]
+    fn test_parse_program_to_bytes_yieldto() {
+        let (_, program) = Program::parse("yieldto $2").unwrap();
+
+        assert_eq!(program.to_bytes().unwrap(), vec![21, 2, 0, 0]);
+    }
+
+    #[test]
+    fn test_opcode_help_examples_assemble() {
+        use crate::assembler::{assembler::Assembler, container};
+
+        for info in crate::instruction::OPCODES {
+            let program = Assembler::new().try_assemble(info.example).unwrap();
+            let sections = container::read_sections(&program).unwrap();
+            let code = container::code_section(&sections)
+                .unwrap()
+                .contents(&program);
+            assert_eq!(code[0], info.opcode as u8, "{}", info.example);
+            assert_eq!(code.len(), 4, "{}", info.example);
+        }
+    }
+
+    #[test]
+    fn test_parse_program_to_bytes_shifts() {
+        let (_, program) = Program::parse("shl $0 $1\nshl $0 #4\nshr $2 #255").unwrap();
+        assert_eq!(
+            program.to_bytes().unwrap(),
+            vec![48, 0, 1, 0, 50, 0, 0, 4, 51, 2, 0, 255]
+        );
+
+        let (_, program) = Program::parse("shr $0 #256").unwrap();
+        assert!(program.to_bytes().is_err());
+    }
+
+    #[test]
+    fn test_parse_program_to_bytes_negative_load() {
+        let (_, program) = Program::parse("load $1 #-2\nload $1 #65535\nloads $1 #5").unwrap();
+
+        assert_eq!(
+            program.to_bytes().unwrap(),
+            vec![22, 1, 255, 254, 0, 1, 255, 255, 22, 1, 0, 5]
+        );
+    }
+
+    #[test]
+    fn test_operand_out_of_range() {
+        for source in [
+            "load $1 #65536",
+            "load $1 #-32769",
+            "loadu $1 #-1",
+            "loads $1 #40000",
+        ] {
+            let (_, program) = Program::parse(source).unwrap();
+            assert!(program.to_bytes().is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn test_parse_label_offset() {
+        assert_eq!(
+            Token::parse_label_usage("@table+8").unwrap(),
+            (
+                "",
+                Token::LabelUsage {
+                    name: "table".to_string(),
+                    offset: 8
+                }
+            )
+        );
+        let (rest, token) = Token::parse_label_usage("@end-4 $1").unwrap();
+        assert_eq!(rest, " $1");
+        assert_eq!(
+            token,
+            Token::LabelUsage {
+                name: "end".to_string(),
+                offset: -4
+            }
+        );
+    }
 }
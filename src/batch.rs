@@ -0,0 +1,231 @@
+use std::{fs, path::Path, time::Duration};
+
+use crate::{assembler::assembler::Assembler, json::Json, vm::VM};
+
+/// One program listed in a batch manifest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    /// Assembly source if it ends in `.asm`, assembled bytecode otherwise. Relative paths
+    /// are resolved against the manifest's directory.
+    pub path: String,
+    /// Registers set before the program starts.
+    pub inputs: Vec<(usize, i32)>,
+    pub fuel: Option<u64>,
+    pub heap_limit: Option<usize>,
+    pub timeout: Option<Duration>,
+}
+
+/// Parses the subset of TOML a manifest uses: `[[program]]` tables whose values are
+/// quoted strings or integers.
+///
+/// ```toml
+/// [[program]]
+/// name = "alice"
+/// path = "alice/sum.asm"
+/// inputs = "$0 = 10, $1 = 32"
+/// fuel = 10000
+/// timeout_ms = 500
+/// ```
+pub fn parse_manifest(source: &str) -> Result<Vec<Entry>, String> {
+    let mut tables: Vec<Vec<(String, String)>> = Vec::new();
+
+    for (number, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "[[program]]" {
+            tables.push(Vec::new());
+            continue;
+        }
+
+        let error = |message: &str| format!("line {}: {message}", number + 1);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected key = value"))?;
+        let table = tables
+            .last_mut()
+            .ok_or_else(|| error("key outside of a [[program]] table"))?;
+        let value = value.trim();
+        let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(string) => string.to_string(),
+            None if value.parse::<u64>().is_ok() => value.to_string(),
+            None => return Err(error(&format!("expected a string or integer, got {value}"))),
+        };
+        table.push((key.trim().to_string(), value));
+    }
+
+    tables
+        .into_iter()
+        .enumerate()
+        .map(|(index, table)| to_entry(table).map_err(|e| format!("program {}: {e}", index + 1)))
+        .collect()
+}
+
+fn to_entry(table: Vec<(String, String)>) -> Result<Entry, String> {
+    let mut name = None;
+    let mut path = None;
+    let mut inputs = Vec::new();
+    let mut fuel = None;
+    let mut heap_limit = None;
+    let mut timeout = None;
+
+    for (key, value) in table {
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("{key} must be a number, got {value}"))
+        };
+        match key.as_str() {
+            "name" => name = Some(value),
+            "path" => path = Some(value),
+            "inputs" => inputs = parse_registers(&value)?,
+            "fuel" => fuel = Some(number()?),
+            "heap_limit" => heap_limit = Some(number()? as usize),
+            "timeout_ms" => timeout = Some(Duration::from_millis(number()?)),
+            _ => return Err(format!("unknown key {key}")),
+        }
+    }
+
+    let path = path.ok_or("missing path")?;
+    Ok(Entry {
+        name: name.unwrap_or_else(|| path.clone()),
+        path,
+        inputs,
+        fuel,
+        heap_limit,
+        timeout,
+    })
+}
+
+// "$1 = 10, $2 = -3"
+fn parse_registers(value: &str) -> Result<Vec<(usize, i32)>, String> {
+    value
+        .split(',')
+        .filter(|assignment| !assignment.trim().is_empty())
+        .map(|assignment| {
+            let (register, number) = assignment
+                .split_once('=')
+                .ok_or_else(|| format!("expected $register = value, got {assignment}"))?;
+            let idx = register
+                .trim()
+                .strip_prefix('$')
+                .and_then(|idx| idx.parse::<usize>().ok())
+                .filter(|&idx| idx < 32)
+                .ok_or_else(|| format!("invalid register: {}", register.trim()))?;
+            let number = number
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid value: {}", number.trim()))?;
+
+            Ok((idx, number))
+        })
+        .collect()
+}
+
+/// Assembles and runs an entry, describing the outcome or why it could not run.
+pub fn run_entry(entry: &Entry, base: &Path) -> Json {
+    let result = load(entry, base).and_then(|program| {
+        let mut vm = VM::new();
+        vm.load_program(program);
+        for &(idx, value) in &entry.inputs {
+            vm.set_register(idx, value)?;
+        }
+        if let Some(fuel) = entry.fuel {
+            vm.set_fuel(fuel);
+        }
+        if let Some(bytes) = entry.heap_limit {
+            vm.set_heap_limit(bytes);
+        }
+        if let Some(timeout) = entry.timeout {
+            vm.set_timeout(timeout);
+        }
+
+        Ok((vm.run(), vm))
+    });
+
+    match result {
+        Ok((outcome, vm)) => Json::object([
+            ("name", Json::from(entry.name.as_str())),
+            ("status", Json::from(outcome.exit.as_str())),
+            ("registers", Json::from(vm.registers().to_vec())),
+            ("instructions", Json::from(outcome.instructions)),
+            ("cycles", Json::from(outcome.cycles)),
+            ("fuel_used", Json::from(outcome.fuel_used)),
+            (
+                "elapsed_us",
+                Json::from(outcome.duration.as_micros() as u64),
+            ),
+        ]),
+        Err(e) => Json::object([
+            ("name", Json::from(entry.name.as_str())),
+            ("status", Json::from("error")),
+            ("error", Json::from(e)),
+        ]),
+    }
+}
+
+fn load(entry: &Entry, base: &Path) -> Result<Vec<u8>, String> {
+    let path = base.join(&entry.path);
+    let display = path.display();
+    if path.extension().is_some_and(|extension| extension == "asm") {
+        let source =
+            fs::read_to_string(&path).map_err(|e| format!("Unable to read {display}: {e}"))?;
+        Assembler::new()
+            .try_assemble(&source)
+            .map_err(|errors| errors.join("\n"))
+    } else {
+        fs::read(&path).map_err(|e| format!("Unable to read {display}: {e}"))
+    }
+}
+
+/// Runs every entry in turn, collecting the results into one report.
+pub fn run(entries: &[Entry], base: &Path) -> Json {
+    let results = entries.iter().map(|entry| run_entry(entry, base)).collect();
+
+    Json::object([("results", Json::Array(results))])
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, time::Duration};
+
+    use crate::batch::{parse_manifest, run};
+
+    #[test]
+    fn test_parse_manifest() {
+        let source = "# graded\n[[program]]\nname = \"alice\"\npath = \"alice.asm\"\ninputs = \"$0 = 2, $1 = -3\"\nfuel = 100\ntimeout_ms = 50\n\n[[program]]\npath = \"bob.bin\"\n";
+        let entries = parse_manifest(source).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "alice");
+        assert_eq!(entries[0].inputs, vec![(0, 2), (1, -3)]);
+        assert_eq!(entries[0].fuel, Some(100));
+        assert_eq!(entries[0].timeout, Some(Duration::from_millis(50)));
+        assert_eq!(entries[1].name, "bob.bin");
+        assert_eq!(entries[1].fuel, None);
+
+        assert!(parse_manifest("path = \"orphan.asm\"").is_err());
+        assert!(parse_manifest("[[program]]\nname = \"no path\"").is_err());
+        assert!(parse_manifest("[[program]]\npath = \"a.asm\"\nfuel = lots").is_err());
+        assert!(parse_manifest("[[program]]\npath = \"a.asm\"\ncolour = \"red\"").is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = env::temp_dir().join(format!("vmariachi-batch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("sum.asm"), "add $0 $1 $2\nhlt").unwrap();
+        fs::write(dir.join("loop.asm"), "load $0 #64\njmp $0").unwrap();
+        let entries = parse_manifest(
+            "[[program]]\npath = \"sum.asm\"\ninputs = \"$0 = 40, $1 = 2\"\n[[program]]\npath = \"loop.asm\"\nfuel = 10\n[[program]]\npath = \"missing.asm\"",
+        )
+        .unwrap();
+
+        let report = run(&entries, &dir).to_string();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(report.contains("\"registers\":[40,2,42,"), "{report}");
+        assert!(report.contains("\"status\":\"out_of_fuel\""), "{report}");
+        assert!(report.contains("\"status\":\"error\""), "{report}");
+    }
+}
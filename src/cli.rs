@@ -1,33 +1,305 @@
-use crate::{assembler::assembler::Assembler, repl::REPL, vm::VM};
+use crate::{
+    analyze::{BlockTransitions, ControlFlowGraph},
+    assembler::{
+        assembler::{Assembler, Warning},
+        container::{self, ProgramWriter, SectionKind},
+    },
+    batch,
+    cost::CostModel,
+    debug::Debugger,
+    encoding, inspect,
+    instruction::Format,
+    project,
+    repl::REPL,
+    server::{
+        auth,
+        service::{
+            Limits, Server, DEFAULT_FUEL, DEFAULT_MEMORY, DEFAULT_WALL_TIME, DEFAULT_WORKERS,
+        },
+    },
+    trace, tutorial,
+    vm::VM,
+};
 
-use clap::{Arg, Command};
-use std::{fs::File, io::Read, path::Path, process};
+#[cfg(unix)]
+use crate::control::ControlServer;
+
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    path::Path,
+    process,
+    time::{Duration, Instant},
+};
 
 pub fn run() {
-    let matches = Command::new("VMariachi")
-        .version("1.0")
-        .about("A 32-bit registered based Virtual Machine")
-        .arg(Arg::new("file").short('f').long("file"))
-        .get_matches();
+    let command =
+        Command::new("VMariachi")
+            .version("1.0")
+            .about("A 32-bit registered based Virtual Machine")
+            .arg(Arg::new("file").short('f').long("file"))
+            .arg(
+                Arg::new("format")
+                    .long("format")
+                    .help("How the file is encoded")
+                    .value_parser(["asm", "bin", "hex", "base64"])
+                    .default_value("asm"),
+            )
+            .arg(
+                Arg::new("timeout")
+                    .long("timeout")
+                    .help("Abort the program after this much wall-clock time, e.g. 500ms, 5s or 2m")
+                    .value_parser(parse_duration),
+            )
+            .arg(
+                Arg::new("set-reg")
+                    .long("set-reg")
+                    .help("Set a register before running, e.g. --set-reg 0=42")
+                    .value_parser(parse_register_assignment)
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                Arg::new("load-heap")
+                    .long("load-heap")
+                    .help("Copy a file into the heap before running, e.g. data.bin@0x100")
+                    .value_parser(parse_heap_load)
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                Arg::new("trace")
+                    .long("trace")
+                    .help("Write every executed instruction to this file, for trace-diff"),
+            )
+            .arg(Arg::new("flow").long("flow").help(
+                "Write the basic-block transitions taken, counted, as DOT to this file. It is \
+                 rewritten every second while the program runs, for viewers that reload it",
+            ))
+            .arg(Arg::new("debug-listen").long("debug-listen").help(
+                "Let `attach` pause and inspect the program while it runs, e.g. 127.0.0.1:2245",
+            ))
+            .arg(
+                Arg::new("token-file").long("token-file").help(
+                    "File holding the token debugger clients must send [env: VMARIACHI_TOKEN]",
+                ),
+            )
+            .arg(
+                Arg::new("insecure")
+                    .long("insecure")
+                    .help("Allow a non-loopback --debug-listen address without authentication")
+                    .action(ArgAction::SetTrue),
+            )
+            .subcommand(assemble_command())
+            .subcommand(inspect_command())
+            .subcommand(analyze_command())
+            .subcommand(serve_command())
+            .subcommand(trace_diff_command())
+            .subcommand(batch_command())
+            .subcommand(
+                Command::new("attach")
+                    .about("Control a program started with --debug-listen")
+                    .arg(Arg::new("address").required(true))
+                    .arg(
+                        Arg::new("token-file")
+                            .long("token-file")
+                            .help("File holding the debugger's token [env: VMARIACHI_TOKEN]"),
+                    ),
+            )
+            .subcommand(
+                Command::new("build")
+                    .about("Assemble the project described by vmariachi.toml")
+                    .arg(Arg::new("dir").help("Project root").default_value(".")),
+            )
+            .subcommand(
+                Command::new("new")
+                    .about("Create a project with a manifest, main.asm, lib/ and tests/")
+                    .arg(Arg::new("name").required(true)),
+            )
+            .subcommand(
+                Command::new("tutorial").about("Learn the instruction set with guided lessons"),
+            );
+    #[cfg(unix)]
+    let command = command.subcommand(control_command());
+    let matches = command.get_matches();
+
+    match matches.subcommand() {
+        Some(("assemble", assemble_matches)) => {
+            assemble(assemble_matches);
+            return;
+        }
+        Some(("inspect", inspect_matches)) => {
+            inspect(inspect_matches);
+            return;
+        }
+        Some(("tutorial", _)) => {
+            tutorial::run();
+            return;
+        }
+        Some(("analyze", analyze_matches)) => {
+            analyze(analyze_matches);
+            return;
+        }
+        Some(("trace-diff", trace_diff_matches)) => {
+            trace_diff(trace_diff_matches);
+            return;
+        }
+        Some(("build", build_matches)) => {
+            let dir = build_matches
+                .get_one::<String>("dir")
+                .expect("dir has a default");
+            match project::build(Path::new(dir)) {
+                Ok(build) => {
+                    for warning in build.warnings {
+                        println!("warning: {warning}");
+                    }
+                    println!("Wrote {} bytes to {}", build.size, build.output.display());
+                }
+                Err(errors) => {
+                    for e in errors {
+                        eprintln!("{e}");
+                    }
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(("new", new_matches)) => {
+            let name = new_matches
+                .get_one::<String>("name")
+                .expect("name is required");
+            if let Err(e) = project::scaffold(Path::new(name)) {
+                eprintln!("{e}");
+                process::exit(1);
+            }
+            println!("Created project {name}");
+            return;
+        }
+        Some(("attach", attach_matches)) => {
+            let address = attach_matches
+                .get_one::<String>("address")
+                .expect("address is required");
+            let token = read_token(attach_matches);
+            if let Err(e) = attach(address, token.as_deref()) {
+                eprintln!("Unable to attach to {address}: {e}");
+                process::exit(1);
+            }
+            return;
+        }
+        Some(("batch", batch_matches)) => {
+            run_batch(batch_matches);
+            return;
+        }
+        Some(("serve", serve_matches)) => {
+            serve(serve_matches);
+            return;
+        }
+        #[cfg(unix)]
+        Some(("control", control_matches)) => {
+            control(control_matches);
+            return;
+        }
+        _ => {}
+    }
 
     match matches.get_one::<String>("file") {
         Some(file) => {
             println!(">> reading file {file}");
 
-            let program = read_file(file);
-            let mut assembler = Assembler::new();
+            let format = matches
+                .get_one::<String>("format")
+                .expect("format has a default");
+            let Some(bytes) = load_program(file, format) else {
+                process::exit(1);
+            };
             let mut vm = VM::new();
+            if let Some(timeout) = matches.get_one::<Duration>("timeout") {
+                vm.set_timeout(*timeout);
+            }
+            vm.load_program(bytes);
+            if let Err(e) = initialise(&mut vm, &matches) {
+                eprintln!("{e}");
+                process::exit(1);
+            }
 
-            println!(">> assembling program");
-            if let Some(bytes) = assembler.assemble(&program) {
-                vm.add_program(bytes);
+            let trace_path = matches.get_one::<String>("trace");
+            if trace_path.is_some() {
+                vm.enable_trace();
+            }
+
+            let mut debugger = matches.get_one::<String>("debug-listen").map(|address| {
+                let token = read_token(&matches);
+                let host = address
+                    .rsplit_once(':')
+                    .map_or(address.as_str(), |(host, _)| host);
+                check_exposure(host, token.is_some(), matches.get_flag("insecure"));
+                let (debugger, local) = Debugger::listen(address, token).unwrap_or_else(|e| {
+                    eprintln!("Unable to listen on {address}: {e}");
+                    process::exit(1);
+                });
+                println!(">> debugger listening on {local}");
+                debugger.attach(&mut vm);
+                debugger
+            });
+
+            let flow_path = matches.get_one::<String>("flow");
+            let mut flow = flow_path.map(|_| {
+                let cfg = ControlFlowGraph::from_program(vm.program()).unwrap_or_else(|e| {
+                    eprintln!("{e}");
+                    process::exit(1);
+                });
+                BlockTransitions::new(cfg)
+            });
+            let write_flow = |flow: &BlockTransitions| {
+                if let Some(path) = flow_path {
+                    if let Err(e) = fs::write(path, flow.to_dot()) {
+                        eprintln!("Unable to write {path}: {e}");
+                    }
+                }
+            };
 
-                println!(">> running program");
-                vm.run();
+            println!(">> running program");
+            let mut written = Instant::now();
+            let outcome = vm.run_with(|vm| {
+                if let Some(debugger) = debugger.as_mut() {
+                    debugger.poll(vm);
+                }
+                if let Some(flow) = flow.as_mut() {
+                    flow.record(vm.program_counter());
+                    if written.elapsed() >= Duration::from_secs(1) {
+                        write_flow(flow);
+                        written = Instant::now();
+                    }
+                }
+            });
+            if let Some(flow) = &flow {
+                write_flow(flow);
+            }
+            if let Some(path) = trace_path {
+                if let Err(e) = fs::write(path, trace::to_text(vm.trace())) {
+                    eprintln!("Unable to write {path}: {e}");
+                    process::exit(1);
+                }
+            }
 
-                println!(">> completed!");
-                process::exit(0);
+            println!(
+                ">> {} after {} instructions ({} fuel, {} cycles, {:?})",
+                outcome.exit.as_str(),
+                outcome.instructions,
+                outcome.fuel_used,
+                outcome.cycles,
+                outcome.duration
+            );
+            if let Some(trap) = outcome.trap {
+                eprintln!(">> trapped at pc {} ({:?})", trap.pc, trap.opcode);
+                if let Some(error) = outcome.error {
+                    eprintln!(">> {error}");
+                }
+                process::exit(1);
             }
+            println!(">> completed!");
+            process::exit(0);
         }
         None => {
             let mut repl = REPL::new();
@@ -36,6 +308,649 @@ pub fn run() {
     }
 }
 
+/// Applies the --set-reg and --load-heap inputs to a freshly loaded VM.
+fn initialise(vm: &mut VM, matches: &ArgMatches) -> Result<(), String> {
+    for (idx, value) in matches
+        .get_many::<(usize, i32)>("set-reg")
+        .unwrap_or_default()
+    {
+        vm.set_register(*idx, *value)?;
+    }
+    for (path, offset) in matches
+        .get_many::<(String, usize)>("load-heap")
+        .unwrap_or_default()
+    {
+        let bytes = fs::read(path).map_err(|e| format!("Unable to read {path}: {e}"))?;
+        vm.write_heap(*offset, &bytes)?;
+    }
+
+    Ok(())
+}
+
+fn assemble_command() -> Command {
+    Command::new("assemble")
+        .about("Assemble a source file into bytecode")
+        .arg(Arg::new("input").required(true))
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .help("Where to write the bytecode [default: stdout]"),
+        )
+        .arg(
+            Arg::new("emit")
+                .long("emit")
+                .help("Encoding of the bytecode")
+                .value_parser(["bin", "hex", "base64"])
+                .default_value("bin"),
+        )
+        .arg(
+            Arg::new("metadata")
+                .long("metadata")
+                .help("Embed a metadata section, e.g. --metadata author=me")
+                .value_parser(parse_metadata)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("lenient")
+                .long("lenient")
+                .help("Warn about unknown directives instead of failing")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("warning")
+                .short('W')
+                .help("Enable a warning: unused-label, unknown-directive, deprecated-mnemonic, or all as -Wall")
+                .value_parser(["all", "unused-label", "unknown-directive", "deprecated-mnemonic"])
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("deny-warnings")
+                .long("deny-warnings")
+                .help("Fail when any enabled warning is reported")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("symbols")
+                .long("symbols")
+                .help("Also write the symbol table as JSON to this file"),
+        )
+        .arg(
+            Arg::new("fuse-branches")
+                .long("fuse-branches")
+                .help("Merge comparisons followed by JEQ into compare-and-branch instructions. Jump targets must be labels")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("instruction-format")
+                .long("instruction-format")
+                .help("Instruction format: narrow (4 bytes, 16 bit integers) or wide (8 bytes, 32 bit integers)")
+                .value_parser(["narrow", "wide"])
+                .default_value("narrow"),
+        )
+}
+
+fn assemble(matches: &ArgMatches) {
+    let input = matches
+        .get_one::<String>("input")
+        .expect("input is required");
+    let mut assembler = Assembler::new();
+    assembler.set_fuse_branches(matches.get_flag("fuse-branches"));
+    if let Some(format) = matches
+        .get_one::<String>("instruction-format")
+        .and_then(|name| Format::from_name(name))
+    {
+        assembler.set_format(format);
+    }
+    assembler.set_strict(!matches.get_flag("lenient"));
+    assembler.set_deny_warnings(matches.get_flag("deny-warnings"));
+    for name in matches.get_many::<String>("warning").unwrap_or_default() {
+        match Warning::from_name(name) {
+            Some(warning) => assembler.enable_warning(warning),
+            None => Warning::ALL
+                .into_iter()
+                .for_each(|warning| assembler.enable_warning(warning)),
+        }
+    }
+    for (name, value) in matches
+        .get_many::<(String, String)>("metadata")
+        .unwrap_or_default()
+    {
+        assembler.add_metadata(name, value.as_bytes().to_vec());
+    }
+    let Some(bytes) = assemble_source(&mut assembler, &read_file(input)) else {
+        process::exit(1);
+    };
+    if let Some(path) = matches.get_one::<String>("symbols") {
+        if let Err(e) = fs::write(path, format!("{}\n", assembler.symbols_json())) {
+            eprintln!("Unable to write {path}: {e}");
+            process::exit(1);
+        }
+    }
+
+    let output = match matches.get_one::<String>("emit").map(String::as_str) {
+        Some("hex") => format!("{}\n", encoding::to_hex(&bytes)).into_bytes(),
+        Some("base64") => format!("{}\n", encoding::to_base64(&bytes)).into_bytes(),
+        _ => bytes,
+    };
+
+    let result = match matches.get_one::<String>("output") {
+        Some(path) => fs::write(path, output),
+        None => io::stdout().write_all(&output),
+    };
+    if let Err(e) = result {
+        eprintln!("Unable to write bytecode: {e}");
+        process::exit(1);
+    }
+}
+
+fn analyze_command() -> Command {
+    Command::new("analyze")
+        .about("Recover the control-flow graph of an assembled program")
+        .arg(Arg::new("file").required(true))
+        .arg(
+            Arg::new("cfg")
+                .long("cfg")
+                .help("Write the graph in DOT format to this file instead of stdout"),
+        )
+}
+
+fn analyze(matches: &ArgMatches) {
+    let file = matches.get_one::<String>("file").expect("file is required");
+    let program = fs::read(file).unwrap_or_else(|e| {
+        eprintln!("Unable to read {file}: {e}");
+        process::exit(1);
+    });
+    let cfg = ControlFlowGraph::from_program(&program).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        process::exit(1);
+    });
+
+    match matches.get_one::<String>("cfg") {
+        Some(path) => {
+            if let Err(e) = fs::write(path, cfg.to_dot()) {
+                eprintln!("Unable to write {path}: {e}");
+                process::exit(1);
+            }
+            println!(
+                "{} basic blocks, {} unresolved jumps",
+                cfg.blocks.len(),
+                cfg.unresolved()
+            );
+        }
+        None => print!("{}", cfg.to_dot()),
+    }
+}
+
+fn trace_diff_command() -> Command {
+    Command::new("trace-diff")
+        .about("Find the first instruction where a run's trace departs from a golden trace")
+        .arg(Arg::new("run").required(true))
+        .arg(Arg::new("golden").required(true))
+        .arg(
+            Arg::new("context")
+                .long("context")
+                .help("Instructions to show around the divergence")
+                .value_parser(value_parser!(usize))
+                .default_value("3"),
+        )
+}
+
+fn trace_diff(matches: &ArgMatches) {
+    let read = |name: &str| {
+        let path = matches
+            .get_one::<String>(name)
+            .expect("traces are required");
+        fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|source| trace::parse(&source))
+            .unwrap_or_else(|e| {
+                eprintln!("Unable to read {path}: {e}");
+                process::exit(1);
+            })
+    };
+    let (run, golden) = (read("run"), read("golden"));
+    let context = *matches
+        .get_one::<usize>("context")
+        .expect("context has a default");
+
+    match trace::diff(&run, &golden, context) {
+        Some(diff) => {
+            print!("{diff}");
+            process::exit(1);
+        }
+        None => println!("Traces match ({} instructions)", run.len()),
+    }
+}
+
+// Sends each line of stdin to the debugger and prints its answer
+fn attach(address: &str, token: Option<&str>) -> io::Result<()> {
+    let stream = TcpStream::connect(address)?;
+    let mut responses = BufReader::new(stream.try_clone()?);
+    if let Some(token) = token {
+        writeln!(&stream, "auth {token}")?;
+        let mut response = String::new();
+        responses.read_line(&mut response)?;
+        if !response.contains("\"authenticated\"") {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the debugger rejected the token",
+            ));
+        }
+    }
+    println!(
+        "Attached. Commands: pause, resume, step [count], state, registers, register <idx>, patch <function> <file>, kill"
+    );
+
+    for line in io::stdin().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match line.trim().strip_prefix("patch ") {
+            // The source goes over the connection, the debugger never reads our files
+            Some(argument) => {
+                let Some((name, path)) = argument.trim().split_once(char::is_whitespace) else {
+                    println!("Usage: patch <function> <file>");
+                    continue;
+                };
+                let source = match fs::read_to_string(path.trim()) {
+                    Ok(source) => source,
+                    Err(e) => {
+                        println!("Unable to read {}: {e}", path.trim());
+                        continue;
+                    }
+                };
+                writeln!(&stream, "patch {name}\n{}\n.end", source.trim_end())?;
+            }
+            None => writeln!(&stream, "{}", line.trim())?,
+        }
+        let mut response = String::new();
+        if responses.read_line(&mut response)? == 0 {
+            println!("Connection closed");
+            break;
+        }
+        print!("{response}");
+    }
+
+    Ok(())
+}
+
+fn batch_command() -> Command {
+    Command::new("batch")
+        .about("Assemble and run every program listed in a manifest, reporting the results as JSON")
+        .arg(Arg::new("manifest").required(true))
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .help("Where to write the report [default: stdout]"),
+        )
+}
+
+fn run_batch(matches: &ArgMatches) {
+    let path = matches
+        .get_one::<String>("manifest")
+        .expect("manifest is required");
+    let entries = batch::parse_manifest(&read_file(path)).unwrap_or_else(|e| {
+        eprintln!("Invalid manifest {path}: {e}");
+        process::exit(1);
+    });
+    let base = Path::new(path).parent().unwrap_or(Path::new("."));
+    let report = format!("{}\n", batch::run(&entries, base));
+
+    let result = match matches.get_one::<String>("output") {
+        Some(output) => fs::write(output, report),
+        None => io::stdout().write_all(report.as_bytes()),
+    };
+    if let Err(e) = result {
+        eprintln!("Unable to write report: {e}");
+        process::exit(1);
+    }
+}
+
+fn inspect_command() -> Command {
+    Command::new("inspect")
+        .about("Describe the header, sections and symbols of an assembled program")
+        .arg(Arg::new("file").required(true))
+        .arg(
+            Arg::new("hexdump")
+                .long("hexdump")
+                .help("Also print a hexdump of every section")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("disassemble")
+                .long("disassemble")
+                .help("List the code with label names instead of describing the program")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("section")
+                .long("section")
+                .help("Print the contents of a single section"),
+        )
+        .arg(
+            Arg::new("set")
+                .long("set")
+                .help("Add or replace a metadata section in place, e.g. --set license=MIT")
+                .value_parser(parse_metadata)
+                .action(ArgAction::Append),
+        )
+}
+
+fn inspect(matches: &ArgMatches) {
+    let file = matches.get_one::<String>("file").expect("file is required");
+    let program = fs::read(file).unwrap_or_else(|e| {
+        eprintln!("Unable to read {file}: {e}");
+        process::exit(1);
+    });
+
+    if let Some(updates) = matches.get_many::<(String, String)>("set") {
+        let result = ProgramWriter::from_program(&program).and_then(|mut writer| {
+            for (name, value) in updates {
+                writer.set_section(SectionKind::Custom, name, value.as_bytes().to_vec())?;
+            }
+            fs::write(file, writer.finish()).map_err(|e| format!("Unable to write {file}: {e}"))
+        });
+        if let Err(e) = result {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(name) = matches.get_one::<String>("section") {
+        let section = container::read_sections(&program).and_then(|sections| {
+            sections
+                .into_iter()
+                .find(|section| &section.name == name)
+                .ok_or_else(|| format!("No section named {name}"))
+        });
+        match section {
+            Ok(section) => {
+                let _ = io::stdout().write_all(section.contents(&program));
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if matches.get_flag("disassemble") {
+        match inspect::disassemble(&program, None, usize::MAX) {
+            Ok(listing) => print!("{listing}"),
+            Err(e) => {
+                eprintln!("{e}");
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    match inspect::describe(&program, matches.get_flag("hexdump")) {
+        Ok(description) => print!("{description}"),
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    }
+}
+
+fn serve_command() -> Command {
+    Command::new("serve")
+        .about("Run an HTTP service that executes submitted programs")
+        .arg(
+            Arg::new("port")
+                .short('p')
+                .long("port")
+                .value_parser(value_parser!(u16))
+                .default_value("8080"),
+        )
+        .arg(Arg::new("host").long("host").default_value("127.0.0.1"))
+        .arg(
+            Arg::new("workers")
+                .long("workers")
+                .help("Programs executed concurrently [default: 4]")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("max-fuel")
+                .long("max-fuel")
+                .help("Instruction budget per program [default: 10000000]")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("max-memory")
+                .long("max-memory")
+                .help("Heap bytes a program may allocate [default: 16777216]")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("max-time-ms")
+                .long("max-time-ms")
+                .help("Wall-clock time per program [default: 5000]")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("cost-model")
+                .long("cost-model")
+                .help("How fuel is charged per instruction")
+                .value_parser(["weighted", "uniform"])
+                .default_value("weighted"),
+        )
+        .arg(
+            Arg::new("sample-interval")
+                .long("sample-interval")
+                .help("Sample the running opcode every N instructions for GET /profile, 0 is off")
+                .value_parser(value_parser!(u64))
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("token-file")
+                .long("token-file")
+                .help("File holding the bearer token clients must send [env: VMARIACHI_TOKEN]"),
+        )
+        .arg(
+            Arg::new("insecure")
+                .long("insecure")
+                .help("Allow serving a non-loopback address without authentication")
+                .action(ArgAction::SetTrue),
+        )
+}
+
+fn serve(matches: &ArgMatches) {
+    let host = matches
+        .get_one::<String>("host")
+        .expect("host has a default");
+    let port = matches.get_one::<u16>("port").expect("port has a default");
+    let workers = matches
+        .get_one::<usize>("workers")
+        .copied()
+        .unwrap_or(DEFAULT_WORKERS);
+    let limits = Limits {
+        fuel: matches
+            .get_one::<u64>("max-fuel")
+            .copied()
+            .unwrap_or(DEFAULT_FUEL),
+        memory: matches
+            .get_one::<usize>("max-memory")
+            .copied()
+            .unwrap_or(DEFAULT_MEMORY),
+        wall_time: matches
+            .get_one::<u64>("max-time-ms")
+            .map(|ms| Duration::from_millis(*ms))
+            .unwrap_or(DEFAULT_WALL_TIME),
+    };
+
+    let token = read_token(matches);
+    check_exposure(host, token.is_some(), matches.get_flag("insecure"));
+
+    let mut server = Server::new(limits, workers);
+    if let Some(token) = token {
+        server.set_token(token);
+    }
+    if matches.get_one::<String>("cost-model").map(String::as_str) == Some("uniform") {
+        server.set_cost_model(CostModel::default());
+    }
+    server.set_sample_interval(
+        *matches
+            .get_one::<u64>("sample-interval")
+            .expect("sample-interval has a default"),
+    );
+    if let Err(e) = server.listen(&format!("{host}:{port}")) {
+        eprintln!("Unable to start server: {e}");
+        process::exit(1);
+    }
+}
+
+/// The token from --token-file, or the environment, exiting if it is invalid.
+fn read_token(matches: &ArgMatches) -> Option<String> {
+    let token = match matches.get_one::<String>("token-file") {
+        Some(path) => Some(auth::read_token_file(Path::new(path))),
+        None => env::var(auth::TOKEN_ENV_VAR)
+            .ok()
+            .map(|token| auth::validate_token(token.trim())),
+    };
+    match token.transpose() {
+        Ok(token) => token,
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Exits unless listening on `host` is safe: it is a loopback address, clients need a
+/// token, or the user passed --insecure.
+fn check_exposure(host: &str, authenticated: bool, insecure: bool) {
+    if !authenticated && !auth::is_loopback(host) && !insecure {
+        eprintln!(
+            "Refusing to listen on {host} without authentication, use --token-file or pass --insecure"
+        );
+        process::exit(1);
+    }
+}
+
+#[cfg(unix)]
+fn control_command() -> Command {
+    Command::new("control")
+        .about("Drive a VM through commands sent over a unix domain socket")
+        .arg(
+            Arg::new("socket")
+                .short('s')
+                .long("socket")
+                .default_value("vmariachi.sock"),
+        )
+}
+
+#[cfg(unix)]
+fn control(matches: &ArgMatches) {
+    let socket = matches
+        .get_one::<String>("socket")
+        .expect("socket has a default");
+
+    let mut control = ControlServer::new();
+    if let Err(e) = control.listen(Path::new(socket)) {
+        eprintln!("Unable to start control socket: {e}");
+        process::exit(1);
+    }
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount = amount
+        .parse::<u64>()
+        .map_err(|_| format!("invalid duration: {value}"))?;
+
+    match unit {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "" | "s" => Ok(Duration::from_secs(amount)),
+        "m" => amount
+            .checked_mul(60)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("duration too large: {value}")),
+        _ => Err(format!(
+            "invalid duration unit '{unit}', expected ms, s or m"
+        )),
+    }
+}
+
+/// Reads a program from `file`, assembling or decoding it according to `format`.
+fn load_program(file: &str, format: &str) -> Option<Vec<u8>> {
+    let decoded = match format {
+        "asm" => {
+            println!(">> assembling program");
+            return assemble_source(&mut Assembler::new(), &read_file(file));
+        }
+        "bin" => fs::read(file.trim()).map_err(|e| format!("Unable to read {file}: {e}")),
+        "hex" => encoding::from_hex(&read_file(file)),
+        "base64" => encoding::from_base64(&read_file(file)),
+        _ => Err(format!("Unknown program format: {format}")),
+    };
+
+    decoded.map_err(|e| eprintln!("{e}")).ok()
+}
+
+fn parse_register_assignment(value: &str) -> Result<(usize, i32), String> {
+    let (idx, number) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected register=value, got '{value}'"))?;
+    let idx = idx.trim().trim_start_matches('$');
+
+    Ok((
+        idx.parse()
+            .map_err(|_| format!("invalid register: {idx}"))?,
+        number
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid register value: {number}"))?,
+    ))
+}
+
+fn parse_heap_load(value: &str) -> Result<(String, usize), String> {
+    let (path, offset) = value.rsplit_once('@').unwrap_or((value, "0"));
+    let offset = match offset.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => offset.parse(),
+    }
+    .map_err(|_| format!("invalid heap offset: {offset}"))?;
+
+    Ok((path.to_string(), offset))
+}
+
+fn parse_metadata(value: &str) -> Result<(String, String), String> {
+    let (name, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected name=value, got '{value}'"))?;
+
+    Ok((name.to_string(), value.to_string()))
+}
+
+// Assembles `source`, printing warnings and errors to stderr so they stay out of the output
+fn assemble_source(assembler: &mut Assembler, source: &str) -> Option<Vec<u8>> {
+    let result = assembler.try_assemble(source);
+    for warning in assembler.warnings() {
+        eprintln!("warning: {warning}");
+    }
+    match result {
+        Ok(bytes) => Some(bytes),
+        Err(errors) => {
+            eprintln!("There was an error assembling the code:");
+            for e in errors {
+                eprintln!("  {e}");
+            }
+            None
+        }
+    }
+}
+
 fn read_file(file: &str) -> String {
     let mut f = File::open(Path::new(file.trim())).expect("Unable to open file");
     let mut content = String::new();
@@ -43,3 +958,65 @@ fn read_file(file: &str) -> String {
 
     content
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::cli::{parse_duration, parse_heap_load, parse_metadata, parse_register_assignment};
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("5s"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("5"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn test_parse_invalid_duration() {
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("5h").is_err());
+        assert!(parse_duration("-5s").is_err());
+        assert!(parse_duration("999999999999999999m").is_err());
+        assert_eq!(
+            parse_duration("18446744073709551615s"),
+            Ok(Duration::from_secs(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn test_parse_metadata() {
+        assert_eq!(
+            parse_metadata("license=MIT"),
+            Ok(("license".to_string(), "MIT".to_string()))
+        );
+        assert_eq!(
+            parse_metadata("build=a=b"),
+            Ok(("build".to_string(), "a=b".to_string()))
+        );
+        assert!(parse_metadata("license").is_err());
+    }
+
+    #[test]
+    fn test_parse_register_assignment() {
+        assert_eq!(parse_register_assignment("0=42"), Ok((0, 42)));
+        assert_eq!(parse_register_assignment("$3=-7"), Ok((3, -7)));
+        assert!(parse_register_assignment("0").is_err());
+        assert!(parse_register_assignment("x=1").is_err());
+    }
+
+    #[test]
+    fn test_parse_heap_load() {
+        assert_eq!(
+            parse_heap_load("data.bin@0x100"),
+            Ok(("data.bin".to_string(), 256))
+        );
+        assert_eq!(
+            parse_heap_load("data.bin@16"),
+            Ok(("data.bin".to_string(), 16))
+        );
+        assert_eq!(parse_heap_load("data.bin"), Ok(("data.bin".to_string(), 0)));
+        assert!(parse_heap_load("data.bin@zz").is_err());
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+};
+
+use crate::{
+    assembler::assembler::Assembler,
+    json::Json,
+    vm::{ExitReason, VM},
+};
+
+/// Upper bound on instructions executed by a single `run` or `step` command.
+pub const RUN_LIMIT: u64 = 10_000_000;
+
+/// Drives a VM through a line based protocol over a unix domain socket.
+///
+/// Each line holds one command and is answered by one line of JSON:
+/// `load <path>`, `reset`, `step [count]`, `run`, `registers`, `register <idx>` and `state`.
+#[derive(Debug, Default)]
+pub struct ControlServer {
+    vm: VM,
+    program: Vec<u8>,
+    exit: Option<ExitReason>,
+}
+
+impl ControlServer {
+    pub fn new() -> Self {
+        Self {
+            vm: VM::new(),
+            program: Vec::new(),
+            exit: None,
+        }
+    }
+
+    pub fn listen(&mut self, path: &Path) -> io::Result<()> {
+        // A socket file left behind by a previous run would make bind fail, but anything
+        // else at the path is not ours to delete
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let listener = UnixListener::bind(path)?;
+        // Only the owner may drive the VM
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        println!(">> listening on {}", path.display());
+
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = self.handle_client(stream) {
+                        eprintln!("Connection error: {e}");
+                    }
+                }
+                Err(e) => eprintln!("Unable to accept connection: {e}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_client(&mut self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            writeln!(writer, "{}", self.execute(&line))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn execute(&mut self, line: &str) -> Json {
+        let line = line.trim();
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, Some(argument.trim())),
+            None => (line, None),
+        };
+
+        let result = match (command, argument) {
+            ("load", Some(path)) => self.load(path),
+            ("reset", None) => self.reset(),
+            ("step", count) => match count.map(str::parse::<u64>).unwrap_or(Ok(1)) {
+                Ok(count) if count <= RUN_LIMIT => Ok(self.step(count)),
+                Ok(_) => Err(format!("Step count exceeds the limit of {RUN_LIMIT}")),
+                Err(_) => Err(format!("Invalid step count: {}", count.unwrap_or_default())),
+            },
+            ("run", None) => Ok(self.step(RUN_LIMIT)),
+            ("registers", None) => Ok(Json::object([(
+                "registers",
+                Json::from(self.vm.registers().to_vec()),
+            )])),
+            ("register", Some(idx)) => match idx
+                .parse::<usize>()
+                .ok()
+                .and_then(|idx| Some((idx, self.vm.register(idx)?)))
+            {
+                Some((idx, value)) => Ok(Json::object([
+                    ("register", Json::from(idx)),
+                    ("value", Json::from(value)),
+                ])),
+                None => Err(format!("Invalid register: {idx}")),
+            },
+            ("state", None) => Ok(self.state()),
+            _ => Err(format!("Unknown command: {line}")),
+        };
+
+        result.unwrap_or_else(|e| Json::object([("error", Json::from(e))]))
+    }
+
+    fn load(&mut self, path: &str) -> Result<Json, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("Unable to read {path}: {e}"))?;
+        let program = Assembler::new()
+            .try_assemble(&source)
+            .map_err(|errors| format!("Unable to assemble {path}: {}", errors.join(", ")))?;
+
+        self.program = program;
+        self.reset()
+    }
+
+    fn reset(&mut self) -> Result<Json, String> {
+        self.vm = VM::new();
+        self.vm.load_program(self.program.clone());
+        self.exit = None;
+        self.vm
+            .start()
+            .map_err(|e| format!("No valid program loaded: {e}"))?;
+
+        Ok(self.state())
+    }
+
+    fn step(&mut self, count: u64) -> Json {
+        let mut executed: u64 = 0;
+        while self.exit.is_none() && executed < count {
+            self.exit = self.vm.run_once();
+            executed += 1;
+        }
+
+        Json::object([("executed", Json::from(executed)), ("state", self.state())])
+    }
+
+    fn state(&self) -> Json {
+        Json::object([
+            ("pc", Json::from(self.vm.program_counter())),
+            ("heap_size", Json::from(self.vm.heap_size())),
+            (
+                "exit",
+                self.exit
+                    .map_or(Json::Null, |exit| Json::from(exit.as_str())),
+            ),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env, fs, io,
+        io::{BufRead, BufReader, Write},
+        os::unix::{fs::PermissionsExt, net::UnixStream},
+        process, thread,
+        time::Duration,
+    };
+
+    use crate::control::ControlServer;
+
+    fn load(source: &str, name: &str) -> ControlServer {
+        let path = env::temp_dir().join(format!("vmariachi-{}-{name}.asm", process::id()));
+        fs::write(&path, source).unwrap();
+        let mut control = ControlServer::new();
+        control.execute(&format!("load {}", path.display()));
+        fs::remove_file(path).unwrap();
+
+        control
+    }
+
+    #[test]
+    fn test_load_and_step() {
+        let mut control = load("load $0 #7\ninc $0\nhlt", "step");
+        assert_eq!(
+            control.execute("state").to_string(),
+            r#"{"pc":64,"heap_size":0,"exit":null}"#
+        );
+        assert_eq!(
+            control.execute("step 2").to_string(),
+            r#"{"executed":2,"state":{"pc":72,"heap_size":0,"exit":null}}"#
+        );
+        assert_eq!(
+            control.execute("register 0").to_string(),
+            r#"{"register":0,"value":8}"#
+        );
+        assert_eq!(
+            control.execute("step").to_string(),
+            r#"{"executed":1,"state":{"pc":73,"heap_size":0,"exit":"halted"}}"#
+        );
+        assert_eq!(
+            control.execute("step").to_string(),
+            r#"{"executed":0,"state":{"pc":73,"heap_size":0,"exit":"halted"}}"#
+        );
+    }
+
+    #[test]
+    fn test_run_and_reset() {
+        let mut control = load("inc $1\ninc $1\nhlt", "run");
+        assert_eq!(
+            control.execute("run").to_string(),
+            r#"{"executed":3,"state":{"pc":73,"heap_size":0,"exit":"halted"}}"#
+        );
+        assert!(control
+            .execute("registers")
+            .to_string()
+            .starts_with(r#"{"registers":[0,2,0"#));
+        control.execute("reset");
+        assert_eq!(
+            control.execute("register 1").to_string(),
+            r#"{"register":1,"value":0}"#
+        );
+    }
+
+    #[test]
+    fn test_invalid_commands() {
+        let mut control = ControlServer::new();
+        assert_eq!(
+            control.execute("jump").to_string(),
+            r#"{"error":"Unknown command: jump"}"#
+        );
+        assert_eq!(
+            control.execute("register 32").to_string(),
+            r#"{"error":"Invalid register: 32"}"#
+        );
+        assert_eq!(
+            control.execute("step many").to_string(),
+            r#"{"error":"Invalid step count: many"}"#
+        );
+        assert_eq!(
+            control.execute("step 18446744073709551615").to_string(),
+            r#"{"error":"Step count exceeds the limit of 10000000"}"#
+        );
+        assert_eq!(
+            control.execute("reset").to_string(),
+            r#"{"error":"No valid program loaded: Invalid header"}"#
+        );
+    }
+
+    #[test]
+    fn test_socket_roundtrip() {
+        let path = env::temp_dir().join(format!("vmariachi-{}.sock", process::id()));
+        let socket = path.clone();
+        thread::spawn(move || ControlServer::new().listen(&socket));
+
+        let mut stream = loop {
+            match UnixStream::connect(&path) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(5)),
+            }
+        };
+        stream.write_all(b"register 3\n").unwrap();
+        let mut response = String::new();
+        BufReader::new(&stream).read_line(&mut response).unwrap();
+        assert_eq!(response, "{\"register\":3,\"value\":0}\n");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_listen_keeps_other_files() {
+        let path = env::temp_dir().join(format!("vmariachi-{}-notes.txt", process::id()));
+        fs::write(&path, "keep me").unwrap();
+        let error = ControlServer::new().listen(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep me");
+
+        fs::remove_file(path).unwrap();
+    }
+}
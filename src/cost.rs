@@ -0,0 +1,113 @@
+use crate::instruction::Opcode;
+
+/// Fuel charged for executing each opcode.
+///
+/// Instructions whose work grows with their operand (ALOC) are additionally charged
+/// `kib_cost` for every started KiB they touch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostModel {
+    costs: [u64; 256],
+    kib_cost: u64,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self::uniform(1)
+    }
+}
+
+impl CostModel {
+    /// Charges every instruction the same amount, so fuel counts executed instructions.
+    pub fn uniform(cost: u64) -> Self {
+        Self {
+            costs: [cost; 256],
+            kib_cost: 0,
+        }
+    }
+
+    /// Approximates the relative expense of each instruction.
+    pub fn weighted() -> Self {
+        let mut model = Self::uniform(1);
+        model.set_cost(Opcode::MUL, 3);
+        model.set_cost(Opcode::DIV, 10);
+        model.set_cost(Opcode::MOD, 10);
+        model.set_cost(Opcode::VADD, 4);
+        model.set_cost(Opcode::VMUL, 12);
+        model.set_cost(Opcode::JMP, 2);
+        model.set_cost(Opcode::JMPF, 2);
+        model.set_cost(Opcode::JMPB, 2);
+        model.set_cost(Opcode::JEQ, 2);
+        model.set_cost(Opcode::JNEQ, 2);
+        model.set_cost(Opcode::JLT, 2);
+        model.set_cost(Opcode::JGT, 2);
+        model.set_cost(Opcode::JLE, 2);
+        model.set_cost(Opcode::JGE, 2);
+        model.set_cost(Opcode::JZ, 2);
+        model.set_cost(Opcode::JNZ, 2);
+        model.set_cost(Opcode::JC, 2);
+        model.set_cost(Opcode::JO, 2);
+        model.set_cost(Opcode::CALL, 2);
+        model.set_cost(Opcode::RET, 2);
+        for opcode in [
+            Opcode::BEQ,
+            Opcode::BNE,
+            Opcode::BGT,
+            Opcode::BLT,
+            Opcode::BGE,
+            Opcode::BLE,
+        ] {
+            model.set_cost(opcode, 2);
+        }
+        model.set_cost(Opcode::ALOC, 5);
+        model.set_kib_cost(1);
+
+        model
+    }
+
+    pub fn set_cost(&mut self, opcode: Opcode, cost: u64) {
+        self.costs[opcode as usize] = cost;
+    }
+
+    pub fn set_kib_cost(&mut self, cost: u64) {
+        self.kib_cost = cost;
+    }
+
+    pub fn cost(&self, opcode: Opcode) -> u64 {
+        self.costs[opcode as usize]
+    }
+
+    /// Extra fuel charged for an instruction touching `bytes` bytes.
+    pub fn size_cost(&self, bytes: usize) -> u64 {
+        self.kib_cost.saturating_mul(bytes.div_ceil(1024) as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{cost::CostModel, instruction::Opcode};
+
+    #[test]
+    fn test_uniform_cost() {
+        let model = CostModel::uniform(2);
+        assert_eq!(model.cost(Opcode::ADD), 2);
+        assert_eq!(model.cost(Opcode::DIV), 2);
+        assert_eq!(model.size_cost(4096), 0);
+    }
+
+    #[test]
+    fn test_weighted_cost() {
+        let model = CostModel::weighted();
+        assert!(model.cost(Opcode::DIV) > model.cost(Opcode::ADD));
+        assert_eq!(model.size_cost(0), 0);
+        assert_eq!(model.size_cost(1), 1);
+        assert_eq!(model.size_cost(1025), 2);
+    }
+
+    #[test]
+    fn test_set_cost() {
+        let mut model = CostModel::default();
+        model.set_cost(Opcode::INC, 7);
+        assert_eq!(model.cost(Opcode::INC), 7);
+        assert_eq!(model.cost(Opcode::DEC), 1);
+    }
+}
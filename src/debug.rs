@@ -0,0 +1,318 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+};
+
+use crate::{json::Json, patch, server::auth, vm::VM};
+
+// A command line from a client, and where to send the answer
+type Request = (String, Sender<Json>);
+
+/// Lets clients attach to a program while it runs, over TCP.
+///
+/// Each line holds one command and is answered by one line of JSON: `pause`, `resume`,
+/// `step [count]`, `state`, `registers`, `register <idx>`, `patch <function>` and `kill`.
+/// Stepping, and reading state that changes quickly, is most useful while paused.
+/// Patching, which replaces a function with the one assembled from the source sent on the
+/// lines after the command, up to a line holding `.end`, needs it.
+///
+/// With a token, clients must first send `auth <token>`; anything else closes the
+/// connection.
+///
+/// Clients are served on their own threads. Commands are handed to the thread running
+/// the program and answered from `poll` between instructions, so the VM is never
+/// shared.
+#[derive(Debug)]
+pub struct Debugger {
+    requests: Receiver<Request>,
+    cancel: Arc<AtomicBool>,
+    paused: bool,
+    // An unfinished `step`: instructions left to run and who to tell when done
+    stepping: Option<(u64, Sender<Json>)>,
+}
+
+impl Debugger {
+    /// Starts accepting clients on `address` in the background, requiring `token` from
+    /// each of them when set.
+    pub fn listen(address: &str, token: Option<String>) -> io::Result<(Debugger, SocketAddr)> {
+        let listener = TcpListener::bind(address)?;
+        let local = listener.local_addr()?;
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let sender = sender.clone();
+                        let token = token.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = serve(stream, sender, token.as_deref()) {
+                                eprintln!("Debugger connection error: {e}");
+                            }
+                        });
+                    }
+                    Err(e) => eprintln!("Unable to accept debugger connection: {e}"),
+                }
+            }
+        });
+
+        let debugger = Debugger {
+            requests,
+            cancel: Arc::new(AtomicBool::new(false)),
+            paused: false,
+            stepping: None,
+        };
+        Ok((debugger, local))
+    }
+
+    /// Makes `kill` stop `vm`. Call before running it.
+    pub fn attach(&self, vm: &mut VM) {
+        vm.set_cancel_flag(Arc::clone(&self.cancel));
+    }
+
+    /// Answers pending commands, blocking for as long as the program is paused. Meant
+    /// for `VM::run_with`.
+    pub fn poll(&mut self, vm: &mut VM) {
+        if let Some((left, reply)) = self.stepping.take() {
+            if left > 0 {
+                self.stepping = Some((left - 1, reply));
+                return;
+            }
+            self.paused = true;
+            let _ = reply.send(state(vm, true));
+        }
+
+        loop {
+            let request = if self.paused {
+                // The listener keeps a sender alive, so this only ends with a request
+                match self.requests.recv() {
+                    Ok(request) => request,
+                    Err(_) => return,
+                }
+            } else {
+                match self.requests.try_recv() {
+                    Ok(request) => request,
+                    Err(_) => return,
+                }
+            };
+            let (line, reply) = request;
+            let answer = self.execute(vm, &line, &reply);
+            if let Some(answer) = answer {
+                let _ = reply.send(answer);
+            }
+            if !self.paused {
+                return;
+            }
+        }
+    }
+
+    // `None` when the answer is sent later, once a step completes
+    fn execute(&mut self, vm: &mut VM, line: &str, reply: &Sender<Json>) -> Option<Json> {
+        let line = line.trim();
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, Some(argument.trim())),
+            None => (line, None),
+        };
+
+        let result = match (command, argument) {
+            ("pause", None) => {
+                self.paused = true;
+                Ok(state(vm, true))
+            }
+            ("resume", None) => {
+                self.paused = false;
+                Ok(state(vm, false))
+            }
+            ("step", count) => match count.map(str::parse::<u64>).unwrap_or(Ok(1)) {
+                Ok(count) if count > 0 => {
+                    self.paused = false;
+                    self.stepping = Some((count - 1, reply.clone()));
+                    return None;
+                }
+                _ => Err(format!("Invalid step count: {}", count.unwrap_or_default())),
+            },
+            ("state", None) => Ok(state(vm, self.paused)),
+            ("registers", None) => Ok(Json::object([(
+                "registers",
+                Json::from(vm.registers().to_vec()),
+            )])),
+            ("register", Some(idx)) => match idx
+                .trim_start_matches('$')
+                .parse::<usize>()
+                .ok()
+                .and_then(|idx| Some((idx, vm.register(idx)?)))
+            {
+                Some((idx, value)) => Ok(Json::object([
+                    ("register", Json::from(idx)),
+                    ("value", Json::from(value)),
+                ])),
+                None => Err(format!("Invalid register: {idx}")),
+            },
+            ("patch", _) if !self.paused => Err("Pause the program before patching".to_string()),
+            ("patch", Some(argument)) => match argument.split_once('\n') {
+                Some((name, source)) => {
+                    patch::patch_function(vm, name.trim(), source).map(|patch| {
+                        Json::object([
+                            ("patched", Json::from(name)),
+                            ("address", Json::from(patch.address as u64)),
+                            ("previous", Json::from(patch.previous as u64)),
+                            ("call_sites", Json::from(patch.call_sites)),
+                        ])
+                    })
+                }
+                None => Err("Usage: patch <function>, then its source and .end".to_string()),
+            },
+            ("kill", None) => {
+                self.cancel.store(true, Ordering::Relaxed);
+                self.paused = false;
+                Ok(Json::object([("killed", Json::from(true))]))
+            }
+            _ => Err(format!("Unknown command: {line}")),
+        };
+
+        Some(result.unwrap_or_else(|e| Json::object([("error", Json::from(e))])))
+    }
+}
+
+fn state(vm: &VM, paused: bool) -> Json {
+    Json::object([
+        ("paused", Json::from(paused)),
+        ("pc", Json::from(vm.program_counter())),
+        ("cycles", Json::from(vm.cycles())),
+        ("flags", Json::from(vm.flags().bits() as u64)),
+        ("stack_pointer", Json::from(vm.stack_pointer())),
+        ("heap_size", Json::from(vm.heap_size())),
+    ])
+}
+
+fn serve(stream: TcpStream, requests: Sender<Request>, token: Option<&str>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut authenticated = token.is_none();
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next() {
+        let mut line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if !authenticated {
+            let given = line.trim().strip_prefix("auth ").unwrap_or_default();
+            authenticated = token.is_some_and(|token| auth::token_matches(given, token));
+            if !authenticated {
+                writeln!(
+                    writer,
+                    "{}",
+                    Json::object([("error", Json::from("Unauthorized"))])
+                )?;
+                return Ok(());
+            }
+            writeln!(
+                writer,
+                "{}",
+                Json::object([("authenticated", Json::from(true))])
+            )?;
+            continue;
+        }
+        // The source of a patch follows on its own lines, handed over as one request
+        if line.split_whitespace().next() == Some("patch") {
+            for source in lines.by_ref() {
+                let source = source?;
+                if source.trim() == ".end" {
+                    break;
+                }
+                line.push('\n');
+                line.push_str(&source);
+            }
+        }
+        let (reply, answer) = mpsc::channel();
+        let answer = requests
+            .send((line, reply))
+            .ok()
+            .and_then(|_| answer.recv().ok())
+            .unwrap_or_else(|| Json::object([("error", Json::from("The program has finished"))]));
+        writeln!(writer, "{answer}")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpStream,
+        thread,
+    };
+
+    use crate::{assembler::assembler::Assembler, debug::Debugger, vm::ExitReason, vm::VM};
+
+    #[test]
+    fn test_attach() {
+        let (mut debugger, address) = Debugger::listen("127.0.0.1:0", None).unwrap();
+        let program = Assembler::new()
+            .try_assemble("load $0 #68\ninc $1\njmp $0")
+            .unwrap();
+        let running = thread::spawn(move || {
+            let mut vm = VM::new();
+            vm.load_program(program);
+            debugger.attach(&mut vm);
+            let outcome = vm.run_with(|vm| debugger.poll(vm));
+            (outcome.exit, vm.register(1))
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut send = |line: &str| {
+            (&stream).write_all(format!("{line}\n").as_bytes()).unwrap();
+            let mut response = String::new();
+            reader.read_line(&mut response).unwrap();
+            response
+        };
+
+        assert!(send("pause").starts_with("{\"paused\":true,"));
+        let counter = |response: String| {
+            response
+                .trim()
+                .strip_prefix("{\"register\":1,\"value\":")
+                .and_then(|rest| rest.strip_suffix('}'))
+                .and_then(|value| value.parse::<i32>().ok())
+                .unwrap()
+        };
+        let before = counter(send("register 1"));
+        // INC then JMP, or JMP then INC
+        assert!(send("step 2").starts_with("{\"paused\":true,"));
+        assert_eq!(counter(send("register 1")), before + 1);
+        assert_eq!(counter(send("register 1")), before + 1);
+        assert!(send("step 0").contains("error"));
+        assert!(send("bogus").contains("Unknown command"));
+        assert!(send("patch loop\n.end").contains("Usage"));
+        assert!(send("patch loop\nload $0 #1\n\nret\n.end").contains("no symbols"));
+        assert!(send("kill").contains("killed"));
+
+        let (exit, counter) = running.join().unwrap();
+        assert_eq!(exit, ExitReason::Cancelled);
+        assert_eq!(counter, Some(before + 1));
+        assert!(send("state").contains("finished"));
+    }
+
+    #[test]
+    fn test_token() {
+        let token = "0123456789abcdef".to_string();
+        let (_debugger, address) = Debugger::listen("127.0.0.1:0", Some(token)).unwrap();
+        let session = |lines: &[&str]| {
+            let stream = TcpStream::connect(address).unwrap();
+            for line in lines {
+                (&stream).write_all(format!("{line}\n").as_bytes()).unwrap();
+            }
+            BufReader::new(stream).lines().next().unwrap().unwrap()
+        };
+
+        assert!(session(&["kill"]).contains("Unauthorized"));
+        assert!(session(&["auth 0123456789abcdeX"]).contains("Unauthorized"));
+        assert!(session(&["auth 0123456789abcdef"]).contains("authenticated"));
+    }
+}
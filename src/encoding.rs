@@ -0,0 +1,168 @@
+//! Byte order and textual encodings used by the bytecode format.
+//!
+//! Every multi-byte value, in instructions and in the header, is stored big-endian so a
+//! binary assembled on one platform runs identically on any other. Encoding and decoding
+//! goes through these helpers rather than ad hoc shifts.
+//!
+//! Whole programs can also be carried as hex or base64 text where binary is inconvenient.
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn encode_u16(value: u16) -> [u8; 2] {
+    value.to_be_bytes()
+}
+
+pub fn encode_u32(value: u32) -> [u8; 4] {
+    value.to_be_bytes()
+}
+
+/// Reads a `u16` at `offset`, or `None` if the bytes run out.
+pub fn decode_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes = bytes.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Reads a `u32` at `offset`, or `None` if the bytes run out.
+pub fn decode_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Decodes hex text, ignoring whitespace so wrapped or spaced dumps are accepted.
+pub fn from_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = text
+        .bytes()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("Hex input has an odd number of digits".to_string());
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).map_err(|_| "Invalid hex digit".to_string())?;
+            u8::from_str_radix(pair, 16).map_err(|_| format!("Invalid hex byte: {pair}"))
+        })
+        .collect()
+}
+
+/// Encodes using the standard alphabet with padding.
+pub fn to_base64(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = u32::from_be_bytes([0, b[0], b[1], b[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3f;
+                text.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+
+    text
+}
+
+/// Decodes standard base64, ignoring whitespace and tolerating missing padding.
+pub fn from_base64(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    let mut group: u32 = 0;
+    let mut bits = 0;
+    for c in text
+        .trim_end_matches(|c: char| c == '=' || c.is_ascii_whitespace())
+        .chars()
+    {
+        if c.is_ascii_whitespace() {
+            continue;
+        }
+        let value = BASE64_ALPHABET
+            .iter()
+            .position(|&symbol| symbol as char == c)
+            .ok_or_else(|| format!("Invalid base64 character: {c}"))?;
+        group = (group << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((group >> bits) as u8);
+            group &= (1 << bits) - 1;
+        }
+    }
+    if bits >= 6 {
+        return Err("Truncated base64 input".to_string());
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::encoding::{
+        decode_u16, decode_u32, encode_u16, encode_u32, from_base64, from_hex, to_base64, to_hex,
+    };
+
+    #[test]
+    fn test_encode_is_big_endian() {
+        assert_eq!(encode_u16(0x1234), [0x12, 0x34]);
+        assert_eq!(encode_u32(0x1234_5678), [0x12, 0x34, 0x56, 0x78]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for value in [0, 1, 0x00ff, 0xff00, u16::MAX] {
+            assert_eq!(decode_u16(&encode_u16(value), 0), Some(value));
+        }
+        for value in [0, 1, 0x00ff_ff00, u32::MAX] {
+            assert_eq!(decode_u32(&encode_u32(value), 0), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_decode_at_offset() {
+        let bytes = [0, 0x01, 0x02, 0x03];
+        assert_eq!(decode_u16(&bytes, 1), Some(0x0102));
+        assert_eq!(decode_u16(&bytes, 3), None);
+        assert_eq!(decode_u32(&bytes, 1), None);
+        assert_eq!(decode_u16(&bytes, usize::MAX), None);
+    }
+
+    #[test]
+    fn test_hex_round_trip() {
+        let bytes = [0x2d, 0x32, 0x00, 0xff];
+        assert_eq!(to_hex(&bytes), "2d3200ff");
+        assert_eq!(from_hex("2d3200ff").unwrap(), bytes);
+        assert_eq!(from_hex("2D 32\n00 FF").unwrap(), bytes);
+        assert!(from_hex("2d3").is_err());
+        assert!(from_hex("zz").is_err());
+    }
+
+    #[test]
+    fn test_base64_round_trip() {
+        assert_eq!(to_base64(b""), "");
+        assert_eq!(to_base64(b"f"), "Zg==");
+        assert_eq!(to_base64(b"fo"), "Zm8=");
+        assert_eq!(to_base64(b"foobar"), "Zm9vYmFy");
+        for text in ["f", "fo", "foo", "foob", "fooba", "foobar"] {
+            assert_eq!(
+                from_base64(&to_base64(text.as_bytes())).unwrap(),
+                text.as_bytes()
+            );
+        }
+        let bytes: Vec<u8> = (0..=255).collect();
+        assert_eq!(from_base64(&to_base64(&bytes)).unwrap(), bytes);
+        assert_eq!(from_base64("Zm9v\nYmFy").unwrap(), b"foobar");
+        assert!(from_base64("Zm9v!").is_err());
+        assert!(from_base64("Z").is_err());
+    }
+}
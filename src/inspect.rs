@@ -0,0 +1,247 @@
+//! Human readable dumps of assembled programs, in the spirit of `readelf` and `objdump -h`.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{
+    assembler::{
+        assembler::{SymbolTable, SymbolType, PIE_HEADER_LENGTH, PIE_HEADER_PREFIX},
+        container::{self, SectionKind},
+    },
+    instruction::{self, Format, Opcode},
+};
+
+const HEXDUMP_WIDTH: usize = 16;
+
+/// Describes the header, section table and symbol table of a program, followed by a
+/// hexdump of every section when `hexdump` is set.
+pub fn describe(program: &[u8], hexdump: bool) -> Result<String, String> {
+    let header = container::read_header(program)?;
+    let sections = container::read_sections(program)?;
+    let mut out = String::new();
+
+    out.push_str("Header:\n");
+    let _ = writeln!(out, "  {:<16}{}", "magic", hex_bytes(&PIE_HEADER_PREFIX));
+    let _ = writeln!(out, "  {:<16}{PIE_HEADER_LENGTH} bytes", "length");
+    let _ = writeln!(
+        out,
+        "  {:<16}{} ({} byte instructions)",
+        "encoding",
+        header.format.name(),
+        header.format.encoding().width()
+    );
+    if header.table_offset == 0 {
+        let _ = writeln!(out, "  {:<16}none", "section table");
+    } else {
+        let _ = writeln!(
+            out,
+            "  {:<16}offset {}, {} entries",
+            "section table", header.table_offset, header.section_count
+        );
+    }
+
+    out.push_str("\nSections:\n");
+    let _ = writeln!(
+        out,
+        "  {:<16} {:<12} {:>8} {:>8}",
+        "NAME", "KIND", "OFFSET", "LENGTH"
+    );
+    for section in &sections {
+        let _ = write!(
+            out,
+            "  {:<16} {:<12} {:>8} {:>8}",
+            section.name,
+            format!("{:?}", section.kind).to_lowercase(),
+            section.offset,
+            section.length
+        );
+        if section.kind == SectionKind::Custom {
+            if let Ok(text) = std::str::from_utf8(section.contents(program)) {
+                let _ = write!(out, "  {text:?}");
+            }
+        }
+        out.push('\n');
+    }
+
+    for section in sections.iter().filter(|s| s.kind == SectionKind::Symbols) {
+        let symbols = SymbolTable::from_bytes(section.contents(program))?;
+        let _ = writeln!(out, "\nSymbols ({}):", section.name);
+        let _ = writeln!(
+            out,
+            "  {:<16} {:<8} {:>8} {:>8}",
+            "NAME", "TYPE", "OFFSET", "ADDRESS"
+        );
+        for symbol in symbols.iter() {
+            let _ = writeln!(
+                out,
+                "  {:<16} {:<8} {:>8} {:>8}",
+                symbol.name(),
+                format!("{:?}", symbol.symbol_type()).to_lowercase(),
+                symbol.offset(),
+                symbol.address()
+            );
+        }
+    }
+
+    if hexdump {
+        for section in sections.iter().filter(|s| s.is_stored()) {
+            let _ = writeln!(out, "\nHexdump of {}:", section.name);
+            out.push_str(&dump(section.contents(program), section.offset));
+        }
+    }
+
+    Ok(out)
+}
+
+/// Lists the code of a program one instruction per line, with label names from its
+/// symbols section. Constants loaded into registers that match a label's address, such as
+/// jump targets, are annotated with the label.
+///
+/// The listing starts at the label `from` when given and stops after `limit`
+/// instructions. A program without a header is listed from its first byte, without labels.
+pub fn disassemble(program: &[u8], from: Option<&str>, limit: usize) -> Result<String, String> {
+    let (code, labels, format) = if program.starts_with(&PIE_HEADER_PREFIX) {
+        let format = container::read_header(program)?.format;
+        let sections = container::read_sections(program)?;
+        let code = container::code_section(&sections)
+            .ok_or("Program has no code section")?
+            .range();
+        let mut labels = BTreeMap::new();
+        for section in sections.iter().filter(|s| s.kind == SectionKind::Symbols) {
+            for symbol in SymbolTable::from_bytes(section.contents(program))?.iter() {
+                if *symbol.symbol_type() == SymbolType::Label {
+                    labels.insert(symbol.address() as usize, symbol.name().to_string());
+                }
+            }
+        }
+        (code, labels, format)
+    } else {
+        (0..program.len(), BTreeMap::new(), Format::Narrow)
+    };
+
+    let start = match from {
+        Some(name) => labels
+            .iter()
+            .find(|(_, label)| *label == name)
+            .map(|(address, _)| *address)
+            .ok_or_else(|| format!("Unknown label: {name}"))?,
+        None => code.start,
+    };
+
+    let encoding = format.encoding();
+    let mut out = String::new();
+    for pc in (start..code.end).step_by(encoding.width()).take(limit) {
+        let bytes = &program[pc..(pc + encoding.width()).min(code.end)];
+        if let Some(label) = labels.get(&pc) {
+            let _ = writeln!(out, "{label}:");
+        }
+        let _ = write!(
+            out,
+            "  {pc:>6}  {}",
+            instruction::disassemble_with(bytes, encoding)
+        );
+        let loads_address = matches!(Opcode::from(bytes[0]), Opcode::LOAD | Opcode::LOADU);
+        if let Some(label) = bytes
+            .get(encoding.operands_offset() + 1..)
+            .and_then(|operand| encoding.decode_immediate(operand))
+            .filter(|_| loads_address)
+            .and_then(|value| labels.get(&(value as usize)))
+        {
+            let _ = write!(out, "  ; @{label}");
+        }
+        out.push('\n');
+    }
+
+    Ok(out)
+}
+
+/// Formats bytes as lines of offset, hex and printable ASCII, labelling them from `base`.
+pub fn dump(bytes: &[u8], base: usize) -> String {
+    let mut out = String::new();
+    for (i, line) in bytes.chunks(HEXDUMP_WIDTH).enumerate() {
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(
+            out,
+            "  {:08x}  {:<width$}  |{ascii}|",
+            base + i * HEXDUMP_WIDTH,
+            hex_bytes(line),
+            width = HEXDUMP_WIDTH * 3 - 1
+        );
+    }
+
+    out
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        assembler::assembler::Assembler,
+        inspect::{describe, disassemble, dump},
+    };
+
+    #[test]
+    fn test_dump() {
+        assert_eq!(
+            dump(b"-21-\x00", 64),
+            "  00000040  2d 32 31 2d 00                                   |-21-.|\n"
+        );
+        assert_eq!(dump(&[0; 17], 0).lines().count(), 2);
+    }
+
+    #[test]
+    fn test_describe() {
+        let mut assembler = Assembler::new();
+        assembler.add_metadata("license", b"MIT".to_vec());
+        let program = assembler.assemble("inc $0\nend: hlt").unwrap();
+
+        let description = describe(&program, true).unwrap();
+        assert!(description.contains("magic           2d 32 31 2d"));
+        assert!(description.contains("section table   offset 84, 3 entries"));
+        assert!(description.contains("license          custom"));
+        assert!(description.contains("\"MIT\""));
+        assert!(description.contains("end              label           4       68"));
+        assert!(description.contains("Hexdump of code:\n  00000040  12 00 00 00 05"));
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let program = Assembler::new()
+            .assemble("load $0 @done\nloop: inc $1\njmp $0\ndone: hlt")
+            .unwrap();
+
+        assert_eq!(
+            disassemble(&program, None, usize::MAX).unwrap(),
+            "      64  load $0 #76  ; @done\nloop:\n      68  inc $1\n      72  jmp $0\ndone:\n      76  hlt\n"
+        );
+        assert_eq!(
+            disassemble(&program, Some("loop"), 2).unwrap(),
+            "loop:\n      68  inc $1\n      72  jmp $0\n"
+        );
+        assert!(disassemble(&program, Some("nowhere"), 1).is_err());
+        assert_eq!(
+            disassemble(&[5, 0, 0, 0], None, 1).unwrap(),
+            "       0  hlt\n"
+        );
+    }
+
+    #[test]
+    fn test_describe_invalid_program() {
+        assert!(describe(&[5, 0, 0, 0], false).is_err());
+    }
+}
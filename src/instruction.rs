@@ -1,26 +1,216 @@
-#[derive(Debug, PartialEq, Clone)]
+use std::fmt;
+
+/// Bytes per instruction in the default, narrow, encoding.
+pub const INSTRUCTION_LENGTH: usize = 4;
+
+/// How instructions are laid out in the code section: the opcode, then registers (one byte
+/// each) and integers, padded up to a fixed width.
+pub trait Encoding: fmt::Debug + Sync {
+    /// Bytes per instruction.
+    fn width(&self) -> usize;
+    /// Bytes in front of the first operand: the opcode and any flags.
+    fn operands_offset(&self) -> usize;
+    /// Bytes of an integer operand, stored big-endian.
+    fn immediate_width(&self) -> usize;
+
+    /// Encodes an integer operand, or `None` if it does not fit. Signed operands are
+    /// sign-extended when executed, the others zero-extended.
+    fn encode_immediate(&self, value: i64, signed: bool) -> Option<Vec<u8>> {
+        let bits = self.immediate_width() as u32 * 8;
+        let range = if signed {
+            -(1 << (bits - 1))..1 << (bits - 1)
+        } else {
+            0..1 << bits
+        };
+        range
+            .contains(&value)
+            .then(|| value.to_be_bytes()[8 - self.immediate_width()..].to_vec())
+    }
+
+    /// Decodes the integer operand at the start of `bytes`, zero-extended.
+    fn decode_immediate(&self, bytes: &[u8]) -> Option<u32> {
+        let bytes = bytes.get(..self.immediate_width())?;
+        Some(
+            bytes
+                .iter()
+                .fold(0, |value, &byte| value << 8 | byte as u32),
+        )
+    }
+
+    /// Sign-extends a decoded integer operand.
+    fn sign_extend(&self, value: u32) -> i32 {
+        let unused = 32 - self.immediate_width() as u32 * 8;
+        ((value << unused) as i32) >> unused
+    }
+
+    /// Lays out an instruction from its opcode and encoded operands, with the flags zeroed.
+    fn instruction(&self, opcode: Opcode, operands: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![opcode as u8];
+        bytes.resize(self.operands_offset(), 0);
+        bytes.extend_from_slice(operands);
+        bytes.resize(self.width().max(bytes.len()), 0);
+
+        bytes
+    }
+}
+
+/// 4 byte instructions with 16-bit integers, the original encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NarrowEncoding;
+
+impl Encoding for NarrowEncoding {
+    fn width(&self) -> usize {
+        INSTRUCTION_LENGTH
+    }
+
+    fn operands_offset(&self) -> usize {
+        1
+    }
+
+    fn immediate_width(&self) -> usize {
+        2
+    }
+}
+
+/// 8 byte instructions with 32-bit integers. A flags byte, reserved and zero for now,
+/// follows the opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WideEncoding;
+
+impl Encoding for WideEncoding {
+    fn width(&self) -> usize {
+        8
+    }
+
+    fn operands_offset(&self) -> usize {
+        2
+    }
+
+    fn immediate_width(&self) -> usize {
+        4
+    }
+}
+
+/// The encoding a program uses, recorded in its header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Narrow,
+    Wide,
+}
+
+impl Format {
+    pub fn encoding(self) -> &'static dyn Encoding {
+        match self {
+            Format::Narrow => &NarrowEncoding,
+            Format::Wide => &WideEncoding,
+        }
+    }
+
+    /// The header byte naming the format.
+    pub fn id(self) -> u8 {
+        match self {
+            Format::Narrow => 0,
+            Format::Wide => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Format::Narrow),
+            1 => Some(Format::Wide),
+            _ => None,
+        }
+    }
+
+    /// The name used on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Narrow => "narrow",
+            Format::Wide => "wide",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Format::Narrow, Format::Wide]
+            .into_iter()
+            .find(|format| format.name() == name)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum Opcode {
-    LOAD, // LOAD
-    ADD,  // ADD
-    SUB,  // SUBTRACT
-    MUL,  // MULTIPLY
-    DIV,  // DIVIDE
-    HLT,  // HALT
-    JMP,  // JUMP (ABSOLUTE)
-    JMPF, // JUMP FORWARD (RELATIVE)
-    JMPB, // JUMP BACKWARD (RELATIVE)
-    EQ,   // EQUAL
-    NEQ,  // NOT EQUAL
-    GT,   // GREATER THAN
-    LT,   // LESS THAN
-    GTE,  // GREATER THAN OR EQUAL
-    LTE,  // LESS THAN OR EQUAL
-    JEQ,  // JUMP IF EQUAL
-    JNEQ, // JUMP IF NOT EQUAL
-    ALOC, // ALLOCATE MEMORY ON THE HEAP
-    INC,  // INCREMENT VALUE IN REGISTER
-    DEC,  // DECREMENT VALUE IN REGISTER
-    IGL,  // ILLEGAL
+    LOAD,    // LOAD
+    ADD,     // ADD
+    SUB,     // SUBTRACT
+    MUL,     // MULTIPLY
+    DIV,     // DIVIDE
+    HLT,     // HALT
+    JMP,     // JUMP (ABSOLUTE)
+    JMPF,    // JUMP FORWARD (RELATIVE)
+    JMPB,    // JUMP BACKWARD (RELATIVE)
+    EQ,      // EQUAL
+    NEQ,     // NOT EQUAL
+    GT,      // GREATER THAN
+    LT,      // LESS THAN
+    GTE,     // GREATER THAN OR EQUAL
+    LTE,     // LESS THAN OR EQUAL
+    JEQ,     // JUMP IF EQUAL
+    JNEQ,    // JUMP IF NOT EQUAL
+    ALOC,    // ALLOCATE MEMORY ON THE HEAP
+    INC,     // INCREMENT VALUE IN REGISTER
+    DEC,     // DECREMENT VALUE IN REGISTER
+    FUEL,    // LOAD REMAINING FUEL INTO REGISTER
+    YIELDTO, // GIVE THE REST OF THE TIME SLICE TO ANOTHER PROGRAM
+    LOADS,   // LOAD SIGN-EXTENDED
+    LOADU,   // LOAD ZERO-EXTENDED
+    MFLAGS,  // MOVE FLAGS INTO REGISTER
+    JLT,     // JUMP IF LESS THAN
+    JGT,     // JUMP IF GREATER THAN
+    JLE,     // JUMP IF LESS THAN OR EQUAL
+    JGE,     // JUMP IF GREATER THAN OR EQUAL
+    CMOV,    // CONDITIONAL MOVE
+    VADD,    // ADD FOUR REGISTERS PAIRWISE
+    VMUL,    // MULTIPLY FOUR REGISTERS PAIRWISE
+    BEQ,     // COMPARE EQUAL AND BRANCH
+    BNE,     // COMPARE NOT EQUAL AND BRANCH
+    BGT,     // COMPARE GREATER THAN AND BRANCH
+    BLT,     // COMPARE LESS THAN AND BRANCH
+    BGE,     // COMPARE GREATER THAN OR EQUAL AND BRANCH
+    BLE,     // COMPARE LESS THAN OR EQUAL AND BRANCH
+    RDPERF,  // READ PERFORMANCE COUNTER
+    LOADC,   // LOAD FROM THE CONSTANT POOL
+    PUSH,    // PUSH REGISTER ONTO THE STACK
+    POP,     // POP THE STACK INTO A REGISTER
+    CALL,    // PUSH THE RETURN ADDRESS AND JUMP
+    RET,     // POP THE RETURN ADDRESS AND JUMP TO IT
+    AND,     // BITWISE AND
+    OR,      // BITWISE OR
+    XOR,     // BITWISE EXCLUSIVE OR
+    NOT,     // BITWISE NOT
+    SHL,     // SHIFT LEFT BY A REGISTER
+    SHR,     // LOGICAL SHIFT RIGHT BY A REGISTER
+    SHLI,    // SHIFT LEFT BY A CONSTANT
+    SHRI,    // LOGICAL SHIFT RIGHT BY A CONSTANT
+    RDCYCLE, // READ THE CYCLE COUNTER
+    MOD,     // REMAINDER
+    MOV,     // COPY A REGISTER
+    LW,      // LOAD A WORD FROM THE HEAP
+    SW,      // STORE A WORD TO THE HEAP
+    LB,      // LOAD A BYTE FROM THE HEAP
+    SB,      // STORE A BYTE TO THE HEAP
+    PRTS,    // PRINT A STRING
+    LUI,     // LOAD THE UPPER 16 BITS
+    ORI,     // OR IN THE LOWER 16 BITS
+    SEQ,     // SET IF EQUAL
+    SNE,     // SET IF NOT EQUAL
+    SLT,     // SET IF LESS THAN
+    SLE,     // SET IF LESS THAN OR EQUAL
+    JZ,      // JUMP IF ZERO
+    JNZ,     // JUMP IF NOT ZERO
+    JC,      // JUMP IF CARRY
+    JO,      // JUMP IF OVERFLOW
+    IGL,     // ILLEGAL
 }
 
 #[derive(Debug)]
@@ -32,45 +222,697 @@ impl Instruction {
     pub fn new(opcode: Opcode) -> Self {
         Self { opcode }
     }
+
+    pub fn opcode(&self) -> Opcode {
+        self.opcode
+    }
+}
+
+/// What an instruction expects in one of its operand slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandKind {
+    /// A register index, one byte.
+    Register,
+    /// A 16 bit big-endian constant or label address.
+    Integer,
+}
+
+impl OperandKind {
+    pub fn width(self) -> usize {
+        match self {
+            OperandKind::Register => 1,
+            OperandKind::Integer => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Operand {
+    pub name: &'static str,
+    pub kind: OperandKind,
+}
+
+const fn reg(name: &'static str) -> Operand {
+    Operand {
+        name,
+        kind: OperandKind::Register,
+    }
+}
+
+const fn int(name: &'static str) -> Operand {
+    Operand {
+        name,
+        kind: OperandKind::Integer,
+    }
+}
+
+/// Reference entry for an opcode: how it is written, how it is encoded and what it does.
+#[derive(Debug, PartialEq, Eq)]
+pub struct OpcodeInfo {
+    pub opcode: Opcode,
+    pub mnemonic: &'static str,
+    /// In the order they are written and encoded.
+    pub operands: &'static [Operand],
+    pub semantics: &'static str,
+    pub example: &'static str,
+}
+
+pub const OPCODES: &[OpcodeInfo] = &[
+    OpcodeInfo {
+        opcode: Opcode::LOAD,
+        mnemonic: "load",
+        operands: &[reg("$reg"), int("#value")],
+        semantics: "$reg = value, zero-extended to 32 bits. Negative literals assemble to loads",
+        example: "load $0 #100",
+    },
+    OpcodeInfo {
+        opcode: Opcode::ADD,
+        mnemonic: "add",
+        operands: &[reg("$a"), reg("$b"), reg("$dst")],
+        semantics: "$dst = $a + $b, wrapping, and sets the flags",
+        example: "add $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::SUB,
+        mnemonic: "sub",
+        operands: &[reg("$a"), reg("$b"), reg("$dst")],
+        semantics: "$dst = $a - $b, wrapping, and sets the flags",
+        example: "sub $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::MUL,
+        mnemonic: "mul",
+        operands: &[reg("$a"), reg("$b"), reg("$dst")],
+        semantics: "$dst = $a * $b, wrapping, and sets the flags",
+        example: "mul $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::DIV,
+        mnemonic: "div",
+        operands: &[reg("$a"), reg("$b"), reg("$dst")],
+        semantics:
+            "$dst = $a / $b and sets the flags, the remainder is kept in the remainder register; traps if $b is 0",
+        example: "div $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::HLT,
+        mnemonic: "hlt",
+        operands: &[],
+        semantics: "stops the program",
+        example: "hlt",
+    },
+    OpcodeInfo {
+        opcode: Opcode::JMP,
+        mnemonic: "jmp",
+        operands: &[reg("$target")],
+        semantics: "jumps to the absolute address in $target",
+        example: "jmp $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::JMPF,
+        mnemonic: "jmpf",
+        operands: &[reg("$bytes")],
+        semantics: "moves the program counter forward by $bytes",
+        example: "jmpf $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::JMPB,
+        mnemonic: "jmpb",
+        operands: &[reg("$bytes")],
+        semantics: "moves the program counter back by $bytes",
+        example: "jmpb $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::EQ,
+        mnemonic: "eq",
+        operands: &[reg("$a"), reg("$b")],
+        semantics: "sets the flags for $a - $b, and the comparison flag if $a == $b",
+        example: "eq $0 $1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::NEQ,
+        mnemonic: "neq",
+        operands: &[reg("$a"), reg("$b")],
+        semantics: "sets the flags for $a - $b, and the comparison flag if $a != $b",
+        example: "neq $0 $1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::GT,
+        mnemonic: "gt",
+        operands: &[reg("$a"), reg("$b")],
+        semantics: "sets the flags for $a - $b, and the comparison flag if $a > $b",
+        example: "gt $0 $1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::LT,
+        mnemonic: "lt",
+        operands: &[reg("$a"), reg("$b")],
+        semantics: "sets the flags for $a - $b, and the comparison flag if $a < $b",
+        example: "lt $0 $1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::GTE,
+        mnemonic: "gte",
+        operands: &[reg("$a"), reg("$b")],
+        semantics: "sets the flags for $a - $b, and the comparison flag if $a >= $b",
+        example: "gte $0 $1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::LTE,
+        mnemonic: "lte",
+        operands: &[reg("$a"), reg("$b")],
+        semantics: "sets the flags for $a - $b, and the comparison flag if $a <= $b",
+        example: "lte $0 $1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::JEQ,
+        mnemonic: "jeq",
+        operands: &[reg("$target")],
+        semantics: "jumps to the absolute address in $target if the comparison flag is set",
+        example: "jeq $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::JNEQ,
+        mnemonic: "jneq",
+        operands: &[reg("$target")],
+        semantics: "jumps to the absolute address in $target if the comparison flag is clear",
+        example: "jneq $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::ALOC,
+        mnemonic: "aloc",
+        operands: &[reg("$bytes")],
+        semantics: "grows the heap by $bytes zeroed bytes",
+        example: "aloc $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::INC,
+        mnemonic: "inc",
+        operands: &[reg("$reg")],
+        semantics: "$reg = $reg + 1, wrapping, and sets the flags",
+        example: "inc $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::DEC,
+        mnemonic: "dec",
+        operands: &[reg("$reg")],
+        semantics: "$reg = $reg - 1, wrapping, and sets the flags",
+        example: "dec $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::FUEL,
+        mnemonic: "fuel",
+        operands: &[reg("$reg")],
+        semantics: "$reg = remaining fuel, capped at i32::MAX, or -1 when unmetered",
+        example: "fuel $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::YIELDTO,
+        mnemonic: "yieldto",
+        operands: &[reg("$pid")],
+        semantics: "ends the time slice and lets the process $pid run next",
+        example: "yieldto $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::LOADS,
+        mnemonic: "loads",
+        operands: &[reg("$reg"), int("#value")],
+        semantics: "$reg = value, sign-extended to 32 bits",
+        example: "loads $0 #-1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::LOADU,
+        mnemonic: "loadu",
+        operands: &[reg("$reg"), int("#value")],
+        semantics: "$reg = value, zero-extended to 32 bits",
+        example: "loadu $0 #65535",
+    },
+    OpcodeInfo {
+        opcode: Opcode::MFLAGS,
+        mnemonic: "mflags",
+        operands: &[reg("$reg")],
+        semantics: "$reg = flags: zero 1, negative 2, carry 4, overflow 8, comparison 16",
+        example: "mflags $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::JLT,
+        mnemonic: "jlt",
+        operands: &[reg("$target")],
+        semantics:
+            "jumps to $target if the last result was negative (signed, negative != overflow)",
+        example: "jlt $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::JGT,
+        mnemonic: "jgt",
+        operands: &[reg("$target")],
+        semantics: "jumps to $target if the last result was positive (signed)",
+        example: "jgt $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::JLE,
+        mnemonic: "jle",
+        operands: &[reg("$target")],
+        semantics: "jumps to $target if the last result was zero or negative (signed)",
+        example: "jle $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::JGE,
+        mnemonic: "jge",
+        operands: &[reg("$target")],
+        semantics: "jumps to $target if the last result was zero or positive (signed)",
+        example: "jge $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::CMOV,
+        mnemonic: "cmov",
+        operands: &[reg("$dst"), reg("$src")],
+        semantics: "$dst = $src if the comparison flag is set",
+        example: "cmov $0 $1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::VADD,
+        mnemonic: "vadd",
+        operands: &[reg("$a"), reg("$b"), reg("$dst")],
+        semantics: "$dst+i = $a+i + $b+i for i in 0..4, wrapping, flags unchanged",
+        example: "vadd $0 $4 $8",
+    },
+    OpcodeInfo {
+        opcode: Opcode::VMUL,
+        mnemonic: "vmul",
+        operands: &[reg("$a"), reg("$b"), reg("$dst")],
+        semantics: "$dst+i = $a+i * $b+i for i in 0..4, wrapping, flags unchanged",
+        example: "vmul $0 $4 $8",
+    },
+    OpcodeInfo {
+        opcode: Opcode::BEQ,
+        mnemonic: "beq",
+        operands: &[reg("$a"), reg("$b"), reg("$target")],
+        semantics: "eq $a $b, then jeq $target: jumps if $a == $b",
+        example: "beq $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::BNE,
+        mnemonic: "bne",
+        operands: &[reg("$a"), reg("$b"), reg("$target")],
+        semantics: "neq $a $b, then jeq $target: jumps if $a != $b",
+        example: "bne $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::BGT,
+        mnemonic: "bgt",
+        operands: &[reg("$a"), reg("$b"), reg("$target")],
+        semantics: "gt $a $b, then jeq $target: jumps if $a > $b",
+        example: "bgt $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::BLT,
+        mnemonic: "blt",
+        operands: &[reg("$a"), reg("$b"), reg("$target")],
+        semantics: "lt $a $b, then jeq $target: jumps if $a < $b",
+        example: "blt $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::BGE,
+        mnemonic: "bge",
+        operands: &[reg("$a"), reg("$b"), reg("$target")],
+        semantics: "gte $a $b, then jeq $target: jumps if $a >= $b",
+        example: "bge $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::BLE,
+        mnemonic: "ble",
+        operands: &[reg("$a"), reg("$b"), reg("$target")],
+        semantics: "lte $a $b, then jeq $target: jumps if $a <= $b",
+        example: "ble $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::RDPERF,
+        mnemonic: "rdperf",
+        operands: &[reg("$reg"), int("#counter")],
+        semantics: "$reg = counter, capped at i32::MAX: 0 instructions executed, 1 branches taken, others 0",
+        example: "rdperf $0 #0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::LOADC,
+        mnemonic: "loadc",
+        operands: &[reg("$reg"), int("#value")],
+        semantics: "$reg = a 32 bit value, stored in the constant pool and encoded as its index",
+        example: "loadc $0 #100000",
+    },
+    OpcodeInfo {
+        opcode: Opcode::PUSH,
+        mnemonic: "push",
+        operands: &[reg("$reg")],
+        semantics: "pushes $reg onto the stack",
+        example: "push $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::POP,
+        mnemonic: "pop",
+        operands: &[reg("$reg")],
+        semantics: "$reg = the value popped off the stack",
+        example: "pop $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::CALL,
+        mnemonic: "call",
+        operands: &[int("#target")],
+        semantics: "pushes the address of the next instruction and jumps to target",
+        example: "call #64",
+    },
+    OpcodeInfo {
+        opcode: Opcode::RET,
+        mnemonic: "ret",
+        operands: &[],
+        semantics: "pops an address off the stack and jumps to it",
+        example: "ret",
+    },
+    OpcodeInfo {
+        opcode: Opcode::AND,
+        mnemonic: "and",
+        operands: &[reg("$a"), reg("$b"), reg("$dst")],
+        semantics: "$dst = $a & $b, and sets the flags",
+        example: "and $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::OR,
+        mnemonic: "or",
+        operands: &[reg("$a"), reg("$b"), reg("$dst")],
+        semantics: "$dst = $a | $b, and sets the flags",
+        example: "or $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::XOR,
+        mnemonic: "xor",
+        operands: &[reg("$a"), reg("$b"), reg("$dst")],
+        semantics: "$dst = $a ^ $b, and sets the flags",
+        example: "xor $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::NOT,
+        mnemonic: "not",
+        operands: &[reg("$src"), reg("$dst")],
+        semantics: "$dst = !$src, every bit flipped, and sets the flags",
+        example: "not $0 $1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::SHL,
+        mnemonic: "shl",
+        operands: &[reg("$reg"), reg("$count")],
+        semantics: "$reg = $reg << $count, 0 from 32 on, and sets the flags; shl $reg #count assembles as shli",
+        example: "shl $0 $1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::SHR,
+        mnemonic: "shr",
+        operands: &[reg("$reg"), reg("$count")],
+        semantics: "$reg = $reg >> $count filling with zeros, 0 from 32 on, and sets the flags; shr $reg #count assembles as shri",
+        example: "shr $0 $1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::SHLI,
+        mnemonic: "shli",
+        operands: &[reg("$reg"), int("#count")],
+        semantics: "$reg = $reg << count, count 0 to 255, and sets the flags",
+        example: "shli $0 #4",
+    },
+    OpcodeInfo {
+        opcode: Opcode::SHRI,
+        mnemonic: "shri",
+        operands: &[reg("$reg"), int("#count")],
+        semantics: "$reg = $reg >> count filling with zeros, count 0 to 255, and sets the flags",
+        example: "shri $0 #4",
+    },
+    OpcodeInfo {
+        opcode: Opcode::RDCYCLE,
+        mnemonic: "rdcycle",
+        operands: &[reg("$reg")],
+        semantics: "$reg = virtual cycles spent so far, capped at i32::MAX",
+        example: "rdcycle $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::MOD,
+        mnemonic: "mod",
+        operands: &[reg("$a"), reg("$b"), reg("$dst")],
+        semantics: "$dst = $a % $b, with the sign of $a, and sets the flags; traps if $b is 0",
+        example: "mod $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::MOV,
+        mnemonic: "mov",
+        operands: &[reg("$src"), reg("$dst")],
+        semantics: "$dst = $src, the flags are left alone",
+        example: "mov $0 $1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::LW,
+        mnemonic: "lw",
+        operands: &[reg("$dst"), reg("$addr")],
+        semantics: "$dst = the big-endian word at heap offset $addr, trapping outside the heap",
+        example: "lw $0 $1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::SW,
+        mnemonic: "sw",
+        operands: &[reg("$src"), reg("$addr")],
+        semantics: "stores $src as a big-endian word at heap offset $addr, trapping outside the heap",
+        example: "sw $0 $1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::LB,
+        mnemonic: "lb",
+        operands: &[reg("$dst"), reg("$addr")],
+        semantics: "$dst = the byte at heap offset $addr, sign-extended so 0xFF reads as -1, trapping outside the heap",
+        example: "lb $0 $1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::SB,
+        mnemonic: "sb",
+        operands: &[reg("$src"), reg("$addr")],
+        semantics: "stores the low 8 bits of $src at heap offset $addr, trapping outside the heap",
+        example: "sb $0 $1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::PRTS,
+        mnemonic: "prts",
+        operands: &[int("#offset")],
+        semantics: "prints the NUL-terminated string at offset in the read-only data, usually an .asciiz label",
+        example: "prts #0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::LUI,
+        mnemonic: "lui",
+        operands: &[reg("$reg"), int("#value")],
+        semantics: "$reg = value << 16, clearing the lower half; follow with ori for a 32-bit constant",
+        example: "lui $0 #1",
+    },
+    OpcodeInfo {
+        opcode: Opcode::ORI,
+        mnemonic: "ori",
+        operands: &[reg("$reg"), int("#value")],
+        semantics: "$reg = $reg | value, zero-extended, e.g. lui $0 #1 then ori $0 #34464 loads 100000",
+        example: "ori $0 #34464",
+    },
+    OpcodeInfo {
+        opcode: Opcode::SEQ,
+        mnemonic: "seq",
+        operands: &[reg("$a"), reg("$b"), reg("$dst")],
+        semantics: "$dst = 1 if $a == $b, else 0; the flags are left alone",
+        example: "seq $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::SNE,
+        mnemonic: "sne",
+        operands: &[reg("$a"), reg("$b"), reg("$dst")],
+        semantics: "$dst = 1 if $a != $b, else 0; the flags are left alone",
+        example: "sne $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::SLT,
+        mnemonic: "slt",
+        operands: &[reg("$a"), reg("$b"), reg("$dst")],
+        semantics: "$dst = 1 if $a < $b (signed), else 0; swap the operands for greater than",
+        example: "slt $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::SLE,
+        mnemonic: "sle",
+        operands: &[reg("$a"), reg("$b"), reg("$dst")],
+        semantics: "$dst = 1 if $a <= $b (signed), else 0; swap the operands for greater or equal",
+        example: "sle $0 $1 $2",
+    },
+    OpcodeInfo {
+        opcode: Opcode::JZ,
+        mnemonic: "jz",
+        operands: &[reg("$target")],
+        semantics: "jumps to $target if the zero flag is set",
+        example: "jz $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::JNZ,
+        mnemonic: "jnz",
+        operands: &[reg("$target")],
+        semantics: "jumps to $target if the zero flag is clear",
+        example: "jnz $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::JC,
+        mnemonic: "jc",
+        operands: &[reg("$target")],
+        semantics: "jumps to $target if the carry flag is set",
+        example: "jc $0",
+    },
+    OpcodeInfo {
+        opcode: Opcode::JO,
+        mnemonic: "jo",
+        operands: &[reg("$target")],
+        semantics: "jumps to $target if the overflow flag is set",
+        example: "jo $0",
+    },
+];
+
+/// Another name the assembler accepts for an opcode. Deprecated aliases still assemble but
+/// produce a warning pointing at the opcode's mnemonic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MnemonicAlias {
+    pub alias: &'static str,
+    pub opcode: Opcode,
+    pub deprecated: bool,
+}
+
+pub const ALIASES: &[MnemonicAlias] = &[
+    MnemonicAlias {
+        alias: "jne",
+        opcode: Opcode::JNEQ,
+        deprecated: false,
+    },
+    MnemonicAlias {
+        alias: "halt",
+        opcode: Opcode::HLT,
+        deprecated: true,
+    },
+];
+
+impl MnemonicAlias {
+    /// Finds an alias, ignoring case.
+    pub fn lookup(alias: &str) -> Option<&'static MnemonicAlias> {
+        let alias = alias.to_lowercase();
+        ALIASES.iter().find(|entry| entry.alias == alias)
+    }
+}
+
+impl Opcode {
+    pub fn info(self) -> Option<&'static OpcodeInfo> {
+        OPCODES.iter().find(|info| info.opcode == self)
+    }
+}
+
+impl OpcodeInfo {
+    pub fn lookup(mnemonic: &str) -> Option<&'static OpcodeInfo> {
+        let mnemonic = mnemonic.to_lowercase();
+        OPCODES
+            .iter()
+            .find(|info| info.mnemonic == mnemonic)
+            .or_else(|| MnemonicAlias::lookup(&mnemonic)?.opcode.info())
+    }
+
+    /// How the instruction is written, e.g. `add $a $b $dst`.
+    pub fn usage(&self) -> String {
+        let mut usage = self.mnemonic.to_string();
+        for operand in self.operands {
+            usage.push(' ');
+            usage.push_str(operand.name);
+        }
+
+        usage
+    }
+
+    /// Byte layout of the instruction, e.g. `01 | $a | $b | $dst`.
+    pub fn encoding(&self) -> String {
+        let mut fields = vec![format!("{:02x}", self.opcode as u8)];
+        let mut length = 1;
+        for operand in self.operands {
+            fields.push(match operand.kind {
+                OperandKind::Register => operand.name.to_string(),
+                OperandKind::Integer => format!("{} (16 bits)", operand.name),
+            });
+            length += operand.kind.width();
+        }
+        fields.extend((length..INSTRUCTION_LENGTH).map(|_| "00".to_string()));
+
+        fields.join(" | ")
+    }
+}
+
+/// Renders one encoded instruction as assembly, e.g. `add $0 $1 $2`.
+pub fn disassemble(instruction: &[u8]) -> String {
+    disassemble_with(instruction, &NarrowEncoding)
+}
+
+/// Like `disassemble`, for an instruction in any encoding.
+pub fn disassemble_with(instruction: &[u8], encoding: &dyn Encoding) -> String {
+    let byte = |index: usize| instruction.get(index).copied().unwrap_or(0);
+    let opcode = Opcode::from(byte(0));
+    let Some(info) = opcode.info() else {
+        return format!("igl {:#04x}", byte(0));
+    };
+
+    let mut text = info.mnemonic.to_string();
+    let mut position = encoding.operands_offset();
+    for operand in info.operands {
+        match operand.kind {
+            OperandKind::Register => {
+                text.push_str(&format!(" ${}", byte(position)));
+                position += 1;
+            }
+            OperandKind::Integer => {
+                let bytes = instruction.get(position..).unwrap_or_default();
+                let value = encoding.decode_immediate(bytes).unwrap_or_default();
+                match opcode {
+                    Opcode::LOADS => text.push_str(&format!(" #{}", encoding.sign_extend(value))),
+                    _ => text.push_str(&format!(" #{value}")),
+                }
+                position += encoding.immediate_width();
+            }
+        }
+    }
+
+    text
+}
+
+impl fmt::Display for OpcodeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.usage())?;
+        writeln!(f, "  encoding  {}", self.encoding())?;
+        writeln!(f, "  effect    {}", self.semantics)?;
+        write!(f, "  example   {}", self.example)
+    }
 }
 
 impl From<&str> for Opcode {
     fn from(v: &str) -> Self {
-        match v {
-            "load" => Opcode::LOAD,
-            "add" => Opcode::ADD,
-            "sub" => Opcode::SUB,
-            "mul" => Opcode::MUL,
-            "div" => Opcode::DIV,
-            "hlt" => Opcode::HLT,
-            "jmp" => Opcode::JMP,
-            "jmpf" => Opcode::JMPF,
-            "jmpb" => Opcode::JMPB,
-            "eq" => Opcode::EQ,
-            "neq" => Opcode::NEQ,
-            "gt" => Opcode::GT,
-            "lt" => Opcode::LT,
-            "gte" => Opcode::GTE,
-            "lte" => Opcode::LTE,
-            "jeq" => Opcode::JEQ,
-            "jneq" => Opcode::JNEQ,
-            "aloc" => Opcode::ALOC,
-            "inc" => Opcode::INC,
-            "dec" => Opcode::DEC,
-            _ => Opcode::IGL,
-        }
+        OPCODES
+            .iter()
+            .find(|info| info.mnemonic == v)
+            .map(|info| info.opcode)
+            .or_else(|| MnemonicAlias::lookup(v).map(|alias| alias.opcode))
+            .unwrap_or(Opcode::IGL)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::instruction::{Instruction, Opcode};
+    use crate::instruction::{
+        disassemble, disassemble_with, Encoding, Format, Instruction, NarrowEncoding, Opcode,
+        OpcodeInfo, WideEncoding, ALIASES, INSTRUCTION_LENGTH, OPCODES,
+    };
 
     #[test]
     fn test_new_opcode() {
         let opcode = Opcode::HLT;
         let instruction = Instruction::new(opcode);
-        assert_eq!(instruction.opcode, Opcode::HLT);
+        assert_eq!(instruction.opcode(), Opcode::HLT);
     }
 
     #[test]
@@ -78,8 +920,97 @@ mod test {
         assert_eq!(Opcode::from("hlt"), Opcode::HLT);
     }
 
+    #[test]
+    fn test_aliases() {
+        assert_eq!(Opcode::from("jne"), Opcode::JNEQ);
+        assert_eq!(Opcode::from("halt"), Opcode::HLT);
+        assert_eq!(OpcodeInfo::lookup("HALT").unwrap().mnemonic, "hlt");
+        for alias in ALIASES {
+            assert!(OpcodeInfo::lookup(alias.alias).is_some());
+            assert!(
+                OPCODES.iter().all(|info| info.mnemonic != alias.alias),
+                "{} shadows a mnemonic",
+                alias.alias
+            );
+        }
+    }
+
     #[test]
     fn test_illegal_opcode_from_str() {
         assert_eq!(Opcode::from("NNN"), Opcode::IGL);
     }
+
+    #[test]
+    fn test_every_opcode_has_info() {
+        for value in 0..=u8::MAX {
+            let opcode = Opcode::from(value);
+            if opcode == Opcode::IGL {
+                continue;
+            }
+            let info = opcode.info().unwrap();
+            assert_eq!(info.opcode as u8, value);
+            assert_eq!(Opcode::from(info.mnemonic), opcode);
+            let width: usize = info.operands.iter().map(|o| o.kind.width()).sum();
+            assert!(width < INSTRUCTION_LENGTH, "{} is too long", info.mnemonic);
+        }
+        assert_eq!(Opcode::IGL.info(), None);
+    }
+
+    #[test]
+    fn test_opcode_help() {
+        let add = OpcodeInfo::lookup("ADD").unwrap();
+        assert_eq!(add.usage(), "add $a $b $dst");
+        assert_eq!(add.encoding(), "01 | $a | $b | $dst");
+        assert_eq!(
+            OpcodeInfo::lookup("load").unwrap().encoding(),
+            "00 | $reg | #value (16 bits)"
+        );
+        assert_eq!(
+            Opcode::HLT.info().unwrap().to_string(),
+            "hlt\n  encoding  05 | 00 | 00 | 00\n  effect    stops the program\n  example   hlt"
+        );
+        assert_eq!(OpcodeInfo::lookup("nop"), None);
+    }
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(disassemble(&[1, 0, 1, 2]), "add $0 $1 $2");
+        assert_eq!(disassemble(&[0, 3, 1, 244]), "load $3 #500");
+        assert_eq!(disassemble(&[22, 3, 255, 254]), "loads $3 #-2");
+        assert_eq!(disassemble(&[5, 0, 0, 0]), "hlt");
+        assert_eq!(disassemble(&[200, 0, 0, 0]), "igl 0xc8");
+        assert_eq!(
+            disassemble_with(&[22, 0, 3, 255, 254, 238, 144, 0], &WideEncoding),
+            "loads $3 #-70000"
+        );
+    }
+
+    #[test]
+    fn test_encodings() {
+        assert_eq!(
+            NarrowEncoding.encode_immediate(65535, false),
+            Some(vec![255, 255])
+        );
+        assert_eq!(NarrowEncoding.encode_immediate(65536, false), None);
+        assert_eq!(NarrowEncoding.encode_immediate(-32769, true), None);
+        assert_eq!(
+            WideEncoding.encode_immediate(-2, true),
+            Some(vec![255, 255, 255, 254])
+        );
+        assert_eq!(WideEncoding.encode_immediate(-2, false), None);
+        assert_eq!(WideEncoding.sign_extend(0xffff_fffe), -2);
+        assert_eq!(NarrowEncoding.sign_extend(0xfffe), -2);
+
+        assert_eq!(
+            WideEncoding.instruction(Opcode::ADD, &[0, 1, 2]),
+            [1, 0, 0, 1, 2, 0, 0, 0]
+        );
+        assert_eq!(NarrowEncoding.instruction(Opcode::HLT, &[]), [5, 0, 0, 0]);
+
+        for format in [Format::Narrow, Format::Wide] {
+            assert_eq!(Format::from_id(format.id()), Some(format));
+            assert_eq!(Format::from_name(format.name()), Some(format));
+        }
+        assert_eq!(Format::from_id(2), None);
+    }
 }
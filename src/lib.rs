@@ -0,0 +1,35 @@
+//! The VM core (`vm`, `manager`, the program container and cost model) builds with no
+//! dependencies. The assembler, REPL, network services and the CLI are behind the
+//! `assembler`, `repl`, `net` and `cli` features.
+
+#[cfg(feature = "assembler")]
+pub mod analyze;
+pub mod assembler;
+#[cfg(feature = "assembler")]
+pub mod batch;
+#[cfg(feature = "cli")]
+pub mod cli;
+#[cfg(all(unix, feature = "net"))]
+pub mod control;
+pub mod cost;
+#[cfg(feature = "net")]
+pub mod debug;
+pub mod encoding;
+#[cfg(feature = "assembler")]
+pub mod inspect;
+pub mod instruction;
+pub mod json;
+pub mod manager;
+pub mod marshal;
+#[cfg(feature = "assembler")]
+pub mod patch;
+#[cfg(feature = "assembler")]
+pub mod project;
+#[cfg(feature = "repl")]
+pub mod repl;
+#[cfg(feature = "net")]
+pub mod server;
+pub mod trace;
+#[cfg(feature = "repl")]
+pub mod tutorial;
+pub mod vm;
@@ -0,0 +1,360 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, VecDeque},
+};
+
+use crate::vm::{ExitReason, VM};
+
+/// Lifecycle of a program loaded into the manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Ready,
+    /// Holds the current time slice.
+    Running,
+    Finished(ExitReason),
+    Killed,
+}
+
+impl ProcessState {
+    pub fn is_runnable(&self) -> bool {
+        matches!(self, ProcessState::Ready | ProcessState::Running)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProcessState::Ready => "ready",
+            ProcessState::Running => "running",
+            ProcessState::Finished(_) => "finished",
+            ProcessState::Killed => "killed",
+        }
+    }
+}
+
+/// A loaded program with its own VM, and therefore its own registers, code and heap.
+#[derive(Debug)]
+pub struct Process {
+    pub pid: u32,
+    pub name: String,
+    pub state: ProcessState,
+    /// Runnable processes with a higher priority always run first.
+    pub priority: u8,
+    vm: VM,
+}
+
+impl Process {
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+}
+
+/// Instructions a process may execute before the scheduler moves on to the next one.
+pub const DEFAULT_TIME_SLICE: u64 = 100;
+
+/// Keeps several programs loaded side by side, each in a separate address space, and
+/// interleaves them with a round-robin scheduler.
+///
+/// Scheduling is strictly by priority, round-robin among processes of equal priority, so
+/// a busy high priority process starves the rest. A YIELDTO ends the yielding process's
+/// slice and runs the target next regardless of its priority.
+#[derive(Debug)]
+pub struct ProgramManager {
+    processes: BTreeMap<u32, Process>,
+    next_pid: u32,
+    run_queue: VecDeque<u32>,
+    time_slice: u64,
+    yielded_to: Option<u32>,
+}
+
+impl Default for ProgramManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgramManager {
+    pub fn new() -> Self {
+        Self {
+            processes: BTreeMap::new(),
+            next_pid: 1,
+            run_queue: VecDeque::new(),
+            time_slice: DEFAULT_TIME_SLICE,
+            yielded_to: None,
+        }
+    }
+
+    pub fn set_time_slice(&mut self, instructions: u64) {
+        self.time_slice = instructions.max(1);
+    }
+
+    /// Loads an assembled program into a fresh VM and returns its pid.
+    pub fn spawn(&mut self, name: &str, program: Vec<u8>) -> Result<u32, String> {
+        let mut vm = VM::new();
+        vm.load_program(program);
+        vm.start()
+            .map_err(|e| format!("Unable to load {name}: {e}"))?;
+
+        let pid = self.next_pid;
+        self.next_pid += 1;
+        self.processes.insert(
+            pid,
+            Process {
+                pid,
+                name: name.to_string(),
+                state: ProcessState::Ready,
+                priority: 0,
+                vm,
+            },
+        );
+        self.run_queue.push_back(pid);
+
+        Ok(pid)
+    }
+
+    pub fn set_priority(&mut self, pid: u32, priority: u8) -> Result<(), String> {
+        self.get_mut(pid)?.priority = priority;
+
+        Ok(())
+    }
+
+    pub fn kill(&mut self, pid: u32) -> Result<(), String> {
+        let process = self.get_mut(pid)?;
+        if !process.state.is_runnable() {
+            return Err(format!("Process {pid} is not running"));
+        }
+        process.state = ProcessState::Killed;
+
+        Ok(())
+    }
+
+    /// Drops a process that is no longer running.
+    pub fn reap(&mut self, pid: u32) -> Result<Process, String> {
+        if self.get_mut(pid)?.state.is_runnable() {
+            return Err(format!("Process {pid} is still running"));
+        }
+
+        self.processes
+            .remove(&pid)
+            .ok_or_else(|| format!("No process with pid {pid}"))
+    }
+
+    /// Executes up to `count` instructions of a process, returning its state afterwards.
+    pub fn step(&mut self, pid: u32, count: u64) -> Result<ProcessState, String> {
+        let process = self.get_mut(pid)?;
+        for _ in 0..count {
+            if !process.state.is_runnable() {
+                break;
+            }
+            if let Some(exit) = process.vm.run_once() {
+                process.state = ProcessState::Finished(exit);
+            }
+            // Only the scheduler acts on yields
+            process.vm.take_yield();
+        }
+
+        Ok(process.state)
+    }
+
+    /// Gives the next runnable process in the queue one time slice, returning its pid, or
+    /// `None` once every process has finished or been killed.
+    pub fn tick(&mut self) -> Option<u32> {
+        // The previous slice is over, whoever held it goes back to waiting
+        for process in self.processes.values_mut() {
+            if process.state == ProcessState::Running {
+                process.state = ProcessState::Ready;
+            }
+        }
+
+        let processes = &self.processes;
+        self.run_queue
+            .retain(|pid| processes.get(pid).is_some_and(|p| p.state.is_runnable()));
+
+        let directed = self
+            .yielded_to
+            .take()
+            .and_then(|target| self.run_queue.iter().position(|&pid| pid == target));
+        let index = directed.or_else(|| {
+            self.run_queue
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, pid)| Reverse(processes[pid].priority))
+                .map(|(index, _)| index)
+        })?;
+        let pid = self.run_queue.remove(index)?;
+
+        let slice = self.time_slice;
+        let process = self.processes.get_mut(&pid)?;
+        process.state = ProcessState::Running;
+        for _ in 0..slice {
+            if let Some(exit) = process.vm.run_once() {
+                process.state = ProcessState::Finished(exit);
+                break;
+            }
+            if let Some(target) = process.vm.take_yield() {
+                self.yielded_to = u32::try_from(target).ok().filter(|&target| target != pid);
+                break;
+            }
+        }
+        if process.state.is_runnable() {
+            self.run_queue.push_back(pid);
+        }
+
+        Some(pid)
+    }
+
+    /// Runs up to `max_slices` time slices, returning how many were used.
+    pub fn schedule(&mut self, max_slices: u64) -> u64 {
+        let mut slices = 0;
+        while slices < max_slices && self.tick().is_some() {
+            slices += 1;
+        }
+
+        slices
+    }
+
+    pub fn get(&self, pid: u32) -> Option<&Process> {
+        self.processes.get(&pid)
+    }
+
+    /// Processes ordered by pid.
+    pub fn processes(&self) -> impl Iterator<Item = &Process> {
+        self.processes.values()
+    }
+
+    fn get_mut(&mut self, pid: u32) -> Result<&mut Process, String> {
+        self.processes
+            .get_mut(&pid)
+            .ok_or_else(|| format!("No process with pid {pid}"))
+    }
+}
+
+#[cfg(all(test, feature = "assembler"))]
+mod test {
+    use crate::{
+        assembler::assembler::Assembler,
+        manager::{ProcessState, ProgramManager},
+        vm::ExitReason,
+    };
+
+    // Counts up in $1 forever
+    const COUNTER: &str = "load $0 #68\ninc $1\njmp $0";
+
+    fn assemble(source: &str) -> Vec<u8> {
+        Assembler::new().assemble(source).unwrap()
+    }
+
+    #[test]
+    fn test_processes_have_separate_state() {
+        let mut manager = ProgramManager::new();
+        let first = manager.spawn("first", assemble("inc $0\nhlt")).unwrap();
+        let second = manager
+            .spawn("second", assemble("load $0 #9\nhlt"))
+            .unwrap();
+        assert_eq!((first, second), (1, 2));
+
+        assert_eq!(
+            manager.step(first, 10),
+            Ok(ProcessState::Finished(ExitReason::Halted))
+        );
+        assert_eq!(manager.step(second, 1), Ok(ProcessState::Ready));
+        assert_eq!(manager.get(first).unwrap().vm().register(0), Some(1));
+        assert_eq!(manager.get(second).unwrap().vm().register(0), Some(9));
+    }
+
+    #[test]
+    fn test_kill_and_reap() {
+        let mut manager = ProgramManager::new();
+        let pid = manager
+            .spawn("loop", assemble("load $0 #64\njmp $0"))
+            .unwrap();
+        assert!(manager.reap(pid).is_err());
+        manager.kill(pid).unwrap();
+        assert_eq!(manager.step(pid, 5), Ok(ProcessState::Killed));
+        assert!(manager.kill(pid).is_err());
+
+        assert_eq!(manager.reap(pid).unwrap().name, "loop");
+        assert_eq!(manager.processes().count(), 0);
+        assert!(manager.kill(pid).is_err());
+    }
+
+    #[test]
+    fn test_spawn_invalid_program() {
+        let mut manager = ProgramManager::new();
+        assert!(manager.spawn("bad", vec![5, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut manager = ProgramManager::new();
+        manager.set_time_slice(4);
+        let first = manager.spawn("first", assemble(COUNTER)).unwrap();
+        let second = manager.spawn("second", assemble(COUNTER)).unwrap();
+
+        assert_eq!(manager.tick(), Some(first));
+        assert_eq!(manager.get(first).unwrap().state, ProcessState::Running);
+        assert_eq!(manager.tick(), Some(second));
+        assert_eq!(manager.get(first).unwrap().state, ProcessState::Ready);
+        assert_eq!(manager.tick(), Some(first));
+
+        // LOAD, then INC/JMP pairs
+        assert_eq!(manager.get(first).unwrap().vm().register(1), Some(4));
+        assert_eq!(manager.get(second).unwrap().vm().register(1), Some(2));
+    }
+
+    #[test]
+    fn test_schedule_until_done() {
+        let mut manager = ProgramManager::new();
+        manager.set_time_slice(2);
+        let short = manager.spawn("short", assemble("inc $0\nhlt")).unwrap();
+        let long = manager
+            .spawn("long", assemble("inc $0\ninc $0\ninc $0\nhlt"))
+            .unwrap();
+        let killed = manager.spawn("killed", assemble(COUNTER)).unwrap();
+        manager.kill(killed).unwrap();
+
+        assert_eq!(manager.schedule(100), 3);
+        assert_eq!(manager.tick(), None);
+        for pid in [short, long] {
+            assert_eq!(
+                manager.get(pid).unwrap().state,
+                ProcessState::Finished(ExitReason::Halted)
+            );
+        }
+    }
+
+    #[test]
+    fn test_priorities() {
+        let mut manager = ProgramManager::new();
+        manager.set_time_slice(2);
+        let low = manager.spawn("low", assemble(COUNTER)).unwrap();
+        let high = manager
+            .spawn("high", assemble("inc $0\ninc $0\nhlt"))
+            .unwrap();
+        manager.set_priority(high, 5).unwrap();
+
+        assert_eq!(manager.tick(), Some(high));
+        assert_eq!(manager.tick(), Some(high));
+        assert_eq!(manager.tick(), Some(low));
+        assert!(manager.set_priority(99, 1).is_err());
+    }
+
+    #[test]
+    fn test_yieldto() {
+        let mut manager = ProgramManager::new();
+        manager.set_time_slice(10);
+        // Hands the CPU to pid 3 straight away, then keeps counting
+        let yielding = manager
+            .spawn(
+                "yield",
+                assemble("load $2 #3\nyieldto $2\nload $0 #76\ninc $1\njmp $0"),
+            )
+            .unwrap();
+        let skipped = manager.spawn("skipped", assemble(COUNTER)).unwrap();
+        let target = manager.spawn("target", assemble(COUNTER)).unwrap();
+
+        assert_eq!(manager.tick(), Some(yielding));
+        assert_eq!(manager.get(yielding).unwrap().vm().program_counter(), 72);
+        assert_eq!(manager.tick(), Some(target));
+        assert_eq!(manager.tick(), Some(skipped));
+        assert_eq!(manager.tick(), Some(yielding));
+    }
+}
@@ -0,0 +1,165 @@
+//! Copying plain host values into guest memory and back.
+//!
+//! The layout matches what guest code reads with LW and LB: integers are big-endian,
+//! and struct fields are packed in declaration order without padding. Structs opt in
+//! with `guest_struct!`.
+
+/// A value with a fixed layout in guest memory.
+pub trait GuestSerialize: Sized {
+    /// Bytes the value occupies.
+    const SIZE: usize;
+
+    /// Writes the value into `bytes`, which is exactly `SIZE` long.
+    fn write_to(&self, bytes: &mut [u8]);
+
+    /// Reads a value from `bytes`, which is exactly `SIZE` long.
+    fn read_from(bytes: &[u8]) -> Self;
+}
+
+macro_rules! impl_integer {
+    ($($ty:ty),*) => {
+        $(
+            impl GuestSerialize for $ty {
+                const SIZE: usize = std::mem::size_of::<$ty>();
+
+                fn write_to(&self, bytes: &mut [u8]) {
+                    bytes.copy_from_slice(&self.to_be_bytes());
+                }
+
+                fn read_from(bytes: &[u8]) -> Self {
+                    <$ty>::from_be_bytes(bytes.try_into().expect("sized by SIZE"))
+                }
+            }
+        )*
+    };
+}
+
+impl_integer!(u8, i8, u16, i16, u32, i32);
+
+/// One byte, nonzero reads as true.
+impl GuestSerialize for bool {
+    const SIZE: usize = 1;
+
+    fn write_to(&self, bytes: &mut [u8]) {
+        bytes[0] = u8::from(*self);
+    }
+
+    fn read_from(bytes: &[u8]) -> Self {
+        bytes[0] != 0
+    }
+}
+
+impl<T: GuestSerialize, const N: usize> GuestSerialize for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+
+    fn write_to(&self, bytes: &mut [u8]) {
+        for (item, chunk) in self.iter().zip(bytes.chunks_exact_mut(T::SIZE)) {
+            item.write_to(chunk);
+        }
+    }
+
+    fn read_from(bytes: &[u8]) -> Self {
+        std::array::from_fn(|index| T::read_from(&bytes[index * T::SIZE..][..T::SIZE]))
+    }
+}
+
+/// Implements `GuestSerialize` for a struct whose fields all implement it, laying the
+/// fields out in the order listed.
+///
+/// ```
+/// use vmariachi::guest_struct;
+///
+/// struct Point {
+///     x: i32,
+///     y: i32,
+///     visible: bool,
+/// }
+///
+/// guest_struct!(Point { x: i32, y: i32, visible: bool });
+/// ```
+#[macro_export]
+macro_rules! guest_struct {
+    ($name:ident { $($field:ident: $ty:ty),* $(,)? }) => {
+        impl $crate::marshal::GuestSerialize for $name {
+            const SIZE: usize = 0 $(+ <$ty as $crate::marshal::GuestSerialize>::SIZE)*;
+
+            fn write_to(&self, bytes: &mut [u8]) {
+                let mut offset = 0;
+                $(
+                    let size = <$ty as $crate::marshal::GuestSerialize>::SIZE;
+                    $crate::marshal::GuestSerialize::write_to(
+                        &self.$field,
+                        &mut bytes[offset..offset + size],
+                    );
+                    offset += size;
+                )*
+                let _ = offset;
+            }
+
+            fn read_from(bytes: &[u8]) -> Self {
+                let mut offset = 0;
+                $(
+                    let size = <$ty as $crate::marshal::GuestSerialize>::SIZE;
+                    let $field = <$ty as $crate::marshal::GuestSerialize>::read_from(
+                        &bytes[offset..offset + size],
+                    );
+                    offset += size;
+                )*
+                let _ = offset;
+                Self { $($field),* }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{marshal::GuestSerialize, vm::VM};
+
+    #[derive(Debug, PartialEq)]
+    struct Particle {
+        id: u8,
+        position: [i32; 2],
+        alive: bool,
+        mass: i16,
+    }
+
+    guest_struct!(Particle {
+        id: u8,
+        position: [i32; 2],
+        alive: bool,
+        mass: i16,
+    });
+
+    #[test]
+    fn test_layout() {
+        let particle = Particle {
+            id: 7,
+            position: [1, -1],
+            alive: true,
+            mass: 258,
+        };
+        assert_eq!(Particle::SIZE, 12);
+        let mut bytes = [0; 12];
+        particle.write_to(&mut bytes);
+        assert_eq!(bytes, [7, 0, 0, 0, 1, 255, 255, 255, 255, 1, 1, 2]);
+        assert_eq!(Particle::read_from(&bytes), particle);
+    }
+
+    #[test]
+    fn test_vm_round_trip() {
+        let particle = Particle {
+            id: 1,
+            position: [300, 400],
+            alive: false,
+            mass: -5,
+        };
+        let mut vm = VM::new();
+        vm.write_heap(0, &[0; 16]).unwrap();
+        vm.write_value(4, &particle).unwrap();
+        assert_eq!(vm.read_i32(5), Ok(300));
+        assert_eq!(vm.read_value::<Particle>(4), Ok(particle));
+        assert!(vm.read_value::<Particle>(5).is_err());
+        assert!(vm.write_value(8, &[0i32; 3]).is_err());
+    }
+}
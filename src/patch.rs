@@ -0,0 +1,156 @@
+//! Replacing a function of a running program, for edit-and-continue.
+//!
+//! The new version is appended to the code section and every `call @name` is pointed at
+//! it, along with the symbol. The old code stays in place, so an activation already
+//! inside the function, and the return addresses on the stack, finish on the old
+//! version; the next call runs the new one. Addresses loaded into registers, as in
+//! `load $0 @name`, are plain numbers and are not updated.
+
+use crate::{
+    assembler::{
+        assembler::{Assembler, SymbolTable, SymbolType},
+        container::{self, code_section, ProgramWriter, SectionKind},
+    },
+    encoding,
+    instruction::{Format, Opcode},
+    vm::VM,
+};
+
+/// Where a patched function moved.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Patch {
+    /// Address of the new version.
+    pub address: u32,
+    /// Address of the version it replaces.
+    pub previous: u32,
+    /// CALL instructions redirected to the new version.
+    pub call_sites: usize,
+}
+
+/// Assembles `source` as the new body of the function labelled `name` and patches it into
+/// the program loaded in `vm`. The source can call the program's other functions and use
+/// its data by label, but cannot add data, BSS or constants of its own.
+pub fn patch_function(vm: &mut VM, name: &str, source: &str) -> Result<Patch, String> {
+    let program = vm.program();
+    if container::read_header(program)?.format != Format::Narrow {
+        return Err("Only programs with 4 byte instructions can be patched".to_string());
+    }
+    let sections = container::read_sections(program)?;
+    let code = code_section(&sections).ok_or("Program has no code section")?;
+    let symbols_section = sections
+        .iter()
+        .find(|section| section.kind == SectionKind::Symbols)
+        .ok_or("The program has no symbols")?;
+    let mut symbols = SymbolTable::from_bytes(symbols_section.contents(program))?;
+    let previous = symbols
+        .iter()
+        .find(|symbol| symbol.name() == name && *symbol.symbol_type() == SymbolType::Label)
+        .map(|symbol| symbol.address())
+        .ok_or_else(|| format!("No function called {name}"))?;
+
+    // CALL takes a 16 bit target
+    let address = code.range().end as u32;
+    let mut assembler = Assembler::new();
+    assembler.set_origin(address);
+    for symbol in symbols.iter() {
+        if let Some(symbol_address) = symbols.address(symbol.name()) {
+            assembler.define_external(symbol.name(), symbol_address);
+        }
+    }
+    let patch = assembler
+        .try_assemble(source)
+        .map_err(|errors| errors.join("\n"))?;
+    let patch_sections = container::read_sections(&patch)?;
+    if patch_sections
+        .iter()
+        .any(|section| !matches!(section.kind, SectionKind::Code | SectionKind::Symbols))
+    {
+        return Err("A patch can only hold code".to_string());
+    }
+    let body = code_section(&patch_sections).ok_or("Patch has no code section")?;
+    let end = address as usize + body.length;
+    if end > u16::MAX as usize + 1 {
+        return Err(format!("No room for {name}: code would end at {end}"));
+    }
+
+    let mut new_code = program[code.range()].to_vec();
+    let mut call_sites = 0;
+    for instruction in new_code.chunks_exact_mut(4) {
+        let target = encoding::decode_u16(instruction, 1).map(u32::from);
+        if Opcode::from(instruction[0]) == Opcode::CALL && target == Some(previous) {
+            instruction[1..3].copy_from_slice(&encoding::encode_u16(address as u16));
+            call_sites += 1;
+        }
+    }
+    new_code.extend_from_slice(&patch[body.range()]);
+    symbols.relocate(name, address);
+
+    let mut writer = ProgramWriter::from_program(program)?;
+    writer.set_code(new_code);
+    writer.set_section(
+        SectionKind::Symbols,
+        &symbols_section.name,
+        symbols.to_bytes()?,
+    )?;
+    vm.replace_program(writer.finish())?;
+
+    Ok(Patch {
+        address,
+        previous,
+        call_sites,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        assembler::assembler::Assembler,
+        patch::patch_function,
+        vm::{ExitReason, VM},
+    };
+
+    #[test]
+    fn test_patch_function() {
+        // Sums what `next` returns over three calls into $2
+        let source = "load $3 #3\n\
+                      load $4 @loop\n\
+                      loop: inc $1\n\
+                      call @next\n\
+                      add $2 $0 $2\n\
+                      bne $1 $3 $4\n\
+                      hlt\n\
+                      next: load $0 #1\n\
+                      ret";
+        let mut vm = VM::new();
+        vm.load_program(Assembler::new().try_assemble(source).unwrap());
+        vm.start().unwrap();
+        // Up to and including the first return from `next`
+        for _ in 0..6 {
+            assert_eq!(vm.run_once(), None);
+        }
+
+        let patch = patch_function(&mut vm, "next", "load $0 #10\nret").unwrap();
+        assert_eq!(
+            (patch.previous, patch.address, patch.call_sites),
+            (92, 100, 1)
+        );
+        // The symbol moved too, so patching again replaces the new version
+        let patch = patch_function(&mut vm, "next", "load $0 #10\nret").unwrap();
+        assert_eq!(
+            (patch.previous, patch.address, patch.call_sites),
+            (100, 108, 1)
+        );
+
+        assert!(patch_function(&mut vm, "missing", "ret").is_err());
+        assert!(patch_function(&mut vm, "next", "load $0 #x\nret").is_err());
+        assert!(patch_function(&mut vm, "next", "msg: .asciiz 'hi'\nret").is_err());
+
+        let exit = loop {
+            if let Some(exit) = vm.run_once() {
+                break exit;
+            }
+        };
+        assert_eq!(exit, ExitReason::Halted);
+        assert_eq!(vm.register(2), Some(21));
+    }
+}
@@ -0,0 +1,398 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::assembler::assembler::Assembler;
+
+/// File name of the manifest at the root of a project.
+pub const MANIFEST: &str = "vmariachi.toml";
+
+const MAIN: &str = "load $0 #10\nload $1 #32\nadd $0 $1 $2\nhlt\n";
+
+/// Creates a project at `dir`: a manifest, `main.asm`, and empty `lib/` and `tests/`
+/// directories. The project is named after the last component of `dir`, which must not
+/// exist yet.
+pub fn scaffold(dir: &Path) -> Result<(), String> {
+    let display = dir.display();
+    let name = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| format!("Invalid project name: {display}"))?;
+    if dir.exists() {
+        return Err(format!("{display} already exists"));
+    }
+
+    let manifest = format!("name = \"{name}\"\nsources = [\"main.asm\"]\ninclude = [\"lib\"]\n");
+    let write = |path: &str, contents: &str| {
+        fs::write(dir.join(path), contents).map_err(|e| format!("Unable to write {path}: {e}"))
+    };
+    for subdir in ["lib", "tests"] {
+        fs::create_dir_all(dir.join(subdir))
+            .map_err(|e| format!("Unable to create {display}/{subdir}: {e}"))?;
+    }
+    write(MANIFEST, &manifest)?;
+    write("main.asm", MAIN)
+}
+
+/// How deeply `.include` may nest, which also stops a file from including itself.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Build settings read from a project's manifest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub name: String,
+    /// Assembled in order as one program, so their labels are shared.
+    pub sources: Vec<String>,
+    /// Directories searched by `.include 'file'` after the including file's own.
+    pub include: Vec<String>,
+    /// `#NAME` operands replaced by the value before assembling.
+    pub defines: Vec<(String, i32)>,
+    /// 0 assembles the code as written, 1 also fuses compare-and-branch pairs.
+    pub opt_level: u8,
+    /// Where the program is written, relative to the project root.
+    pub output: String,
+}
+
+enum Value {
+    String(String),
+    Integer(i64),
+    List(Vec<String>),
+}
+
+impl Manifest {
+    /// Parses the subset of TOML a manifest uses: top-level keys whose values are quoted
+    /// strings, integers or arrays of strings.
+    ///
+    /// ```toml
+    /// name = "myprog"
+    /// sources = ["main.asm", "lib/math.asm"]
+    /// include = ["lib"]
+    /// defines = ["SIZE=64"]
+    /// opt_level = 1
+    /// output = "build/myprog.bin"
+    /// ```
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut name = None;
+        let mut sources = None;
+        let mut include = Vec::new();
+        let mut defines = Vec::new();
+        let mut opt_level = 0;
+        let mut output = None;
+
+        for (number, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |message: String| format!("line {}: {message}", number + 1);
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error("expected key = value".to_string()))?;
+            let key = key.trim();
+            match (key, parse_value(value.trim()).map_err(error)?) {
+                ("name", Value::String(value)) => name = Some(value),
+                ("sources", Value::List(values)) => sources = Some(values),
+                ("include", Value::List(values)) => include = values,
+                ("defines", Value::List(values)) => {
+                    defines = values
+                        .iter()
+                        .map(|define| parse_define(define))
+                        .collect::<Result<_, _>>()
+                        .map_err(error)?
+                }
+                ("opt_level", Value::Integer(level @ 0..=1)) => opt_level = level as u8,
+                ("opt_level", _) => return Err(error("opt_level must be 0 or 1".to_string())),
+                ("output", Value::String(value)) => output = Some(value),
+                ("name" | "output", _) => return Err(error(format!("{key} must be a string"))),
+                ("sources" | "include" | "defines", _) => {
+                    return Err(error(format!("{key} must be an array of strings")))
+                }
+                _ => return Err(error(format!("unknown key {key}"))),
+            }
+        }
+
+        let name = name.ok_or("missing name")?;
+        let sources = sources.filter(|sources| !sources.is_empty());
+        Ok(Manifest {
+            output: output.unwrap_or_else(|| format!("{name}.bin")),
+            name,
+            sources: sources.ok_or("missing sources")?,
+            include,
+            defines,
+            opt_level,
+        })
+    }
+
+    /// Reads the manifest at the root of a project.
+    pub fn load(root: &Path) -> Result<Self, String> {
+        let path = root.join(MANIFEST);
+        let source = fs::read_to_string(&path)
+            .map_err(|e| format!("Unable to read {}: {e}", path.display()))?;
+        Manifest::parse(&source).map_err(|e| format!("{MANIFEST}: {e}"))
+    }
+
+    /// The sources joined into one program, with includes expanded and defines
+    /// substituted, alongside the file and line every line of it came from.
+    pub fn preprocess(&self, root: &Path) -> Result<(String, Vec<(String, usize)>), String> {
+        let mut lines = Vec::new();
+        for source in &self.sources {
+            self.expand(root, &root.join(source), 0, &mut lines)?;
+        }
+
+        let text = lines
+            .iter()
+            .map(|(text, _)| self.substitute(text) + "\n")
+            .collect();
+        let origins = lines.into_iter().map(|(_, origin)| origin).collect();
+        Ok((text, origins))
+    }
+
+    fn expand(
+        &self,
+        root: &Path,
+        path: &Path,
+        depth: usize,
+        lines: &mut Vec<(String, (String, usize))>,
+    ) -> Result<(), String> {
+        let display = path
+            .strip_prefix(root)
+            .unwrap_or(path)
+            .display()
+            .to_string();
+        let source =
+            fs::read_to_string(path).map_err(|e| format!("Unable to read {display}: {e}"))?;
+
+        for (index, line) in source.lines().enumerate() {
+            let Some(target) = include_target(line) else {
+                lines.push((line.to_string(), (display.clone(), index + 1)));
+                continue;
+            };
+            let error = |message: &str| format!("{display}:{}: {message}", index + 1);
+            if depth >= MAX_INCLUDE_DEPTH {
+                return Err(error("includes are nested too deeply"));
+            }
+            let found = path
+                .parent()
+                .into_iter()
+                .map(Path::to_path_buf)
+                .chain(self.include.iter().map(|dir| root.join(dir)))
+                .map(|dir| dir.join(target))
+                .find(|candidate| candidate.is_file())
+                .ok_or_else(|| error(&format!("cannot find {target} to include")))?;
+            self.expand(root, &found, depth + 1, lines)?;
+        }
+
+        Ok(())
+    }
+
+    fn substitute(&self, line: &str) -> String {
+        let mut line = line.to_string();
+        for (name, value) in &self.defines {
+            let pattern = format!("#{name}");
+            let mut from = 0;
+            while let Some(start) = line[from..].find(&pattern).map(|index| from + index) {
+                let end = start + pattern.len();
+                let whole = !line[end..].starts_with(|c: char| c.is_alphanumeric() || c == '_');
+                if whole {
+                    line.replace_range(start..end, &format!("#{value}"));
+                }
+                from = start + 1;
+            }
+        }
+
+        line
+    }
+}
+
+/// A successful build.
+#[derive(Debug)]
+pub struct Build {
+    pub output: PathBuf,
+    pub size: usize,
+    pub warnings: Vec<String>,
+}
+
+/// Assembles the project at `root` as its manifest describes and writes the program.
+/// Errors and warnings refer to the original file and line.
+pub fn build(root: &Path) -> Result<Build, Vec<String>> {
+    let manifest = Manifest::load(root).map_err(|e| vec![e])?;
+    let (source, origins) = manifest.preprocess(root).map_err(|e| vec![e])?;
+    let locate = |message: &String| match message
+        .strip_prefix("line ")
+        .and_then(|rest| rest.split_once(':'))
+        .and_then(|(number, rest)| Some((origins.get(number.parse::<usize>().ok()? - 1)?, rest)))
+    {
+        Some(((file, line), rest)) => format!("{file}:{line}:{rest}"),
+        None => message.clone(),
+    };
+
+    let mut assembler = Assembler::new();
+    assembler.set_fuse_branches(manifest.opt_level >= 1);
+    let bytes = assembler
+        .try_assemble(&source)
+        .map_err(|errors| errors.iter().map(locate).collect::<Vec<_>>())?;
+
+    let output = root.join(&manifest.output);
+    if let Some(dir) = output.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| vec![format!("Unable to create {}: {e}", dir.display())])?;
+    }
+    fs::write(&output, &bytes)
+        .map_err(|e| vec![format!("Unable to write {}: {e}", output.display())])?;
+
+    Ok(Build {
+        output,
+        size: bytes.len(),
+        warnings: assembler.warnings().iter().map(locate).collect(),
+    })
+}
+
+fn parse_value(value: &str) -> Result<Value, String> {
+    let string = |value: &str| {
+        value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .map(str::to_string)
+            .ok_or_else(|| format!("expected a quoted string, got {value}"))
+    };
+
+    if let Some(items) = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+        return items
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(string)
+            .collect::<Result<_, _>>()
+            .map(Value::List);
+    }
+    if let Ok(number) = value.parse() {
+        return Ok(Value::Integer(number));
+    }
+
+    string(value).map(Value::String)
+}
+
+// "SIZE=64"
+fn parse_define(define: &str) -> Result<(String, i32), String> {
+    let (name, value) = define
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=value, got {define}"))?;
+    let name = name.trim();
+    if !name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        || !name.chars().all(|c| c.is_alphanumeric() || c == '_')
+    {
+        return Err(format!("invalid define name: {name}"));
+    }
+    let value = value
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid value for {name}: {}", value.trim()))?;
+
+    Ok((name.to_string(), value))
+}
+
+// `.include 'file'` or `.include "file"` on a line of its own
+fn include_target(line: &str) -> Option<&str> {
+    let target = line.trim().strip_prefix(".include")?.trim();
+    ['\'', '"'].into_iter().find_map(|quote| {
+        target
+            .strip_prefix(quote)
+            .and_then(|target| target.strip_suffix(quote))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, path::PathBuf};
+
+    use crate::{
+        assembler::assembler::Assembler,
+        project::{build, scaffold, Manifest, MANIFEST},
+        vm::VM,
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("vmariachi-{name}-{}", std::process::id()))
+    }
+
+    #[test]
+    fn test_scaffold() {
+        let root = temp_dir("new");
+        let dir = root.join("myprog");
+        scaffold(&dir).unwrap();
+
+        let manifest = fs::read_to_string(dir.join(MANIFEST)).unwrap();
+        let main = fs::read_to_string(dir.join("main.asm")).unwrap();
+        let layout = (dir.join("lib").is_dir(), dir.join("tests").is_dir());
+        let again = scaffold(&dir);
+        let built = build(&dir).map(|build| build.output);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(manifest.starts_with("name = \"myprog\"\n"), "{manifest}");
+        assert!(Assembler::new().try_assemble(&main).is_ok());
+        assert_eq!(layout, (true, true));
+        assert!(again.is_err());
+        assert_eq!(built, Ok(dir.join("myprog.bin")));
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = Manifest::parse(
+            "# comment\nname = \"app\"\nsources = [\"a.asm\", \"b.asm\"]\ninclude = [\"lib\"]\ndefines = [\"SIZE=64\", \"NEG = -1\"]\nopt_level = 1\n",
+        )
+        .unwrap();
+        assert_eq!(manifest.sources, ["a.asm", "b.asm"]);
+        assert_eq!(manifest.include, ["lib"]);
+        assert_eq!(
+            manifest.defines,
+            [("SIZE".to_string(), 64), ("NEG".to_string(), -1)]
+        );
+        assert_eq!(manifest.opt_level, 1);
+        assert_eq!(manifest.output, "app.bin");
+
+        assert!(Manifest::parse("sources = [\"a.asm\"]").is_err());
+        assert!(Manifest::parse("name = \"app\"").is_err());
+        assert!(Manifest::parse("name = \"app\"\nsources = \"a.asm\"").is_err());
+        assert!(Manifest::parse("name = \"app\"\nsources = []").is_err());
+        assert!(Manifest::parse("name = \"a\"\nsources = [\"a\"]\nopt_level = 3").is_err());
+        assert!(Manifest::parse("name = \"a\"\nsources = [\"a\"]\ndefines = [\"1X=2\"]").is_err());
+        assert!(Manifest::parse("name = \"a\"\nsources = [\"a\"]\ncolour = \"red\"").is_err());
+    }
+
+    #[test]
+    fn test_build() {
+        let root = temp_dir("build");
+        fs::create_dir_all(root.join("lib")).unwrap();
+        fs::write(
+            root.join(MANIFEST),
+            "name = \"app\"\nsources = [\"main.asm\", \"extra.asm\"]\ninclude = [\"lib\"]\ndefines = [\"SIZE=6\"]\noutput = \"out/app.bin\"\n",
+        )
+        .unwrap();
+        fs::write(root.join("main.asm"), "load $0 #SIZE\ncall @double\nhlt\n").unwrap();
+        fs::write(root.join("extra.asm"), ".include 'math.asm'\n").unwrap();
+        fs::write(root.join("lib/math.asm"), "double: add $0 $0 $0\nret\n").unwrap();
+        let built = build(&root);
+
+        fs::write(root.join("extra.asm"), ".include 'math.asm'\nload $0 #x\n").unwrap();
+        let failed = build(&root);
+        fs::write(root.join("extra.asm"), ".include 'extra.asm'\n").unwrap();
+        let recursive = build(&root);
+        let program = fs::read(root.join("out/app.bin")).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        let built = built.unwrap();
+        assert!(built.output.ends_with("out/app.bin"));
+        assert_eq!(built.size, program.len());
+        let mut vm = VM::new();
+        vm.load_program(program);
+        vm.run();
+        assert_eq!(vm.register(0), Some(12));
+
+        let errors = failed.unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("extra.asm:2:"), "{errors:?}");
+        assert!(recursive.unwrap_err()[0].contains("nested too deeply"));
+    }
+}
@@ -1,17 +1,78 @@
 use std::{
-    fs::File,
+    fmt::Write as _,
+    fs::{self, File},
     io::{self, Read, Write},
-    num::ParseIntError,
     path::Path,
-    process,
+    process, thread,
+    time::Duration,
 };
 
-use crate::{assembler::parser::Program, vm::VM};
+use crate::{
+    assembler::{assembler::Assembler, container, parser::Program},
+    inspect,
+    instruction::{Opcode, OpcodeInfo, OPCODES},
+    manager::ProgramManager,
+    vm::{ExitReason, StateFormat, VM},
+};
+
+/// Upper bound on instructions executed by a single `!run` command.
+const RUN_LIMIT: u64 = 10_000_000;
+
+/// Instructions listed by `!goto`.
+const GOTO_WINDOW: usize = 16;
 
 #[derive(Debug, Default)]
 pub struct REPL {
     vm: VM,
     command_buffer: Vec<String>,
+    manager: ProgramManager,
+    number_format: NumberFormat,
+}
+
+/// How register values are shown by `!registers` and `!animate`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NumberFormat {
+    #[default]
+    Signed,
+    Unsigned,
+    Hex,
+    Binary,
+}
+
+impl NumberFormat {
+    pub fn format(self, value: i32) -> String {
+        match self {
+            NumberFormat::Signed => value.to_string(),
+            NumberFormat::Unsigned => (value as u32).to_string(),
+            NumberFormat::Hex => format!("{value:#010x}"),
+            NumberFormat::Binary => format!("{value:#034b}"),
+        }
+    }
+
+    // Widest value in this format, to line up columns
+    fn width(self) -> usize {
+        match self {
+            NumberFormat::Signed | NumberFormat::Unsigned => 11,
+            NumberFormat::Hex => 10,
+            NumberFormat::Binary => 34,
+        }
+    }
+}
+
+impl TryFrom<&str> for NumberFormat {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "signed" => Ok(NumberFormat::Signed),
+            "unsigned" => Ok(NumberFormat::Unsigned),
+            "hex" => Ok(NumberFormat::Hex),
+            "binary" => Ok(NumberFormat::Binary),
+            _ => Err(format!(
+                "Unknown format {value}, expected signed, unsigned, hex or binary"
+            )),
+        }
+    }
 }
 
 impl REPL {
@@ -19,6 +80,8 @@ impl REPL {
         Self {
             vm: VM::new(),
             command_buffer: Vec::new(),
+            manager: ProgramManager::new(),
+            number_format: NumberFormat::default(),
         }
     }
 
@@ -39,14 +102,49 @@ impl REPL {
 
             match command {
                 "!program" => {
-                    self.vm.program.iter().for_each(|byte| println!("{}", byte));
+                    self.vm
+                        .program()
+                        .iter()
+                        .for_each(|byte| println!("{}", byte));
 
                     println!("End of program");
                 }
+                "!begin" | "!paste" => {
+                    // A lone `.` is easier to type after pasting than a command
+                    let terminator = if command == "!paste" { "." } else { "!end" };
+                    let source = read_block(terminator);
+                    if let Err(e) = self.run_block(&source) {
+                        eprintln!("{e}");
+                    }
+                }
+                "!disasm" => match inspect::disassemble(self.vm.program(), None, usize::MAX) {
+                    Ok(listing) => print!("{listing}"),
+                    Err(e) => eprintln!("{e}"),
+                },
+                _ if command.starts_with("!goto @") => {
+                    let label = command["!goto @".len()..].trim();
+                    match inspect::disassemble(self.vm.program(), Some(label), GOTO_WINDOW) {
+                        Ok(listing) => print!("{listing}"),
+                        Err(e) => eprintln!("{e}"),
+                    }
+                }
                 "!registers" => {
-                    println!("{:#?}", self.vm.registers);
+                    let format = self.number_format;
+                    for (idx, value) in self.vm.registers().iter().enumerate() {
+                        println!(
+                            "${idx:<2} {:>width$}",
+                            format.format(*value),
+                            width = format.width()
+                        );
+                    }
                     println!("End of registers");
                 }
+                _ if command.starts_with("!format ") => {
+                    match NumberFormat::try_from(command["!format ".len()..].trim()) {
+                        Ok(format) => self.number_format = format,
+                        Err(e) => eprintln!("{e}"),
+                    }
+                }
                 "!load_file" => {
                     print!("Enter the path of the file: ");
                     io::stdout().flush().expect("Unable to flush to stdout");
@@ -76,7 +174,7 @@ impl REPL {
                         }
                     };
 
-                    self.vm.program.extend_from_slice(&bytes);
+                    self.vm.add_program(bytes);
                 }
                 "!quit" => {
                     println!("My work is done, I quit");
@@ -86,7 +184,72 @@ impl REPL {
                     self.command_buffer.iter().for_each(|cmd| println!("{cmd}"));
                 }
                 "!clear" => {
-                    self.vm.program.clear();
+                    self.vm.load_program(Vec::new());
+                }
+                "!ps" => {
+                    println!(
+                        "{:>5}  {:<10} {:>4} {:>6}  NAME",
+                        "PID", "STATE", "PRI", "PC"
+                    );
+                    for process in self.manager.processes() {
+                        println!(
+                            "{:>5}  {:<10} {:>4} {:>6}  {}",
+                            process.pid,
+                            process.state.as_str(),
+                            process.priority,
+                            process.vm().program_counter(),
+                            process.name
+                        );
+                    }
+                }
+                "!help" => {
+                    let mnemonics: Vec<_> = OPCODES.iter().map(|info| info.mnemonic).collect();
+                    println!("Opcodes: {}", mnemonics.join(" "));
+                    println!("Use !help <mnemonic> for the operands and encoding of one");
+                }
+                _ if command.starts_with("!help ") => {
+                    let mnemonic = command["!help ".len()..].trim();
+                    match OpcodeInfo::lookup(mnemonic) {
+                        Some(info) => println!("{info}"),
+                        None => eprintln!("Unknown opcode: {mnemonic}"),
+                    }
+                }
+                _ if command.starts_with("!export registers ")
+                    || command.starts_with("!import registers ") =>
+                {
+                    if let Err(e) = self.transfer_state(command) {
+                        eprintln!("{e}");
+                    }
+                }
+                _ if command.starts_with("!find ") => {
+                    match parse_pattern(command["!find ".len()..].trim()) {
+                        Ok(pattern) => {
+                            for (region, memory) in
+                                [("program", self.vm.program()), ("heap", self.vm.heap())]
+                            {
+                                for offset in find(memory, &pattern) {
+                                    println!("{region} {offset:#06x}");
+                                }
+                            }
+                        }
+                        Err(e) => eprintln!("{e}"),
+                    }
+                }
+                _ if command.starts_with("!animate ") => {
+                    if let Err(e) = self.animate(command["!animate ".len()..].trim()) {
+                        eprintln!("{e}");
+                    }
+                }
+                _ if command.starts_with("!spawn ")
+                    || command.starts_with("!kill ")
+                    || command.starts_with("!run ")
+                    || command.starts_with("!slice ")
+                    || command.starts_with("!priority ")
+                    || command.starts_with("!schedule") =>
+                {
+                    if let Err(e) = self.manage(command) {
+                        eprintln!("{e}");
+                    }
                 }
                 _ => {
                     let (_, program) = match Program::parse(command) {
@@ -105,29 +268,268 @@ impl REPL {
                         }
                     };
 
-                    self.vm.program.extend_from_slice(&bytes);
+                    self.vm.add_program(bytes);
 
-                    // hex instruction
-                    //
-                    // match self.parse_hex(&command) {
-                    //     Ok(instruction) => self.vm.program.extend_from_slice(&instruction),
-                    //     Err(_) => {
-                    //         eprintln!(
-                    //             "Error: Invalid hexadecimal instruction provided. The input must consist of 4 bytes in hexadecimal format, separated by spaces (e.g., '2A 00 02 FA'). Each byte should be a two-digit hexadecimal number."
-                    //         )
-                    //     }
-                    // }
-
-                    self.vm.run_once();
+                    if let Some(exit) = self.vm.run_once().filter(ExitReason::is_trap) {
+                        self.report_exit(exit);
+                    }
                 }
             }
         }
     }
 
-    fn parse_hex(&mut self, input: &str) -> Result<Vec<u8>, ParseIntError> {
-        input
-            .split(' ')
-            .map(|hex_number| u8::from_str_radix(hex_number, 16))
-            .collect()
+    // Handles the `!spawn <path>`, `!kill <pid>`, `!run <pid>`, `!slice <instructions>`,
+    // `!priority <pid> <priority>` and `!schedule [slices]` process commands
+    fn manage(&mut self, command: &str) -> Result<(), String> {
+        let (command, argument) = command.split_once(' ').unwrap_or((command, ""));
+        let argument = argument.trim();
+
+        if command == "!spawn" {
+            let mut content = String::new();
+            File::open(Path::new(argument))
+                .and_then(|mut f| f.read_to_string(&mut content))
+                .map_err(|e| format!("Unable to read {argument}: {e}"))?;
+            let mut assembler = Assembler::new();
+            let program = assembler.try_assemble(&content).map_err(|errors| {
+                format!("Unable to assemble {argument}:\n{}", errors.join("\n"))
+            })?;
+            for warning in assembler.warnings() {
+                println!("warning: {warning}");
+            }
+            let pid = self.manager.spawn(argument, program)?;
+            println!("Spawned {argument} as pid {pid}");
+            return Ok(());
+        }
+
+        if command == "!schedule" {
+            let slices = match argument {
+                "" => RUN_LIMIT,
+                n => n.parse().map_err(|_| format!("Invalid slice count: {n}"))?,
+            };
+            let used = self.manager.schedule(slices);
+            println!("Ran {used} time slices");
+            return Ok(());
+        }
+        if command == "!slice" {
+            let instructions = argument
+                .parse()
+                .map_err(|_| format!("Invalid time slice: {argument}"))?;
+            self.manager.set_time_slice(instructions);
+            return Ok(());
+        }
+
+        if command == "!priority" {
+            let (pid, priority) = argument
+                .split_once(' ')
+                .and_then(|(pid, priority)| {
+                    Some((pid.parse().ok()?, priority.trim().parse().ok()?))
+                })
+                .ok_or_else(|| format!("Expected !priority <pid> <0-255>, got {argument}"))?;
+            return self.manager.set_priority(pid, priority);
+        }
+
+        let pid = argument
+            .parse::<u32>()
+            .map_err(|_| format!("Invalid pid: {argument}"))?;
+        match command {
+            "!kill" => self.manager.kill(pid),
+            _ => {
+                let state = self.manager.step(pid, RUN_LIMIT)?;
+                println!("Process {pid} is {}", state.as_str());
+                Ok(())
+            }
+        }
+    }
+
+    // Assembles a whole block of source as one unit, so labels can be used before they are
+    // declared, then appends it to the program and runs it
+    fn run_block(&mut self, source: &str) -> Result<(), String> {
+        let origin = self.vm.program().len();
+        let mut assembler = Assembler::new();
+        assembler.set_origin(origin as u32);
+        let program = assembler
+            .try_assemble(source)
+            .map_err(|errors| errors.join("\n"))?;
+        for warning in assembler.warnings() {
+            println!("warning: {warning}");
+        }
+        let sections = container::read_sections(&program)?;
+        let code = container::code_section(&sections).ok_or("Program has no code section")?;
+        self.vm.add_program(code.contents(&program).to_vec());
+
+        for _ in 0..RUN_LIMIT {
+            if self.vm.program_counter() >= self.vm.program().len() {
+                break;
+            }
+            if let Some(exit) = self.vm.run_once() {
+                self.report_exit(exit);
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn report_exit(&self, exit: ExitReason) {
+        match self.vm.error() {
+            Some(error) => println!("Stopped: {} ({error})", exit.as_str()),
+            None => println!("Stopped: {}", exit.as_str()),
+        }
+    }
+
+    // Handles `!export registers <path>` and `!import registers <path>`, in JSON or CSV
+    // depending on the extension
+    fn transfer_state(&mut self, command: &str) -> Result<(), String> {
+        let (command, path) = command
+            .split_once(" registers ")
+            .expect("only called for register transfers");
+        let path = path.trim();
+        let format = StateFormat::from_path(path)?;
+
+        if command == "!export" {
+            fs::write(path, self.vm.export_state(format))
+                .map_err(|e| format!("Unable to write {path}: {e}"))?;
+            println!("Registers written to {path}");
+        } else {
+            let state =
+                fs::read_to_string(path).map_err(|e| format!("Unable to read {path}: {e}"))?;
+            self.vm.import_state(&state, format)?;
+        }
+
+        Ok(())
+    }
+
+    // Steps the VM `hz` times a second, redrawing the registers after every instruction
+    fn animate(&mut self, hz: &str) -> Result<(), String> {
+        let hz: u32 = hz
+            .parse()
+            .ok()
+            .filter(|hz| (1..=1000).contains(hz))
+            .ok_or_else(|| format!("Expected !animate <1-1000>, got {hz}"))?;
+        let interval = Duration::from_secs(1) / hz;
+
+        for _ in 0..RUN_LIMIT {
+            print!("\x1b[2J\x1b[H{}", render(&self.vm, self.number_format));
+            io::stdout().flush().expect("Unable to flush to stdout");
+            if let Some(exit) = self.vm.run_once() {
+                print!("\x1b[2J\x1b[H{}", render(&self.vm, self.number_format));
+                self.report_exit(exit);
+                break;
+            }
+            thread::sleep(interval);
+        }
+
+        Ok(())
+    }
+}
+
+// Reads lines until one that is just `terminator`, or the end of input
+fn read_block(terminator: &str) -> String {
+    let mut source = String::new();
+    loop {
+        print!("... ");
+        io::stdout().flush().expect("Unable to flush to stdout");
+
+        let mut line = String::new();
+        let read = io::stdin()
+            .read_line(&mut line)
+            .expect("Unable to read user input");
+        if read == 0 || line.trim() == terminator {
+            return source;
+        }
+        source.push_str(&line);
+    }
+}
+
+// PC, the next instruction, flags and registers, four to a row
+fn render(vm: &VM, format: NumberFormat) -> String {
+    let pc = vm.program_counter();
+    let mut out = String::new();
+    let next = vm.program().get(pc).map_or("end".to_string(), |&byte| {
+        format!("{:?}", Opcode::from(byte))
+    });
+    let _ = writeln!(out, "pc {pc:>6}  next {next}");
+    let _ = writeln!(out, "flags {:#07b}", vm.flags().bits());
+    for (row, registers) in vm.registers().chunks(4).enumerate() {
+        for (column, value) in registers.iter().enumerate() {
+            let _ = write!(
+                out,
+                "${:<2} {:>width$}  ",
+                row * 4 + column,
+                format.format(*value),
+                width = format.width()
+            );
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+// The bytes to look for from `bytes de ad be ef` or `str 'Hello'`
+fn parse_pattern(pattern: &str) -> Result<Vec<u8>, String> {
+    let bytes = match pattern.split_once(' ') {
+        Some(("bytes", hex)) => hex
+            .split_whitespace()
+            .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| format!("Invalid byte: {byte}")))
+            .collect::<Result<Vec<_>, _>>()?,
+        Some(("str", string)) => string
+            .trim()
+            .strip_prefix('\'')
+            .and_then(|string| string.strip_suffix('\''))
+            .ok_or_else(|| format!("Expected a quoted string, got {}", string.trim()))?
+            .as_bytes()
+            .to_vec(),
+        _ => {
+            return Err(format!(
+                "Expected !find bytes <hex...> or !find str '<text>', got {pattern}"
+            ))
+        }
+    };
+    if bytes.is_empty() {
+        return Err("Nothing to find".to_string());
+    }
+
+    Ok(bytes)
+}
+
+// Offsets of every occurrence of `needle`, overlapping ones included
+fn find(haystack: &[u8], needle: &[u8]) -> Vec<usize> {
+    haystack
+        .windows(needle.len())
+        .enumerate()
+        .filter(|(_, window)| *window == needle)
+        .map(|(offset, _)| offset)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::repl::{find, parse_pattern, NumberFormat};
+
+    #[test]
+    fn test_number_formats() {
+        assert_eq!(NumberFormat::Signed.format(-1), "-1");
+        assert_eq!(NumberFormat::Unsigned.format(-1), "4294967295");
+        assert_eq!(NumberFormat::Hex.format(255), "0x000000ff");
+        assert_eq!(NumberFormat::Binary.format(5), format!("0b{:032b}", 5));
+        assert_eq!(NumberFormat::try_from("hex"), Ok(NumberFormat::Hex));
+        assert!(NumberFormat::try_from("octal").is_err());
+    }
+
+    #[test]
+    fn test_find() {
+        assert_eq!(
+            parse_pattern("bytes de ad be ef"),
+            Ok(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+        assert_eq!(parse_pattern("str 'Hi there'"), Ok(b"Hi there".to_vec()));
+        assert!(parse_pattern("bytes zz").is_err());
+        assert!(parse_pattern("str Hello").is_err());
+        assert!(parse_pattern("str ''").is_err());
+        assert!(parse_pattern("words 1 2").is_err());
+
+        assert_eq!(find(&[1, 1, 1, 2, 1, 1], &[1, 1]), vec![0, 1, 4]);
+        assert_eq!(find(&[1, 2], &[1, 2, 3]), Vec::<usize>::new());
     }
 }
@@ -0,0 +1,4 @@
+pub mod auth;
+pub mod http;
+pub mod pool;
+pub mod service;
@@ -0,0 +1,121 @@
+use std::{fs, net::IpAddr, path::Path};
+
+use crate::server::http::Request;
+
+pub const TOKEN_ENV_VAR: &str = "VMARIACHI_TOKEN";
+
+/// Checks the request's `Authorization: Bearer <token>` header against the expected token.
+pub fn is_authorized(request: &Request, token: &str) -> bool {
+    request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| token_matches(given, token))
+}
+
+/// Compares a token sent by a client, ignoring surrounding whitespace, with the expected one.
+pub fn token_matches(given: &str, token: &str) -> bool {
+    constant_time_eq(given.trim().as_bytes(), token.as_bytes())
+}
+
+/// Whether `host`, a name or an IP address optionally in brackets, only accepts local
+/// connections. Anything else needs a token, or an explicit opt out, to be listened on.
+pub fn is_loopback(host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host == "localhost"
+        || host
+            .parse::<IpAddr>()
+            .is_ok_and(|address| address.is_loopback())
+}
+
+pub fn read_token_file(path: &Path) -> Result<String, String> {
+    let token = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read token file {}: {e}", path.display()))?;
+
+    validate_token(token.trim())
+}
+
+pub fn validate_token(token: &str) -> Result<String, String> {
+    if token.len() < 16 {
+        return Err("Authentication tokens must be at least 16 characters long".to_string());
+    }
+
+    Ok(token.to_string())
+}
+
+// Compares every byte so the response time does not leak how much of the token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::{
+        auth::{constant_time_eq, is_authorized, is_loopback, token_matches, validate_token},
+        http::Request,
+    };
+
+    fn request(authorization: Option<&str>) -> Request {
+        Request {
+            method: "GET".to_string(),
+            path: "/programs/1".to_string(),
+            query: Vec::new(),
+            headers: authorization
+                .map(|value| vec![("Authorization".to_string(), value.to_string())])
+                .unwrap_or_default(),
+            body: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_is_authorized() {
+        let token = "0123456789abcdef";
+        assert!(is_authorized(
+            &request(Some("Bearer 0123456789abcdef")),
+            token
+        ));
+        assert!(!is_authorized(
+            &request(Some("Bearer 0123456789abcdeX")),
+            token
+        ));
+        assert!(!is_authorized(
+            &request(Some("Basic 0123456789abcdef")),
+            token
+        ));
+        assert!(!is_authorized(&request(None), token));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches(" 0123456789abcdef\n", "0123456789abcdef"));
+        assert!(!token_matches("0123456789abcdeX", "0123456789abcdef"));
+    }
+
+    #[test]
+    fn test_is_loopback() {
+        assert!(is_loopback("localhost"));
+        assert!(is_loopback("127.0.0.1"));
+        assert!(is_loopback("[::1]"));
+        assert!(!is_loopback("0.0.0.0"));
+        assert!(!is_loopback("example.com"));
+    }
+
+    #[test]
+    fn test_validate_token() {
+        assert!(validate_token("short").is_err());
+        assert_eq!(
+            validate_token("0123456789abcdef").unwrap(),
+            "0123456789abcdef"
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use std::io::{self, BufRead, Read, Write};
+
+use crate::json::Json;
+
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Request, String> {
+        let request_line = read_line(reader)?;
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/") => {
+                (method.to_string(), target)
+            }
+            _ => return Err(format!("Malformed request line: {request_line}")),
+        };
+
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), parse_query(query)),
+            None => (target.to_string(), Vec::new()),
+        };
+
+        let mut headers = Vec::new();
+        loop {
+            let line = read_line(reader)?;
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| format!("Malformed header: {line}"))?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        let mut request = Request {
+            method,
+            path,
+            query,
+            headers,
+            body: Vec::new(),
+        };
+
+        let length = match request.header("content-length") {
+            Some(value) => value
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid Content-Length: {value}"))?,
+            None => 0,
+        };
+        if length > MAX_BODY_SIZE {
+            return Err(format!(
+                "Request body of {length} bytes exceeds the {MAX_BODY_SIZE} bytes limit"
+            ));
+        }
+
+        request.body.resize(length, 0);
+        reader
+            .read_exact(&mut request.body)
+            .map_err(|e| format!("Unable to read request body: {e}"))?;
+
+        Ok(request)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(status: u16, body: Json) -> Self {
+        Self {
+            status,
+            body: body.to_string(),
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        Self::json(status, Json::object([("error", Json::from(message))]))
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            reason_phrase(self.status),
+            self.body.len(),
+            self.body
+        )?;
+        writer.flush()
+    }
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, String> {
+    let mut line = String::new();
+    let read = reader
+        .by_ref()
+        .take(8 * 1024)
+        .read_line(&mut line)
+        .map_err(|e| format!("Unable to read request: {e}"))?;
+    if read == 0 {
+        return Err("Connection closed before the request was complete".to_string());
+    }
+
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => (pair.to_string(), String::new()),
+        })
+        .collect()
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::server::http::{Request, Response, MAX_BODY_SIZE};
+
+    #[test]
+    fn test_read_request() {
+        let raw = "POST /programs?format=asm&fuel=10 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\nhlt";
+        let request = Request::read_from(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/programs");
+        assert_eq!(request.query_param("format"), Some("asm"));
+        assert_eq!(request.query_param("fuel"), Some("10"));
+        assert_eq!(request.header("HOST"), Some("localhost"));
+        assert_eq!(request.body, b"hlt");
+    }
+
+    #[test]
+    fn test_read_request_without_body() {
+        let raw = "GET /programs/1 HTTP/1.1\r\n\r\n";
+        let request = Request::read_from(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/programs/1");
+        assert!(request.query.is_empty());
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn test_read_malformed_request() {
+        assert!(Request::read_from(&mut Cursor::new("garbage\r\n\r\n")).is_err());
+        assert!(Request::read_from(&mut Cursor::new("")).is_err());
+    }
+
+    #[test]
+    fn test_read_request_body_too_large() {
+        let raw = format!(
+            "POST /programs HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        assert!(Request::read_from(&mut Cursor::new(raw)).is_err());
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut output = Vec::new();
+        Response::error(404, "not found")
+            .write_to(&mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 404 Not Found\r\nContent-Type: application/json\r\nContent-Length: 21\r\nConnection: close\r\n\r\n{\"error\":\"not found\"}"
+        );
+    }
+}
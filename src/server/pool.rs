@@ -0,0 +1,146 @@
+use std::{
+    sync::{
+        atomic::AtomicBool,
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    cost::CostModel,
+    server::service::{Limits, Profile},
+};
+
+/// A program waiting to be executed by one of the pool workers.
+#[derive(Debug)]
+pub struct Job {
+    pub id: u64,
+    pub program: Vec<u8>,
+    pub limits: Limits,
+    pub costs: Arc<CostModel>,
+    pub trace: bool,
+    pub cancel: Arc<AtomicBool>,
+    pub profile: Arc<Profile>,
+}
+
+/// A fixed number of worker threads pulling jobs from a bounded queue.
+#[derive(Debug)]
+pub struct WorkerPool {
+    sender: Option<SyncSender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new<F>(size: usize, capacity: usize, runner: F) -> Self
+    where
+        F: Fn(Job) + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let runner = Arc::new(runner);
+
+        let workers = (0..size.max(1))
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                let runner = Arc::clone(&runner);
+                thread::spawn(move || loop {
+                    // The lock is only held while waiting, never while running a job
+                    let job = match receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_) => return,
+                    };
+                    match job {
+                        Ok(job) => runner(job),
+                        Err(_) => return,
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// Queues a job, handing it back if the queue is full.
+    pub fn submit(&self, job: Job) -> Result<(), Job> {
+        let Some(sender) = &self.sender else {
+            return Err(job);
+        };
+
+        sender.try_send(job).map_err(|e| match e {
+            TrySendError::Full(job) | TrySendError::Disconnected(job) => job,
+        })
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Closing the channel lets every worker finish its queue and exit
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc,
+    };
+
+    use crate::server::{
+        pool::{Job, WorkerPool},
+        service::Limits,
+    };
+
+    fn job(id: u64) -> Job {
+        Job {
+            id,
+            program: Vec::new(),
+            limits: Limits::default(),
+            costs: Arc::default(),
+            trace: false,
+            cancel: Arc::new(AtomicBool::new(false)),
+            profile: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn test_pool_runs_every_job() {
+        let total = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&total);
+        let pool = WorkerPool::new(4, 16, move |job| {
+            counter.fetch_add(job.id, Ordering::SeqCst);
+        });
+        for id in 1..=10 {
+            pool.submit(job(id)).unwrap();
+        }
+        drop(pool);
+        assert_eq!(total.load(Ordering::SeqCst), 55);
+    }
+
+    #[test]
+    fn test_pool_rejects_when_full() {
+        let (release, blocked) = mpsc::channel::<()>();
+        let blocked = std::sync::Mutex::new(blocked);
+        let (started_sender, started) = mpsc::channel::<()>();
+        let started_sender = std::sync::Mutex::new(started_sender);
+        let pool = WorkerPool::new(1, 1, move |_| {
+            started_sender.lock().unwrap().send(()).unwrap();
+            blocked.lock().unwrap().recv().unwrap();
+        });
+
+        pool.submit(job(1)).unwrap();
+        started.recv().unwrap();
+        pool.submit(job(2)).unwrap();
+        assert_eq!(pool.submit(job(3)).unwrap_err().id, 3);
+
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+    }
+}
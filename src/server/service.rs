@@ -0,0 +1,1044 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufReader, Read, Write},
+    mem,
+    net::{TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    assembler::assembler::Assembler,
+    cost::CostModel,
+    encoding,
+    instruction::Opcode,
+    json::Json,
+    server::{
+        auth,
+        http::{Request, Response},
+        pool::{Job, WorkerPool},
+    },
+    vm::{TraceEntry, TrapInfo, VM},
+};
+
+pub const DEFAULT_FUEL: u64 = 10_000_000;
+pub const DEFAULT_MEMORY: usize = 16 * 1024 * 1024;
+pub const DEFAULT_WALL_TIME: Duration = Duration::from_secs(5);
+pub const DEFAULT_WORKERS: usize = 4;
+pub const QUEUE_CAPACITY: usize = 64;
+/// Most instructions a trace keeps, whatever the memory limit.
+pub const MAX_TRACE_ENTRIES: usize = 100_000;
+/// How long the result of a finished program can be fetched.
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(10 * 60);
+/// Most finished programs kept at once, the oldest are forgotten first.
+pub const MAX_FINISHED_RUNS: usize = 1024;
+
+/// Connections read at once, each on its own thread. Others are turned away with a 503.
+pub const MAX_CONNECTIONS: usize = 64;
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+// Time a client has to send its whole request, however slowly the bytes arrive
+const REQUEST_DEADLINE: Duration = Duration::from_secs(10);
+
+/// Upper bounds applied to every submitted program. Requests may ask for lower limits.
+/// A recorded trace is also kept within `memory` bytes, and cut short past that. Printed
+/// output is kept within `memory` bytes too, a program printing more traps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub fuel: u64,
+    pub memory: usize,
+    pub wall_time: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            fuel: DEFAULT_FUEL,
+            memory: DEFAULT_MEMORY,
+            wall_time: DEFAULT_WALL_TIME,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RunRecord {
+    status: &'static str,
+    registers: [i32; 32],
+    instructions: u64,
+    fuel_used: u64,
+    heap_size: usize,
+    elapsed: Duration,
+    trap: Option<TrapInfo>,
+    error: Option<String>,
+    trace: Option<Vec<TraceEntry>>,
+    // Whether the program ran more instructions than the trace kept
+    trace_truncated: bool,
+    // What the program printed with PRTS
+    output: String,
+}
+
+#[derive(Debug)]
+enum RunState {
+    Queued,
+    Running,
+    Finished(Box<RunRecord>, Instant),
+}
+
+#[derive(Debug)]
+struct RunEntry {
+    state: RunState,
+    cancel: Arc<AtomicBool>,
+}
+
+type RunTable = Mutex<HashMap<u64, RunEntry>>;
+
+/// Opcodes sampled every `interval` instructions from every program the server runs, a
+/// profile cheap enough to leave on.
+#[derive(Debug)]
+pub struct Profile {
+    // 0 turns sampling off
+    interval: AtomicU64,
+    // Samples by opcode byte
+    samples: Mutex<Vec<u64>>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            interval: AtomicU64::new(0),
+            samples: Mutex::new(vec![0; 256]),
+        }
+    }
+}
+
+impl Profile {
+    fn samples(&self) -> MutexGuard<'_, Vec<u64>> {
+        self.samples.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Starts sampling `vm` at the current interval, programs already running keep theirs
+    fn attach(self: &Arc<Self>, vm: &mut VM) {
+        let profile = Arc::clone(self);
+        vm.set_sampler(self.interval.load(Ordering::Relaxed), move |vm| {
+            let byte = vm.program().get(vm.program_counter()).copied();
+            profile.samples()[byte.unwrap_or_default() as usize] += 1;
+        });
+    }
+
+    fn to_json(&self) -> Json {
+        let samples = self.samples();
+        let opcodes = samples
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(byte, count)| {
+                let name = format!("{:?}", Opcode::from(byte as u8));
+                (name, Json::from(*count))
+            })
+            .collect();
+
+        Json::object([
+            (
+                "interval",
+                Json::from(self.interval.load(Ordering::Relaxed)),
+            ),
+            ("samples", Json::from(samples.iter().sum::<u64>())),
+            ("opcodes", Json::Object(opcodes)),
+        ])
+    }
+}
+
+/// Accepts programs over HTTP and runs each one in a fresh VM on a pool of workers.
+#[derive(Debug)]
+pub struct Server {
+    limits: Limits,
+    runs: Arc<RunTable>,
+    pool: WorkerPool,
+    next_id: u64,
+    token: Option<String>,
+    costs: Arc<CostModel>,
+    profile: Arc<Profile>,
+    retention: Duration,
+}
+
+impl Server {
+    pub fn new(limits: Limits, workers: usize) -> Self {
+        let runs = Arc::new(RunTable::default());
+        let table = Arc::clone(&runs);
+        let pool = WorkerPool::new(workers, QUEUE_CAPACITY, move |job| run_job(&table, job));
+
+        Self {
+            limits,
+            runs,
+            pool,
+            next_id: 1,
+            token: None,
+            costs: Arc::new(CostModel::weighted()),
+            profile: Arc::default(),
+            retention: DEFAULT_RETENTION,
+        }
+    }
+
+    /// How long results stay available once a program finishes. Defaults to
+    /// `DEFAULT_RETENTION`.
+    pub fn set_retention(&mut self, retention: Duration) {
+        self.retention = retention;
+    }
+
+    /// Samples the running opcode every `interval` instructions of every program, for
+    /// `GET /profile`. Off (0) by default; `PUT /profile?interval=N` changes it while the
+    /// server runs.
+    pub fn set_sample_interval(&mut self, interval: u64) {
+        self.profile.interval.store(interval, Ordering::Relaxed);
+    }
+
+    /// Sets how fuel is charged per instruction. Defaults to `CostModel::weighted`.
+    pub fn set_cost_model(&mut self, costs: CostModel) {
+        self.costs = Arc::new(costs);
+    }
+
+    /// Requires every request to carry `Authorization: Bearer <token>`.
+    pub fn set_token(&mut self, token: String) {
+        self.token = Some(token);
+    }
+
+    pub fn listen(&mut self, address: &str) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        println!(">> listening on http://{}", listener.local_addr()?);
+
+        self.serve(listener)
+    }
+
+    /// Answers requests from `listener` forever. Connections are read on their own
+    /// threads, so a slow client only holds up itself, and the requests handled here one
+    /// at a time.
+    pub fn serve(&mut self, listener: TcpListener) -> io::Result<()> {
+        let (sender, requests) = mpsc::channel::<(Request, Sender<Response>)>();
+        thread::spawn(move || {
+            let active = Arc::new(AtomicUsize::new(0));
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(mut stream) if active.load(Ordering::SeqCst) >= MAX_CONNECTIONS => {
+                        let _ = stream.set_write_timeout(Some(READ_TIMEOUT));
+                        let _ = Response::error(503, "Too many connections, try again later")
+                            .write_to(&mut stream);
+                    }
+                    Ok(stream) => {
+                        active.fetch_add(1, Ordering::SeqCst);
+                        let active = Arc::clone(&active);
+                        let sender = sender.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = read_connection(stream, &sender) {
+                                eprintln!("Connection error: {e}");
+                            }
+                            active.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    }
+                    Err(e) => eprintln!("Unable to accept connection: {e}"),
+                }
+            }
+        });
+
+        for (request, reply) in requests {
+            let _ = reply.send(self.handle(&request));
+        }
+
+        Ok(())
+    }
+
+    pub fn handle(&mut self, request: &Request) -> Response {
+        self.evict_finished();
+        if let Some(token) = &self.token {
+            if !auth::is_authorized(request, token) {
+                return Response::error(401, "Missing or invalid authentication token");
+            }
+        }
+
+        let segments: Vec<&str> = request
+            .path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+
+        match (request.method.as_str(), segments.as_slice()) {
+            ("POST", ["programs"]) => self.submit(request),
+            ("GET", ["programs", id]) => {
+                self.find(id, |id, entry| Response::json(200, entry.to_json(id)))
+            }
+            ("DELETE", ["programs", id]) => self.find(id, |id, entry| {
+                entry.cancel.store(true, Ordering::Relaxed);
+                Response::json(202, entry.to_json(id))
+            }),
+            ("GET", ["programs", id, "trace"]) => self.find(id, |id, entry| match &entry.state {
+                RunState::Finished(run, _) => match &run.trace {
+                    Some(trace) => {
+                        Response::json(200, trace_to_json(id, trace, run.trace_truncated))
+                    }
+                    None => Response::error(404, "No trace was recorded for this program"),
+                },
+                _ => Response::error(409, "Program has not finished running"),
+            }),
+            ("GET", ["profile"]) => Response::json(200, self.profile.to_json()),
+            ("PUT", ["profile"]) => match parse_param::<u64>(request, "interval") {
+                Ok(Some(interval)) => {
+                    self.set_sample_interval(interval);
+                    self.profile.samples().fill(0);
+                    Response::json(200, self.profile.to_json())
+                }
+                Ok(None) => Response::error(400, "Missing interval"),
+                Err(e) => Response::error(400, &e),
+            },
+            (_, ["programs"])
+            | (_, ["programs", _])
+            | (_, ["programs", _, "trace"])
+            | (_, ["profile"]) => Response::error(405, "Method not allowed"),
+            _ => Response::error(404, "Not found"),
+        }
+    }
+
+    fn submit(&mut self, request: &Request) -> Response {
+        let limits = match self.request_limits(request) {
+            Ok(limits) => limits,
+            Err(e) => return Response::error(400, &e),
+        };
+
+        let program = match request.query_param("format").unwrap_or("asm") {
+            "asm" => {
+                let Ok(source) = std::str::from_utf8(&request.body) else {
+                    return Response::error(400, "Assembly source must be valid UTF-8");
+                };
+                match Assembler::new().try_assemble(source) {
+                    Ok(bytes) => bytes,
+                    Err(errors) => return Response::error(400, &errors.join("; ")),
+                }
+            }
+            "bytecode" => request.body.clone(),
+            "hex" => match encoding::from_hex(&String::from_utf8_lossy(&request.body)) {
+                Ok(bytes) => bytes,
+                Err(e) => return Response::error(400, &e),
+            },
+            "base64" => match encoding::from_base64(&String::from_utf8_lossy(&request.body)) {
+                Ok(bytes) => bytes,
+                Err(e) => return Response::error(400, &e),
+            },
+            format => return Response::error(400, &format!("Unknown program format: {format}")),
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let entry = RunEntry {
+            state: RunState::Queued,
+            cancel: Arc::clone(&cancel),
+        };
+        let response = Response::json(202, entry.to_json(id));
+        lock(&self.runs).insert(id, entry);
+
+        let job = Job {
+            id,
+            program,
+            limits,
+            costs: Arc::clone(&self.costs),
+            trace: request.query_param("trace") == Some("true"),
+            cancel,
+            profile: Arc::clone(&self.profile),
+        };
+        if self.pool.submit(job).is_err() {
+            lock(&self.runs).remove(&id);
+            return Response::error(503, "Too many programs queued, try again later");
+        }
+
+        response
+    }
+
+    // Forgets finished programs past the retention period, and the oldest beyond
+    // `MAX_FINISHED_RUNS`
+    fn evict_finished(&self) {
+        let mut runs = lock(&self.runs);
+        runs.retain(|_, entry| match entry.state {
+            RunState::Finished(_, at) => at.elapsed() < self.retention,
+            _ => true,
+        });
+
+        let mut finished: Vec<(Instant, u64)> = runs
+            .iter()
+            .filter_map(|(id, entry)| match entry.state {
+                RunState::Finished(_, at) => Some((at, *id)),
+                _ => None,
+            })
+            .collect();
+        if finished.len() > MAX_FINISHED_RUNS {
+            finished.sort_unstable();
+            for (_, id) in &finished[..finished.len() - MAX_FINISHED_RUNS] {
+                runs.remove(id);
+            }
+        }
+    }
+
+    fn find<F>(&self, id: &str, respond: F) -> Response
+    where
+        F: FnOnce(u64, &RunEntry) -> Response,
+    {
+        let runs = lock(&self.runs);
+        match id
+            .parse::<u64>()
+            .ok()
+            .and_then(|id| Some((id, runs.get(&id)?)))
+        {
+            Some((id, entry)) => respond(id, entry),
+            None => Response::error(404, "Unknown program id"),
+        }
+    }
+
+    fn request_limits(&self, request: &Request) -> Result<Limits, String> {
+        let mut limits = self.limits;
+        if let Some(fuel) = parse_param::<u64>(request, "fuel")? {
+            limits.fuel = limits.fuel.min(fuel);
+        }
+        if let Some(memory) = parse_param::<usize>(request, "memory")? {
+            limits.memory = limits.memory.min(memory);
+        }
+        if let Some(timeout) = parse_param::<u64>(request, "timeout_ms")? {
+            limits.wall_time = limits.wall_time.min(Duration::from_millis(timeout));
+        }
+
+        Ok(limits)
+    }
+}
+
+impl RunEntry {
+    fn to_json(&self, id: u64) -> Json {
+        match &self.state {
+            RunState::Queued => Json::object([("id", Json::from(id)), ("status", "queued".into())]),
+            RunState::Running => {
+                Json::object([("id", Json::from(id)), ("status", "running".into())])
+            }
+            RunState::Finished(run, _) => run.to_json(id),
+        }
+    }
+}
+
+impl RunRecord {
+    fn to_json(&self, id: u64) -> Json {
+        Json::object([
+            ("id", Json::from(id)),
+            ("status", Json::from(self.status)),
+            ("registers", Json::from(self.registers.to_vec())),
+            ("instructions", Json::from(self.instructions)),
+            ("fuel_used", Json::from(self.fuel_used)),
+            ("heap_size", Json::from(self.heap_size)),
+            ("elapsed_us", Json::from(self.elapsed.as_micros() as u64)),
+            (
+                "trap",
+                self.trap.map_or(Json::Null, |trap| {
+                    Json::object([
+                        ("pc", Json::from(trap.pc)),
+                        (
+                            "opcode",
+                            trap.opcode
+                                .map_or(Json::Null, |opcode| Json::from(format!("{opcode:?}"))),
+                        ),
+                    ])
+                }),
+            ),
+            ("error", self.error.clone().map_or(Json::Null, Json::from)),
+            ("output", Json::from(self.output.clone())),
+        ])
+    }
+}
+
+// Reads one request within `REQUEST_DEADLINE`, has it handled and writes the answer
+fn read_connection(
+    stream: TcpStream,
+    requests: &Sender<(Request, Sender<Response>)>,
+) -> io::Result<()> {
+    stream.set_write_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(DeadlineReader {
+        stream: stream.try_clone()?,
+        deadline: Instant::now() + REQUEST_DEADLINE,
+    });
+    let response = match Request::read_from(&mut reader) {
+        Ok(request) => {
+            let (reply, answer) = mpsc::channel();
+            requests
+                .send((request, reply))
+                .ok()
+                .and_then(|_| answer.recv().ok())
+                .unwrap_or_else(|| Response::error(503, "The server is shutting down"))
+        }
+        Err(e) => Response::error(400, &e),
+    };
+
+    response.write_to(&mut &stream)
+}
+
+// Fails reads once the deadline passes, however the bytes trickle in
+struct DeadlineReader {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self
+            .deadline
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "request took too long"))?;
+        self.stream
+            .set_read_timeout(Some(remaining.min(READ_TIMEOUT)))?;
+        self.stream.read(buf)
+    }
+}
+
+fn lock(runs: &RunTable) -> MutexGuard<'_, HashMap<u64, RunEntry>> {
+    // Programs run outside of the lock, so a poisoned table is still consistent
+    runs.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn run_job(runs: &RunTable, job: Job) {
+    if let Some(entry) = lock(runs).get_mut(&job.id) {
+        entry.state = RunState::Running;
+    }
+
+    let id = job.id;
+    let run = execute(job);
+
+    if let Some(entry) = lock(runs).get_mut(&id) {
+        entry.state = RunState::Finished(Box::new(run), Instant::now());
+    }
+}
+
+fn parse_param<T: std::str::FromStr>(request: &Request, name: &str) -> Result<Option<T>, String> {
+    request
+        .query_param(name)
+        .map(|value| {
+            value
+                .parse::<T>()
+                .map_err(|_| format!("Invalid value for {name}: {value}"))
+        })
+        .transpose()
+}
+
+fn execute(job: Job) -> RunRecord {
+    let Job {
+        program,
+        limits,
+        costs,
+        trace,
+        cancel,
+        profile,
+        ..
+    } = job;
+
+    let started = Instant::now();
+    let mut vm = VM::new();
+    vm.set_fuel(limits.fuel);
+    vm.set_cost_model(CostModel::clone(&costs));
+    vm.set_heap_limit(limits.memory);
+    vm.set_timeout(limits.wall_time);
+    vm.set_cancel_flag(cancel);
+    if trace {
+        vm.enable_trace();
+        vm.set_trace_limit(trace_limit(limits.memory));
+    }
+    profile.attach(&mut vm);
+    let output = OutputBuffer {
+        bytes: Arc::default(),
+        limit: limits.memory,
+    };
+    vm.set_output(Box::new(output.clone()));
+    vm.load_program(program);
+
+    // A misbehaving program must not take the whole service down with it
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| vm.run())).ok();
+    let printed = mem::take(&mut *lock_output(&output.bytes));
+
+    RunRecord {
+        status: outcome
+            .as_ref()
+            .map_or("crashed", |outcome| outcome.exit.as_str()),
+        registers: *vm.registers(),
+        instructions: outcome.as_ref().map_or(0, |outcome| outcome.instructions),
+        fuel_used: outcome.as_ref().map_or_else(
+            || limits.fuel - vm.fuel().unwrap_or(0),
+            |outcome| outcome.fuel_used,
+        ),
+        heap_size: vm.heap_size(),
+        elapsed: outcome
+            .as_ref()
+            .map_or_else(|| started.elapsed(), |outcome| outcome.duration),
+        trap: outcome.as_ref().and_then(|outcome| outcome.trap),
+        trace_truncated: outcome
+            .as_ref()
+            .is_some_and(|outcome| outcome.instructions > vm.trace().len() as u64),
+        error: outcome.and_then(|outcome| outcome.error.map(|error| error.to_string())),
+        trace: trace.then(|| vm.trace().to_vec()),
+        output: String::from_utf8_lossy(&printed).into_owned(),
+    }
+}
+
+// Collects what a program prints, failing writes past `limit` bytes
+#[derive(Debug, Clone)]
+struct OutputBuffer {
+    bytes: Arc<Mutex<Vec<u8>>>,
+    limit: usize,
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut bytes = lock_output(&self.bytes);
+        if bytes.len() + buf.len() > self.limit {
+            return Err(io::Error::other(format!(
+                "output exceeds the limit of {} bytes",
+                self.limit
+            )));
+        }
+        bytes.extend_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn lock_output(bytes: &Mutex<Vec<u8>>) -> MutexGuard<'_, Vec<u8>> {
+    // Plain bytes, so a buffer left behind by a crashed run is still usable
+    bytes.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Entries of a trace fitting in `memory` bytes, up to `MAX_TRACE_ENTRIES`
+fn trace_limit(memory: usize) -> usize {
+    (memory / mem::size_of::<TraceEntry>()).min(MAX_TRACE_ENTRIES)
+}
+
+fn trace_to_json(id: u64, trace: &[TraceEntry], truncated: bool) -> Json {
+    let entries = trace
+        .iter()
+        .map(|entry| {
+            Json::object([
+                ("pc", Json::from(entry.pc)),
+                ("opcode", Json::from(format!("{:?}", entry.opcode))),
+            ])
+        })
+        .collect();
+
+    Json::object([
+        ("id", Json::from(id)),
+        ("truncated", Json::from(truncated)),
+        ("trace", Json::Array(entries)),
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        cost::CostModel,
+        encoding,
+        server::{
+            http::{Request, Response},
+            service::{Limits, Server},
+        },
+    };
+
+    // LOAD $0 #64, JMP $0: jumps back to the first instruction forever
+    const INFINITE_LOOP: &[u8] = b"load $0 #64\njmp $0";
+
+    fn request(method: &str, target: &str, body: &[u8]) -> Request {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            headers: Vec::new(),
+            body: body.to_vec(),
+        }
+    }
+
+    fn unlimited() -> Limits {
+        Limits {
+            fuel: u64::MAX,
+            memory: 1024,
+            wall_time: Duration::from_secs(60),
+        }
+    }
+
+    fn wait(server: &mut Server, id: u64) -> Response {
+        let started = Instant::now();
+        loop {
+            let response = server.handle(&request("GET", &format!("/programs/{id}"), b""));
+            if !response.body.contains(r#""status":"queued""#)
+                && !response.body.contains(r#""status":"running""#)
+            {
+                return response;
+            }
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "program never finished"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn run(server: &mut Server, target: &str, body: &[u8]) -> Response {
+        let submitted = server.handle(&request("POST", target, body));
+        assert_eq!(submitted.status, 202, "{}", submitted.body);
+        let id = submitted.body[6..submitted.body.find(',').unwrap()]
+            .parse()
+            .unwrap();
+        wait(server, id)
+    }
+
+    #[test]
+    fn test_submit_is_queued() {
+        let mut server = Server::new(Limits::default(), 1);
+        assert_eq!(
+            server.handle(&request("POST", "/programs", b"hlt")),
+            Response {
+                status: 202,
+                body: r#"{"id":1,"status":"queued"}"#.to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_submit_assembly() {
+        let mut server = Server::new(Limits::default(), 1);
+        let response = run(
+            &mut server,
+            "/programs",
+            b"load $0 #100\nload $1 #5\nadd $0 $1 $2\nhlt",
+        );
+        assert_eq!(response.status, 200);
+        assert!(response
+            .body
+            .starts_with(r#"{"id":1,"status":"halted","registers":[100,5,105,0,"#));
+        assert!(response.body.contains(r#""fuel_used":4"#));
+    }
+
+    #[test]
+    fn test_submit_bytecode() {
+        let mut server = Server::new(Limits::default(), 1);
+        let mut program = vec![45, 50, 49, 45];
+        program.resize(64, 0);
+        program.extend_from_slice(&[18, 3, 0, 0]); // INC $3
+        let response = run(&mut server, "/programs?format=bytecode", &program);
+        assert!(response
+            .body
+            .contains(r#""status":"end_of_program","registers":[0,0,0,1,"#));
+    }
+
+    #[test]
+    fn test_submit_textual_bytecode() {
+        let mut program = vec![45, 50, 49, 45];
+        program.resize(64, 0);
+        program.extend_from_slice(&[18, 3, 0, 0]); // INC $3
+
+        let mut server = Server::new(Limits::default(), 1);
+        let hex = encoding::to_hex(&program);
+        let response = run(&mut server, "/programs?format=hex", hex.as_bytes());
+        assert!(response.body.contains(r#""registers":[0,0,0,1,"#));
+        let base64 = encoding::to_base64(&program);
+        let response = run(&mut server, "/programs?format=base64", base64.as_bytes());
+        assert!(response.body.contains(r#""registers":[0,0,0,1,"#));
+
+        let response = server.handle(&request("POST", "/programs?format=hex", b"abc"));
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn test_submit_invalid_header() {
+        let mut server = Server::new(Limits::default(), 1);
+        let response = run(&mut server, "/programs?format=bytecode", &[5, 0, 0, 0]);
+        assert!(response.body.contains(r#""status":"invalid_header""#));
+    }
+
+    #[test]
+    fn test_submit_with_fuel_limit() {
+        let mut server = Server::new(Limits::default(), 1);
+        let response = run(
+            &mut server,
+            "/programs?fuel=2",
+            b"inc $0\ninc $0\ninc $0\nhlt",
+        );
+        assert!(response
+            .body
+            .contains(r#""status":"out_of_fuel","registers":[2,"#));
+        assert!(response.body.contains(r#""instructions":2,"fuel_used":2"#));
+        assert!(response.body.contains(r#""trap":{"pc":72,"opcode":"INC"}"#));
+        assert!(response.body.contains(r#""error":"Out of fuel""#));
+    }
+
+    #[test]
+    fn test_submit_with_memory_limit() {
+        let mut server = Server::new(Limits::default(), 1);
+        let response = run(
+            &mut server,
+            "/programs?memory=100",
+            b"load $0 #101\naloc $0\nhlt",
+        );
+        assert!(response.body.contains(r#""status":"heap_limit_exceeded""#));
+    }
+
+    #[test]
+    fn test_output_is_captured() {
+        let mut server = Server::new(Limits::default(), 1);
+        let program = b"hi: .asciiz 'hi'\nprts @hi\nprts @hi\nhlt";
+        let response = run(&mut server, "/programs", program);
+        assert!(response.body.contains(r#""status":"halted""#));
+        assert!(response.body.contains(r#""output":"hihi""#));
+
+        let response = run(&mut server, "/programs?memory=3", program);
+        assert!(response.body.contains(r#""status":"output_failed""#));
+        assert!(response
+            .body
+            .contains(r#""error":"Unable to write output: output exceeds the limit of 3 bytes""#));
+        assert!(response.body.contains(r#""output":"hi""#));
+    }
+
+    #[test]
+    fn test_fuel_uses_cost_model() {
+        let mut server = Server::new(Limits::default(), 1);
+        let response = run(&mut server, "/programs", b"load $1 #1\ndiv $0 $1 $2\nhlt");
+        assert!(response.body.contains(r#""fuel_used":12"#));
+
+        server.set_cost_model(CostModel::default());
+        let response = run(&mut server, "/programs", b"load $1 #1\ndiv $0 $1 $2\nhlt");
+        assert!(response.body.contains(r#""fuel_used":3"#));
+    }
+
+    #[test]
+    fn test_submit_with_wall_time_limit() {
+        let mut server = Server::new(unlimited(), 1);
+        let response = run(&mut server, "/programs?timeout_ms=10", INFINITE_LOOP);
+        assert!(response.body.contains(r#""status":"timeout""#));
+    }
+
+    #[test]
+    fn test_request_cannot_raise_limits() {
+        let server = Server::new(
+            Limits {
+                fuel: 10,
+                memory: 10,
+                wall_time: Duration::from_millis(10),
+            },
+            1,
+        );
+        let limits = server
+            .request_limits(&request(
+                "POST",
+                "/programs?fuel=100&memory=100&timeout_ms=100",
+                b"",
+            ))
+            .unwrap();
+        assert_eq!(limits.fuel, 10);
+        assert_eq!(limits.memory, 10);
+        assert_eq!(limits.wall_time, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_submit_invalid_params() {
+        let mut server = Server::new(Limits::default(), 1);
+        assert_eq!(
+            server.handle(&request("POST", "/programs?fuel=abc", b"hlt")),
+            Response::error(400, "Invalid value for fuel: abc")
+        );
+        assert_eq!(
+            server.handle(&request("POST", "/programs?format=elf", b"hlt")),
+            Response::error(400, "Unknown program format: elf")
+        );
+    }
+
+    #[test]
+    fn test_profile() {
+        let mut server = Server::new(Limits::default(), 1);
+        run(&mut server, "/programs", b"inc $0\ninc $0\nhlt");
+        assert_eq!(
+            server.handle(&request("GET", "/profile", b"")).body,
+            r#"{"interval":0,"samples":0,"opcodes":{}}"#
+        );
+
+        let response = server.handle(&request("PUT", "/profile?interval=2", b""));
+        assert_eq!(response.status, 200);
+        // Samples the second INC of each run
+        run(&mut server, "/programs", b"inc $0\ninc $0\nhlt");
+        run(&mut server, "/programs", b"inc $0\ninc $0\nhlt");
+        assert_eq!(
+            server.handle(&request("GET", "/profile", b"")).body,
+            r#"{"interval":2,"samples":2,"opcodes":{"INC":2}}"#
+        );
+
+        assert_eq!(server.handle(&request("PUT", "/profile", b"")).status, 400);
+        assert_eq!(server.handle(&request("POST", "/profile", b"")).status, 405);
+    }
+
+    #[test]
+    fn test_submit_trapping_program() {
+        let mut server = Server::new(Limits::default(), 1);
+        let response = run(&mut server, "/programs", b"div $0 $1 $2\nhlt");
+        assert!(response.body.contains(r#""status":"division_by_zero""#));
+
+        // the worker survives and keeps serving programs
+        let response = run(&mut server, "/programs", b"hlt");
+        assert!(response.body.contains(r#""status":"halted""#));
+    }
+
+    #[test]
+    fn test_cancel_program() {
+        let mut server = Server::new(unlimited(), 1);
+        server.handle(&request("POST", "/programs", INFINITE_LOOP));
+        let cancelled = server.handle(&request("DELETE", "/programs/1", b""));
+        assert_eq!(cancelled.status, 202);
+        assert!(wait(&mut server, 1)
+            .body
+            .contains(r#""status":"cancelled""#));
+    }
+
+    #[test]
+    fn test_hostile_program_does_not_starve_others() {
+        let mut server = Server::new(unlimited(), 2);
+        server.handle(&request("POST", "/programs", INFINITE_LOOP));
+        let response = run(&mut server, "/programs", b"inc $0\nhlt");
+        assert!(response.body.contains(r#""status":"halted""#));
+        assert!(server
+            .handle(&request("GET", "/programs/1", b""))
+            .body
+            .contains(r#""status":"running""#));
+        server.handle(&request("DELETE", "/programs/1", b""));
+    }
+
+    #[test]
+    fn test_full_queue_is_rejected() {
+        let mut server = Server::new(unlimited(), 1);
+        server.handle(&request("POST", "/programs", INFINITE_LOOP));
+        while !server
+            .handle(&request("GET", "/programs/1", b""))
+            .body
+            .contains(r#""status":"running""#)
+        {
+            thread::sleep(Duration::from_millis(1));
+        }
+        for _ in 0..super::QUEUE_CAPACITY {
+            let queued = server.handle(&request("POST", "/programs", INFINITE_LOOP));
+            assert_eq!(queued.status, 202);
+        }
+        let rejected = server.handle(&request("POST", "/programs", INFINITE_LOOP));
+        assert_eq!(rejected.status, 503);
+
+        for id in 1..=super::QUEUE_CAPACITY + 2 {
+            server.handle(&request("DELETE", &format!("/programs/{id}"), b""));
+        }
+    }
+
+    #[test]
+    fn test_fetch_unknown_program() {
+        let mut server = Server::new(Limits::default(), 1);
+        assert_eq!(
+            server.handle(&request("GET", "/programs/2", b"")),
+            Response::error(404, "Unknown program id")
+        );
+    }
+
+    #[test]
+    fn test_finished_runs_are_evicted() {
+        let mut server = Server::new(Limits::default(), 1);
+        server.set_retention(Duration::ZERO);
+        let response = run(&mut server, "/programs", b"hlt");
+        assert_eq!(response, Response::error(404, "Unknown program id"));
+    }
+
+    #[test]
+    fn test_fetch_trace() {
+        let mut server = Server::new(Limits::default(), 1);
+        run(&mut server, "/programs?trace=true", b"inc $0\nhlt");
+        run(&mut server, "/programs", b"inc $0\nhlt");
+        assert_eq!(
+            server
+                .handle(&request("GET", "/programs/1/trace", b""))
+                .body,
+            r#"{"id":1,"truncated":false,"trace":[{"pc":64,"opcode":"INC"},{"pc":68,"opcode":"HLT"}]}"#
+        );
+        assert_eq!(
+            server.handle(&request("GET", "/programs/2/trace", b"")),
+            Response::error(404, "No trace was recorded for this program")
+        );
+
+        // Two entries fit in 32 bytes
+        run(
+            &mut server,
+            "/programs?trace=true&memory=32",
+            b"inc $0\ninc $0\ninc $0\nhlt",
+        );
+        let body = server
+            .handle(&request("GET", "/programs/3/trace", b""))
+            .body;
+        assert!(body.starts_with(r#"{"id":3,"truncated":true,"#), "{body}");
+        assert_eq!(body.matches("\"pc\"").count(), 2);
+    }
+
+    #[test]
+    fn test_token_authentication() {
+        let mut server = Server::new(Limits::default(), 1);
+        server.set_token("0123456789abcdef".to_string());
+
+        assert_eq!(
+            server.handle(&request("POST", "/programs", b"hlt")),
+            Response::error(401, "Missing or invalid authentication token")
+        );
+
+        let mut authorized = request("POST", "/programs", b"hlt");
+        authorized.headers.push((
+            "Authorization".to_string(),
+            "Bearer 0123456789abcdef".to_string(),
+        ));
+        assert_eq!(server.handle(&authorized).status, 202);
+    }
+
+    #[test]
+    fn test_unknown_routes() {
+        let mut server = Server::new(Limits::default(), 1);
+        assert_eq!(server.handle(&request("GET", "/", b"")).status, 404);
+        assert_eq!(server.handle(&request("PUT", "/programs", b"")).status, 405);
+    }
+
+    #[test]
+    fn test_slow_client_does_not_block_others() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || Server::new(Limits::default(), 1).serve(listener));
+
+        let mut slow = TcpStream::connect(address).unwrap();
+        slow.write_all(b"G").unwrap();
+
+        let started = Instant::now();
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .write_all(b"GET /profile HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(started.elapsed() < Duration::from_secs(2));
+        drop(slow);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::{
+    instruction::{Opcode, OpcodeInfo},
+    vm::TraceEntry,
+};
+
+/// Renders a trace as text, one `<pc> <mnemonic>` line per executed instruction.
+pub fn to_text(trace: &[TraceEntry]) -> String {
+    trace.iter().map(|entry| line(entry) + "\n").collect()
+}
+
+/// Reads a trace written by `to_text`.
+pub fn parse(source: &str) -> Result<Vec<TraceEntry>, String> {
+    source
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            let error = || format!("line {}: expected <pc> <mnemonic>, got {line}", number + 1);
+            let (pc, name) = line.trim().split_once(' ').ok_or_else(error)?;
+            let pc = pc.parse().map_err(|_| error())?;
+            let opcode = match name.trim() {
+                "igl" => Opcode::IGL,
+                name => OpcodeInfo::lookup(name).ok_or_else(error)?.opcode,
+            };
+
+            Ok(TraceEntry { pc, opcode })
+        })
+        .collect()
+}
+
+/// Index of the first instruction where the traces differ, `None` if they are identical.
+/// When one trace is a prefix of the other, they diverge where the shorter one ends.
+pub fn first_divergence(run: &[TraceEntry], golden: &[TraceEntry]) -> Option<usize> {
+    run.iter()
+        .zip(golden)
+        .position(|(a, b)| a != b)
+        .or_else(|| (run.len() != golden.len()).then(|| run.len().min(golden.len())))
+}
+
+/// Describes where `run` departs from `golden`, with up to `context` instructions either
+/// side, in a unified diff style.
+pub fn diff(run: &[TraceEntry], golden: &[TraceEntry], context: usize) -> Option<String> {
+    let index = first_divergence(run, golden)?;
+    let mut out = format!("Traces diverge at instruction {index}\n");
+    for entry in &golden[index.saturating_sub(context)..index] {
+        out.push_str(&format!("  {}\n", line(entry)));
+    }
+    for (marker, trace) in [('-', golden), ('+', run)] {
+        for entry in trace.iter().skip(index).take(context + 1) {
+            out.push_str(&format!("{marker} {}\n", line(entry)));
+        }
+    }
+
+    Some(out)
+}
+
+fn line(entry: &TraceEntry) -> String {
+    format!("{} {}", entry.pc, mnemonic(entry.opcode))
+}
+
+fn mnemonic(opcode: Opcode) -> &'static str {
+    opcode.info().map_or("igl", |info| info.mnemonic)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        instruction::Opcode,
+        trace::{diff, first_divergence, parse, to_text},
+        vm::TraceEntry,
+    };
+
+    fn trace(entries: &[(usize, Opcode)]) -> Vec<TraceEntry> {
+        entries
+            .iter()
+            .map(|&(pc, opcode)| TraceEntry { pc, opcode })
+            .collect()
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let entries = trace(&[(64, Opcode::LOAD), (68, Opcode::JMP), (0, Opcode::IGL)]);
+        let text = to_text(&entries);
+        assert_eq!(text, "64 load\n68 jmp\n0 igl\n");
+        assert_eq!(parse(&text), Ok(entries));
+        assert!(parse("64").is_err());
+        assert!(parse("x load").is_err());
+        assert!(parse("64 nope").is_err());
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let golden = trace(&[(64, Opcode::LOAD), (68, Opcode::INC), (72, Opcode::HLT)]);
+        assert_eq!(first_divergence(&golden, &golden), None);
+        assert_eq!(first_divergence(&golden[..2], &golden), Some(2));
+
+        let run = trace(&[(64, Opcode::LOAD), (68, Opcode::DEC), (72, Opcode::HLT)]);
+        assert_eq!(first_divergence(&run, &golden), Some(1));
+        assert_eq!(
+            diff(&run, &golden, 1).unwrap(),
+            "Traces diverge at instruction 1\n  64 load\n- 68 inc\n- 72 hlt\n+ 68 dec\n+ 72 hlt\n"
+        );
+        assert_eq!(diff(&golden, &golden, 3), None);
+    }
+}
@@ -0,0 +1,260 @@
+use std::io::{self, Write};
+
+use crate::{assembler::parser::Program, vm::VM};
+
+const LESSONS: &str = include_str!("tutorial/lessons.toml");
+
+/// Upper bound on instructions executed for one line of input, in case it loops.
+const STEP_LIMIT: u64 = 100_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lesson {
+    pub title: String,
+    pub task: String,
+    pub hint: Option<String>,
+    pub solution: String,
+    /// Registers set before the lesson starts.
+    pub setup: Vec<(usize, i32)>,
+    /// Registers that must hold these values for the lesson to be complete.
+    pub expect: Vec<(usize, i32)>,
+}
+
+impl Lesson {
+    /// A VM with the lesson's starting state.
+    pub fn prepare(&self) -> VM {
+        let mut vm = VM::new();
+        for &(idx, value) in &self.setup {
+            vm.set_register(idx, value)
+                .expect("lesson registers are validated when parsed");
+        }
+
+        vm
+    }
+
+    pub fn is_complete(&self, vm: &VM) -> bool {
+        self.expect
+            .iter()
+            .all(|&(idx, value)| vm.register(idx) == Some(value))
+    }
+}
+
+/// The lessons shipped with the binary.
+pub fn lessons() -> Vec<Lesson> {
+    parse_lessons(LESSONS).expect("embedded lessons are valid")
+}
+
+/// Parses the subset of TOML the lessons file uses: `[[lesson]]` tables of string keys.
+pub fn parse_lessons(source: &str) -> Result<Vec<Lesson>, String> {
+    let mut tables: Vec<Vec<(String, String)>> = Vec::new();
+
+    for (number, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line == "[[lesson]]" {
+            tables.push(Vec::new());
+            continue;
+        }
+
+        let error = |message: &str| format!("line {}: {message}", number + 1);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected key = \"value\""))?;
+        let table = tables
+            .last_mut()
+            .ok_or_else(|| error("key outside of a [[lesson]] table"))?;
+        let value = parse_string(value.trim()).map_err(|e| error(&e))?;
+        table.push((key.trim().to_string(), value));
+    }
+
+    tables
+        .into_iter()
+        .enumerate()
+        .map(|(index, table)| to_lesson(table).map_err(|e| format!("lesson {}: {e}", index + 1)))
+        .collect()
+}
+
+fn to_lesson(table: Vec<(String, String)>) -> Result<Lesson, String> {
+    let mut title = None;
+    let mut task = None;
+    let mut hint = None;
+    let mut solution = None;
+    let mut setup = Vec::new();
+    let mut expect = None;
+
+    for (key, value) in table {
+        match key.as_str() {
+            "title" => title = Some(value),
+            "task" => task = Some(value),
+            "hint" => hint = Some(value),
+            "solution" => solution = Some(value),
+            "setup" => setup = parse_registers(&value)?,
+            "expect" => expect = Some(parse_registers(&value)?),
+            _ => return Err(format!("unknown key {key}")),
+        }
+    }
+
+    Ok(Lesson {
+        title: title.ok_or("missing title")?,
+        task: task.ok_or("missing task")?,
+        hint,
+        solution: solution.ok_or("missing solution")?,
+        setup,
+        expect: expect.ok_or("missing expect")?,
+    })
+}
+
+// A basic TOML string: double quoted, with \n, \" and \\ escapes
+fn parse_string(value: &str) -> Result<String, String> {
+    let inner = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a quoted string, got {value}"))?;
+
+    let mut string = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => string.push('\n'),
+            Some('"') => string.push('"'),
+            Some('\\') => string.push('\\'),
+            other => return Err(format!("unsupported escape \\{}", other.unwrap_or(' '))),
+        }
+    }
+
+    Ok(string)
+}
+
+// "$1 = 10, $2 = -3"
+fn parse_registers(value: &str) -> Result<Vec<(usize, i32)>, String> {
+    value
+        .split(',')
+        .map(|assignment| {
+            let (register, number) = assignment
+                .split_once('=')
+                .ok_or_else(|| format!("expected $register = value, got {assignment}"))?;
+            let idx = register
+                .trim()
+                .strip_prefix('$')
+                .and_then(|idx| idx.parse::<usize>().ok())
+                .filter(|&idx| idx < 32)
+                .ok_or_else(|| format!("invalid register: {}", register.trim()))?;
+            let number = number
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid value: {}", number.trim()))?;
+
+            Ok((idx, number))
+        })
+        .collect()
+}
+
+/// Assembles `source`, appends it to the VM's program and runs it.
+pub fn execute(vm: &mut VM, source: &str) -> Result<(), String> {
+    let (_, program) = Program::parse(source).map_err(|e| e.to_string())?;
+    vm.add_program(program.to_bytes()?);
+
+    for _ in 0..STEP_LIMIT {
+        if vm.program_counter() >= vm.program().len() || vm.run_once().is_some() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Walks through the lessons, reading instructions from stdin.
+pub fn run() {
+    let lessons = lessons();
+    println!("Welcome to the VMariachi tutorial!");
+    println!("Type instructions to solve each task. !hint, !solution, !reset, !skip and !quit also work.");
+
+    for (number, lesson) in lessons.iter().enumerate() {
+        println!();
+        println!(
+            "Lesson {} of {}: {}",
+            number + 1,
+            lessons.len(),
+            lesson.title
+        );
+        println!("{}", lesson.task);
+
+        let mut vm = lesson.prepare();
+        while !lesson.is_complete(&vm) {
+            print!("tutorial> ");
+            io::stdout().flush().expect("Unable to flush to stdout");
+
+            let mut input = String::new();
+            if io::stdin()
+                .read_line(&mut input)
+                .expect("Unable to read user input")
+                == 0
+            {
+                return;
+            }
+
+            match input.trim() {
+                "" => {}
+                "!hint" => println!("{}", lesson.hint.as_deref().unwrap_or("No hint, sorry!")),
+                "!solution" => println!("{}", lesson.solution),
+                "!reset" => vm = lesson.prepare(),
+                "!skip" => break,
+                "!quit" => return,
+                line => {
+                    if let Err(e) = execute(&mut vm, line) {
+                        eprintln!("{e}");
+                    }
+                }
+            }
+        }
+
+        if lesson.is_complete(&vm) {
+            println!("Well done!");
+        }
+    }
+
+    println!();
+    println!("That was the last lesson, you are ready for the REPL.");
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tutorial::{execute, lessons, parse_lessons};
+
+    #[test]
+    fn test_lesson_solutions() {
+        for lesson in lessons() {
+            let mut vm = lesson.prepare();
+            assert!(!lesson.is_complete(&vm), "{}", lesson.title);
+            execute(&mut vm, &lesson.solution).unwrap();
+            assert!(lesson.is_complete(&vm), "{}", lesson.title);
+        }
+    }
+
+    #[test]
+    fn test_parse_lessons() {
+        let source = "# comment\n[[lesson]]\ntitle = \"One\"\ntask = \"Say \\\"hi\\\"\"\nsolution = \"inc $0\\ninc $0\"\nexpect = \"$0 = 2, $31 = -1\"\n";
+        let lessons = parse_lessons(source).unwrap();
+        assert_eq!(lessons.len(), 1);
+        assert_eq!(lessons[0].task, "Say \"hi\"");
+        assert_eq!(lessons[0].solution, "inc $0\ninc $0");
+        assert_eq!(lessons[0].hint, None);
+        assert_eq!(lessons[0].expect, vec![(0, 2), (31, -1)]);
+    }
+
+    #[test]
+    fn test_parse_lessons_errors() {
+        assert!(parse_lessons("title = \"orphan\"").is_err());
+        assert!(parse_lessons("[[lesson]]\ntitle = unquoted").is_err());
+        assert!(parse_lessons("[[lesson]]\ntitle = \"no task\"").is_err());
+        assert!(parse_lessons(
+            "[[lesson]]\ntitle = \"t\"\ntask = \"t\"\nsolution = \"hlt\"\nexpect = \"$32 = 1\""
+        )
+        .is_err());
+    }
+}
@@ -1,15 +1,384 @@
-use std::usize;
+use std::{
+    fmt,
+    io::{self, Write},
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::{assembler::assembler::PIE_HEADER_PREFIX, instruction::Opcode};
+use crate::{
+    assembler::container::{self, code_section, SectionKind},
+    cost::CostModel,
+    instruction::{Format, Opcode, OperandKind},
+    json::Json,
+    marshal::GuestSerialize,
+};
+
+/// Number of consecutive registers VADD and VMUL operate on.
+pub const VECTOR_WIDTH: usize = 4;
+
+/// Values the stack holds unless changed with `VM::set_stack_limit`.
+pub const DEFAULT_STACK_LIMIT: usize = 1024;
+
+/// RDPERF counter for instructions executed so far, including the RDPERF itself.
+pub const PERF_INSTRUCTIONS: u16 = 0;
+/// RDPERF counter for jumps and branches that changed the program counter.
+pub const PERF_BRANCHES: u16 = 1;
+
+// Instructions between two reads of the clock for the timeout
+const CLOCK_INTERVAL: u64 = 1024;
 
 #[derive(Debug, Default)]
 pub struct VM {
-    pub registers: [i32; 32],
-    pub program: Vec<u8>,
+    registers: [i32; 32],
+    program: Vec<u8>,
     program_counter: usize,
     heap: Vec<u8>,
+    constants: Vec<i32>,
+    // Read-only data, where PRTS finds its strings
+    rodata: Vec<u8>,
+    // The stack pointer is its length
+    stack: Vec<i32>,
+    stack_limit: usize,
     remainder: u32,
-    equal_flag: bool,
+    flags: Flags,
+    fuel: Option<u64>,
+    costs: CostModel,
+    cycle_costs: CostModel,
+    cycles: u64,
+    heap_limit: Option<usize>,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    trace: Option<Vec<TraceEntry>>,
+    trace_limit: usize,
+    cancel: Option<Arc<AtomicBool>>,
+    // The code section once `start` has read the section table
+    code: Option<Range<usize>>,
+    // Instruction encoding, from the header
+    format: Format,
+    instructions: u64,
+    branches_taken: u64,
+    fuel_used: u64,
+    hooks: ExitHooks,
+    sampler: Option<Sampler>,
+    output: Output,
+    // Why the last trap stopped the program
+    error: Option<VmError>,
+    yield_to: Option<i32>,
+}
+
+/// Callback run with the final VM state when a program stops.
+pub type ExitHook = Box<dyn FnMut(&VM, ExitReason) + Send>;
+
+#[derive(Default)]
+struct ExitHooks {
+    halt: Vec<ExitHook>,
+    trap: Vec<ExitHook>,
+    out_of_fuel: Vec<ExitHook>,
+}
+
+impl fmt::Debug for ExitHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExitHooks")
+            .field("halt", &self.halt.len())
+            .field("trap", &self.trap.len())
+            .field("out_of_fuel", &self.out_of_fuel.len())
+            .finish()
+    }
+}
+
+// Where PRTS writes, stdout unless changed with `VM::set_output`
+struct Output(Box<dyn Write + Send>);
+
+impl Default for Output {
+    fn default() -> Self {
+        Output(Box::new(io::stdout()))
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Output")
+    }
+}
+
+/// Callback run with the VM state by a sampling profiler, see `VM::set_sampler`.
+pub type SampleHook = Box<dyn FnMut(&VM) + Send>;
+
+struct Sampler {
+    interval: u64,
+    // Instructions left until the next sample
+    countdown: u64,
+    hook: SampleHook,
+}
+
+impl fmt::Debug for Sampler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sampler")
+            .field("interval", &self.interval)
+            .field("countdown", &self.countdown)
+            .finish()
+    }
+}
+
+/// Why the VM stopped executing a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    Halted,
+    EndOfProgram,
+    IllegalOpcode,
+    OutOfFuel,
+    HeapLimitExceeded,
+    Timeout,
+    Cancelled,
+    InvalidHeader,
+    /// LOADC referenced an index past the end of the constant pool.
+    InvalidConstant,
+    /// PUSH or CALL on a full stack.
+    StackOverflow,
+    /// POP or RET on an empty stack.
+    StackUnderflow,
+    /// A heap load or store addressed bytes outside the heap.
+    HeapOutOfBounds,
+    /// An instruction ran past the end of the code, or a jump, call or return left it.
+    CodeOutOfBounds,
+    /// ALOC with a negative size, or one that would overflow the heap size.
+    InvalidAllocation,
+    /// PRTS referenced an offset past the end of the read-only data.
+    InvalidString,
+    /// PRTS could not write to the output.
+    OutputFailed,
+    /// DIV or MOD with a zero divisor.
+    DivisionByZero,
+    /// An operand named a register past the last one.
+    InvalidRegister,
+}
+
+impl ExitReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExitReason::Halted => "halted",
+            ExitReason::EndOfProgram => "end_of_program",
+            ExitReason::IllegalOpcode => "illegal_opcode",
+            ExitReason::OutOfFuel => "out_of_fuel",
+            ExitReason::HeapLimitExceeded => "heap_limit_exceeded",
+            ExitReason::Timeout => "timeout",
+            ExitReason::Cancelled => "cancelled",
+            ExitReason::InvalidHeader => "invalid_header",
+            ExitReason::InvalidConstant => "invalid_constant",
+            ExitReason::StackOverflow => "stack_overflow",
+            ExitReason::StackUnderflow => "stack_underflow",
+            ExitReason::HeapOutOfBounds => "heap_out_of_bounds",
+            ExitReason::CodeOutOfBounds => "code_out_of_bounds",
+            ExitReason::InvalidAllocation => "invalid_allocation",
+            ExitReason::InvalidString => "invalid_string",
+            ExitReason::OutputFailed => "output_failed",
+            ExitReason::DivisionByZero => "division_by_zero",
+            ExitReason::InvalidRegister => "invalid_register",
+        }
+    }
+
+    /// Whether the program was stopped by the VM rather than finishing on its own.
+    pub fn is_trap(&self) -> bool {
+        !matches!(self, ExitReason::Halted | ExitReason::EndOfProgram)
+    }
+}
+
+/// What stopped a program that trapped, with the details to report or act on it. Each
+/// maps to a trap `ExitReason`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    /// The program could not be started: a bad header or section table, or a BSS larger
+    /// than the heap limit.
+    InvalidHeader(String),
+    /// The byte at the program counter is not an opcode.
+    IllegalOpcode(u8),
+    OutOfFuel,
+    /// ALOC would have grown the heap to `requested` bytes.
+    HeapLimitExceeded {
+        requested: usize,
+        limit: usize,
+    },
+    /// ALOC was asked for `bytes`, which cannot be added to the heap.
+    InvalidAllocation(i32),
+    Timeout,
+    Cancelled,
+    InvalidConstant,
+    StackOverflow,
+    StackUnderflow,
+    /// An access of `len` bytes at `address` reached outside `region`. For the code, the
+    /// address is the pc of the instruction being fetched or the target of a jump, or the
+    /// pc of a relative jump whose target overflowed.
+    OutOfBounds {
+        region: Region,
+        address: i64,
+        len: usize,
+    },
+    InvalidString,
+    /// Writing a string to the output failed with the given reason.
+    Output(String),
+    DivByZero,
+    /// The instruction at `pc` names register `register`, which does not exist.
+    InvalidRegister {
+        register: u8,
+        pc: usize,
+    },
+}
+
+/// Memory a program addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Heap,
+    Code,
+}
+
+impl VmError {
+    pub fn reason(&self) -> ExitReason {
+        match self {
+            VmError::InvalidHeader(_) => ExitReason::InvalidHeader,
+            VmError::IllegalOpcode(_) => ExitReason::IllegalOpcode,
+            VmError::OutOfFuel => ExitReason::OutOfFuel,
+            VmError::HeapLimitExceeded { .. } => ExitReason::HeapLimitExceeded,
+            VmError::InvalidAllocation(_) => ExitReason::InvalidAllocation,
+            VmError::Timeout => ExitReason::Timeout,
+            VmError::Cancelled => ExitReason::Cancelled,
+            VmError::InvalidConstant => ExitReason::InvalidConstant,
+            VmError::StackOverflow => ExitReason::StackOverflow,
+            VmError::StackUnderflow => ExitReason::StackUnderflow,
+            VmError::OutOfBounds {
+                region: Region::Heap,
+                ..
+            } => ExitReason::HeapOutOfBounds,
+            VmError::OutOfBounds {
+                region: Region::Code,
+                ..
+            } => ExitReason::CodeOutOfBounds,
+            VmError::InvalidString => ExitReason::InvalidString,
+            VmError::Output(_) => ExitReason::OutputFailed,
+            VmError::DivByZero => ExitReason::DivisionByZero,
+            VmError::InvalidRegister { .. } => ExitReason::InvalidRegister,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::InvalidHeader(reason) => write!(f, "{reason}"),
+            VmError::IllegalOpcode(byte) => write!(f, "Illegal opcode {byte}"),
+            VmError::OutOfFuel => write!(f, "Out of fuel"),
+            VmError::HeapLimitExceeded { requested, limit } => write!(
+                f,
+                "Heap of {requested} bytes would exceed the limit of {limit} bytes"
+            ),
+            VmError::InvalidAllocation(bytes) => write!(f, "Invalid allocation of {bytes} bytes"),
+            VmError::Timeout => write!(f, "Execution timed out"),
+            VmError::Cancelled => write!(f, "Execution was cancelled"),
+            VmError::InvalidConstant => write!(f, "Constant index out of range"),
+            VmError::StackOverflow => write!(f, "Stack overflow"),
+            VmError::StackUnderflow => write!(f, "Stack underflow"),
+            VmError::OutOfBounds {
+                region: Region::Heap,
+                address,
+                len,
+            } => write!(
+                f,
+                "Heap access of {len} bytes at {address} is out of bounds"
+            ),
+            VmError::OutOfBounds {
+                region: Region::Code,
+                address,
+                len,
+            } => write!(
+                f,
+                "Code access of {len} bytes at pc {address} is out of bounds"
+            ),
+            VmError::InvalidString => write!(f, "String offset out of range"),
+            VmError::Output(reason) => write!(f, "Unable to write output: {reason}"),
+            VmError::DivByZero => write!(f, "Division by zero"),
+            VmError::InvalidRegister { register, pc } => {
+                write!(f, "Invalid register ${register} at pc {pc}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// Condition flags.
+///
+/// Arithmetic and comparisons set ZERO, NEGATIVE, CARRY and OVERFLOW from their result, a
+/// comparison from `$a - $b`. Comparisons also store their outcome in COMPARISON, which
+/// is what JEQ and JNEQ test.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Flags(u8);
+
+impl Flags {
+    pub const ZERO: u8 = 1;
+    pub const NEGATIVE: u8 = 1 << 1;
+    pub const CARRY: u8 = 1 << 2;
+    pub const OVERFLOW: u8 = 1 << 3;
+    pub const COMPARISON: u8 = 1 << 4;
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(self, flag: u8) -> bool {
+        self.0 & flag != 0
+    }
+
+    pub fn set(&mut self, flag: u8, value: bool) {
+        if value {
+            self.0 |= flag;
+        } else {
+            self.0 &= !flag;
+        }
+    }
+
+    fn set_result(&mut self, result: i32, carry: bool, overflow: bool) {
+        self.set(Self::ZERO, result == 0);
+        self.set(Self::NEGATIVE, result < 0);
+        self.set(Self::CARRY, carry);
+        self.set(Self::OVERFLOW, overflow);
+    }
+
+    // Signed less than, after a subtraction
+    fn less(self) -> bool {
+        self.contains(Self::NEGATIVE) != self.contains(Self::OVERFLOW)
+    }
+}
+
+/// Where a program was stopped by a trap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrapInfo {
+    pub pc: usize,
+    pub opcode: Option<Opcode>,
+}
+
+/// What happened during a call to `VM::run`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunOutcome {
+    pub exit: ExitReason,
+    /// Instructions dispatched, including the one that stopped the program.
+    pub instructions: u64,
+    pub fuel_used: u64,
+    /// Virtual cycles, see `VM::set_cycle_model`.
+    pub cycles: u64,
+    pub duration: Duration,
+    pub trap: Option<TrapInfo>,
+    /// Why the VM stopped the program, when it trapped.
+    pub error: Option<VmError>,
+}
+
+/// A single executed instruction, recorded when tracing is enabled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub pc: usize,
+    pub opcode: Opcode,
 }
 
 impl VM {
@@ -19,175 +388,1180 @@ impl VM {
             program: Vec::new(),
             program_counter: 0,
             heap: Vec::new(),
+            constants: Vec::new(),
+            rodata: Vec::new(),
+            stack: Vec::new(),
+            stack_limit: DEFAULT_STACK_LIMIT,
             remainder: 0,
-            equal_flag: false,
+            flags: Flags::default(),
+            fuel: None,
+            costs: CostModel::default(),
+            cycle_costs: CostModel::weighted(),
+            cycles: 0,
+            heap_limit: None,
+            timeout: None,
+            deadline: None,
+            trace: None,
+            trace_limit: usize::MAX,
+            cancel: None,
+            code: None,
+            format: Format::default(),
+            instructions: 0,
+            branches_taken: 0,
+            fuel_used: 0,
+            hooks: ExitHooks::default(),
+            sampler: None,
+            output: Output::default(),
+            error: None,
+            yield_to: None,
+        }
+    }
+
+    pub fn run(&mut self) -> RunOutcome {
+        self.run_with(|_| {})
+    }
+
+    /// Like `run`, but calls `before_each` ahead of every instruction, which lets a
+    /// debugger inspect the VM or hold it paused.
+    pub fn run_with(&mut self, mut before_each: impl FnMut(&mut VM)) -> RunOutcome {
+        let started = Instant::now();
+        let instructions = self.instructions;
+        let fuel_used = self.fuel_used;
+        let cycles = self.cycles;
+
+        let (exit, pc) = match self.start() {
+            Ok(()) => loop {
+                before_each(self);
+                let pc = self.program_counter;
+                if let Some(exit) = self.execute_instruction() {
+                    break (exit, pc);
+                }
+            },
+            Err(error) => (self.trap(error).expect("errors stop the program"), 0),
+        };
+        self.fire_exit_hooks(exit);
+
+        RunOutcome {
+            exit,
+            instructions: self.instructions - instructions,
+            fuel_used: self.fuel_used - fuel_used,
+            cycles: self.cycles - cycles,
+            duration: started.elapsed(),
+            trap: exit.is_trap().then(|| TrapInfo {
+                pc,
+                opcode: self.program.get(pc).map(|byte| Opcode::from(*byte)),
+            }),
+            error: self.error.clone().filter(|_| exit.is_trap()),
+        }
+    }
+
+    /// Validates the program header and moves the program counter to the first instruction.
+    pub fn start(&mut self) -> Result<(), VmError> {
+        self.error = None;
+        let header = container::read_header(&self.program).map_err(VmError::InvalidHeader)?;
+        let sections = container::read_sections(&self.program).map_err(VmError::InvalidHeader)?;
+        let code = code_section(&sections)
+            .map(|section| section.range())
+            .ok_or_else(|| VmError::InvalidHeader("Program has no code section".to_string()))?;
+
+        // BSS sections are mapped, zeroed, at the start of the heap
+        let bss: usize = sections
+            .iter()
+            .filter(|section| section.kind == SectionKind::Bss)
+            .map(|section| section.length)
+            .sum();
+        if self.heap_limit.is_some_and(|limit| bss > limit) {
+            return Err(VmError::InvalidHeader(format!(
+                "BSS of {bss} bytes exceeds the heap limit"
+            )));
+        }
+        if self.heap.len() < bss {
+            self.heap.resize(bss, 0);
+        }
+        self.constants = sections
+            .iter()
+            .filter(|section| section.kind == SectionKind::Constants)
+            .flat_map(|section| section.contents(&self.program).chunks_exact(4))
+            .map(|value| i32::from_be_bytes(value.try_into().unwrap()))
+            .collect();
+        self.rodata = sections
+            .iter()
+            .filter(|section| section.kind == SectionKind::Data)
+            .flat_map(|section| section.contents(&self.program))
+            .copied()
+            .collect();
+        self.program_counter = code.start;
+        self.code = Some(code);
+        self.format = header.format;
+
+        Ok(())
+    }
+
+    /// Executes a single instruction, returning the exit reason if the program stopped.
+    pub fn run_once(&mut self) -> Option<ExitReason> {
+        let exit = self.execute_instruction();
+        if let Some(exit) = exit {
+            if !exit.is_trap() {
+                self.error = None;
+            }
+            self.fire_exit_hooks(exit);
+        }
+
+        exit
+    }
+
+    /// Why the VM stopped the program, if the last exit was a trap.
+    pub fn error(&self) -> Option<&VmError> {
+        self.error.as_ref()
+    }
+
+    /// Takes the operand of the last YIELDTO, for a scheduler to end the time slice and
+    /// run the requested program next. Without a scheduler YIELDTO does nothing.
+    pub fn take_yield(&mut self) -> Option<i32> {
+        self.yield_to.take()
+    }
+
+    /// Calls `hook` when the program stops without a trap, on HLT or at the end of the code.
+    pub fn on_halt(&mut self, hook: impl FnMut(&VM, ExitReason) + Send + 'static) {
+        self.hooks.halt.push(Box::new(hook));
+    }
+
+    /// Calls `hook` when the VM stops the program, see `ExitReason::is_trap`.
+    pub fn on_trap(&mut self, hook: impl FnMut(&VM, ExitReason) + Send + 'static) {
+        self.hooks.trap.push(Box::new(hook));
+    }
+
+    /// Calls `hook` when the program runs out of fuel, after any `on_trap` hooks.
+    pub fn on_out_of_fuel(&mut self, hook: impl FnMut(&VM, ExitReason) + Send + 'static) {
+        self.hooks.out_of_fuel.push(Box::new(hook));
+    }
+
+    /// Calls `hook` before every `interval`th instruction, with the program counter on
+    /// it. Much cheaper than a hook per instruction, for profiling that stays on. An
+    /// interval of 0 removes the sampler.
+    pub fn set_sampler(&mut self, interval: u64, hook: impl FnMut(&VM) + Send + 'static) {
+        self.sampler = (interval > 0).then(|| Sampler {
+            interval,
+            countdown: interval,
+            hook: Box::new(hook),
+        });
+    }
+
+    /// Changes how often the sampler fires, starting from the next sample. An interval of
+    /// 0 removes it.
+    pub fn set_sample_interval(&mut self, interval: u64) {
+        if interval == 0 {
+            self.sampler = None;
+        } else if let Some(sampler) = self.sampler.as_mut() {
+            sampler.interval = interval;
+            sampler.countdown = sampler.countdown.min(interval);
+        }
+    }
+
+    fn sample(&mut self) {
+        // The hook borrows the VM, so the sampler is moved out while it runs
+        if let Some(mut sampler) = self.sampler.take() {
+            sampler.countdown = sampler.interval;
+            (sampler.hook)(self);
+            self.sampler = Some(sampler);
         }
     }
 
-    pub fn run(&mut self) {
-        if !self.has_valid_header() {
-            eprintln!("Invalid header");
-            return;
+    fn fire_exit_hooks(&mut self, exit: ExitReason) {
+        // Hooks borrow the VM, so they are moved out while running
+        let mut hooks = std::mem::take(&mut self.hooks);
+        let hooks_for_exit = if !exit.is_trap() {
+            vec![&mut hooks.halt]
+        } else if exit == ExitReason::OutOfFuel {
+            vec![&mut hooks.trap, &mut hooks.out_of_fuel]
+        } else {
+            vec![&mut hooks.trap]
+        };
+        for hook in hooks_for_exit.into_iter().flatten() {
+            hook(self, exit);
         }
-        // skip remaining heder bytes
-        self.program_counter = 64;
+        self.hooks = hooks;
+    }
+
+    /// Limits the fuel the VM may consume, see `set_cost_model` for how it is charged.
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(fuel);
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Sets how much fuel each instruction consumes. Defaults to one per instruction.
+    pub fn set_cost_model(&mut self, costs: CostModel) {
+        self.costs = costs;
+    }
+
+    /// Sets how many virtual cycles each instruction takes. Defaults to
+    /// `CostModel::weighted`. Unlike wall time, cycles are the same on every run, which
+    /// makes them suitable for comparing algorithms.
+    pub fn set_cycle_model(&mut self, costs: CostModel) {
+        self.cycle_costs = costs;
+    }
+
+    /// Virtual cycles spent so far.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Limits the size in bytes the heap may grow to through ALOC.
+    pub fn set_heap_limit(&mut self, bytes: usize) {
+        self.heap_limit = Some(bytes);
+    }
+
+    pub fn program_counter(&self) -> usize {
+        self.program_counter
+    }
+
+    /// Limits the wall-clock time the VM may spend executing, measured from the first
+    /// instruction and checked every 1024 instructions. Unlike fuel this also bounds
+    /// instructions that block.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+        self.deadline = None;
+    }
+
+    /// Limits the number of values on the stack, shared by PUSH/POP and CALL/RET.
+    pub fn set_stack_limit(&mut self, values: usize) {
+        self.stack_limit = values;
+    }
+
+    /// Number of values on the stack, which is where the next PUSH goes.
+    pub fn stack_pointer(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// The stack, bottom first.
+    pub fn stack(&self) -> &[i32] {
+        &self.stack
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
 
-        while self.execute_instruction().is_some() {
-            self.execute_instruction();
+    /// Copies `bytes` into the heap at `offset`, growing it as needed within the heap limit.
+    pub fn write_heap(&mut self, offset: usize, bytes: &[u8]) -> Result<(), String> {
+        let end = offset
+            .checked_add(bytes.len())
+            .ok_or("Heap write is out of range")?;
+        if self.heap_limit.is_some_and(|limit| end > limit) {
+            return Err(format!(
+                "Heap write up to {end} bytes exceeds the heap limit"
+            ));
+        }
+        if self.heap.len() < end {
+            self.heap.resize(end, 0);
         }
+        self.heap[offset..end].copy_from_slice(bytes);
+
+        Ok(())
+    }
+
+    /// The `len` heap bytes at `address`.
+    pub fn read_bytes(&self, address: usize, len: usize) -> Result<&[u8], String> {
+        Ok(&self.heap[self.heap_bounds(address, len)?])
+    }
+
+    /// The big-endian word at `address`, as LW reads it.
+    pub fn read_i32(&self, address: usize) -> Result<i32, String> {
+        let bytes = self.read_bytes(address, 4)?;
+        Ok(i32::from_be_bytes(
+            bytes.try_into().expect("words are 4 bytes"),
+        ))
+    }
+
+    /// The UTF-8 string starting at `address`, up to but not including its NUL terminator.
+    pub fn read_cstr(&self, address: usize) -> Result<String, String> {
+        self.heap_bounds(address, 0)?;
+        let tail = &self.heap[address..];
+        let len = tail
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or_else(|| format!("No NUL terminator after heap address {address}"))?;
+        String::from_utf8(tail[..len].to_vec())
+            .map_err(|_| format!("Invalid UTF-8 at heap address {address}"))
+    }
+
+    /// Overwrites heap bytes at `address`. Unlike `write_heap`, never grows the heap.
+    pub fn write_bytes(&mut self, address: usize, bytes: &[u8]) -> Result<(), String> {
+        let range = self.heap_bounds(address, bytes.len())?;
+        self.heap[range].copy_from_slice(bytes);
+
+        Ok(())
+    }
+
+    /// Stores a big-endian word at `address`, as SW writes it.
+    pub fn write_i32(&mut self, address: usize, value: i32) -> Result<(), String> {
+        self.write_bytes(address, &value.to_be_bytes())
+    }
+
+    /// Reads a value laid out as `GuestSerialize` describes from `address`.
+    pub fn read_value<T: GuestSerialize>(&self, address: usize) -> Result<T, String> {
+        self.read_bytes(address, T::SIZE).map(T::read_from)
     }
 
-    pub fn run_once(&mut self) {
-        self.execute_instruction();
+    /// Copies `value` into the heap at `address`, without growing it.
+    pub fn write_value<T: GuestSerialize>(
+        &mut self,
+        address: usize,
+        value: &T,
+    ) -> Result<(), String> {
+        let range = self.heap_bounds(address, T::SIZE)?;
+        value.write_to(&mut self.heap[range]);
+
+        Ok(())
+    }
+
+    /// Stops execution with `ExitReason::Cancelled` once the flag is set, e.g. from another
+    /// thread.
+    pub fn set_cancel_flag(&mut self, cancel: Arc<AtomicBool>) {
+        self.cancel = Some(cancel);
+    }
+
+    /// Sends what PRTS prints to `output` instead of stdout.
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.output = Output(output);
+    }
+
+    /// Starts recording every executed instruction.
+    pub fn enable_trace(&mut self) {
+        self.trace = Some(Vec::new());
+    }
+
+    /// Keeps only the first `entries` instructions of the trace.
+    pub fn set_trace_limit(&mut self, entries: usize) {
+        self.trace_limit = entries;
+    }
+
+    pub fn trace(&self) -> &[TraceEntry] {
+        self.trace.as_deref().unwrap_or_default()
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    // Wrapping `a - b`, setting the flags for it
+    fn subtract(&mut self, a: i32, b: i32) -> i32 {
+        let (result, overflow) = a.overflowing_sub(b);
+        self.flags
+            .set_result(result, (a as u32) < (b as u32), overflow);
+
+        result
     }
 
-    fn execute_instruction(&mut self) -> Option<()> {
-        if self.program_counter >= self.program.len() {
-            return None;
+    fn execute_instruction(&mut self) -> Option<ExitReason> {
+        if self.program_counter >= self.code_range().end {
+            return Some(ExitReason::EndOfProgram);
         }
 
-        match self.decode_opcode() {
-            Opcode::LOAD => {
+        if self
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+        {
+            return self.trap(VmError::Cancelled);
+        }
+
+        // Reading the clock costs more than most instructions, and a deadline does not need
+        // to be met to the instruction
+        if let Some(timeout) = self
+            .timeout
+            .filter(|_| self.instructions.is_multiple_of(CLOCK_INTERVAL))
+        {
+            let now = Instant::now();
+            match self.deadline.or_else(|| now.checked_add(timeout)) {
+                Some(deadline) if now >= deadline => return self.trap(VmError::Timeout),
+                Some(deadline) => self.deadline = Some(deadline),
+                // A timeout too large to land on the clock never expires
+                None => self.timeout = None,
+            }
+        }
+
+        if let Some(sampler) = self.sampler.as_mut() {
+            sampler.countdown -= 1;
+            if sampler.countdown == 0 {
+                self.sample();
+            }
+        }
+
+        let pc = self.program_counter;
+        let opcode = self.decode_opcode();
+        if let Err(error) = self.check_operands(opcode, pc) {
+            self.program_counter = pc;
+            return self.trap(error);
+        }
+        if !self.consume_fuel(self.costs.cost(opcode)) {
+            self.program_counter = pc;
+            return self.trap(VmError::OutOfFuel);
+        }
+        self.instructions += 1;
+        self.cycles = self.cycles.saturating_add(self.cycle_costs.cost(opcode));
+        if let Some(trace) = self
+            .trace
+            .as_mut()
+            .filter(|trace| trace.len() < self.trace_limit)
+        {
+            trace.push(TraceEntry { pc, opcode });
+        }
+
+        match opcode {
+            Opcode::LOAD | Opcode::LOADU => {
                 let register_idx = self.next_8_bits() as usize;
-                let number = self.next_16_bits();
+                let number = self.next_immediate();
                 self.registers[register_idx] = number as i32;
             }
+            Opcode::LUI => {
+                let register_idx = self.next_8_bits() as usize;
+                let number = self.next_immediate();
+                self.registers[register_idx] = (number << 16) as i32;
+            }
+            Opcode::ORI => {
+                let register_idx = self.next_8_bits() as usize;
+                let number = self.next_immediate();
+                self.registers[register_idx] |= number as i32;
+            }
+            Opcode::LOADS => {
+                let register_idx = self.next_8_bits() as usize;
+                let number = self.next_immediate();
+                self.registers[register_idx] = self.format.encoding().sign_extend(number);
+            }
             Opcode::ADD => {
                 let first_register = self.registers[self.next_8_bits() as usize];
                 let second_register = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = first_register + second_register;
+                let (result, overflow) = first_register.overflowing_add(second_register);
+                let carry = (first_register as u32)
+                    .overflowing_add(second_register as u32)
+                    .1;
+                self.flags.set_result(result, carry, overflow);
+                self.registers[self.next_8_bits() as usize] = result;
             }
             Opcode::SUB => {
                 let first_register = self.registers[self.next_8_bits() as usize];
                 let second_register = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = first_register - second_register;
+                let result = self.subtract(first_register, second_register);
+                self.registers[self.next_8_bits() as usize] = result;
             }
             Opcode::MUL => {
                 let first_register = self.registers[self.next_8_bits() as usize];
                 let second_register = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = first_register * second_register;
+                let (result, overflow) = first_register.overflowing_mul(second_register);
+                self.flags.set_result(result, overflow, overflow);
+                self.registers[self.next_8_bits() as usize] = result;
+            }
+            Opcode::AND | Opcode::OR | Opcode::XOR => {
+                let first_register = self.registers[self.next_8_bits() as usize];
+                let second_register = self.registers[self.next_8_bits() as usize];
+                let result = match opcode {
+                    Opcode::AND => first_register & second_register,
+                    Opcode::OR => first_register | second_register,
+                    _ => first_register ^ second_register,
+                };
+                self.flags.set_result(result, false, false);
+                self.registers[self.next_8_bits() as usize] = result;
+            }
+            Opcode::NOT => {
+                let result = !self.registers[self.next_8_bits() as usize];
+                self.flags.set_result(result, false, false);
+                self.registers[self.next_8_bits() as usize] = result;
+            }
+            Opcode::SHL | Opcode::SHR | Opcode::SHLI | Opcode::SHRI => {
+                let register = self.next_8_bits() as usize;
+                let count = match opcode {
+                    Opcode::SHL | Opcode::SHR => self.registers[self.next_8_bits() as usize] as u32,
+                    _ => self.next_immediate(),
+                };
+                let value = self.registers[register] as u32;
+                let result = match opcode {
+                    Opcode::SHL | Opcode::SHLI => value.checked_shl(count),
+                    _ => value.checked_shr(count),
+                }
+                .unwrap_or(0) as i32;
+                self.flags.set_result(result, false, false);
+                self.registers[register] = result;
             }
             Opcode::DIV => {
                 let first_register = self.registers[self.next_8_bits() as usize];
                 let second_register = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = first_register / second_register;
-                // TODO: handle division by 0
-                self.remainder = (first_register % second_register) as u32;
+                if second_register == 0 {
+                    return self.trap(VmError::DivByZero);
+                }
+                // i32::MIN / -1 is the one quotient that does not fit, it wraps to i32::MIN
+                let (result, overflow) = first_register.overflowing_div(second_register);
+                self.flags.set_result(result, false, overflow);
+                self.registers[self.next_8_bits() as usize] = result;
+                self.remainder = first_register.wrapping_rem(second_register) as u32;
+            }
+            Opcode::MOD => {
+                let first_register = self.registers[self.next_8_bits() as usize];
+                let second_register = self.registers[self.next_8_bits() as usize];
+                if second_register == 0 {
+                    return self.trap(VmError::DivByZero);
+                }
+                let result = first_register.wrapping_rem(second_register);
+                self.flags.set_result(result, false, false);
+                self.registers[self.next_8_bits() as usize] = result;
             }
-            Opcode::HLT => {
-                println!("HTL encountered");
-                return None;
+            Opcode::MOV => {
+                let value = self.registers[self.next_8_bits() as usize];
+                self.registers[self.next_8_bits() as usize] = value;
             }
+            Opcode::HLT => return Some(ExitReason::Halted),
             Opcode::JMP => {
                 let target = self.registers[self.next_8_bits() as usize];
-                self.program_counter = target as usize;
+                return self.jump(pc, target.into());
             }
-            Opcode::JMPF => {
+            Opcode::JMPF | Opcode::JMPB => {
                 let jumps = self.registers[self.next_8_bits() as usize];
-                self.program_counter += jumps as usize;
+                let target = usize::try_from(jumps).ok().and_then(|jumps| match opcode {
+                    Opcode::JMPF => self.program_counter.checked_add(jumps),
+                    _ => self.program_counter.checked_sub(jumps),
+                });
+                let Some(target) = target else {
+                    self.program_counter = pc;
+                    return self.trap(VmError::OutOfBounds {
+                        region: Region::Code,
+                        address: pc as i64,
+                        len: self.format.encoding().width(),
+                    });
+                };
+                return self.jump(pc, target as i64);
             }
-            Opcode::JMPB => {
-                let jumps = self.registers[self.next_8_bits() as usize];
-                self.program_counter -= jumps as usize;
+            Opcode::SEQ | Opcode::SNE | Opcode::SLT | Opcode::SLE => {
+                let first_value = self.registers[self.next_8_bits() as usize];
+                let second_value = self.registers[self.next_8_bits() as usize];
+                let result = match opcode {
+                    Opcode::SEQ => first_value == second_value,
+                    Opcode::SNE => first_value != second_value,
+                    Opcode::SLT => first_value < second_value,
+                    _ => first_value <= second_value,
+                };
+                self.registers[self.next_8_bits() as usize] = result as i32;
             }
             Opcode::EQ => {
                 let first_value = self.registers[self.next_8_bits() as usize];
                 let second_value = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = first_value == second_value;
-                self.next_8_bits();
+                self.subtract(first_value, second_value);
+                self.flags
+                    .set(Flags::COMPARISON, first_value == second_value);
             }
             Opcode::NEQ => {
                 let first_value = self.registers[self.next_8_bits() as usize];
                 let second_value = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = first_value != second_value;
-                self.next_8_bits();
+                self.subtract(first_value, second_value);
+                self.flags
+                    .set(Flags::COMPARISON, first_value != second_value);
             }
             Opcode::GT => {
                 let first_value = self.registers[self.next_8_bits() as usize];
                 let second_value = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = first_value > second_value;
-                self.next_8_bits();
+                self.subtract(first_value, second_value);
+                self.flags
+                    .set(Flags::COMPARISON, first_value > second_value);
             }
             Opcode::LT => {
                 let first_value = self.registers[self.next_8_bits() as usize];
                 let second_value = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = first_value < second_value;
-                self.next_8_bits();
+                self.subtract(first_value, second_value);
+                self.flags
+                    .set(Flags::COMPARISON, first_value < second_value);
             }
             Opcode::GTE => {
                 let first_value = self.registers[self.next_8_bits() as usize];
                 let second_value = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = first_value >= second_value;
-                self.next_8_bits();
+                self.subtract(first_value, second_value);
+                self.flags
+                    .set(Flags::COMPARISON, first_value >= second_value);
             }
             Opcode::LTE => {
                 let first_value = self.registers[self.next_8_bits() as usize];
                 let second_value = self.registers[self.next_8_bits() as usize];
-                self.equal_flag = first_value <= second_value;
-                self.next_8_bits();
+                self.subtract(first_value, second_value);
+                self.flags
+                    .set(Flags::COMPARISON, first_value <= second_value);
             }
             Opcode::JEQ => {
                 let target = self.registers[self.next_8_bits() as usize];
-                if self.equal_flag {
-                    self.program_counter = target as usize;
+                if self.flags.contains(Flags::COMPARISON) {
+                    return self.jump(pc, target.into());
                 }
             }
             Opcode::JNEQ => {
                 let target = self.registers[self.next_8_bits() as usize];
-                if !self.equal_flag {
-                    self.program_counter = target as usize;
+                if !self.flags.contains(Flags::COMPARISON) {
+                    return self.jump(pc, target.into());
                 }
             }
             Opcode::ALOC => {
                 let register = self.next_8_bits() as usize;
                 let bytes = self.registers[register];
-                self.heap.resize(self.heap.len() + bytes as usize, 0);
+                let Some(heap_size) = usize::try_from(bytes)
+                    .ok()
+                    .and_then(|bytes| self.heap.len().checked_add(bytes))
+                else {
+                    return self.trap(VmError::InvalidAllocation(bytes));
+                };
+                if !self.consume_fuel(self.costs.size_cost(bytes as usize)) {
+                    self.program_counter = pc;
+                    return self.trap(VmError::OutOfFuel);
+                }
+                if let Some(limit) = self.heap_limit.filter(|&limit| heap_size > limit) {
+                    return self.trap(VmError::HeapLimitExceeded {
+                        requested: heap_size,
+                        limit,
+                    });
+                }
+                self.heap.resize(heap_size, 0);
+            }
+            Opcode::LW => {
+                let destination = self.next_8_bits() as usize;
+                let address = self.registers[self.next_8_bits() as usize];
+                let Some(word) = self.heap_range(address, 4) else {
+                    return self.trap(VmError::OutOfBounds {
+                        region: Region::Heap,
+                        address: address.into(),
+                        len: 4,
+                    });
+                };
+                let bytes = self.heap[word].try_into().expect("words are 4 bytes");
+                self.registers[destination] = i32::from_be_bytes(bytes);
+            }
+            Opcode::SW => {
+                let value = self.registers[self.next_8_bits() as usize];
+                let address = self.registers[self.next_8_bits() as usize];
+                let Some(word) = self.heap_range(address, 4) else {
+                    return self.trap(VmError::OutOfBounds {
+                        region: Region::Heap,
+                        address: address.into(),
+                        len: 4,
+                    });
+                };
+                self.heap[word].copy_from_slice(&value.to_be_bytes());
+            }
+            Opcode::LB => {
+                let destination = self.next_8_bits() as usize;
+                let address = self.registers[self.next_8_bits() as usize];
+                let Some(byte) = self.heap_range(address, 1) else {
+                    return self.trap(VmError::OutOfBounds {
+                        region: Region::Heap,
+                        address: address.into(),
+                        len: 1,
+                    });
+                };
+                self.registers[destination] = self.heap[byte.start] as i8 as i32;
+            }
+            Opcode::SB => {
+                let value = self.registers[self.next_8_bits() as usize];
+                let address = self.registers[self.next_8_bits() as usize];
+                let Some(byte) = self.heap_range(address, 1) else {
+                    return self.trap(VmError::OutOfBounds {
+                        region: Region::Heap,
+                        address: address.into(),
+                        len: 1,
+                    });
+                };
+                self.heap[byte.start] = value as u8;
             }
             Opcode::INC => {
                 let register = self.next_8_bits() as usize;
-                self.registers[register] += 1;
+                let value = self.registers[register];
+                let (result, overflow) = value.overflowing_add(1);
+                self.flags.set_result(result, value == -1, overflow);
+                self.registers[register] = result;
             }
             Opcode::DEC => {
                 let register = self.next_8_bits() as usize;
-                self.registers[register] -= 1;
+                self.registers[register] = self.subtract(self.registers[register], 1);
+            }
+            Opcode::FUEL => {
+                let register = self.next_8_bits() as usize;
+                // -1 tells the guest it is not metered
+                self.registers[register] = self
+                    .fuel
+                    .map_or(-1, |fuel| fuel.min(i32::MAX as u64) as i32);
+            }
+            Opcode::YIELDTO => {
+                let register = self.next_8_bits() as usize;
+                self.yield_to = Some(self.registers[register]);
+            }
+            Opcode::MFLAGS => {
+                let register = self.next_8_bits() as usize;
+                self.registers[register] = self.flags.bits() as i32;
+            }
+            Opcode::RDPERF => {
+                let register = self.next_8_bits() as usize;
+                let counter = match u16::try_from(self.next_immediate()).unwrap_or(u16::MAX) {
+                    PERF_INSTRUCTIONS => self.instructions,
+                    PERF_BRANCHES => self.branches_taken,
+                    _ => 0,
+                };
+                self.registers[register] = counter.min(i32::MAX as u64) as i32;
+            }
+            Opcode::LOADC => {
+                let register = self.next_8_bits() as usize;
+                let index = self.next_immediate() as usize;
+                let Some(&value) = self.constants.get(index) else {
+                    return self.trap(VmError::InvalidConstant);
+                };
+                self.registers[register] = value;
+            }
+            Opcode::RDCYCLE => {
+                let register = self.next_8_bits() as usize;
+                self.registers[register] = self.cycles.min(i32::MAX as u64) as i32;
+            }
+            Opcode::PUSH => {
+                let value = self.registers[self.next_8_bits() as usize];
+                if let Err(error) = self.push(value) {
+                    return self.trap(error);
+                }
+            }
+            Opcode::POP => {
+                let register = self.next_8_bits() as usize;
+                let Some(value) = self.stack.pop() else {
+                    return self.trap(VmError::StackUnderflow);
+                };
+                self.registers[register] = value;
+            }
+            Opcode::PRTS => {
+                let offset = self.next_immediate() as usize;
+                let Some(tail) = self.rodata.get(offset..).filter(|tail| !tail.is_empty()) else {
+                    return self.trap(VmError::InvalidString);
+                };
+                let len = tail
+                    .iter()
+                    .position(|&byte| byte == 0)
+                    .unwrap_or(tail.len());
+                let output = &mut self.output.0;
+                if let Err(e) = output.write_all(&tail[..len]).and_then(|_| output.flush()) {
+                    return self.trap(VmError::Output(e.to_string()));
+                }
+            }
+            Opcode::CALL => {
+                let target = self.next_immediate().into();
+                let width = self.format.encoding().width();
+                if let Err(error) = self
+                    .code_address(target)
+                    .and_then(|_| self.push((pc + width) as i32))
+                {
+                    self.program_counter = pc;
+                    return self.trap(error);
+                }
+                return self.jump(pc, target);
+            }
+            Opcode::RET => {
+                let Some(&target) = self.stack.last() else {
+                    return self.trap(VmError::StackUnderflow);
+                };
+                let exit = self.jump(pc, target.into());
+                if exit.is_none() {
+                    self.stack.pop();
+                }
+                return exit;
+            }
+            Opcode::CMOV => {
+                let destination = self.next_8_bits() as usize;
+                let source = self.registers[self.next_8_bits() as usize];
+                if self.flags.contains(Flags::COMPARISON) {
+                    self.registers[destination] = source;
+                }
+            }
+            Opcode::VADD | Opcode::VMUL => {
+                let first = self.next_8_bits() as usize;
+                let second = self.next_8_bits() as usize;
+                let destination = self.next_8_bits() as usize;
+                let lane = |registers: &[i32; 32], base: usize| -> [i32; VECTOR_WIDTH] {
+                    registers[base..base + VECTOR_WIDTH].try_into().unwrap()
+                };
+                let (a, b) = (lane(&self.registers, first), lane(&self.registers, second));
+                for i in 0..VECTOR_WIDTH {
+                    self.registers[destination + i] = match opcode {
+                        Opcode::VADD => a[i].wrapping_add(b[i]),
+                        _ => a[i].wrapping_mul(b[i]),
+                    };
+                }
+            }
+            Opcode::BEQ | Opcode::BNE | Opcode::BGT | Opcode::BLT | Opcode::BGE | Opcode::BLE => {
+                let first_value = self.registers[self.next_8_bits() as usize];
+                let second_value = self.registers[self.next_8_bits() as usize];
+                let target = self.registers[self.next_8_bits() as usize];
+                self.subtract(first_value, second_value);
+                let holds = match opcode {
+                    Opcode::BEQ => first_value == second_value,
+                    Opcode::BNE => first_value != second_value,
+                    Opcode::BGT => first_value > second_value,
+                    Opcode::BLT => first_value < second_value,
+                    Opcode::BGE => first_value >= second_value,
+                    _ => first_value <= second_value,
+                };
+                self.flags.set(Flags::COMPARISON, holds);
+                if holds {
+                    return self.jump(pc, target.into());
+                }
+            }
+            Opcode::JLT | Opcode::JGT | Opcode::JLE | Opcode::JGE => {
+                let target = self.registers[self.next_8_bits() as usize];
+                let less = self.flags.less();
+                let zero = self.flags.contains(Flags::ZERO);
+                let taken = match opcode {
+                    Opcode::JLT => less,
+                    Opcode::JGT => !less && !zero,
+                    Opcode::JLE => less || zero,
+                    _ => !less,
+                };
+                if taken {
+                    return self.jump(pc, target.into());
+                }
             }
-            _ => {
-                println!("unrecognized opcode found! Terminating!");
-                return None;
+            Opcode::JZ | Opcode::JNZ | Opcode::JC | Opcode::JO => {
+                let target = self.registers[self.next_8_bits() as usize];
+                let taken = match opcode {
+                    Opcode::JZ => self.flags.contains(Flags::ZERO),
+                    Opcode::JNZ => !self.flags.contains(Flags::ZERO),
+                    Opcode::JC => self.flags.contains(Flags::CARRY),
+                    _ => self.flags.contains(Flags::OVERFLOW),
+                };
+                if taken {
+                    return self.jump(pc, target.into());
+                }
             }
+            _ => return self.trap(VmError::IllegalOpcode(self.program[pc])),
         }
 
-        Some(())
-    }
+        // skip the padding of instructions that do not use the full width
+        self.program_counter = pc + self.format.encoding().width();
 
-    pub fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.program_counter]);
-        self.program_counter += 1;
+        None
+    }
 
-        opcode
+    // The code section, or the whole program when it was loaded without a header
+    fn code_range(&self) -> Range<usize> {
+        self.code.clone().unwrap_or(0..self.program.len())
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let operand = self.program[self.program_counter];
-        self.program_counter += 1;
+    // Checks that `target` is an address inside the code section
+    fn code_address(&self, target: i64) -> Result<usize, VmError> {
+        usize::try_from(target)
+            .ok()
+            .filter(|target| self.code_range().contains(target))
+            .ok_or(VmError::OutOfBounds {
+                region: Region::Code,
+                address: target,
+                len: self.format.encoding().width(),
+            })
+    }
 
-        operand
+    // Moves the program counter to `target`, trapping at `pc` if it lies outside the code
+    fn jump(&mut self, pc: usize, target: i64) -> Option<ExitReason> {
+        match self.code_address(target) {
+            Ok(target) => {
+                self.program_counter = target;
+                self.branches_taken += 1;
+                None
+            }
+            Err(error) => {
+                self.program_counter = pc;
+                self.trap(error)
+            }
+        }
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        let operand: u16 = ((self.program[self.program_counter] as u16) << 8)
-            | (self.program[self.program_counter + 1] as u16);
-        self.program_counter += 2;
+    // Checks the operands of the instruction at `pc` before it runs: they must lie within
+    // the code and name existing registers. Vector instructions name the first of
+    // `VECTOR_WIDTH` consecutive registers.
+    fn check_operands(&self, opcode: Opcode, pc: usize) -> Result<(), VmError> {
+        let Some(info) = opcode.info() else {
+            return Ok(());
+        };
+        let encoding = self.format.encoding();
+        let code = &self.program[..self.code_range().end.min(self.program.len())];
+        let width = |kind: OperandKind| match kind {
+            OperandKind::Register => 1,
+            OperandKind::Integer => encoding.immediate_width(),
+        };
+        let len = encoding.operands_offset()
+            + info
+                .operands
+                .iter()
+                .map(|operand| width(operand.kind))
+                .sum::<usize>();
+        if pc + len > code.len() {
+            return Err(VmError::OutOfBounds {
+                region: Region::Code,
+                address: pc as i64,
+                len,
+            });
+        }
 
-        operand
-    }
+        let span = match opcode {
+            Opcode::VADD | Opcode::VMUL => VECTOR_WIDTH,
+            _ => 1,
+        };
+        let mut position = pc + encoding.operands_offset();
+        for operand in info.operands {
+            if operand.kind == OperandKind::Register {
+                let register = code[position];
+                if register as usize + span > self.registers.len() {
+                    return Err(VmError::InvalidRegister { register, pc });
+                }
+            }
+            position += width(operand.kind);
+        }
 
-    pub fn add_program(&mut self, bytes: Vec<u8>) {
-        self.program.extend_from_slice(&bytes);
+        Ok(())
     }
 
-    fn has_valid_header(&self) -> bool {
-        self.program[..4] == PIE_HEADER_PREFIX
+    // Records why the program is being stopped
+    fn trap(&mut self, error: VmError) -> Option<ExitReason> {
+        let exit = error.reason();
+        self.error = Some(error);
+
+        Some(exit)
+    }
+
+    // The heap range of the `len` bytes at `address`, if they lie entirely within the heap
+    fn heap_range(&self, address: i32, len: usize) -> Option<Range<usize>> {
+        let start = usize::try_from(address).ok()?;
+        self.heap_bounds(start, len).ok()
+    }
+
+    fn heap_bounds(&self, address: usize, len: usize) -> Result<Range<usize>, String> {
+        address
+            .checked_add(len)
+            .filter(|&end| end <= self.heap.len())
+            .map(|end| address..end)
+            .ok_or_else(|| {
+                format!(
+                    "Heap access of {len} bytes at {address} is outside the {} byte heap",
+                    self.heap.len()
+                )
+            })
+    }
+
+    fn push(&mut self, value: i32) -> Result<(), VmError> {
+        if self.stack.len() >= self.stack_limit {
+            return Err(VmError::StackOverflow);
+        }
+        self.stack.push(value);
+
+        Ok(())
+    }
+
+    fn consume_fuel(&mut self, amount: u64) -> bool {
+        match self.fuel.as_mut() {
+            Some(fuel) if *fuel < amount => return false,
+            Some(fuel) => *fuel -= amount,
+            None => {}
+        }
+        self.fuel_used = self.fuel_used.saturating_add(amount);
+
+        true
+    }
+
+    /// Reads the opcode at the program counter and moves to the first operand. Flags this
+    /// VM does not know about make the instruction illegal.
+    pub fn decode_opcode(&mut self) -> Opcode {
+        let opcode = Opcode::from(self.program[self.program_counter]);
+        let start = self.program_counter + 1;
+        self.program_counter += self.format.encoding().operands_offset();
+        let known_flags = self
+            .program
+            .get(start..self.program_counter)
+            .is_some_and(|flags| flags.iter().all(|&flag| flag == 0));
+
+        if known_flags {
+            opcode
+        } else {
+            Opcode::IGL
+        }
+    }
+
+    // Operand fetches are in bounds once `check_operands` accepted the instruction
+    fn next_8_bits(&mut self) -> u8 {
+        let operand = self.program[self.program_counter];
+        self.program_counter += 1;
+
+        operand
+    }
+
+    // An integer operand, zero-extended
+    fn next_immediate(&mut self) -> u32 {
+        let encoding = self.format.encoding();
+        let operand = self
+            .program
+            .get(self.program_counter..)
+            .and_then(|bytes| encoding.decode_immediate(bytes))
+            .expect("operands are checked before the instruction runs");
+        self.program_counter += encoding.immediate_width();
+
+        operand
+    }
+
+    /// Appends bytes to the end of the loaded program.
+    pub fn add_program(&mut self, bytes: Vec<u8>) {
+        self.program.extend_from_slice(&bytes);
+    }
+
+    /// Replaces the loaded program without touching the registers, heap, stack or program
+    /// counter, for patching code while it runs. The new code section must start where the
+    /// old one did; constants and read-only data keep the values loaded by `start`.
+    pub fn replace_program(&mut self, bytes: Vec<u8>) -> Result<(), String> {
+        let sections = container::read_sections(&bytes)?;
+        let code = code_section(&sections).ok_or("Program has no code section")?;
+        self.code = Some(code.range());
+        self.format = container::read_header(&bytes)?.format;
+        self.program = bytes;
+
+        Ok(())
+    }
+
+    /// Replaces the loaded program and rewinds the program counter.
+    pub fn load_program(&mut self, bytes: Vec<u8>) {
+        self.program = bytes;
+        self.program_counter = 0;
+        self.code = None;
+        self.format = Format::default();
+    }
+
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    pub fn registers(&self) -> &[i32; 32] {
+        &self.registers
+    }
+
+    pub fn register(&self, idx: usize) -> Option<i32> {
+        self.registers.get(idx).copied()
+    }
+
+    pub fn set_register(&mut self, idx: usize, value: i32) -> Result<(), String> {
+        let register = self
+            .registers
+            .get_mut(idx)
+            .ok_or_else(|| format!("Invalid register: {idx}"))?;
+        *register = value;
+
+        Ok(())
+    }
+
+    /// Serializes the registers and flags.
+    ///
+    /// JSON is an object `{"registers": [32 integers], "flags": integer}`. CSV has a
+    /// `name,value` header followed by one row per register, `$0,5`, and a `flags` row.
+    /// Flags use the bits documented on `Flags`.
+    pub fn export_state(&self, format: StateFormat) -> String {
+        match format {
+            StateFormat::Json => Json::object([
+                ("registers", Json::from(self.registers.to_vec())),
+                ("flags", Json::from(self.flags.bits() as i32)),
+            ])
+            .to_string(),
+            StateFormat::Csv => {
+                let mut csv = String::from("name,value\n");
+                for (idx, value) in self.registers.iter().enumerate() {
+                    csv.push_str(&format!("${idx},{value}\n"));
+                }
+                csv.push_str(&format!("flags,{}\n", self.flags.bits()));
+                csv
+            }
+        }
+    }
+
+    /// Restores registers and flags written by `export_state`. Registers or flags missing
+    /// from the input keep their current values.
+    pub fn import_state(&mut self, source: &str, format: StateFormat) -> Result<(), String> {
+        let mut values = Vec::new();
+        match format {
+            StateFormat::Json => {
+                let state = Json::parse(source)?;
+                if let Some(registers) = state.get("registers") {
+                    let Json::Array(registers) = registers else {
+                        return Err("registers must be an array".to_string());
+                    };
+                    for (idx, value) in registers.iter().enumerate() {
+                        values.push((Some(idx), value.as_i64()));
+                    }
+                }
+                if let Some(flags) = state.get("flags") {
+                    values.push((None, flags.as_i64()));
+                }
+            }
+            StateFormat::Csv => {
+                for line in source
+                    .lines()
+                    .skip(1)
+                    .filter(|line| !line.trim().is_empty())
+                {
+                    let (name, value) = line
+                        .split_once(',')
+                        .ok_or_else(|| format!("Expected name,value, got {line}"))?;
+                    let idx = match name.trim() {
+                        "flags" => None,
+                        register => Some(
+                            register
+                                .strip_prefix('$')
+                                .and_then(|idx| idx.parse().ok())
+                                .ok_or_else(|| format!("Invalid register: {register}"))?,
+                        ),
+                    };
+                    values.push((idx, value.trim().parse().ok()));
+                }
+            }
+        }
+
+        let mut registers = self.registers;
+        let mut flags = self.flags;
+        for (idx, value) in values {
+            match idx {
+                Some(idx) => {
+                    let value = value
+                        .and_then(|value| i32::try_from(value).ok())
+                        .ok_or_else(|| format!("Invalid value for register {idx}"))?;
+                    *registers
+                        .get_mut(idx)
+                        .ok_or_else(|| format!("Invalid register: {idx}"))? = value;
+                }
+                None => {
+                    flags = value
+                        .and_then(|value| u8::try_from(value).ok())
+                        .filter(|&bits| bits < Flags::COMPARISON << 1)
+                        .map(Flags)
+                        .ok_or("Invalid flags")?;
+                }
+            }
+        }
+        self.registers = registers;
+        self.flags = flags;
+
+        Ok(())
+    }
+}
+
+/// Encoding used by `VM::export_state` and `VM::import_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateFormat {
+    Json,
+    Csv,
+}
+
+impl StateFormat {
+    /// Picks the format from a file extension, `.json` or `.csv`.
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        match path.rsplit_once('.').map(|(_, extension)| extension) {
+            Some("json") => Ok(StateFormat::Json),
+            Some("csv") => Ok(StateFormat::Csv),
+            _ => Err(format!("Expected a .json or .csv file, got {path}")),
+        }
     }
 }
 
@@ -214,6 +1588,56 @@ impl From<u8> for Opcode {
             17 => Opcode::ALOC,
             18 => Opcode::INC,
             19 => Opcode::DEC,
+            20 => Opcode::FUEL,
+            21 => Opcode::YIELDTO,
+            22 => Opcode::LOADS,
+            23 => Opcode::LOADU,
+            24 => Opcode::MFLAGS,
+            25 => Opcode::JLT,
+            26 => Opcode::JGT,
+            27 => Opcode::JLE,
+            28 => Opcode::JGE,
+            29 => Opcode::CMOV,
+            30 => Opcode::VADD,
+            31 => Opcode::VMUL,
+            32 => Opcode::BEQ,
+            33 => Opcode::BNE,
+            34 => Opcode::BGT,
+            35 => Opcode::BLT,
+            36 => Opcode::BGE,
+            37 => Opcode::BLE,
+            38 => Opcode::RDPERF,
+            39 => Opcode::LOADC,
+            40 => Opcode::PUSH,
+            41 => Opcode::POP,
+            42 => Opcode::CALL,
+            43 => Opcode::RET,
+            44 => Opcode::AND,
+            45 => Opcode::OR,
+            46 => Opcode::XOR,
+            47 => Opcode::NOT,
+            48 => Opcode::SHL,
+            49 => Opcode::SHR,
+            50 => Opcode::SHLI,
+            51 => Opcode::SHRI,
+            52 => Opcode::RDCYCLE,
+            53 => Opcode::MOD,
+            54 => Opcode::MOV,
+            55 => Opcode::LW,
+            56 => Opcode::SW,
+            57 => Opcode::LB,
+            58 => Opcode::SB,
+            59 => Opcode::PRTS,
+            60 => Opcode::LUI,
+            61 => Opcode::ORI,
+            62 => Opcode::SEQ,
+            63 => Opcode::SNE,
+            64 => Opcode::SLT,
+            65 => Opcode::SLE,
+            66 => Opcode::JZ,
+            67 => Opcode::JNZ,
+            68 => Opcode::JC,
+            69 => Opcode::JO,
             _ => Opcode::IGL,
         }
     }
@@ -221,9 +1645,20 @@ impl From<u8> for Opcode {
 
 #[cfg(test)]
 mod test {
+    use std::{
+        io::{self, Write},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
     use crate::{
-        assembler::assembler::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX},
-        vm::VM,
+        assembler::container::{ProgramWriter, SectionKind, PIE_HEADER_LENGTH, PIE_HEADER_PREFIX},
+        cost::CostModel,
+        instruction::{Format, Opcode, OperandKind},
+        vm::{ExitReason, Flags, Region, StateFormat, TraceEntry, TrapInfo, VmError, VM},
     };
 
     fn prepend_header(mut program_body: Vec<u8>) -> Vec<u8> {
@@ -232,361 +1667,1457 @@ mod test {
         let mut program = header.to_vec();
         program.append(&mut program_body);
 
-        program
+        program
+    }
+
+    #[test]
+    fn test_new_vm() {
+        let vm = VM::new();
+        assert_eq!(vm.registers, [0; 32]);
+    }
+
+    #[test]
+    fn test_opcode_hlt() {
+        let mut vm = VM::new();
+        vm.program = vec![5, 0, 0, 0];
+        vm.run_once();
+        assert_eq!(vm.program_counter, 1);
+    }
+
+    #[test]
+    fn test_opcode_igl() {
+        let mut vm = VM::new();
+        vm.program = vec![255, 0, 0, 0];
+        vm.run_once();
+        assert_eq!(vm.program_counter, 1);
+    }
+
+    #[test]
+    fn test_opcode_load() {
+        let mut vm = VM::new();
+        // [opcode, register, operand, operand]
+        vm.program = vec![0, 0, 1, 244];
+        vm.run_once();
+        assert_eq!(vm.registers[0], 500);
+    }
+
+    #[test]
+    fn test_opcode_load_extension() {
+        let mut vm = VM::new();
+        // LOADS $0 #-2, LOADU $1 #65534, LOAD $2 #65534
+        vm.program = vec![22, 0, 255, 254, 23, 1, 255, 254, 0, 2, 255, 254];
+        for _ in 0..3 {
+            vm.run_once();
+        }
+        assert_eq!(vm.registers[..3], [-2, 65534, 65534]);
+    }
+
+    #[test]
+    fn test_opcode_add() {
+        let mut vm = VM::new();
+        // [opcode, register, operand, operand]
+        vm.program = prepend_header(vec![0, 0, 1, 244]); // LOAD $0 #500
+        vm.program.extend_from_slice(&[0, 1, 0, 7]); // LOAD $1 #7
+        vm.program.extend_from_slice(&[1, 0, 1, 2]); // ADD $0 $1 $2 (ADD  registers 0 and 1 and set result to register 2)
+        vm.run();
+        assert_eq!(vm.registers[2], 507);
+    }
+
+    #[test]
+    fn test_opcode_sub() {
+        let mut vm = VM::new();
+        // [opcode, register, operand, operand]
+        vm.program = prepend_header(vec![0, 0, 1, 244]); // LOAD $0 #500
+        vm.program.extend_from_slice(&[0, 1, 0, 7]); // LOAD $1 #7
+        vm.program.extend_from_slice(&[2, 0, 1, 2]); // SUB $0 $1 $2 (ADD  registers 0 and 1 and set result to register 2)
+        vm.run();
+        assert_eq!(vm.registers[2], 493);
+    }
+
+    #[test]
+    fn test_opcode_mul() {
+        let mut vm = VM::new();
+        // [opcode, register, operand, operand]
+        vm.program = prepend_header(vec![0, 0, 1, 244]); // LOAD $0 #500
+        vm.program.extend_from_slice(&[0, 1, 0, 7]); // LOAD $1 #7
+        vm.program.extend_from_slice(&[3, 0, 1, 2]); // MUL $0 $1 $2 (ADD  registers 0 and 1 and set result to register 2)
+        vm.run();
+        assert_eq!(vm.registers[2], 3500);
+    }
+
+    #[test]
+    fn test_opcode_div_without_remainder() {
+        let mut vm = VM::new();
+        // [opcode, register, operand, operand]
+        vm.program = prepend_header(vec![0, 0, 1, 244]); // LOAD $0 #500
+        vm.program.extend_from_slice(&[0, 1, 0, 5]); // LOAD $1 #5
+        vm.program.extend_from_slice(&[4, 0, 1, 2]); // MUL $0 $1 $2 (ADD  registers 0 and 1 and set result to register 2)
+        vm.run();
+        assert_eq!(vm.registers[2], 100);
+        assert_eq!(vm.remainder, 0);
+    }
+
+    #[test]
+    fn test_opcode_div_with_remainder() {
+        let mut vm = VM::new();
+        // [opcode, register, operand, operand]
+        vm.program = prepend_header(vec![0, 0, 1, 244]); // LOAD $0 #500
+        vm.program.extend_from_slice(&[0, 1, 0, 6]); // LOAD $1 #6
+        vm.program.extend_from_slice(&[4, 0, 1, 2]); // MUL $0 $1 $2 (ADD  registers 0 and 1 and set result to register 2)
+        vm.run();
+        assert_eq!(vm.registers[2], 83);
+        assert_eq!(vm.remainder, 2);
+    }
+
+    #[test]
+    fn test_wide_format() {
+        let mut writer = ProgramWriter::new(vec![
+            0, 0, 0, 0, 1, 134, 160, 0, // LOAD $0 #100000
+            22, 0, 1, 255, 254, 238, 144, 0, // LOADS $1 #-70000
+            1, 0, 0, 1, 2, 0, 0, 0, // ADD $0 $1 $2
+            5, 1, 0, 0, 0, 0, 0, 0, // HLT with an unknown flag
+        ]);
+        writer.set_format(Format::Wide);
+        let mut vm = VM::new();
+        vm.load_program(writer.finish());
+        let outcome = vm.run();
+        assert_eq!(outcome.exit, ExitReason::IllegalOpcode);
+        assert_eq!(outcome.trap.unwrap().pc, 64 + 24);
+        assert_eq!(&vm.registers[..3], &[100000, -70000, 30000]);
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        let mut vm = VM::new();
+        vm.program = prepend_header(vec![18, 0, 0, 0, 0, 40, 0, 1]); // INC $0, LOAD $40 #1
+        let outcome = vm.run();
+        assert_eq!(outcome.exit, ExitReason::InvalidRegister);
+        assert_eq!(outcome.trap.unwrap().pc, 68);
+        assert_eq!(
+            outcome.error,
+            Some(VmError::InvalidRegister {
+                register: 40,
+                pc: 68
+            })
+        );
+        assert_eq!(
+            outcome.error.unwrap().to_string(),
+            "Invalid register $40 at pc 68"
+        );
+        assert_eq!(vm.program_counter, 68);
+
+        // The last lane of $29 would be $32
+        let mut vm = VM::new();
+        vm.program = vec![Opcode::VADD as u8, 0, 29, 4];
+        assert_eq!(vm.run_once(), Some(ExitReason::InvalidRegister));
+
+        for value in 0..=u8::MAX {
+            let opcode = Opcode::from(value);
+            let has_register = opcode.info().is_some_and(|info| {
+                info.operands
+                    .iter()
+                    .any(|operand| operand.kind == OperandKind::Register)
+            });
+            if has_register {
+                let mut vm = VM::new();
+                vm.program = vec![value, 32, 32, 32];
+                assert_eq!(
+                    vm.run_once(),
+                    Some(ExitReason::InvalidRegister),
+                    "{opcode:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_negative_allocation() {
+        let mut vm = VM::new();
+        vm.program = prepend_header(vec![22, 1, 255, 255, 17, 1, 0, 0]); // LOADS $1 #-1, ALOC $1
+        let outcome = vm.run();
+        assert_eq!(outcome.exit, ExitReason::InvalidAllocation);
+        assert_eq!(outcome.error, Some(VmError::InvalidAllocation(-1)));
+        assert_eq!(vm.heap_size(), 0);
+    }
+
+    #[test]
+    fn test_relative_jump_out_of_bounds() {
+        let mut vm = VM::new();
+        vm.program = prepend_header(vec![22, 1, 255, 254, 7, 1, 0, 0]); // LOADS $1 #-2, JMPF $1
+        assert_eq!(vm.run().exit, ExitReason::CodeOutOfBounds);
+        assert_eq!(vm.program_counter, 68);
+
+        let mut vm = VM::new();
+        vm.program = vec![0, 1, 0, 100, 8, 1, 0, 0]; // LOAD $1 #100, JMPB $1
+        vm.run_once();
+        assert_eq!(vm.run_once(), Some(ExitReason::CodeOutOfBounds));
+        assert_eq!(
+            vm.error(),
+            Some(&VmError::OutOfBounds {
+                region: Region::Code,
+                address: 4,
+                len: 4
+            })
+        );
+    }
+
+    #[test]
+    fn test_absolute_jump_out_of_bounds() {
+        // (code, pc of the jump, its target), the code starting at 64
+        let cases = [
+            (vec![22, 0, 255, 255, 6, 0, 0, 0], 68, -1), // LOADS $0 #-1, JMP $0
+            (vec![0, 0, 0, 8, 6, 0, 0, 0], 68, 8),       // LOAD $0 #8, JMP $0 into the header
+            (vec![0, 0, 0, 72, 6, 0, 0, 0], 68, 72),     // LOAD $0 #72, JMP $0 past the end
+            (vec![22, 0, 255, 248, 9, 0, 0, 0, 15, 0, 0, 0], 72, -8), // LOADS, EQ, JEQ $0
+            (vec![0, 0, 0, 200, 9, 0, 0, 0, 15, 0, 0, 0], 72, 200), // LOAD, EQ, JEQ $0
+            (vec![42, 0, 8, 0], 64, 8),                  // CALL #8
+            (vec![42, 1, 0, 0], 64, 256),                // CALL #256
+            (vec![22, 0, 255, 248, 40, 0, 0, 0, 43, 0, 0, 0], 72, -8), // LOADS, PUSH $0, RET
+            (vec![0, 0, 0, 8, 40, 0, 0, 0, 43, 0, 0, 0], 72, 8), // LOAD, PUSH $0, RET
+        ];
+        for (code, pc, target) in cases {
+            let mut vm = VM::new();
+            let pushed = code.contains(&40);
+            vm.program = prepend_header(code);
+            let outcome = vm.run();
+            assert_eq!(outcome.exit, ExitReason::CodeOutOfBounds, "{target}");
+            assert_eq!(
+                outcome.error,
+                Some(VmError::OutOfBounds {
+                    region: Region::Code,
+                    address: target,
+                    len: 4
+                })
+            );
+            assert_eq!(vm.program_counter, pc);
+            assert_eq!(vm.stack.len(), pushed as usize);
+        }
+    }
+
+    #[test]
+    fn test_truncated_instruction() {
+        let mut vm = VM::new();
+        vm.program = prepend_header(vec![0, 0]); // LOAD $0, missing its value
+        let outcome = vm.run();
+        assert_eq!(outcome.exit, ExitReason::CodeOutOfBounds);
+        assert_eq!(
+            outcome.error,
+            Some(VmError::OutOfBounds {
+                region: Region::Code,
+                address: 64,
+                len: 4
+            })
+        );
+
+        let mut vm = VM::new();
+        vm.program = prepend_header(vec![18]); // INC, missing its register
+        assert_eq!(vm.run().exit, ExitReason::CodeOutOfBounds);
+        assert_eq!(vm.program_counter, 64);
+
+        let mut writer = ProgramWriter::new(vec![0, 0, 0, 0, 1]); // LOAD $0, half its value
+        writer.set_format(Format::Wide);
+        let mut vm = VM::new();
+        vm.load_program(writer.finish());
+        assert_eq!(vm.run().exit, ExitReason::CodeOutOfBounds);
+
+        // Every instruction cut short traps, and every one exactly as long as its operands
+        // runs without reading past them
+        for value in 0..=u8::MAX {
+            let Some(info) = Opcode::from(value).info() else {
+                continue;
+            };
+            let len: usize = 1 + info.operands.iter().map(|o| o.kind.width()).sum::<usize>();
+            let mut vm = VM::new();
+            vm.program = vec![value; len - 1];
+            if len > 1 {
+                assert_eq!(vm.run_once(), Some(ExitReason::CodeOutOfBounds));
+            }
+            let mut vm = VM::new();
+            vm.program = vec![value];
+            vm.program.resize(len, 0);
+            vm.run_once();
+        }
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        for opcode in [4, 53] {
+            let mut vm = VM::new();
+            vm.registers[0] = 7;
+            vm.registers[2] = 9;
+            vm.program = prepend_header(vec![opcode, 0, 1, 2]); // DIV/MOD $0 $1 $2
+            let outcome = vm.run();
+            assert_eq!(outcome.exit, ExitReason::DivisionByZero);
+            assert_eq!(outcome.trap.unwrap().pc, 64);
+            assert_eq!(vm.registers[2], 9);
+        }
+
+        let mut vm = VM::new();
+        vm.registers[0] = i32::MIN;
+        vm.registers[1] = -1;
+        vm.program = vec![4, 0, 1, 2, 53, 0, 1, 3]; // DIV $0 $1 $2, MOD $0 $1 $3
+        vm.run_once();
+        assert_eq!(vm.registers[2], i32::MIN);
+        assert!(vm.flags.contains(Flags::OVERFLOW));
+        vm.run_once();
+        assert_eq!(vm.registers[3], 0);
+    }
+
+    #[test]
+    fn test_opcode_mod() {
+        let mut vm = VM::new();
+        vm.registers[0] = -500;
+        vm.registers[1] = 6;
+        vm.remainder = 7;
+        vm.program = vec![53, 0, 1, 2]; // MOD $0 $1 $2
+        vm.run_once();
+        assert_eq!(vm.registers[2], -2);
+        assert_eq!(vm.remainder, 7);
+        assert!(vm.flags.contains(Flags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_opcode_mov() {
+        let mut vm = VM::new();
+        vm.registers[3] = -9;
+        vm.program = vec![54, 3, 7, 0]; // MOV $3 $7
+        vm.run_once();
+        assert_eq!(vm.registers[7], -9);
+        assert_eq!(vm.registers[3], -9);
+        assert_eq!(vm.flags.bits(), 0);
+    }
+
+    #[test]
+    fn test_opcode_jmp() {
+        let mut vm = VM::new();
+        // [opcode, register, operand, operand]
+        vm.registers[2] = 4;
+        vm.program = vec![6, 2, 0, 0, 5, 0, 0, 0]; // JMP $1 (JMP to Opcode at program[idx] where idx is the value stored at register 2)
+        vm.run_once();
+        assert_eq!(vm.program_counter, 4);
+    }
+
+    #[test]
+    fn test_opcode_jmpf() {
+        let mut vm = VM::new();
+        // [opcode, register, operand, operand]
+        vm.registers[2] = 2;
+        vm.program = vec![7, 2, 0, 0, 0, 0, 1, 124]; // JMP $1 (JMP to Opcode at program[idx] where idx is the value stored at register 2)
+        vm.run_once();
+        assert_eq!(vm.program_counter, 4);
+    }
+
+    #[test]
+    fn test_opcode_jmpb() {
+        let mut vm = VM::new();
+        // [opcode, register, operand, operand]
+        vm.registers[2] = 2;
+        vm.program = vec![8, 2, 0, 0, 0, 0, 1, 124]; // JMP $1 (JMP to Opcode at program[idx] where idx is the value stored at register 2)
+        vm.run_once();
+        assert_eq!(vm.program_counter, 0);
+    }
+
+    #[test]
+    fn test_opcode_eq_true() {
+        let mut vm = VM::new();
+        vm.registers[0] = 2;
+        vm.registers[1] = 2;
+        vm.program = vec![9, 0, 1, 0]; // EQ $0 $1
+        vm.run_once();
+        assert!(vm.flags.contains(Flags::COMPARISON));
+    }
+
+    #[test]
+    fn test_opcode_eq_false() {
+        let mut vm = VM::new();
+        vm.registers[0] = 2;
+        vm.registers[1] = 5;
+        vm.program = vec![9, 0, 1, 0]; // EQ $0 $1
+        vm.run_once();
+        assert!(!vm.flags.contains(Flags::COMPARISON));
+    }
+
+    #[test]
+    fn test_opcode_neq_true() {
+        let mut vm = VM::new();
+        vm.registers[0] = 1;
+        vm.registers[1] = 6;
+        vm.program = vec![10, 0, 1, 0]; // NEQ $0 $1
+        vm.run_once();
+        assert!(vm.flags.contains(Flags::COMPARISON));
+    }
+
+    #[test]
+    fn test_opcode_neq_false() {
+        let mut vm = VM::new();
+        vm.registers[0] = 2;
+        vm.registers[1] = 2;
+        vm.program = vec![10, 0, 1, 0]; // NEQ $0 $1
+        vm.run_once();
+        assert!(!vm.flags.contains(Flags::COMPARISON));
+    }
+
+    #[test]
+    fn test_opcode_gt_true() {
+        let mut vm = VM::new();
+        vm.registers[0] = 6;
+        vm.registers[1] = 5;
+        vm.program = vec![11, 0, 1, 0]; // GT $0 $1
+        vm.run_once();
+        assert!(vm.flags.contains(Flags::COMPARISON));
+    }
+
+    #[test]
+    fn test_opcode_gt_false() {
+        let mut vm = VM::new();
+        vm.registers[0] = 2;
+        vm.registers[1] = 2;
+        vm.program = vec![11, 0, 1, 0]; // GT $0 $1
+        vm.run_once();
+        assert!(!vm.flags.contains(Flags::COMPARISON));
+    }
+
+    #[test]
+    fn test_opcode_lt_true() {
+        let mut vm = VM::new();
+        vm.registers[0] = 5;
+        vm.registers[1] = 6;
+        vm.program = vec![12, 0, 1, 0]; // LT $0 $1
+        vm.run_once();
+        assert!(vm.flags.contains(Flags::COMPARISON));
+    }
+
+    #[test]
+    fn test_opcode_lt_false() {
+        let mut vm = VM::new();
+        vm.registers[0] = 2;
+        vm.registers[1] = 2;
+        vm.program = vec![12, 0, 1, 0]; // LT $0 $1
+        vm.run_once();
+        assert!(!vm.flags.contains(Flags::COMPARISON));
+    }
+
+    #[test]
+    fn test_opcode_gte_greater_true() {
+        let mut vm = VM::new();
+        vm.registers[0] = 6;
+        vm.registers[1] = 5;
+        vm.program = vec![13, 0, 1, 0]; // GTE $0 $1
+        vm.run_once();
+        assert!(vm.flags.contains(Flags::COMPARISON));
+    }
+
+    #[test]
+    fn test_opcode_gte_equal_true() {
+        let mut vm = VM::new();
+        vm.registers[0] = 6;
+        vm.registers[1] = 6;
+        vm.program = vec![13, 0, 1, 0]; // GTE $0 $1
+        vm.run_once();
+        assert!(vm.flags.contains(Flags::COMPARISON));
+    }
+
+    #[test]
+    fn test_opcode_gte_false() {
+        let mut vm = VM::new();
+        vm.registers[0] = 2;
+        vm.registers[1] = 4;
+        vm.program = vec![13, 0, 1, 0]; // GTE $0 $1
+        vm.run_once();
+        assert!(!vm.flags.contains(Flags::COMPARISON));
+    }
+
+    #[test]
+    fn test_opcode_lte_less_true() {
+        let mut vm = VM::new();
+        vm.registers[0] = 5;
+        vm.registers[1] = 6;
+        vm.program = vec![14, 0, 1, 0]; // LTE $0 $1
+        vm.run_once();
+        assert!(vm.flags.contains(Flags::COMPARISON));
+    }
+
+    #[test]
+    fn test_opcode_lte_equal_true() {
+        let mut vm = VM::new();
+        vm.registers[0] = 6;
+        vm.registers[1] = 6;
+        vm.program = vec![14, 0, 1, 0]; // LTE $0 $1
+        vm.run_once();
+        assert!(vm.flags.contains(Flags::COMPARISON));
+    }
+
+    #[test]
+    fn test_opcode_lte_false() {
+        let mut vm = VM::new();
+        vm.registers[0] = 4;
+        vm.registers[1] = 2;
+        vm.program = vec![14, 0, 1, 0]; // LTE $0 $1
+        vm.run_once();
+        assert!(!vm.flags.contains(Flags::COMPARISON));
+    }
+
+    #[test]
+    fn test_opcode_jeq() {
+        let mut vm = VM::new();
+        vm.registers[2] = 4;
+        vm.flags.set(Flags::COMPARISON, true);
+        vm.program = vec![15, 2, 0, 0, 5, 0, 0, 0]; // JEQ $0, HLT
+        vm.run_once();
+        assert_eq!(vm.program_counter, 4);
+    }
+
+    #[test]
+    fn test_opcode_jneq() {
+        let mut vm = VM::new();
+        vm.registers[2] = 4;
+        vm.flags.set(Flags::COMPARISON, false);
+        vm.program = vec![16, 2, 0, 0, 5, 0, 0, 0]; // JNEQ $0, HLT
+        vm.run_once();
+        assert_eq!(vm.program_counter, 4);
+    }
+
+    #[test]
+    fn test_arithmetic_flags() {
+        let mut vm = VM::new();
+        vm.registers[0] = i32::MAX;
+        vm.registers[1] = 1;
+        vm.registers[2] = -1;
+        // ADD $0 $1 $3, ADD $2 $1 $4, SUB $1 $1 $5, MFLAGS $6
+        vm.program = vec![1, 0, 1, 3, 1, 2, 1, 4, 2, 1, 1, 5, 24, 6, 0, 0];
+        vm.run_once();
+        assert_eq!(vm.registers[3], i32::MIN);
+        assert_eq!(vm.flags.bits(), Flags::NEGATIVE | Flags::OVERFLOW);
+        vm.run_once();
+        assert_eq!(vm.flags.bits(), Flags::ZERO | Flags::CARRY);
+        vm.run_once();
+        vm.run_once();
+        assert_eq!(vm.registers[6], Flags::ZERO as i32);
+    }
+
+    #[test]
+    fn test_signed_branches() {
+        // (a, b, opcodes that jump after LT $a $b)
+        let cases = [
+            (1, 2, [true, false, true, false]),
+            (2, 2, [false, false, true, true]),
+            (3, 2, [false, true, false, true]),
+            (i32::MIN, 1, [true, false, true, false]),
+            (i32::MAX, -1, [false, true, false, true]),
+        ];
+        for (a, b, expected) in cases {
+            for (opcode, taken) in (25..=28).zip(expected) {
+                let mut vm = VM::new();
+                vm.registers[0] = a;
+                vm.registers[1] = b;
+                vm.registers[2] = 12;
+                // LT $0 $1, then JLT/JGT/JLE/JGE $2, HLT, HLT
+                vm.program = vec![12, 0, 1, 0, opcode, 2, 0, 0, 5, 0, 0, 0, 5, 0, 0, 0];
+                vm.run_once();
+                vm.run_once();
+                let expected_pc = if taken { 12 } else { 8 };
+                assert_eq!(vm.program_counter, expected_pc, "{a} {b} opcode {opcode}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_flag_jumps() {
+        // (a, b, [JZ, JNZ, JC, JO] taken after ADD $0 $1 $3)
+        let cases = [
+            (0, 0, [true, false, false, false]),
+            (-1, 1, [true, false, true, false]),
+            (i32::MAX, 1, [false, true, false, true]),
+            (2, 3, [false, true, false, false]),
+        ];
+        for (a, b, expected) in cases {
+            for (opcode, taken) in (66..=69).zip(expected) {
+                let mut vm = VM::new();
+                vm.registers[0] = a;
+                vm.registers[1] = b;
+                vm.registers[2] = 12;
+                vm.program = vec![1, 0, 1, 3, opcode, 2, 0, 0, 5, 0, 0, 0, 5, 0, 0, 0];
+                vm.run_once();
+                vm.run_once();
+                let expected_pc = if taken { 12 } else { 8 };
+                assert_eq!(vm.program_counter, expected_pc, "{a} {b} opcode {opcode}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_opcode_cmov() {
+        let mut vm = VM::new();
+        vm.registers[0] = 3;
+        vm.registers[1] = 7;
+        // GT $1 $0, CMOV $2 $1, LT $1 $0, CMOV $3 $1
+        vm.program = vec![11, 1, 0, 0, 29, 2, 1, 0, 12, 1, 0, 0, 29, 3, 1, 0];
+        for _ in 0..4 {
+            vm.run_once();
+        }
+        assert_eq!(vm.registers[2..4], [7, 0]);
+    }
+
+    #[test]
+    fn test_bitwise_ops() {
+        let mut vm = VM::new();
+        vm.registers[0] = 0b1100;
+        vm.registers[1] = 0b1010;
+        // AND $0 $1 $2, OR $0 $1 $3, XOR $0 $1 $4, NOT $0 $5, XOR $0 $0 $6
+        vm.program = vec![
+            44, 0, 1, 2, 45, 0, 1, 3, 46, 0, 1, 4, 47, 0, 5, 0, 46, 0, 0, 6,
+        ];
+        for _ in 0..4 {
+            vm.run_once();
+        }
+        assert_eq!(vm.registers[2..6], [0b1000, 0b1110, 0b0110, !0b1100]);
+        assert!(vm.flags().contains(Flags::NEGATIVE));
+        vm.run_once();
+        assert_eq!(vm.registers[6], 0);
+        assert!(vm.flags().contains(Flags::ZERO));
+    }
+
+    #[test]
+    fn test_shifts() {
+        let mut vm = VM::new();
+        vm.registers[0] = 1;
+        vm.registers[1] = 4;
+        vm.registers[2] = -16;
+        vm.registers[3] = 40;
+        // SHL $0 $1, SHRI $2 #2, SHLI $1 #29, SHR $0 $3
+        vm.program = vec![48, 0, 1, 0, 51, 2, 0, 2, 50, 1, 0, 29, 49, 0, 3, 0];
+        vm.run_once();
+        vm.run_once();
+        assert_eq!(vm.registers[0], 16);
+        assert_eq!(vm.registers[2], (-16i32 as u32 >> 2) as i32);
+        vm.run_once();
+        assert_eq!(vm.registers[1], i32::MIN);
+        assert!(vm.flags().contains(Flags::NEGATIVE));
+        vm.run_once();
+        assert_eq!(vm.registers[0], 0);
+        assert!(vm.flags().contains(Flags::ZERO));
+    }
+
+    #[test]
+    fn test_opcode_lw_sw() {
+        let mut vm = VM::new();
+        vm.heap = vec![0; 8];
+        vm.registers[0] = -2;
+        vm.registers[1] = 4;
+        // SW $0 $1, LW $2 $1
+        vm.program = vec![56, 0, 1, 0, 55, 2, 1, 0];
+        vm.run_once();
+        assert_eq!(vm.heap[4..], [255, 255, 255, 254]);
+        vm.run_once();
+        assert_eq!(vm.registers[2], -2);
+
+        for address in [5, -1] {
+            vm.registers[1] = address;
+            vm.program_counter = 0;
+            assert_eq!(vm.run_once(), Some(ExitReason::HeapOutOfBounds));
+            vm.program_counter = 4;
+            assert_eq!(vm.run_once(), Some(ExitReason::HeapOutOfBounds));
+        }
+        assert_eq!(vm.heap.len(), 8);
+    }
+
+    #[test]
+    fn test_opcode_lb_sb() {
+        let mut vm = VM::new();
+        vm.heap = vec![0; 2];
+        vm.registers[0] = 0x1F0;
+        vm.registers[1] = 1;
+        // SB $0 $1, LB $2 $1
+        vm.program = vec![58, 0, 1, 0, 57, 2, 1, 0];
+        vm.run_once();
+        assert_eq!(vm.heap, [0, 0xF0]);
+        vm.run_once();
+        assert_eq!(vm.registers[2], -16);
+
+        vm.registers[1] = 2;
+        vm.program_counter = 0;
+        assert_eq!(vm.run_once(), Some(ExitReason::HeapOutOfBounds));
+        vm.program_counter = 4;
+        assert_eq!(vm.run_once(), Some(ExitReason::HeapOutOfBounds));
+    }
+
+    #[test]
+    fn test_opcode_prts() {
+        let mut writer = ProgramWriter::new(vec![59, 0, 3, 0, 59, 0, 9, 0]); // PRTS #3, PRTS #9
+        writer
+            .add_section(SectionKind::Data, "rodata", b"hi\0yo\0".to_vec())
+            .unwrap();
+        let mut vm = VM::new();
+        vm.load_program(writer.finish());
+        assert!(vm.start().is_ok());
+        assert_eq!(vm.rodata, b"hi\0yo\0");
+        assert_eq!(vm.run_once(), None);
+        assert_eq!(vm.run_once(), Some(ExitReason::InvalidString));
+    }
+
+    #[test]
+    fn test_prts_output_error() {
+        struct Closed;
+        impl Write for Closed {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut writer = ProgramWriter::new(vec![59, 0, 0, 0]); // PRTS #0
+        writer
+            .add_section(SectionKind::Data, "rodata", b"hi\0".to_vec())
+            .unwrap();
+        let mut vm = VM::new();
+        vm.set_output(Box::new(Closed));
+        vm.load_program(writer.finish());
+        let outcome = vm.run();
+        assert_eq!(outcome.exit, ExitReason::OutputFailed);
+        assert_eq!(
+            outcome.error.unwrap().to_string(),
+            "Unable to write output: broken pipe"
+        );
+    }
+
+    #[test]
+    fn test_opcode_lui_ori() {
+        let mut vm = VM::new();
+        vm.registers[0] = 5;
+        // LUI $0 #0xDEAD, ORI $0 #0xBEEF, LUI $1 #1, ORI $1 #0x86A0
+        vm.program = vec![
+            60, 0, 0xDE, 0xAD, 61, 0, 0xBE, 0xEF, 60, 1, 0, 1, 61, 1, 0x86, 0xA0,
+        ];
+        vm.run_once();
+        assert_eq!(vm.registers[0], 0xDEAD0000_u32 as i32);
+        vm.run_once();
+        assert_eq!(vm.registers[0], 0xDEADBEEF_u32 as i32);
+        vm.run_once();
+        vm.run_once();
+        assert_eq!(vm.registers[1], 100_000);
+    }
+
+    #[test]
+    fn test_set_on_comparison() {
+        let mut vm = VM::new();
+        vm.registers[0] = -3;
+        vm.registers[1] = 2;
+        // SEQ, SNE, SLT and SLE of $0 and $1 into $2..$5, then SLT and SLE of $1 and $0
+        vm.program = vec![
+            62, 0, 1, 2, 63, 0, 1, 3, 64, 0, 1, 4, 65, 0, 1, 5, 64, 1, 0, 6, 65, 0, 0, 7,
+        ];
+        for _ in 0..6 {
+            vm.run_once();
+        }
+        assert_eq!(vm.registers[2..8], [0, 1, 1, 1, 0, 1]);
+        assert_eq!(vm.flags.bits(), 0);
+    }
+
+    #[test]
+    fn test_opcode_push_pop() {
+        let mut vm = VM::new();
+        vm.registers[0] = 5;
+        vm.registers[1] = 6;
+        // PUSH $0, PUSH $1, POP $2, POP $3, POP $4
+        vm.program = vec![
+            40, 0, 0, 0, 40, 1, 0, 0, 41, 2, 0, 0, 41, 3, 0, 0, 41, 4, 0, 0,
+        ];
+        vm.run_once();
+        vm.run_once();
+        assert_eq!(vm.stack(), &[5, 6]);
+        assert_eq!(vm.stack_pointer(), 2);
+        vm.run_once();
+        vm.run_once();
+        assert_eq!(vm.registers[2..4], [6, 5]);
+        assert_eq!(vm.run_once(), Some(ExitReason::StackUnderflow));
+
+        let mut vm = VM::new();
+        vm.set_stack_limit(1);
+        vm.program = vec![40, 0, 0, 0, 40, 0, 0, 0];
+        vm.run_once();
+        assert_eq!(vm.run_once(), Some(ExitReason::StackOverflow));
+    }
+
+    #[test]
+    fn test_opcode_call_ret() {
+        let mut vm = VM::new();
+        // CALL #12, INC $0, HLT, INC $1, RET
+        vm.program = vec![
+            42, 0, 12, 0, 18, 0, 0, 0, 5, 0, 0, 0, 18, 1, 0, 0, 43, 0, 0, 0,
+        ];
+        vm.run_once();
+        assert_eq!(vm.program_counter(), 12);
+        assert_eq!(vm.stack(), &[4]);
+        vm.run_once();
+        vm.run_once();
+        assert_eq!(vm.program_counter(), 4);
+        assert!(vm.stack().is_empty());
+        assert_eq!(vm.run_once(), None);
+        assert_eq!(vm.run_once(), Some(ExitReason::Halted));
+        assert_eq!(vm.registers[..2], [1, 1]);
+        // Nothing left to return to
+        vm.program_counter = 16;
+        assert_eq!(vm.run_once(), Some(ExitReason::StackUnderflow));
+    }
+
+    #[test]
+    fn test_opcode_rdcycle() {
+        let mut vm = VM::new();
+        let mut model = CostModel::uniform(1);
+        model.set_cost(Opcode::MUL, 5);
+        vm.set_cycle_model(model);
+        // MUL $0 $0 $0, RDCYCLE $1, HLT
+        vm.program = vec![3, 0, 0, 0, 52, 1, 0, 0, 5, 0, 0, 0];
+        for _ in 0..3 {
+            vm.run_once();
+        }
+        assert_eq!(vm.registers[1], 6);
+        assert_eq!(vm.cycles(), 7);
+    }
+
+    #[test]
+    fn test_opcode_rdperf() {
+        let mut vm = VM::new();
+        vm.registers[0] = 8;
+        // JMP $0, RDPERF $1 #0, RDPERF $2 #1, RDPERF $3 #9
+        vm.program = vec![6, 0, 0, 0, 38, 1, 0, 0, 38, 2, 0, 1, 38, 3, 0, 9];
+        for _ in 0..3 {
+            vm.run_once();
+        }
+        assert_eq!(vm.registers[1..4], [0, 1, 0]);
+        assert_eq!(vm.program_counter(), 16);
+
+        vm.program_counter = 4;
+        vm.run_once();
+        assert_eq!(vm.registers[1], 4);
+    }
+
+    #[test]
+    fn test_export_import_state() {
+        let mut vm = VM::new();
+        vm.registers[0] = 5;
+        vm.registers[31] = -7;
+        vm.flags.set(Flags::CARRY | Flags::COMPARISON, true);
+
+        for format in [StateFormat::Json, StateFormat::Csv] {
+            let state = vm.export_state(format);
+            let mut imported = VM::new();
+            imported.import_state(&state, format).unwrap();
+            assert_eq!(imported.registers, vm.registers);
+            assert_eq!(imported.flags, vm.flags);
+        }
+        assert!(vm
+            .export_state(StateFormat::Json)
+            .starts_with(r#"{"registers":[5,0,"#));
+        assert!(vm
+            .export_state(StateFormat::Csv)
+            .starts_with("name,value\n$0,5\n$1,0\n"));
+
+        let mut partial = VM::new();
+        partial
+            .import_state(r#"{"registers": [1, 2]}"#, StateFormat::Json)
+            .unwrap();
+        assert_eq!(partial.registers[..3], [1, 2, 0]);
+
+        for (source, format) in [
+            (r#"{"registers": [4294967296]}"#, StateFormat::Json),
+            (r#"{"flags": 64}"#, StateFormat::Json),
+            (r#"{"registers": 1}"#, StateFormat::Json),
+            ("name,value\n$32,1", StateFormat::Csv),
+            ("name,value\n$0,x", StateFormat::Csv),
+        ] {
+            let mut vm = VM::new();
+            vm.registers[0] = 9;
+            assert!(vm.import_state(source, format).is_err(), "{source}");
+            assert_eq!(vm.registers[0], 9);
+        }
+    }
+
+    #[test]
+    fn test_vector_ops() {
+        let mut vm = VM::new();
+        vm.registers[..8].copy_from_slice(&[1, 2, 3, i32::MAX, 10, 20, 30, 1]);
+        // VADD $0 $4 $8, VMUL $0 $4 $12, VADD $0 $0 $0
+        vm.program = vec![30, 0, 4, 8, 31, 0, 4, 12, 30, 0, 0, 0];
+        for _ in 0..3 {
+            vm.run_once();
+        }
+        assert_eq!(vm.registers[8..12], [11, 22, 33, i32::MIN]);
+        assert_eq!(vm.registers[12..16], [10, 40, 90, i32::MAX]);
+        assert_eq!(vm.registers[..4], [2, 4, 6, -2]);
+    }
+
+    #[test]
+    fn test_compare_and_branch() {
+        let mut vm = VM::new();
+        vm.registers[0] = 1;
+        vm.registers[1] = 2;
+        vm.registers[2] = 12;
+        // BGT $0 $1 $2, BLT $0 $1 $2, HLT, HLT
+        vm.program = vec![34, 0, 1, 2, 35, 0, 1, 2, 5, 0, 0, 0, 5, 0, 0, 0];
+        vm.run_once();
+        assert_eq!(vm.program_counter, 4);
+        assert!(!vm.flags.contains(Flags::COMPARISON));
+        vm.run_once();
+        assert_eq!(vm.program_counter, 12);
+        assert!(vm.flags.contains(Flags::COMPARISON));
+        assert!(vm.flags.contains(Flags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_opcode_aloc_on_empty_heap() {
+        let mut vm = VM::new();
+        vm.registers[0] = 1024;
+        vm.program = vec![17, 0, 0, 0]; // ALOC $0
+        vm.run_once();
+        assert_eq!(vm.heap.len(), 1024);
+    }
+
+    #[test]
+    fn test_opcode_aloc_extend_heap() {
+        let mut vm = VM::new();
+        vm.heap.extend_from_slice(&[0u8; 8]);
+        vm.registers[0] = 1024;
+        vm.program = vec![17, 0, 0, 0]; // ALOC $0
+        vm.run_once();
+        assert_eq!(vm.heap.len(), 1032);
+    }
+
+    #[test]
+    fn test_opcode_inc() {
+        let mut vm = VM::new();
+        println!("=>> {}", vm.program_counter);
+        vm.registers[0] = 1024;
+        vm.program = vec![18, 0, 0, 0]; // INC $0
+        vm.run_once();
+        println!("{:?}", vm.registers);
+        assert_eq!(vm.registers[0], 1025);
     }
 
     #[test]
-    fn test_new_vm() {
-        let vm = VM::new();
-        assert_eq!(vm.registers, [0; 32]);
+    fn test_opcode_dec() {
+        let mut vm = VM::new();
+        vm.registers[0] = 1024;
+        vm.program = vec![19, 0, 0, 0]; // DEC $0
+        vm.run_once();
+        assert_eq!(vm.registers[0], 1023);
     }
 
     #[test]
-    fn test_opcode_hlt() {
+    fn test_opcode_fuel() {
         let mut vm = VM::new();
-        vm.program = vec![5, 0, 0, 0];
+        vm.set_fuel(10);
+        vm.program = vec![20, 3, 0, 0]; // FUEL $3
         vm.run_once();
-        assert_eq!(vm.program_counter, 1);
+        assert_eq!(vm.registers[3], 9);
     }
 
     #[test]
-    fn test_opcode_igl() {
+    fn test_opcode_fuel_unmetered() {
         let mut vm = VM::new();
-        vm.program = vec![255, 0, 0, 0];
+        vm.program = vec![20, 3, 0, 0]; // FUEL $3
         vm.run_once();
-        assert_eq!(vm.program_counter, 1);
+        assert_eq!(vm.registers[3], -1);
     }
 
     #[test]
-    fn test_opcode_load() {
+    fn test_opcode_fuel_saturates() {
         let mut vm = VM::new();
-        // [opcode, register, operand, operand]
-        vm.program = vec![0, 0, 1, 244];
+        vm.set_fuel(u64::MAX);
+        vm.program = vec![20, 3, 0, 0]; // FUEL $3
         vm.run_once();
-        assert_eq!(vm.registers[0], 500);
+        assert_eq!(vm.registers[3], i32::MAX);
     }
 
     #[test]
-    fn test_opcode_add() {
+    fn test_add_program() {
         let mut vm = VM::new();
-        // [opcode, register, operand, operand]
-        vm.program = prepend_header(vec![0, 0, 1, 244]); // LOAD $0 #500
-        vm.program.extend_from_slice(&vec![0, 1, 0, 7]); // LOAD $1 #7
-        vm.program.extend_from_slice(&vec![1, 0, 1, 2]); // ADD $0 $1 $2 (ADD  registers 0 and 1 and set result to register 2)
-        vm.run();
-        assert_eq!(vm.registers[2], 507);
+        let bytes = vec![19, 0, 0, 0]; // DEC $0
+        vm.add_program(bytes.clone());
+        assert_eq!(vm.program, bytes);
     }
 
     #[test]
-    fn test_opcode_sub() {
+    fn test_extend_program() {
         let mut vm = VM::new();
-        // [opcode, register, operand, operand]
-        vm.program = prepend_header(vec![0, 0, 1, 244]); // LOAD $0 #500
-        vm.program.extend_from_slice(&vec![0, 1, 0, 7]); // LOAD $1 #7
-        vm.program.extend_from_slice(&vec![2, 0, 1, 2]); // SUB $0 $1 $2 (ADD  registers 0 and 1 and set result to register 2)
-        vm.run();
-        assert_eq!(vm.registers[2], 493);
+        vm.program = vec![18, 0, 0, 0]; // INC $0
+        let bytes = vec![19, 0, 0, 0]; // DEC $0
+        vm.add_program(bytes.clone());
+        assert_eq!(vm.program, vec![18, 0, 0, 0, 19, 0, 0, 0]);
     }
 
     #[test]
-    fn test_opcode_mul() {
+    fn test_valid_header_true() {
         let mut vm = VM::new();
-        // [opcode, register, operand, operand]
-        vm.program = prepend_header(vec![0, 0, 1, 244]); // LOAD $0 #500
-        vm.program.extend_from_slice(&vec![0, 1, 0, 7]); // LOAD $1 #7
-        vm.program.extend_from_slice(&vec![3, 0, 1, 2]); // MUL $0 $1 $2 (ADD  registers 0 and 1 and set result to register 2)
-        vm.run();
-        assert_eq!(vm.registers[2], 3500);
+        let mut header = [0u8; 64];
+        header[..4].copy_from_slice(&PIE_HEADER_PREFIX);
+        let mut program = header.to_vec();
+        program.append(&mut vec![18, 0, 0, 0, 19, 0, 0, 0]);
+        vm.program = program;
+        assert!(vm.start().is_ok());
     }
 
     #[test]
-    fn test_opcode_div_without_remainder() {
+    fn test_valid_header_false() {
         let mut vm = VM::new();
-        // [opcode, register, operand, operand]
-        vm.program = prepend_header(vec![0, 0, 1, 244]); // LOAD $0 #500
-        vm.program.extend_from_slice(&vec![0, 1, 0, 5]); // LOAD $1 #5
-        vm.program.extend_from_slice(&vec![4, 0, 1, 2]); // MUL $0 $1 $2 (ADD  registers 0 and 1 and set result to register 2)
-        vm.run();
-        assert_eq!(vm.registers[2], 100);
-        assert_eq!(vm.remainder, 0);
+        let header = [0u8; 64];
+        let mut program = header.to_vec();
+        program.append(&mut vec![18, 0, 0, 0, 19, 0, 0, 0]);
+        vm.program = program;
+        assert!(vm.start().is_err());
     }
 
     #[test]
-    fn test_opcode_div_with_remainder() {
+    fn test_run_stops_at_hlt() {
         let mut vm = VM::new();
-        // [opcode, register, operand, operand]
-        vm.program = prepend_header(vec![0, 0, 1, 244]); // LOAD $0 #500
-        vm.program.extend_from_slice(&vec![0, 1, 0, 6]); // LOAD $1 #6
-        vm.program.extend_from_slice(&vec![4, 0, 1, 2]); // MUL $0 $1 $2 (ADD  registers 0 and 1 and set result to register 2)
+        vm.program = prepend_header(vec![5, 0, 0, 0]); // HLT
+        vm.program.extend_from_slice(&[18, 0, 0, 0]); // INC $0
         vm.run();
-        assert_eq!(vm.registers[2], 83);
-        assert_eq!(vm.remainder, 2);
+        assert_eq!(vm.registers[0], 0);
     }
 
     #[test]
-    fn test_opcode_jmp() {
+    fn test_run_stops_at_end_of_code_section() {
+        let mut writer = ProgramWriter::new(vec![18, 0, 0, 0]); // INC $0
+        writer
+            .add_section(SectionKind::Data, "data", vec![18, 0, 0, 0])
+            .unwrap();
         let mut vm = VM::new();
-        // [opcode, register, operand, operand]
-        vm.registers[2] = 7;
-        vm.program = vec![6, 2, 0, 0]; // JMP $1 (JMP to Opcode at program[idx] where idx is the value stored at register 2)
-        vm.run_once();
-        assert_eq!(vm.program_counter, 7);
+        vm.load_program(writer.finish());
+        let outcome = vm.run();
+        assert_eq!(outcome.exit, ExitReason::EndOfProgram);
+        assert_eq!(vm.registers[0], 1);
     }
 
     #[test]
-    fn test_opcode_jmpf() {
+    fn test_start_maps_bss_into_heap() {
+        let mut writer = ProgramWriter::new(vec![5, 0, 0, 0]); // HLT
+        writer.add_bss("bss", 100).unwrap();
+        let program = writer.finish();
+
         let mut vm = VM::new();
-        // [opcode, register, operand, operand]
-        vm.registers[2] = 2;
-        vm.program = vec![7, 2, 0, 0, 0, 0, 1, 124]; // JMP $1 (JMP to Opcode at program[idx] where idx is the value stored at register 2)
-        vm.run_once();
-        assert_eq!(vm.program_counter, 4);
+        vm.load_program(program.clone());
+        assert!(vm.start().is_ok());
+        assert_eq!(vm.heap_size(), 100);
+
+        let mut vm = VM::new();
+        vm.set_heap_limit(99);
+        vm.load_program(program);
+        assert!(vm.start().is_err());
     }
 
     #[test]
-    fn test_opcode_jmpb() {
+    fn test_opcode_loadc_out_of_pool() {
+        // LOADC $0 #1 with a one entry pool
+        let mut writer = ProgramWriter::new(vec![39, 0, 0, 1]);
+        writer
+            .add_section(SectionKind::Constants, "constants", vec![0, 0, 0, 7])
+            .unwrap();
+
         let mut vm = VM::new();
-        // [opcode, register, operand, operand]
-        vm.registers[2] = 2;
-        vm.program = vec![8, 2, 0, 0, 0, 0, 1, 124]; // JMP $1 (JMP to Opcode at program[idx] where idx is the value stored at register 2)
-        vm.run_once();
-        assert_eq!(vm.program_counter, 0);
+        vm.load_program(writer.finish());
+        assert_eq!(vm.run().exit, ExitReason::InvalidConstant);
     }
 
     #[test]
-    fn test_opcode_eq_true() {
+    fn test_write_heap() {
         let mut vm = VM::new();
-        vm.registers[0] = 2;
-        vm.registers[1] = 2;
-        vm.program = vec![9, 0, 1, 0]; // EQ $0 $1
-        vm.run_once();
-        assert!(vm.equal_flag);
+        vm.set_heap_limit(16);
+        vm.write_heap(4, &[1, 2]).unwrap();
+        assert_eq!(vm.heap(), &[0, 0, 0, 0, 1, 2]);
+        vm.write_heap(0, &[9]).unwrap();
+        assert_eq!(vm.heap_size(), 6);
+        assert!(vm.write_heap(15, &[1, 2]).is_err());
+        assert!(vm.write_heap(usize::MAX, &[1]).is_err());
     }
 
     #[test]
-    fn test_opcode_eq_false() {
+    fn test_heap_accessors() {
         let mut vm = VM::new();
-        vm.registers[0] = 2;
-        vm.registers[1] = 5;
-        vm.program = vec![9, 0, 1, 0]; // EQ $0 $1
-        vm.run_once();
-        assert!(!vm.equal_flag);
+        vm.write_heap(0, &[0; 12]).unwrap();
+        vm.write_i32(0, -2).unwrap();
+        vm.write_bytes(4, b"hi\0").unwrap();
+        assert_eq!(vm.read_i32(0), Ok(-2));
+        assert_eq!(vm.read_bytes(0, 4), Ok(&[255, 255, 255, 254][..]));
+        assert_eq!(vm.read_cstr(4), Ok("hi".to_string()));
+        assert_eq!(vm.read_cstr(6), Ok(String::new()));
+
+        assert!(vm.read_i32(9).is_err());
+        assert!(vm.read_bytes(usize::MAX, 2).is_err());
+        assert!(vm.write_bytes(11, &[1, 2]).is_err());
+        assert_eq!(vm.heap_size(), 12);
+        vm.write_bytes(8, &[b'x'; 4]).unwrap();
+        assert!(vm.read_cstr(8).is_err());
+        assert!(vm.read_cstr(13).is_err());
     }
 
     #[test]
-    fn test_opcode_neq_true() {
+    fn test_start_keeps_initialised_heap() {
+        let mut writer = ProgramWriter::new(vec![5, 0, 0, 0]); // HLT
+        writer.add_bss("bss", 8).unwrap();
         let mut vm = VM::new();
-        vm.registers[0] = 1;
-        vm.registers[1] = 6;
-        vm.program = vec![10, 0, 1, 0]; // NEQ $0 $1
-        vm.run_once();
-        assert!(vm.equal_flag);
+        vm.load_program(writer.finish());
+        vm.write_heap(2, &[7]).unwrap();
+        assert!(vm.start().is_ok());
+        assert_eq!(vm.heap(), &[0, 0, 7, 0, 0, 0, 0, 0]);
     }
 
     #[test]
-    fn test_opcode_neq_false() {
+    fn test_run_without_header() {
         let mut vm = VM::new();
-        vm.registers[0] = 2;
-        vm.registers[1] = 2;
-        vm.program = vec![10, 0, 1, 0]; // NEQ $0 $1
-        vm.run_once();
-        assert!(!vm.equal_flag);
+        vm.program = vec![18, 0, 0, 0]; // INC $0
+        vm.run();
+        assert_eq!(vm.registers[0], 0);
     }
 
     #[test]
-    fn test_opcode_gt_true() {
+    fn test_register_accessors() {
         let mut vm = VM::new();
-        vm.registers[0] = 6;
-        vm.registers[1] = 5;
-        vm.program = vec![11, 0, 1, 0]; // GT $0 $1
-        vm.run_once();
-        assert!(vm.equal_flag);
+        vm.set_register(3, 42).unwrap();
+        assert_eq!(vm.register(3), Some(42));
+        assert_eq!(vm.registers()[3], 42);
+        assert_eq!(vm.register(32), None);
+        assert_eq!(
+            vm.set_register(32, 1),
+            Err("Invalid register: 32".to_string())
+        );
     }
 
     #[test]
-    fn test_opcode_gt_false() {
+    fn test_load_program() {
         let mut vm = VM::new();
-        vm.registers[0] = 2;
-        vm.registers[1] = 2;
-        vm.program = vec![11, 0, 1, 0]; // GT $0 $1
+        vm.add_program(vec![18, 0, 0, 0]); // INC $0
         vm.run_once();
-        assert!(!vm.equal_flag);
+        vm.load_program(vec![19, 1, 0, 0]); // DEC $1
+        assert_eq!(vm.program_counter(), 0);
+        assert_eq!(vm.program(), &[19, 1, 0, 0]);
+        vm.run_once();
+        assert_eq!(vm.register(1), Some(-1));
     }
 
     #[test]
-    fn test_opcode_lt_true() {
+    fn test_opcode_yieldto() {
         let mut vm = VM::new();
-        vm.registers[0] = 5;
-        vm.registers[1] = 6;
-        vm.program = vec![12, 0, 1, 0]; // LT $0 $1
-        vm.run_once();
-        assert!(vm.equal_flag);
+        vm.registers[2] = 7;
+        vm.program = vec![21, 2, 0, 0, 18, 0, 0, 0]; // YIELDTO $2, INC $0
+        assert_eq!(vm.run_once(), None);
+        assert_eq!(vm.take_yield(), Some(7));
+        assert_eq!(vm.take_yield(), None);
+        assert_eq!(vm.run_once(), None);
+        assert_eq!(vm.registers[0], 1);
     }
 
     #[test]
-    fn test_opcode_lt_false() {
+    fn test_exit_hooks() {
+        let events = Arc::new(Mutex::new(Vec::new()));
         let mut vm = VM::new();
-        vm.registers[0] = 2;
-        vm.registers[1] = 2;
-        vm.program = vec![12, 0, 1, 0]; // LT $0 $1
-        vm.run_once();
-        assert!(!vm.equal_flag);
+        let log = Arc::clone(&events);
+        vm.on_halt(move |vm, exit| log.lock().unwrap().push(("halt", exit, vm.registers[0])));
+        let log = Arc::clone(&events);
+        vm.on_trap(move |vm, exit| log.lock().unwrap().push(("trap", exit, vm.registers[0])));
+        let log = Arc::clone(&events);
+        vm.on_out_of_fuel(move |_, exit| log.lock().unwrap().push(("fuel", exit, 0)));
+
+        vm.program = prepend_header(vec![18, 0, 0, 0, 5, 0, 0, 0]); // INC $0, HLT
+        vm.run();
+        vm.set_fuel(1);
+        vm.run();
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                ("halt", ExitReason::Halted, 1),
+                ("trap", ExitReason::OutOfFuel, 2),
+                ("fuel", ExitReason::OutOfFuel, 0),
+            ]
+        );
     }
 
     #[test]
-    fn test_opcode_gte_greater_true() {
+    fn test_exit_hooks_fire_from_run_once() {
+        let halted = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&halted);
         let mut vm = VM::new();
-        vm.registers[0] = 6;
-        vm.registers[1] = 5;
-        vm.program = vec![13, 0, 1, 0]; // GTE $0 $1
-        vm.run_once();
-        assert!(vm.equal_flag);
+        vm.on_halt(move |_, _| flag.store(true, Ordering::Relaxed));
+        vm.program = vec![18, 0, 0, 0]; // INC $0
+        assert_eq!(vm.run_once(), None);
+        assert!(!halted.load(Ordering::Relaxed));
+        assert_eq!(vm.run_once(), Some(ExitReason::EndOfProgram));
+        assert!(halted.load(Ordering::Relaxed));
     }
 
     #[test]
-    fn test_opcode_gte_equal_true() {
+    fn test_sampler() {
+        let samples = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&samples);
         let mut vm = VM::new();
-        vm.registers[0] = 6;
-        vm.registers[1] = 6;
-        vm.program = vec![13, 0, 1, 0]; // GTE $0 $1
-        vm.run_once();
-        assert!(vm.equal_flag);
+        vm.set_sampler(3, move |vm| log.lock().unwrap().push(vm.program_counter()));
+        vm.program = prepend_header([18, 0, 0, 0].repeat(8)); // INC $0 x8
+        vm.run();
+        assert_eq!(*samples.lock().unwrap(), [72, 84]);
+
+        samples.lock().unwrap().clear();
+        vm.set_sample_interval(4);
+        vm.run();
+        // One instruction was left from the last run
+        assert_eq!(*samples.lock().unwrap(), [64, 80]);
+
+        vm.set_sample_interval(0);
+        vm.run();
+        assert_eq!(samples.lock().unwrap().len(), 2);
     }
 
     #[test]
-    fn test_opcode_gte_false() {
+    fn test_vm_error() {
         let mut vm = VM::new();
-        vm.registers[0] = 2;
-        vm.registers[1] = 4;
-        vm.program = vec![13, 0, 1, 0]; // GTE $0 $1
-        vm.run_once();
-        assert!(!vm.equal_flag);
+        vm.program = vec![1, 2, 3];
+        let outcome = vm.run();
+        assert_eq!(outcome.exit, ExitReason::InvalidHeader);
+        assert_eq!(
+            outcome.error,
+            Some(VmError::InvalidHeader("Invalid header".into()))
+        );
+
+        vm.program = prepend_header(vec![200, 0, 0, 0]);
+        let outcome = vm.run();
+        assert_eq!(outcome.error, Some(VmError::IllegalOpcode(200)));
+        assert_eq!(outcome.error.unwrap().reason(), outcome.exit);
+
+        vm.set_heap_limit(16);
+        vm.registers[0] = 32;
+        vm.program = prepend_header(vec![17, 0, 0, 0]); // ALOC $0
+        let error = vm.run().error.unwrap();
+        assert_eq!(
+            error,
+            VmError::HeapLimitExceeded {
+                requested: 32,
+                limit: 16
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "Heap of 32 bytes would exceed the limit of 16 bytes"
+        );
+
+        vm.program = vec![55, 1, 0, 0, 5, 0, 0, 0]; // LW $1 $0, HLT
+        vm.program_counter = 0;
+        vm.code = None;
+        assert_eq!(vm.run_once(), Some(ExitReason::HeapOutOfBounds));
+        assert_eq!(
+            vm.error(),
+            Some(&VmError::OutOfBounds {
+                region: Region::Heap,
+                address: 32,
+                len: 4
+            })
+        );
+        vm.program_counter = 4;
+        assert_eq!(vm.run_once(), Some(ExitReason::Halted));
+        assert_eq!(vm.error(), None);
     }
 
     #[test]
-    fn test_opcode_lte_less_true() {
+    fn test_run_outcome() {
         let mut vm = VM::new();
-        vm.registers[0] = 5;
-        vm.registers[1] = 6;
-        vm.program = vec![14, 0, 1, 0]; // LTE $0 $1
-        vm.run_once();
-        assert!(vm.equal_flag);
+        vm.program = prepend_header(vec![18, 0, 0, 0]); // INC $0
+        vm.program.extend_from_slice(&[5, 0, 0, 0]); // HLT
+        let outcome = vm.run();
+        assert_eq!(outcome.exit, ExitReason::Halted);
+        assert_eq!(outcome.instructions, 2);
+        assert_eq!(outcome.fuel_used, 2);
+        assert_eq!(outcome.trap, None);
     }
 
     #[test]
-    fn test_opcode_lte_equal_true() {
+    fn test_run_outcome_trap() {
         let mut vm = VM::new();
-        vm.registers[0] = 6;
-        vm.registers[1] = 6;
-        vm.program = vec![14, 0, 1, 0]; // LTE $0 $1
-        vm.run_once();
-        assert!(vm.equal_flag);
+        vm.program = prepend_header(vec![18, 0, 0, 0]); // INC $0
+        vm.program.extend_from_slice(&[255, 0, 0, 0]);
+        let outcome = vm.run();
+        assert_eq!(outcome.exit, ExitReason::IllegalOpcode);
+        assert_eq!(
+            outcome.trap,
+            Some(TrapInfo {
+                pc: 68,
+                opcode: Some(Opcode::IGL)
+            })
+        );
     }
 
     #[test]
-    fn test_opcode_lte_false() {
+    fn test_run_outcome_invalid_header() {
         let mut vm = VM::new();
-        vm.registers[0] = 4;
-        vm.registers[1] = 2;
-        vm.program = vec![14, 0, 1, 0]; // LTE $0 $1
-        vm.run_once();
-        assert!(!vm.equal_flag);
+        vm.program = vec![5, 0, 0, 0];
+        let outcome = vm.run();
+        assert_eq!(outcome.exit, ExitReason::InvalidHeader);
+        assert_eq!(outcome.instructions, 0);
     }
 
     #[test]
-    fn test_opcode_jeq() {
+    fn test_run_outcome_fuel_used_follows_cost_model() {
         let mut vm = VM::new();
-        vm.registers[2] = 4;
-        vm.equal_flag = true;
-        vm.program = vec![15, 2, 0, 0]; // JEQ $0
-        vm.run_once();
-        assert_eq!(vm.program_counter, 4);
+        vm.set_cost_model(CostModel::weighted());
+        vm.program = prepend_header(vec![4, 0, 1, 2]); // DIV $0 $1 $2
+        vm.registers[1] = 1;
+        let outcome = vm.run();
+        assert_eq!(outcome.exit, ExitReason::EndOfProgram);
+        assert_eq!(outcome.instructions, 1);
+        assert_eq!(outcome.fuel_used, 10);
     }
 
     #[test]
-    fn test_opcode_jneq() {
+    fn test_cancel() {
         let mut vm = VM::new();
-        vm.registers[2] = 4;
-        vm.equal_flag = false;
-        vm.program = vec![16, 2, 0, 0]; // JEQ $0
-        vm.run_once();
-        assert_eq!(vm.program_counter, 4);
+        let cancel = Arc::new(AtomicBool::new(false));
+        vm.set_cancel_flag(Arc::clone(&cancel));
+        vm.program = vec![18, 0, 0, 0, 18, 0, 0, 0]; // INC $0 (x2)
+        assert_eq!(vm.run_once(), None);
+        cancel.store(true, Ordering::Relaxed);
+        assert_eq!(vm.run_once(), Some(ExitReason::Cancelled));
+        assert_eq!(vm.registers[0], 1);
     }
 
     #[test]
-    fn test_opcode_aloc_on_empty_heap() {
+    fn test_run_once_exit_reasons() {
         let mut vm = VM::new();
-        vm.registers[0] = 1024;
-        vm.program = vec![17, 0, 0, 0]; // ALOC $0
-        vm.run_once();
-        assert_eq!(vm.heap.len(), 1024);
+        vm.program = vec![18, 0, 0, 0, 5, 0, 0, 0]; // INC $0, HLT
+        assert_eq!(vm.run_once(), None);
+        assert_eq!(vm.run_once(), Some(ExitReason::Halted));
+
+        let mut vm = VM::new();
+        assert_eq!(vm.run_once(), Some(ExitReason::EndOfProgram));
+
+        let mut vm = VM::new();
+        vm.program = vec![255, 0, 0, 0];
+        assert_eq!(vm.run_once(), Some(ExitReason::IllegalOpcode));
     }
 
     #[test]
-    fn test_opcode_aloc_extend_heap() {
+    fn test_out_of_fuel() {
         let mut vm = VM::new();
-        vm.heap.extend_from_slice(&[0u8; 8]);
-        vm.registers[0] = 1024;
+        vm.set_fuel(2);
+        vm.program = vec![18, 0, 0, 0, 18, 0, 0, 0, 18, 0, 0, 0]; // INC $0 (x3)
+        assert_eq!(vm.run_once(), None);
+        assert_eq!(vm.run_once(), None);
+        assert_eq!(vm.run_once(), Some(ExitReason::OutOfFuel));
+        assert_eq!(vm.registers[0], 2);
+        assert_eq!(vm.fuel(), Some(0));
+    }
+
+    #[test]
+    fn test_heap_limit_exceeded() {
+        let mut vm = VM::new();
+        vm.set_heap_limit(1024);
+        vm.registers[0] = 1025;
         vm.program = vec![17, 0, 0, 0]; // ALOC $0
-        vm.run_once();
-        assert_eq!(vm.heap.len(), 1032);
+        assert_eq!(vm.run_once(), Some(ExitReason::HeapLimitExceeded));
+        assert_eq!(vm.heap_size(), 0);
     }
 
     #[test]
-    fn test_opcode_inc() {
+    fn test_trace() {
         let mut vm = VM::new();
-        println!("=>> {}", vm.program_counter);
-        vm.registers[0] = 1024;
-        vm.program = vec![18, 0, 0, 0]; // INC $0
+        vm.enable_trace();
+        vm.program = vec![18, 0, 0, 0, 5, 0, 0, 0]; // INC $0, HLT
         vm.run_once();
-        println!("{:?}", vm.registers);
-        assert_eq!(vm.registers[0], 1025);
+        vm.run_once();
+        assert_eq!(
+            vm.trace(),
+            &[
+                TraceEntry {
+                    pc: 0,
+                    opcode: Opcode::INC
+                },
+                TraceEntry {
+                    pc: 4,
+                    opcode: Opcode::HLT
+                },
+            ]
+        );
+
+        let mut vm = VM::new();
+        vm.enable_trace();
+        vm.set_trace_limit(1);
+        vm.program = vec![18, 0, 0, 0, 5, 0, 0, 0]; // INC $0, HLT
+        vm.run_once();
+        vm.run_once();
+        assert_eq!(vm.trace().len(), 1);
     }
 
     #[test]
-    fn test_opcode_dec() {
+    fn test_timeout() {
         let mut vm = VM::new();
-        vm.registers[0] = 1024;
-        vm.program = vec![19, 0, 0, 0]; // DEC $0
-        vm.run_once();
-        assert_eq!(vm.registers[0], 1023);
+        vm.set_timeout(Duration::from_millis(10));
+        vm.registers[0] = 64;
+        vm.program = prepend_header(vec![6, 0, 0, 0]); // JMP $0
+        vm.run();
+        assert_eq!(vm.run_once(), Some(ExitReason::Timeout));
     }
 
     #[test]
-    fn test_add_program() {
+    fn test_timeout_reads_the_clock_periodically() {
         let mut vm = VM::new();
-        let bytes = vec![19, 0, 0, 0]; // DEC $0
-        vm.add_program(bytes.clone());
-        assert_eq!(vm.program, bytes);
+        vm.program = vec![18, 0, 0, 0, 6, 1, 0, 0]; // INC $0, JMP $1
+        assert_eq!(vm.run_once(), None);
+        vm.set_timeout(Duration::ZERO);
+        let exit = loop {
+            if let Some(exit) = vm.run_once() {
+                break exit;
+            }
+        };
+        assert_eq!(exit, ExitReason::Timeout);
+        assert_eq!(vm.instructions, 1024);
     }
 
     #[test]
-    fn test_extend_program() {
+    fn test_unrepresentable_timeout_never_expires() {
+        let mut vm = VM::new();
+        vm.set_fuel(3);
+        vm.set_timeout(Duration::MAX);
+        vm.registers[0] = 64;
+        vm.program = prepend_header(vec![6, 0, 0, 0]); // JMP $0
+        vm.run();
+        assert_eq!(vm.run_once(), Some(ExitReason::OutOfFuel));
+    }
+
+    #[test]
+    fn test_timeout_is_independent_of_fuel() {
         let mut vm = VM::new();
+        vm.set_fuel(u64::MAX);
+        vm.set_timeout(Duration::ZERO);
         vm.program = vec![18, 0, 0, 0]; // INC $0
-        let bytes = vec![19, 0, 0, 0]; // DEC $0
-        vm.add_program(bytes.clone());
-        assert_eq!(vm.program, vec![18, 0, 0, 0, 19, 0, 0, 0]);
+        assert_eq!(vm.run_once(), Some(ExitReason::Timeout));
+        assert_eq!(vm.registers[0], 0);
+        assert_eq!(vm.fuel(), Some(u64::MAX));
     }
 
     #[test]
-    fn test_valid_header_true() {
+    fn test_cost_model() {
+        let mut costs = CostModel::uniform(1);
+        costs.set_cost(Opcode::MUL, 3);
         let mut vm = VM::new();
-        let mut header = [0u8; 64];
-        header[..4].copy_from_slice(&PIE_HEADER_PREFIX);
-        let mut program = header.to_vec();
-        program.append(&mut vec![18, 0, 0, 0, 19, 0, 0, 0]);
-        vm.program = program;
-        assert!(vm.has_valid_header());
+        vm.set_cost_model(costs);
+        vm.set_fuel(4);
+        vm.program = vec![3, 0, 1, 2, 3, 0, 1, 2]; // MUL $0 $1 $2 (x2)
+        assert_eq!(vm.run_once(), None);
+        assert_eq!(vm.fuel(), Some(1));
+        assert_eq!(vm.run_once(), Some(ExitReason::OutOfFuel));
+        assert_eq!(vm.program_counter, 4);
     }
 
     #[test]
-    fn test_valid_header_false() {
+    fn test_cost_model_size_cost() {
+        let mut costs = CostModel::uniform(1);
+        costs.set_kib_cost(2);
         let mut vm = VM::new();
-        let header = [0u8; 64];
-        let mut program = header.to_vec();
-        program.append(&mut vec![18, 0, 0, 0, 19, 0, 0, 0]);
-        vm.program = program;
-        assert!(!vm.has_valid_header());
+        vm.set_cost_model(costs);
+        vm.set_fuel(6);
+        vm.registers[0] = 2048;
+        vm.program = vec![17, 0, 0, 0, 17, 0, 0, 0]; // ALOC $0 (x2)
+        assert_eq!(vm.run_once(), None);
+        assert_eq!(vm.fuel(), Some(1));
+        assert_eq!(vm.run_once(), Some(ExitReason::OutOfFuel));
+        assert_eq!(vm.heap_size(), 2048);
     }
 }